@@ -102,7 +102,7 @@ pub struct Registration {
     /// Whether the application service wants to receive ephemeral data.
     ///
     /// Defaults to `false`.
-    #[serde(default)]
+    #[serde(default, alias = "de.sorunome.msc2409.push_ephemeral")]
     pub receive_ephemeral: bool,
 
     /// Whether the application service wants to do device management, as part of MSC4190.
@@ -110,6 +110,13 @@ pub struct Registration {
     /// Defaults to `false`
     #[serde(default, rename = "io.element.msc4190")]
     pub device_management: bool,
+
+    /// Whether the application service wants device list updates, one-time key counts and
+    /// unused fallback key types of its users' devices in transactions, as part of MSC3202.
+    ///
+    /// Defaults to `false`
+    #[serde(default, rename = "org.matrix.msc3202")]
+    pub transaction_extensions: bool,
 }
 
 impl Registration {
@@ -7,11 +7,17 @@
 //!
 //! [spec]: https://spec.matrix.org/latest/application-service-api/#put_matrixappv1transactionstxnid
 use std::borrow::Cow;
+#[cfg(feature = "unstable-msc3202")]
+use std::collections::BTreeMap;
 
 use reqwest::Url;
 use salvo::oapi::ToSchema;
 use serde::{Deserialize, Deserializer, Serialize};
 
+#[cfg(feature = "unstable-msc3202")]
+use crate::DeviceKeyAlgorithm;
+#[cfg(feature = "unstable-msc4203")]
+use crate::UserId;
 use crate::events::AnyTimelineEvent;
 use crate::events::presence::PresenceEvent;
 use crate::events::receipt::ReceiptEvent;
@@ -19,11 +25,11 @@ use crate::events::typing::TypingEvent;
 #[cfg(feature = "unstable-msc4203")]
 use crate::events::{AnyToDeviceEvent, AnyToDeviceEventContent, ToDeviceEventType};
 use crate::sending::{SendRequest, SendResult};
-use crate::serde::{JsonObject, JsonValue, RawJson, RawJsonValue, from_raw_json_value};
 #[cfg(feature = "unstable-msc4203")]
 use crate::serde::JsonCastable;
-#[cfg(feature = "unstable-msc4203")]
-use crate::{OwnedDeviceId, OwnedUserId, UserId};
+use crate::serde::{JsonObject, JsonValue, RawJson, RawJsonValue, from_raw_json_value};
+#[cfg(any(feature = "unstable-msc3202", feature = "unstable-msc4203"))]
+use crate::{OwnedDeviceId, OwnedUserId};
 
 // /// `PUT /_matrix/app/*/transactions/{txn_id}`
 // ///
@@ -62,41 +68,47 @@ pub struct PushEventsReqBody {
 
     /// A list of events.
     pub events: Vec<RawJson<AnyTimelineEvent>>,
-    // /// Information on E2E device updates.
-    // #[serde(
-    //     default,
-    //     skip_serializing_if = "DeviceLists::is_empty",
-    //     rename = "org.matrix.msc3202.device_lists"
-    // )]
-    // pub device_lists: DeviceLists,
-
-    // /// The number of unclaimed one-time keys currently held on the server for this device, for
-    // /// each algorithm.
-    // #[serde(
-    //     default,
-    //     skip_serializing_if = "BTreeMap::is_empty",
-    //     rename = "org.matrix.msc3202.device_one_time_keys_count"
-    // )]
-    // pub device_one_time_keys_count: BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId,
-    // BTreeMap<DeviceKeyAlgorithm, u64>>>,
-
-    // /// A list of key algorithms for which the server has an unused fallback key for the
-    // /// device.
-    // #[serde(
-    //     default,
-    //     skip_serializing_if = "BTreeMap::is_empty",
-    //     rename = "org.matrix.msc3202.device_unused_fallback_key_types"
-    // )]
-    // pub device_unused_fallback_key_types: BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId,
-    // Vec<DeviceKeyAlgorithm>>>,
-
-    // /// A list of EDUs.
-    // #[serde(
-    //     default,
-    //     skip_serializing_if = "<[_]>::is_empty",
-    //     rename = "de.sorunome.msc2409.ephemeral"
-    // )]
-    // pub ephemeral: Vec<Edu>,
+
+    /// Information on E2E device updates.
+    #[cfg(feature = "unstable-msc3202")]
+    #[serde(
+        default,
+        skip_serializing_if = "DeviceLists::is_empty",
+        rename = "org.matrix.msc3202.device_lists"
+    )]
+    pub device_lists: DeviceLists,
+
+    /// The number of unclaimed one-time keys currently held on the server for this device, for
+    /// each algorithm.
+    #[cfg(feature = "unstable-msc3202")]
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        rename = "org.matrix.msc3202.device_one_time_keys_count"
+    )]
+    pub device_one_time_keys_count:
+        BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, BTreeMap<DeviceKeyAlgorithm, u64>>>,
+
+    /// A list of key algorithms for which the server has an unused fallback key for the
+    /// device.
+    #[cfg(feature = "unstable-msc3202")]
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        rename = "org.matrix.msc3202.device_unused_fallback_key_types"
+    )]
+    pub device_unused_fallback_key_types:
+        BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, Vec<DeviceKeyAlgorithm>>>,
+
+    /// A list of ephemeral data.
+    #[serde(
+        default,
+        skip_serializing_if = "<[_]>::is_empty",
+        rename = "de.sorunome.msc2409.ephemeral"
+    )]
+    #[salvo(schema(value_type = Vec<Object>))]
+    pub ephemeral: Vec<EphemeralData>,
+
     /// A list of to-device messages.
     #[cfg(feature = "unstable-msc4203")]
     #[serde(
@@ -109,7 +121,7 @@ pub struct PushEventsReqBody {
 crate::json_body_modifier!(PushEventsReqBody);
 
 /// Information on E2E device updates.
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(ToSchema, Clone, Debug, Default, Deserialize, Serialize)]
 #[cfg(feature = "unstable-msc3202")]
 pub struct DeviceLists {
     /// List of users who have updated their device identity keys or who now
@@ -110,9 +110,10 @@ pub struct KeysResBody {
 pub struct UploadKeysReqBody {
     /// Identity keys for the device.
     ///
-    /// May be absent if no new identity keys are required.
+    /// May be absent if no new identity keys are required. Kept raw, as the
+    /// signature covers fields `DeviceKeys` doesn't know about.
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub device_keys: Option<DeviceKeys>,
+    pub device_keys: Option<RawJson<DeviceKeys>>,
 
     /// One-time public keys for "pre-key" messages.
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
@@ -323,6 +324,15 @@ pub struct Failure {
     /// Human-readable error message.
     error: String,
 }
+impl Failure {
+    /// Creates a new `Failure` with the given error code and message.
+    pub fn new(errcode: FailureErrorCode, error: impl Into<String>) -> Self {
+        Self {
+            errcode,
+            error: error.into(),
+        }
+    }
+}
 
 /// Error code for signed key processing failures.
 #[doc = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/doc/string_enum.md"))]
@@ -333,6 +343,9 @@ pub enum FailureErrorCode {
     /// The signature is invalid.
     InvalidSignature,
 
+    /// A required parameter, such as the signatures of the key, is missing.
+    MissingParam,
+
     #[doc(hidden)]
     #[salvo(schema(skip))]
     _Custom(PrivOwnedStr),
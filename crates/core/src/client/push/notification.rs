@@ -22,8 +22,8 @@ use crate::{OwnedRoomId, UnixMillis};
 //     }
 // };
 
-#[derive(ToSchema, Default, Serialize, Deserialize)]
-pub struct NotificationsReqBody {
+#[derive(ToParameters, Default, Serialize, Deserialize, Debug)]
+pub struct NotificationsReqArgs {
     /// Pagination token given to retrieve the next set of events.
     #[salvo(parameter(parameter_in = Query))]
     #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -7,7 +7,7 @@ use std::collections::BTreeMap;
 use salvo::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::serde::{Base64, StringEnum};
+use crate::serde::{Base64, JsonValue, StringEnum};
 use crate::{EventEncryptionAlgorithm, OwnedDeviceId, OwnedDeviceKeyId, OwnedUserId, PrivOwnedStr};
 
 /// Identity keys for a device.
@@ -37,6 +37,12 @@ pub struct DeviceKeys {
     /// servers, and not covered by the signatures.
     #[serde(default, skip_serializing_if = "UnsignedDeviceInfo::is_empty")]
     pub unsigned: UnsignedDeviceInfo,
+
+    /// Other fields, such as `dehydrated`, which are covered by the signatures
+    /// and so must be served back as they were uploaded.
+    #[serde(flatten)]
+    #[salvo(schema(value_type = Object, additional_properties = true))]
+    pub other: BTreeMap<String, JsonValue>,
 }
 
 impl DeviceKeys {
@@ -56,6 +62,7 @@ impl DeviceKeys {
             keys,
             signatures,
             unsigned: Default::default(),
+            other: BTreeMap::new(),
         }
     }
 }
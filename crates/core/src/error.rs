@@ -17,7 +17,8 @@ mod kind;
 /// Separate module because it's a lot of code.
 mod kind_serde;
 pub use kind::*;
-use kind_serde::{ErrorCode, RetryAfter};
+use kind_serde::ErrorCode;
+pub use kind_serde::RetryAfter;
 
 use crate::{MatrixVersion, RoomVersionId};
 
@@ -217,6 +218,22 @@ impl Scribe for MatrixError {
         };
 
         let Self { kind, mut body, .. } = self;
+        if let ErrorKind::LimitExceeded {
+            retry_after: Some(retry_after),
+        } = &kind
+        {
+            if let Ok(value) = http::HeaderValue::try_from(retry_after) {
+                res.add_header(header::RETRY_AFTER, value, true).ok();
+            }
+            if let RetryAfter::Delay(duration) = retry_after {
+                body.0.insert(
+                    "retry_after_ms".to_owned(),
+                    u64::try_from(duration.as_millis())
+                        .unwrap_or(u64::MAX)
+                        .into(),
+                );
+            }
+        }
         body.0
             .insert("errcode".to_owned(), kind.code().to_string().into());
 
@@ -13,6 +13,7 @@ use serde::{Deserialize, Serialize, Serializer};
 use crate::directory::{PublicRoomFilter, QueryCriteria, RoomNetwork, Server};
 use crate::federation::discovery::ServerSigningKeys;
 use crate::sending::{SendRequest, SendResult};
+use crate::serde::RawJson;
 use crate::{OwnedServerName, OwnedServerSigningKeyId, UnixMillis};
 
 // /// `POST /_matrix/federation/*/publicRooms`
@@ -138,11 +139,11 @@ crate::json_body_modifier!(RemoteServerKeysBatchReqBody);
 
 pub struct RemoteServerKeysBatchResBody {
     /// The queried server's keys, signed by the notary server.
-    pub server_keys: Vec<ServerSigningKeys>,
+    pub server_keys: Vec<RawJson<ServerSigningKeys>>,
 }
 impl RemoteServerKeysBatchResBody {
     /// Creates a new `Response` with the given keys.
-    pub fn new(server_keys: Vec<ServerSigningKeys>) -> Self {
+    pub fn new(server_keys: Vec<RawJson<ServerSigningKeys>>) -> Self {
         Self { server_keys }
     }
 }
@@ -197,11 +198,11 @@ pub struct RemoteServerKeysReqArgs {
 
 pub struct RemoteServerKeysResBody {
     /// The queried server's keys, signed by the notary server.
-    pub server_keys: Vec<ServerSigningKeys>,
+    pub server_keys: Vec<RawJson<ServerSigningKeys>>,
 }
 impl RemoteServerKeysResBody {
     /// Creates a new `Response` with the given keys.
-    pub fn new(server_keys: Vec<ServerSigningKeys>) -> Self {
+    pub fn new(server_keys: Vec<RawJson<ServerSigningKeys>>) -> Self {
         Self { server_keys }
     }
 }
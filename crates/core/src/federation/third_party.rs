@@ -8,14 +8,15 @@
 /// `/v1/` ([spec])
 ///
 /// [spec]: https://spec.matrix.org/latest/server-server-api/#put_matrixfederationv13pidonbind
-use std::collections::BTreeMap;
-
+use reqwest::Url;
 use salvo::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use crate::events::StateEventType;
+use crate::events::room::member::{RoomMemberEventContent, SignedContent};
+use crate::sending::{SendRequest, SendResult};
 use crate::third_party::Medium;
-use crate::{OwnedRoomId, OwnedServerName, OwnedServerSigningKeyId, OwnedUserId};
+use crate::{OwnedRoomId, OwnedUserId};
 // const METADATA: Metadata = metadata! {
 //     method: PUT,
 //     rate_limited: false,
@@ -67,7 +68,7 @@ pub struct ThirdPartyInvite {
     pub sender: OwnedUserId,
 
     /// Signature from the identity server using a long-term private key.
-    pub signed: BTreeMap<OwnedServerName, BTreeMap<OwnedServerSigningKeyId, String>>,
+    pub signed: SignedContent,
 }
 
 impl ThirdPartyInvite {
@@ -77,7 +78,7 @@ impl ThirdPartyInvite {
         mxid: OwnedUserId,
         room_id: OwnedRoomId,
         sender: OwnedUserId,
-        signed: BTreeMap<OwnedServerName, BTreeMap<OwnedServerSigningKeyId, String>>,
+        signed: SignedContent,
     ) -> Self {
         Self {
             medium: Medium::Email,
@@ -110,8 +111,19 @@ impl ThirdPartyInvite {
 //     }
 // };
 
+pub fn exchange_invite_request(
+    origin: &str,
+    body: ExchangeInviteReqBody,
+) -> SendResult<SendRequest> {
+    let url = Url::parse(&format!(
+        "{origin}/_matrix/federation/v1/exchange_third_party_invite/{}",
+        body.room_id
+    ))?;
+    crate::sending::put(url).stuff(body)
+}
+
 /// Request type for the `exchange_invite` endpoint.
-#[derive(ToSchema, Deserialize, Debug)]
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
 pub struct ExchangeInviteReqBody {
     /// The room ID to exchange a third party invite in.
     #[salvo(parameter(parameter_in = Path))]
@@ -129,16 +141,19 @@ pub struct ExchangeInviteReqBody {
     /// The user ID of the invited user.
     pub state_key: OwnedUserId,
 
-    /// The content of the invite event.
-    pub content: ThirdPartyInvite,
+    /// The content of the invite event, with the signed `third_party_invite`
+    /// of the invited user.
+    pub content: RoomMemberEventContent,
 }
+crate::json_body_modifier!(ExchangeInviteReqBody);
+
 impl ExchangeInviteReqBody {
     /// Creates a new `Request` for a third party invite exchange
     pub fn new(
         room_id: OwnedRoomId,
         sender: OwnedUserId,
         state_key: OwnedUserId,
-        content: ThirdPartyInvite,
+        content: RoomMemberEventContent,
     ) -> Self {
         Self {
             room_id,
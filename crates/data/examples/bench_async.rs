@@ -0,0 +1,102 @@
+//! Compares `connect()` and `connect_async()` while slow queries run on a small
+//! multi-threaded runtime, by measuring how late a 5ms timer task wakes up.
+//!
+//! ```sh
+//! DATABASE_URL=postgres://postgres@localhost/palpo_bench cargo run -p palpo-data --example bench_async
+//! ```
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use diesel::prelude::*;
+use palpo_data::DbConfig;
+
+const TASKS: usize = 16;
+const QUERIES_PER_TASK: usize = 10;
+
+fn stats(mut v: Vec<f64>) -> String {
+    v.sort_by(|a, b| a.total_cmp(b));
+    let p = |q: f64| v[((v.len() as f64 - 1.0) * q) as usize];
+    format!(
+        "ticks={} p50={:.1}ms p99={:.1}ms max={:.1}ms",
+        v.len(),
+        p(0.5),
+        p(0.99),
+        p(1.0)
+    )
+}
+
+async fn run(async_mode: bool) {
+    let stop = Arc::new(AtomicBool::new(false));
+    let ticker = tokio::spawn({
+        let stop = stop.clone();
+        async move {
+            let mut lateness = Vec::new();
+            while !stop.load(Ordering::Relaxed) {
+                let start = Instant::now();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                lateness.push((start.elapsed().as_secs_f64() * 1000.0 - 5.0).max(0.0));
+            }
+            lateness
+        }
+    });
+
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for _ in 0..TASKS {
+        tasks.push(tokio::spawn(async move {
+            for _ in 0..QUERIES_PER_TASK {
+                if async_mode {
+                    palpo_data::connect_async(|conn| {
+                        diesel::sql_query("SELECT pg_sleep(0.05)").execute(conn)?;
+                        Ok(())
+                    })
+                    .await
+                    .unwrap();
+                } else {
+                    let mut conn = palpo_data::connect().unwrap();
+                    diesel::sql_query("SELECT pg_sleep(0.05)")
+                        .execute(&mut conn)
+                        .unwrap();
+                }
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let wall = start.elapsed();
+    stop.store(true, Ordering::Relaxed);
+    let lateness = ticker.await.unwrap();
+    println!(
+        "{}: wall={wall:?} ticker lateness {}",
+        if async_mode {
+            "connect_async"
+        } else {
+            "connect (blocking)"
+        },
+        stats(lateness)
+    );
+}
+
+fn main() {
+    let url = std::env::var("DATABASE_URL").expect("DATABASE_URL should be set");
+    let config: DbConfig = serde_json::from_value(serde_json::json!({
+        "url": url,
+        "pool_size": TASKS,
+    }))
+    .unwrap();
+    palpo_data::init(&config);
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        for _ in 0..2 {
+            run(false).await;
+            run(true).await;
+        }
+    });
+}
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS event_push_actions_user_id_event_sn_idx;
+ALTER TABLE event_push_actions DROP COLUMN IF EXISTS is_read;
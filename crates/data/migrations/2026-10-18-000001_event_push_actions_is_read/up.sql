@@ -0,0 +1,6 @@
+-- Keep push actions around after they are read so they can be listed by
+-- /notifications; read receipts now mark them instead of deleting them.
+ALTER TABLE event_push_actions ADD COLUMN is_read BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX IF NOT EXISTS event_push_actions_user_id_event_sn_idx
+    ON event_push_actions (user_id, event_sn);
@@ -0,0 +1 @@
+ALTER TABLE appservice_registrations DROP COLUMN IF EXISTS transaction_extensions;
@@ -0,0 +1,4 @@
+-- Opt-in for the MSC3202 device list, one-time key count and fallback key
+-- fields in appservice transactions.
+ALTER TABLE appservice_registrations
+    ADD COLUMN IF NOT EXISTS transaction_extensions BOOLEAN NOT NULL DEFAULT FALSE;
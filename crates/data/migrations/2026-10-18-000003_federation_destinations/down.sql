@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS federation_destination_rooms_event_sn_idx;
+DROP TABLE IF EXISTS federation_destination_rooms;
+DROP TABLE IF EXISTS federation_destinations;
@@ -0,0 +1,25 @@
+-- Retry state of federation destinations, kept across restarts.
+CREATE TABLE IF NOT EXISTS federation_destinations (
+    server_id TEXT NOT NULL PRIMARY KEY,
+    -- Number of consecutive failed transactions.
+    failure_count BIGINT NOT NULL DEFAULT 0,
+    -- When the current run of failures started, NULL if the destination is up.
+    failure_ts BIGINT,
+    retry_last_ts BIGINT NOT NULL DEFAULT 0,
+    retry_interval BIGINT NOT NULL DEFAULT 0,
+    -- Event sn of the latest PDU the destination acknowledged.
+    last_successful_sn BIGINT
+);
+
+-- The latest PDU of each room that should have been sent to a destination,
+-- used to catch the destination up after it comes back.
+CREATE TABLE IF NOT EXISTS federation_destination_rooms (
+    server_id TEXT NOT NULL,
+    room_id TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    event_sn BIGINT NOT NULL,
+    PRIMARY KEY (server_id, room_id)
+);
+
+CREATE INDEX IF NOT EXISTS federation_destination_rooms_event_sn_idx
+    ON federation_destination_rooms (server_id, event_sn);
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS scheduled_tasks_resource_id_idx;
+DROP INDEX IF EXISTS scheduled_tasks_status_idx;
+DROP TABLE IF EXISTS scheduled_tasks;
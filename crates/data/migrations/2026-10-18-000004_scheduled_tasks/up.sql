@@ -0,0 +1,21 @@
+-- Long running admin operations, run in the background and resumed after a restart.
+CREATE TABLE IF NOT EXISTS scheduled_tasks (
+    id TEXT NOT NULL PRIMARY KEY,
+    action TEXT NOT NULL,
+    -- One of scheduled, active, complete or failed.
+    status TEXT NOT NULL,
+    -- When the task was scheduled, or last changed status.
+    timestamp_ms BIGINT NOT NULL,
+    resource_id TEXT,
+    params JSONB,
+    progress JSONB,
+    result JSONB,
+    error TEXT,
+    -- The process running an active task and until when it holds the task.
+    -- Other processes only take over active tasks whose lease expired.
+    owner TEXT,
+    lease_until_ms BIGINT
+);
+
+CREATE INDEX IF NOT EXISTS scheduled_tasks_status_idx ON scheduled_tasks (status);
+CREATE INDEX IF NOT EXISTS scheduled_tasks_resource_id_idx ON scheduled_tasks (action, resource_id);
@@ -0,0 +1 @@
+DROP INDEX IF EXISTS events_room_id_sn_idx;
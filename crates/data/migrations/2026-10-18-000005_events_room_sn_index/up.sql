@@ -0,0 +1,2 @@
+-- Lets room purges select the oldest events of a room without a full scan.
+CREATE INDEX IF NOT EXISTS events_room_id_sn_idx ON events (room_id, sn);
@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS sliding_sync_connections;
+DROP TABLE IF EXISTS lazy_load_waitings;
+ALTER TABLE user_uiaa_datas DROP COLUMN IF EXISTS created_at;
+ALTER TABLE user_uiaa_datas DROP COLUMN IF EXISTS request;
@@ -0,0 +1,30 @@
+-- State that used to be kept in memory by each process, shared through the
+-- database so several processes can serve the same clients.
+ALTER TABLE user_uiaa_datas ADD COLUMN IF NOT EXISTS request JSON;
+-- Existing sessions are counted from now, so they don't expire right away.
+ALTER TABLE user_uiaa_datas ADD COLUMN IF NOT EXISTS created_at BIGINT NOT NULL
+    DEFAULT (EXTRACT(EPOCH FROM now()) * 1000)::BIGINT;
+
+-- Members sent lazily in a sync response, until the client confirms it got it
+-- by syncing from `until_sn`.
+CREATE TABLE IF NOT EXISTS lazy_load_waitings (
+    id BIGSERIAL NOT NULL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    room_id TEXT NOT NULL,
+    until_sn BIGINT NOT NULL,
+    lazy_load_user_ids JSON NOT NULL,
+    created_at BIGINT NOT NULL,
+    CONSTRAINT lazy_load_waitings_udx UNIQUE (user_id, device_id, room_id, until_sn)
+);
+
+-- Sticky parameters and known rooms of sliding sync connections.
+CREATE TABLE IF NOT EXISTS sliding_sync_connections (
+    id BIGSERIAL NOT NULL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    conn_id TEXT NOT NULL DEFAULT '',
+    cache JSON NOT NULL,
+    updated_at BIGINT NOT NULL,
+    CONSTRAINT sliding_sync_connections_udx UNIQUE (user_id, device_id, conn_id)
+);
@@ -0,0 +1 @@
+ALTER TABLE server_signing_keys DROP COLUMN IF EXISTS signed_keys;
@@ -0,0 +1,3 @@
+-- The latest keys of each server as signed by the server itself, served to
+-- the servers using this one as a notary.
+ALTER TABLE server_signing_keys ADD COLUMN IF NOT EXISTS signed_keys JSON;
@@ -0,0 +1 @@
+ALTER TABLE threepid_validation_sessions DROP COLUMN IF EXISTS purpose;
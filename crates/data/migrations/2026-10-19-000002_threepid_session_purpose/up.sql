@@ -0,0 +1,4 @@
+-- Sessions started before the purpose was recorded can not be matched to
+-- the request they were meant for, drop them.
+DELETE FROM threepid_validation_sessions;
+ALTER TABLE threepid_validation_sessions ADD COLUMN purpose text NOT NULL;
@@ -0,0 +1 @@
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS purged_depth bigint NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+DROP INDEX IF EXISTS threepid_id_servers_udx;
@@ -0,0 +1,10 @@
+-- Keep one row per binding before enforcing it.
+DELETE FROM threepid_id_servers a
+    USING threepid_id_servers b
+    WHERE a.id > b.id
+        AND a.user_id = b.user_id
+        AND a.medium = b.medium
+        AND a.address = b.address
+        AND a.id_server = b.id_server;
+CREATE UNIQUE INDEX IF NOT EXISTS threepid_id_servers_udx
+    ON threepid_id_servers (user_id, medium, address, id_server);
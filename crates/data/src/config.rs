@@ -69,6 +69,19 @@ pub struct DbConfig {
     /// Whether to enforce that all the database connections are encrypted with TLS.
     #[serde(default = "default_false")]
     pub enforce_tls: bool,
+    /// An optional read-only replica used for heavy read queries.
+    #[serde(default)]
+    pub replica: Option<DbReplicaConfig>,
+}
+
+/// Settings for the read-only replica database. Timeouts and TLS settings are
+/// shared with the primary database.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DbReplicaConfig {
+    pub url: String,
+    #[serde(default = "default_db_pool_size")]
+    pub pool_size: u32,
+    pub min_idle: Option<u32>,
 }
 
 impl fmt::Display for DbConfig {
@@ -102,7 +115,7 @@ impl fmt::Display for DbConfig {
 #[derive(Debug, Clone, Copy)]
 pub struct ConnectionConfig {
     pub statement_timeout: u64,
-    // pub read_only: bool,
+    pub read_only: bool,
 }
 
 impl CustomizeConnection<PgConnection, r2d2::Error> for ConnectionConfig {
@@ -120,11 +133,11 @@ impl CustomizeConnection<PgConnection, r2d2::Error> for ConnectionConfig {
         sql_query(format!("SET statement_timeout = {}", timeout))
             .execute(conn)
             .map_err(r2d2::Error::QueryError)?;
-        // if self.read_only {
-        //     sql_query("SET default_transaction_read_only = 't'")
-        //         .execute(conn)
-        //         .map_err(r2d2::Error::QueryError)?;
-        // }
+        if self.read_only {
+            sql_query("SET default_transaction_read_only = 't'")
+                .execute(conn)
+                .map_err(r2d2::Error::QueryError)?;
+        }
         Ok(())
     }
 }
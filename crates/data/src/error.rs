@@ -53,6 +53,8 @@ pub enum DataError {
     ImageError(#[from] image::ImageError),
     #[error("Signatures: `{0}`")]
     Signatures(#[from] palpo_core::signatures::Error),
+    #[error("blocking task: `{0}`")]
+    Join(#[from] tokio::task::JoinError),
 }
 
 impl DataError {
@@ -1,5 +1,6 @@
-use std::sync::{Arc, OnceLock};
-use std::time::Duration;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
 use diesel::prelude::*;
 use diesel::r2d2::{self, State};
@@ -13,7 +14,7 @@ mod macros;
 mod config;
 pub use palpo_core as core;
 
-pub use crate::config::DbConfig;
+pub use crate::config::{DbConfig, DbReplicaConfig};
 
 pub mod full_text_search;
 
@@ -23,6 +24,7 @@ pub use pool::{DieselPool, PgPooledConnection, PoolError};
 pub mod media;
 pub mod misc;
 pub mod room;
+pub mod scheduled_task;
 pub mod schema;
 pub mod sending;
 pub mod user;
@@ -37,6 +39,13 @@ pub type DataResult<T> = Result<T, DataError>;
 pub static DIESEL_POOL: OnceLock<DieselPool> = OnceLock::new();
 pub static REPLICA_POOL: OnceLock<Option<DieselPool>> = OnceLock::new();
 
+const REPLICA_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long reads go to the primary after the replica failed.
+const REPLICA_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Until when the replica is not asked for connections.
+static REPLICA_DOWN_UNTIL: Mutex<Option<Instant>> = Mutex::new(None);
+
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
 pub fn init(config: &DbConfig) {
@@ -46,6 +55,7 @@ pub fn init(config: &DbConfig) {
         .connection_timeout(Duration::from_millis(config.connection_timeout))
         .connection_customizer(Box::new(config::ConnectionConfig {
             statement_timeout: config.statement_timeout,
+            read_only: false,
         }))
         .thread_pool(Arc::new(ScheduledThreadPool::new(config.helper_threads)));
 
@@ -53,6 +63,28 @@ pub fn init(config: &DbConfig) {
         DieselPool::new(&config.url, config, builder).expect("diesel pool should be created");
     DIESEL_POOL.set(pool).expect("diesel pool should be set");
     migrate();
+
+    let replica = config.replica.as_ref().and_then(|replica| {
+        let builder = r2d2::Pool::builder()
+            .max_size(replica.pool_size)
+            .min_idle(replica.min_idle)
+            .connection_timeout(Duration::from_millis(config.connection_timeout))
+            .connection_customizer(Box::new(config::ConnectionConfig {
+                statement_timeout: config.statement_timeout,
+                read_only: true,
+            }))
+            .thread_pool(Arc::new(ScheduledThreadPool::new(config.helper_threads)));
+        match DieselPool::new(&replica.url, config, builder) {
+            Ok(pool) => Some(pool),
+            Err(e) => {
+                tracing::error!("replica pool could not be created, reads use the primary: {e}");
+                None
+            }
+        }
+    });
+    REPLICA_POOL
+        .set(replica)
+        .expect("replica pool should be set");
 }
 pub fn migrate() {
     let conn = &mut connect().expect("db connect should worked");
@@ -69,6 +101,73 @@ pub fn connect() -> Result<PgPooledConnection, PoolError> {
         }
     }
 }
+
+/// Gets a connection for read-only queries that can tolerate replication lag.
+///
+/// Uses the replica pool when one is configured, and falls back to the primary
+/// when there is none or it can't hand out a connection within
+/// `REPLICA_CONNECT_TIMEOUT`. After such a failure the replica is left alone
+/// for `REPLICA_BACKOFF`, so reads don't all wait for it in the meantime.
+pub fn connect_read() -> Result<PgPooledConnection, PoolError> {
+    match REPLICA_POOL.get() {
+        Some(Some(pool)) => read_with_fallback(
+            &REPLICA_DOWN_UNTIL,
+            Instant::now(),
+            || pool.get_timeout(REPLICA_CONNECT_TIMEOUT),
+            connect,
+        ),
+        _ => connect(),
+    }
+}
+
+/// Gets the connection from `replica` unless it is in backoff according to
+/// `down_until`, and from `primary` otherwise or when `replica` fails.
+fn read_with_fallback<T, R: Display, E>(
+    down_until: &Mutex<Option<Instant>>,
+    now: Instant,
+    replica: impl FnOnce() -> Result<T, R>,
+    primary: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E> {
+    if down_until
+        .lock()
+        .expect("locking should not fail")
+        .is_some_and(|until| now < until)
+    {
+        return primary();
+    }
+    match replica() {
+        Ok(conn) => Ok(conn),
+        Err(e) => {
+            tracing::warn!(
+                "replica connect error, reads use the primary for {REPLICA_BACKOFF:?}: {e}"
+            );
+            *down_until.lock().expect("locking should not fail") = Some(now + REPLICA_BACKOFF);
+            primary()
+        }
+    }
+}
+
+/// Opens a connection outside of the pools, for long lived sessions such as
+/// the one listening for notifications of other processes.
+pub fn connect_dedicated(config: &DbConfig) -> DataResult<PgConnection> {
+    PgConnection::establish(&connection_url(config, &config.url))
+        .map_err(|e| DataError::internal(format!("db connect error: {e}")))
+}
+
+/// Runs `f` with a pooled connection on the blocking thread pool, so waiting
+/// for a connection or a slow query doesn't stall the async worker threads.
+pub async fn connect_async<F, T>(f: F) -> DataResult<T>
+where
+    F: FnOnce(&mut PgConnection) -> DataResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let mut conn = connect()?;
+        f(&mut conn)
+    })
+    .await?
+}
+
 pub fn state() -> State {
     DIESEL_POOL.get().expect("diesel pool should set").state()
 }
@@ -107,3 +206,56 @@ pub fn curr_sn() -> DataResult<Seqnum> {
         .get_result::<Seqnum>(&mut connect()?)
         .map_err(Into::into)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_from_healthy_replica() {
+        let down_until = Mutex::new(None);
+        let conn = read_with_fallback(
+            &down_until,
+            Instant::now(),
+            || Ok::<_, &str>("replica"),
+            || Ok::<_, ()>("primary"),
+        );
+        assert_eq!(conn, Ok("replica"));
+        assert!(down_until.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn falls_back_to_primary_and_backs_off_from_failed_replica() {
+        let down_until = Mutex::new(None);
+        let now = Instant::now();
+        let conn = read_with_fallback(
+            &down_until,
+            now,
+            || Err("timed out"),
+            || Ok::<_, ()>("primary"),
+        );
+        assert_eq!(conn, Ok("primary"));
+        assert_eq!(*down_until.lock().unwrap(), Some(now + REPLICA_BACKOFF));
+
+        let conn = read_with_fallback(
+            &down_until,
+            now + REPLICA_BACKOFF / 2,
+            || -> Result<_, &str> { panic!("replica should not be asked during its backoff") },
+            || Ok::<_, ()>("primary"),
+        );
+        assert_eq!(conn, Ok("primary"));
+    }
+
+    #[test]
+    fn tries_replica_again_after_backoff() {
+        let now = Instant::now();
+        let down_until = Mutex::new(Some(now + REPLICA_BACKOFF));
+        let conn = read_with_fallback(
+            &down_until,
+            now + REPLICA_BACKOFF,
+            || Ok::<_, &str>("replica"),
+            || Ok::<_, ()>("primary"),
+        );
+        assert_eq!(conn, Ok("replica"));
+    }
+}
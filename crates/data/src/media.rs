@@ -3,7 +3,7 @@ use diesel::prelude::*;
 use crate::core::UnixMillis;
 use crate::core::identifiers::*;
 use crate::schema::*;
-use crate::{DataResult, connect};
+use crate::{DataResult, connect, connect_read};
 
 #[derive(Insertable, Identifiable, Queryable, Debug, Clone)]
 #[diesel(table_name = media_metadatas)]
@@ -98,6 +98,12 @@ pub fn get_thumbnail_by_dimension(
         .map_err(Into::into)
 }
 
+pub fn get_all_thumbnails() -> DataResult<Vec<DbThumbnail>> {
+    media_thumbnails::table
+        .load::<DbThumbnail>(&mut connect()?)
+        .map_err(Into::into)
+}
+
 #[derive(Identifiable, Queryable, Debug, Clone)]
 #[diesel(table_name = media_url_previews)]
 pub struct DbUrlPreview {
@@ -165,7 +171,7 @@ pub fn list_media_by_user(
     let total = media_metadatas::table
         .filter(media_metadatas::created_by.eq(user_id.as_str()))
         .count()
-        .get_result::<i64>(&mut connect()?)?;
+        .get_result::<i64>(&mut connect_read()?)?;
 
     // Build query with ordering
     let direction_desc = direction.map(|d| d == "b").unwrap_or(true);
@@ -215,7 +221,7 @@ pub fn list_media_by_user(
     let media = query
         .offset(from)
         .limit(limit)
-        .load::<DbMetadata>(&mut connect()?)?;
+        .load::<DbMetadata>(&mut connect_read()?)?;
 
     Ok((media, total))
 }
@@ -407,3 +413,13 @@ struct CountResult {
     #[diesel(sql_type = diesel::sql_types::BigInt)]
     count: i64,
 }
+
+/// Total size in bytes of the stored media and thumbnails.
+pub fn total_storage_size() -> DataResult<i64> {
+    diesel::dsl::sql::<diesel::sql_types::BigInt>(
+        "SELECT (SELECT COALESCE(SUM(file_size), 0) FROM media_metadatas)::bigint \
+         + (SELECT COALESCE(SUM(file_size), 0) FROM media_thumbnails)::bigint",
+    )
+    .get_result::<i64>(&mut connect()?)
+    .map_err(Into::into)
+}
@@ -26,6 +26,8 @@ pub struct DbRoom {
     pub has_auth_chain_index: bool,
     pub disabled: bool,
     pub created_at: UnixMillis,
+    /// Events up to this depth were purged and must not be backfilled again.
+    pub purged_depth: i64,
 }
 #[derive(Insertable, Debug, Clone)]
 #[diesel(table_name = rooms)]
@@ -542,3 +544,214 @@ pub fn get_timeline_gaps(
 //         .execute(conn)?;
 //     Ok(())
 // }
+
+/// How many events `purge_room_events` deletes per transaction.
+pub const PURGE_BATCH_SIZE: i64 = 5_000;
+
+/// Deletes the oldest events of a room together with the rows derived from
+/// them, at most `PURGE_BATCH_SIZE` in one transaction so large rooms don't
+/// hold a single huge one. Returns how many events were deleted.
+pub fn purge_room_events(room_id: &RoomId) -> DataResult<usize> {
+    macro_rules! delete_up_to_sn {
+        ($conn:expr, $max_sn:expr, $($table:ident),+ $(,)?) => {
+            $(
+                diesel::delete(
+                    $table::table
+                        .filter($table::room_id.eq(room_id))
+                        .filter($table::event_sn.le($max_sn)),
+                )
+                .execute($conn)?;
+            )+
+        };
+    }
+
+    connect()?.transaction::<_, crate::DataError, _>(|conn| {
+        let batch = events::table
+            .filter(events::room_id.eq(room_id))
+            .order(events::sn.asc())
+            .limit(PURGE_BATCH_SIZE)
+            .select((events::id, events::sn))
+            .load::<(OwnedEventId, Seqnum)>(conn)?;
+        let Some(&(_, max_sn)) = batch.last() else {
+            return Ok(0);
+        };
+        let event_ids = batch.iter().map(|(id, _)| id).collect::<Vec<_>>();
+
+        diesel::delete(event_phases::table.filter(event_phases::event_id.eq_any(&event_ids)))
+            .execute(conn)?;
+        delete_up_to_sn!(
+            conn,
+            max_sn,
+            event_datas,
+            event_edges,
+            event_missings,
+            event_points,
+            event_push_actions,
+            event_relations,
+            event_searches,
+            threads,
+            timeline_gaps,
+        );
+        diesel::delete(
+            events::table
+                .filter(events::room_id.eq(room_id))
+                .filter(events::sn.le(max_sn)),
+        )
+        .execute(conn)?;
+        Ok(batch.len())
+    })
+}
+
+/// Deletes all events, state and per-user data of a room. Blocking it in
+/// `banned_rooms` and event reports about it are kept.
+///
+/// For large rooms, call `purge_room_events` until it returns 0 first.
+pub fn purge_room(room_id: &RoomId) -> DataResult<()> {
+    macro_rules! delete_by_room {
+        ($conn:expr, $($table:ident),+ $(,)?) => {
+            $(
+                diesel::delete($table::table.filter($table::room_id.eq(room_id)))
+                    .execute($conn)?;
+            )+
+        };
+    }
+
+    connect()?.transaction::<_, crate::DataError, _>(|conn| {
+        let event_ids = events::table
+            .filter(events::room_id.eq(room_id))
+            .select(events::id);
+        diesel::delete(event_phases::table.filter(event_phases::event_id.eq_any(event_ids)))
+            .execute(conn)?;
+        delete_by_room!(
+            conn,
+            event_backward_extremities,
+            event_datas,
+            event_edges,
+            event_forward_extremities,
+            event_idempotents,
+            event_missings,
+            event_points,
+            event_push_actions,
+            event_push_summaries,
+            event_receipts,
+            event_relations,
+            event_searches,
+            events,
+            federation_destination_rooms,
+            lazy_load_deliveries,
+            room_aliases,
+            room_joined_servers,
+            room_lookup_servers,
+            room_state_deltas,
+            room_state_frames,
+            room_tags,
+            room_users,
+            stats_room_currents,
+            threads,
+            timeline_gaps,
+            user_datas,
+            user_profiles,
+            e2e_key_changes,
+            e2e_room_keys,
+        );
+        diesel::delete(rooms::table.find(room_id)).execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Which events of a room `purge_history` deletes.
+#[derive(Debug, Clone, Default)]
+pub struct PurgeHistoryFilter {
+    /// Delete events before this sn.
+    pub before_sn: Option<Seqnum>,
+    /// Delete events sent before this timestamp.
+    pub before_ts: Option<i64>,
+    /// Keep the events sent by users of this server.
+    pub keep_server: Option<OwnedServerName>,
+}
+
+/// Deletes up to `PURGE_BATCH_SIZE` old events of a room matching the filter,
+/// with the rows derived from them. State events and the forward extremities
+/// of the room are kept. The depth of the deleted events is recorded as the
+/// purged depth of the room, so they are not backfilled again. Returns how
+/// many events were deleted.
+pub fn purge_history(room_id: &RoomId, filter: &PurgeHistoryFilter) -> DataResult<usize> {
+    macro_rules! delete_by_event {
+        ($conn:expr, $event_ids:expr, $($table:ident),+ $(,)?) => {
+            $(
+                diesel::delete($table::table.filter($table::event_id.eq_any($event_ids)))
+                    .execute($conn)?;
+            )+
+        };
+    }
+
+    connect()?.transaction::<_, crate::DataError, _>(|conn| {
+        let extremities = event_forward_extremities::table
+            .filter(event_forward_extremities::room_id.eq(room_id))
+            .select(event_forward_extremities::event_id);
+        let mut query = events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::state_key.is_null())
+            .filter(events::id.ne_all(extremities))
+            .into_boxed();
+        if let Some(before_sn) = filter.before_sn {
+            query = query.filter(events::sn.lt(before_sn));
+        }
+        if let Some(before_ts) = filter.before_ts {
+            query = query.filter(events::origin_server_ts.lt(before_ts));
+        }
+        if let Some(keep_server) = &filter.keep_server {
+            // Localparts can't contain `:`, the server name is all after the first one.
+            query = query.filter(
+                diesel::dsl::sql::<diesel::sql_types::Bool>(
+                    "substr(events.sender_id, strpos(events.sender_id, ':') + 1) <> ",
+                )
+                .bind::<diesel::sql_types::Text, _>(keep_server.as_str()),
+            );
+        }
+        let (event_ids, depths): (Vec<OwnedEventId>, Vec<i64>) = query
+            .order(events::sn.asc())
+            .limit(PURGE_BATCH_SIZE)
+            .select((events::id, events::depth))
+            .load::<(OwnedEventId, i64)>(conn)?
+            .into_iter()
+            .unzip();
+        let Some(max_depth) = depths.into_iter().max() else {
+            return Ok(0);
+        };
+
+        delete_by_event!(
+            conn,
+            &event_ids,
+            event_datas,
+            event_edges,
+            event_idempotents,
+            event_missings,
+            event_phases,
+            event_points,
+            event_push_actions,
+            event_receipts,
+            event_relations,
+            event_searches,
+            threads,
+            timeline_gaps,
+        );
+        diesel::delete(events::table.filter(events::id.eq_any(&event_ids))).execute(conn)?;
+        diesel::update(rooms::table.find(room_id))
+            .filter(rooms::purged_depth.lt(max_depth))
+            .set(rooms::purged_depth.eq(max_depth))
+            .execute(conn)?;
+        Ok(event_ids.len())
+    })
+}
+
+/// Depth up to which events of the room were purged, 0 if none were.
+pub fn purged_depth(room_id: &RoomId) -> DataResult<i64> {
+    rooms::table
+        .find(room_id)
+        .select(rooms::purged_depth)
+        .first::<i64>(&mut connect()?)
+        .optional()
+        .map(Option::unwrap_or_default)
+        .map_err(Into::into)
+}
@@ -4,7 +4,7 @@ use crate::core::UnixMillis;
 use crate::core::identifiers::*;
 use crate::core::serde::JsonValue;
 use crate::schema::*;
-use crate::{DataResult, connect};
+use crate::{DataResult, connect, connect_read};
 
 /// Database model for event reports
 #[derive(Identifiable, Queryable, Debug, Clone)]
@@ -116,7 +116,7 @@ pub fn list_event_reports(filter: &EventReportFilter) -> DataResult<(Vec<EventRe
     }
 
     // Get total count
-    let total = count_query.count().get_result::<i64>(&mut connect()?)?;
+    let total = count_query.count().get_result::<i64>(&mut connect_read()?)?;
 
     // Apply ordering - default is backwards (newest first)
     let direction_forward = filter
@@ -139,7 +139,7 @@ pub fn list_event_reports(filter: &EventReportFilter) -> DataResult<(Vec<EventRe
     let limit = filter.limit.unwrap_or(100).min(1000);
     query = query.limit(limit);
 
-    let reports = query.load::<DbEventReport>(&mut connect()?)?;
+    let reports = query.load::<DbEventReport>(&mut connect_read()?)?;
 
     Ok((reports.into_iter().map(Into::into).collect(), total))
 }
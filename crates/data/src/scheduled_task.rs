@@ -0,0 +1,219 @@
+use diesel::prelude::*;
+
+use crate::core::UnixMillis;
+use crate::core::serde::JsonValue;
+use crate::schema::*;
+use crate::{DataResult, connect, connect_read};
+
+pub const STATUS_SCHEDULED: &str = "scheduled";
+pub const STATUS_ACTIVE: &str = "active";
+pub const STATUS_COMPLETE: &str = "complete";
+pub const STATUS_FAILED: &str = "failed";
+
+/// Database model for scheduled tasks
+#[derive(Identifiable, Queryable, Insertable, Debug, Clone)]
+#[diesel(table_name = scheduled_tasks)]
+pub struct DbScheduledTask {
+    pub id: String,
+    pub action: String,
+    pub status: String,
+    pub timestamp_ms: i64,
+    pub resource_id: Option<String>,
+    pub params: Option<JsonValue>,
+    pub progress: Option<JsonValue>,
+    pub result: Option<JsonValue>,
+    pub error: Option<String>,
+    /// Process running the task while it is active.
+    pub owner: Option<String>,
+    /// The owner must renew its claim before this, or others take over.
+    pub lease_until_ms: Option<i64>,
+}
+
+impl DbScheduledTask {
+    pub fn new(
+        id: String,
+        action: String,
+        resource_id: Option<String>,
+        params: Option<JsonValue>,
+    ) -> Self {
+        Self {
+            id,
+            action,
+            status: STATUS_SCHEDULED.to_owned(),
+            timestamp_ms: UnixMillis::now().get() as i64,
+            resource_id,
+            params,
+            progress: None,
+            result: None,
+            error: None,
+            owner: None,
+            lease_until_ms: None,
+        }
+    }
+
+    /// Whether the task completed or failed.
+    pub fn is_finished(&self) -> bool {
+        self.status == STATUS_COMPLETE || self.status == STATUS_FAILED
+    }
+}
+
+/// Filter options for listing scheduled tasks
+#[derive(Debug, Clone, Default)]
+pub struct ScheduledTaskFilter {
+    pub action: Option<String>,
+    pub resource_id: Option<String>,
+    pub status: Option<String>,
+    pub max_timestamp: Option<i64>,
+}
+
+/// Insert a new task
+pub fn create_task(task: &DbScheduledTask) -> DataResult<()> {
+    diesel::insert_into(scheduled_tasks::table)
+        .values(task)
+        .execute(&mut connect()?)?;
+    Ok(())
+}
+
+/// Get a single task by ID
+pub fn get_task(id: &str) -> DataResult<Option<DbScheduledTask>> {
+    scheduled_tasks::table
+        .find(id)
+        .first::<DbScheduledTask>(&mut connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+/// List tasks matching the filter, newest first
+pub fn list_tasks(filter: &ScheduledTaskFilter) -> DataResult<Vec<DbScheduledTask>> {
+    let mut query = scheduled_tasks::table.into_boxed();
+    if let Some(ref action) = filter.action {
+        query = query.filter(scheduled_tasks::action.eq(action));
+    }
+    if let Some(ref resource_id) = filter.resource_id {
+        query = query.filter(scheduled_tasks::resource_id.eq(resource_id));
+    }
+    if let Some(ref status) = filter.status {
+        query = query.filter(scheduled_tasks::status.eq(status));
+    }
+    if let Some(max_timestamp) = filter.max_timestamp {
+        query = query.filter(scheduled_tasks::timestamp_ms.le(max_timestamp));
+    }
+    query
+        .order(scheduled_tasks::timestamp_ms.desc())
+        .load::<DbScheduledTask>(&mut connect_read()?)
+        .map_err(Into::into)
+}
+
+/// Get tasks waiting to run and active tasks whose owner stopped renewing
+/// its lease before `now`, oldest first
+pub fn claimable_tasks(now: i64) -> DataResult<Vec<DbScheduledTask>> {
+    scheduled_tasks::table
+        .filter(
+            scheduled_tasks::status
+                .eq(STATUS_SCHEDULED)
+                .or(scheduled_tasks::status.eq(STATUS_ACTIVE).and(
+                    scheduled_tasks::lease_until_ms
+                        .is_null()
+                        .or(scheduled_tasks::lease_until_ms.lt(now)),
+                )),
+        )
+        .order(scheduled_tasks::timestamp_ms.asc())
+        .load::<DbScheduledTask>(&mut connect()?)
+        .map_err(Into::into)
+}
+
+/// Mark a task as run by `owner` until `lease_until` if it waits to run or
+/// its lease expired before `now`, returning it only when this call changed
+/// it. Concurrent runners can't both claim it.
+pub fn claim_task(
+    id: &str,
+    owner: &str,
+    now: i64,
+    lease_until: i64,
+) -> DataResult<Option<DbScheduledTask>> {
+    diesel::update(
+        scheduled_tasks::table.find(id).filter(
+            scheduled_tasks::status
+                .eq(STATUS_SCHEDULED)
+                .or(scheduled_tasks::status.eq(STATUS_ACTIVE).and(
+                    scheduled_tasks::lease_until_ms
+                        .is_null()
+                        .or(scheduled_tasks::lease_until_ms.lt(now)),
+                )),
+        ),
+    )
+    .set((
+        scheduled_tasks::status.eq(STATUS_ACTIVE),
+        scheduled_tasks::timestamp_ms.eq(now),
+        scheduled_tasks::owner.eq(owner),
+        scheduled_tasks::lease_until_ms.eq(lease_until),
+    ))
+    .get_result::<DbScheduledTask>(&mut connect()?)
+    .optional()
+    .map_err(Into::into)
+}
+
+/// Extend the lease of an active task, returning false when `owner` no
+/// longer holds it.
+pub fn renew_task_lease(id: &str, owner: &str, lease_until: i64) -> DataResult<bool> {
+    let updated = diesel::update(
+        scheduled_tasks::table
+            .find(id)
+            .filter(scheduled_tasks::status.eq(STATUS_ACTIVE))
+            .filter(scheduled_tasks::owner.eq(owner)),
+    )
+    .set(scheduled_tasks::lease_until_ms.eq(lease_until))
+    .execute(&mut connect()?)?;
+    Ok(updated > 0)
+}
+
+/// Record the progress of a running task
+pub fn set_task_progress(id: &str, progress: &JsonValue) -> DataResult<()> {
+    diesel::update(scheduled_tasks::table.find(id))
+        .set(scheduled_tasks::progress.eq(progress))
+        .execute(&mut connect()?)?;
+    Ok(())
+}
+
+/// Record the outcome of a task still held by `owner`
+pub fn finish_task(
+    id: &str,
+    owner: &str,
+    result: Result<Option<JsonValue>, String>,
+) -> DataResult<()> {
+    let now = UnixMillis::now().get() as i64;
+    let query = diesel::update(
+        scheduled_tasks::table
+            .find(id)
+            .filter(scheduled_tasks::status.eq(STATUS_ACTIVE))
+            .filter(scheduled_tasks::owner.eq(owner)),
+    );
+    match result {
+        Ok(result) => query
+            .set((
+                scheduled_tasks::status.eq(STATUS_COMPLETE),
+                scheduled_tasks::timestamp_ms.eq(now),
+                scheduled_tasks::result.eq(result),
+            ))
+            .execute(&mut connect()?)?,
+        Err(error) => query
+            .set((
+                scheduled_tasks::status.eq(STATUS_FAILED),
+                scheduled_tasks::timestamp_ms.eq(now),
+                scheduled_tasks::error.eq(error),
+            ))
+            .execute(&mut connect()?)?,
+    };
+    Ok(())
+}
+
+/// Delete finished tasks that last changed before `before_ts`
+pub fn delete_finished_tasks(before_ts: i64) -> DataResult<usize> {
+    diesel::delete(
+        scheduled_tasks::table
+            .filter(scheduled_tasks::status.eq_any([STATUS_COMPLETE, STATUS_FAILED]))
+            .filter(scheduled_tasks::timestamp_ms.lt(before_ts)),
+    )
+    .execute(&mut connect()?)
+    .map_err(Into::into)
+}
@@ -15,6 +15,7 @@ diesel::table! {
         protocols -> Nullable<Json>,
         receive_ephemeral -> Bool,
         device_management -> Bool,
+        transaction_extensions -> Bool,
     }
 }
 
@@ -316,6 +317,7 @@ diesel::table! {
         highlight -> Bool,
         unread -> Bool,
         thread_id -> Nullable<Text>,
+        is_read -> Bool,
     }
 }
 
@@ -429,6 +431,32 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::full_text_search::*;
+
+    federation_destination_rooms (server_id, room_id) {
+        server_id -> Text,
+        room_id -> Text,
+        event_id -> Text,
+        event_sn -> Int8,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::full_text_search::*;
+
+    federation_destinations (server_id) {
+        server_id -> Text,
+        failure_count -> Int8,
+        failure_ts -> Nullable<Int8>,
+        retry_last_ts -> Int8,
+        retry_interval -> Int8,
+        last_successful_sn -> Nullable<Int8>,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use crate::full_text_search::*;
@@ -442,6 +470,21 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::full_text_search::*;
+
+    lazy_load_waitings (id) {
+        id -> Int8,
+        user_id -> Text,
+        device_id -> Text,
+        room_id -> Text,
+        until_sn -> Int8,
+        lazy_load_user_ids -> Json,
+        created_at -> Int8,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use crate::full_text_search::*;
@@ -636,6 +679,7 @@ diesel::table! {
         has_auth_chain_index -> Bool,
         disabled -> Bool,
         created_at -> Int8,
+        purged_depth -> Int8,
     }
 }
 
@@ -648,6 +692,21 @@ diesel::table! {
         key_data -> Json,
         updated_at -> Int8,
         created_at -> Int8,
+        signed_keys -> Nullable<Json>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::full_text_search::*;
+
+    sliding_sync_connections (id) {
+        id -> Int8,
+        user_id -> Text,
+        device_id -> Text,
+        conn_id -> Text,
+        cache -> Json,
+        updated_at -> Int8,
     }
 }
 
@@ -662,6 +721,25 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::full_text_search::*;
+
+    scheduled_tasks (id) {
+        id -> Text,
+        action -> Text,
+        status -> Text,
+        timestamp_ms -> Int8,
+        resource_id -> Nullable<Text>,
+        params -> Nullable<Jsonb>,
+        progress -> Nullable<Jsonb>,
+        result -> Nullable<Jsonb>,
+        error -> Nullable<Text>,
+        owner -> Nullable<Text>,
+        lease_until_ms -> Nullable<Int8>,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use crate::full_text_search::*;
@@ -745,6 +823,7 @@ diesel::table! {
         last_send_attempt -> Int8,
         validated_at -> Nullable<Int8>,
         created_at -> Int8,
+        purpose -> Text,
     }
 }
 
@@ -1047,6 +1126,8 @@ diesel::table! {
         device_id -> Text,
         session -> Text,
         uiaa_info -> Json,
+        request -> Nullable<Json>,
+        created_at -> Int8,
     }
 }
 
@@ -1112,7 +1193,10 @@ diesel::allow_tables_to_appear_in_same_query!(
     event_relations,
     event_searches,
     events,
+    federation_destination_rooms,
+    federation_destinations,
     lazy_load_deliveries,
+    lazy_load_waitings,
     media_metadatas,
     media_thumbnails,
     media_url_previews,
@@ -1126,7 +1210,9 @@ diesel::allow_tables_to_appear_in_same_query!(
     room_tags,
     room_users,
     rooms,
+    scheduled_tasks,
     server_signing_keys,
+    sliding_sync_connections,
     stats_monthly_active_users,
     stats_room_currents,
     stats_user_daily_visits,
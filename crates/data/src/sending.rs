@@ -1,11 +1,12 @@
 use std::fmt::Debug;
 
 use diesel::prelude::*;
+use diesel::upsert::excluded;
 
 use crate::core::identifiers::*;
 pub use crate::core::sending::*;
 use crate::schema::*;
-use crate::{DataResult, connect};
+use crate::{DataResult, connect, connect_read};
 
 #[derive(Identifiable, Queryable, Insertable, Debug, Clone)]
 #[diesel(table_name = outgoing_requests)]
@@ -33,37 +34,227 @@ pub struct NewDbOutgoingRequest {
     pub edu_json: Option<Vec<u8>>,
 }
 
-/// Get all known federation destinations
-pub fn get_all_destinations() -> DataResult<Vec<OwnedServerName>> {
-    let servers: Vec<OwnedServerName> = outgoing_requests::table
-        .filter(outgoing_requests::server_id.is_not_null())
-        .select(outgoing_requests::server_id)
-        .distinct()
-        .load::<Option<OwnedServerName>>(&mut connect()?)?
-        .into_iter()
-        .flatten()
-        .collect();
-    Ok(servers)
+/// Retry state of a federation destination.
+#[derive(Identifiable, Queryable, Insertable, AsChangeset, Debug, Clone)]
+#[diesel(table_name = federation_destinations, primary_key(server_id))]
+#[diesel(treat_none_as_null = true)]
+pub struct DbDestination {
+    pub server_id: OwnedServerName,
+    pub failure_count: i64,
+    pub failure_ts: Option<i64>,
+    pub retry_last_ts: i64,
+    pub retry_interval: i64,
+    pub last_successful_sn: Option<i64>,
+}
+
+impl DbDestination {
+    pub fn new(server_id: OwnedServerName) -> Self {
+        Self {
+            server_id,
+            failure_count: 0,
+            failure_ts: None,
+            retry_last_ts: 0,
+            retry_interval: 0,
+            last_successful_sn: None,
+        }
+    }
+
+    /// Whether the last transaction to this destination failed.
+    pub fn is_down(&self) -> bool {
+        self.failure_ts.is_some()
+    }
+
+    /// Whether the backoff since the last failure has elapsed at `now`.
+    pub fn is_due(&self, now: i64) -> bool {
+        now >= self.retry_last_ts.saturating_add(self.retry_interval)
+    }
+}
+
+/// Filter options for listing federation destinations
+#[derive(Debug, Clone, Default)]
+pub struct DestinationFilter {
+    pub from: Option<i64>,
+    pub limit: Option<i64>,
+    pub destination: Option<String>,
+    pub order_by: Option<String>,
+    pub direction: Option<String>,
+}
+
+/// List known federation destinations with pagination and filtering
+pub fn list_destinations(filter: &DestinationFilter) -> DataResult<(Vec<DbDestination>, i64)> {
+    let mut count_query = federation_destinations::table.into_boxed();
+    let mut query = federation_destinations::table.into_boxed();
+
+    if let Some(ref destination) = filter.destination {
+        let pattern = format!("%{destination}%");
+        count_query = count_query.filter(federation_destinations::server_id.like(pattern.clone()));
+        query = query.filter(federation_destinations::server_id.like(pattern));
+    }
+
+    let total = count_query
+        .count()
+        .get_result::<i64>(&mut connect_read()?)?;
+
+    let backwards = filter.direction.as_deref() == Some("b");
+    query = match (filter.order_by.as_deref(), backwards) {
+        (Some("retry_last_ts"), false) => query.order(federation_destinations::retry_last_ts.asc()),
+        (Some("retry_last_ts"), true) => query.order(federation_destinations::retry_last_ts.desc()),
+        (Some("retry_interval"), false) => {
+            query.order(federation_destinations::retry_interval.asc())
+        }
+        (Some("retry_interval"), true) => {
+            query.order(federation_destinations::retry_interval.desc())
+        }
+        (Some("failure_ts"), false) => query.order(federation_destinations::failure_ts.asc()),
+        (Some("failure_ts"), true) => query.order(federation_destinations::failure_ts.desc()),
+        (Some("last_successful_stream_ordering"), false) => {
+            query.order(federation_destinations::last_successful_sn.asc())
+        }
+        (Some("last_successful_stream_ordering"), true) => {
+            query.order(federation_destinations::last_successful_sn.desc())
+        }
+        (_, false) => query.order(federation_destinations::server_id.asc()),
+        (_, true) => query.order(federation_destinations::server_id.desc()),
+    };
+    // Keep pagination stable when the sort column has duplicates.
+    query = query.then_order_by(federation_destinations::server_id.asc());
+
+    if let Some(from) = filter.from {
+        query = query.offset(from);
+    }
+    query = query.limit(filter.limit.unwrap_or(100).min(1000));
+
+    let destinations = query.load::<DbDestination>(&mut connect_read()?)?;
+    Ok((destinations, total))
+}
+
+/// Get the retry state of a destination, if anything was ever sent to it.
+pub fn get_destination(server: &ServerName) -> DataResult<Option<DbDestination>> {
+    federation_destinations::table
+        .find(server)
+        .first::<DbDestination>(&mut connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+/// Get all destinations whose last transaction failed.
+pub fn down_destinations() -> DataResult<Vec<DbDestination>> {
+    federation_destinations::table
+        .filter(federation_destinations::failure_ts.is_not_null())
+        .load::<DbDestination>(&mut connect()?)
+        .map_err(Into::into)
+}
+
+/// Insert or replace the retry state of a destination.
+pub fn save_destination(destination: &DbDestination) -> DataResult<()> {
+    diesel::insert_into(federation_destinations::table)
+        .values(destination)
+        .on_conflict(federation_destinations::server_id)
+        .do_update()
+        .set(destination)
+        .execute(&mut connect()?)?;
+    Ok(())
 }
 
 /// Check if a destination is known
 pub fn is_destination_known(server: &ServerName) -> DataResult<bool> {
-    let query = outgoing_requests::table.filter(outgoing_requests::server_id.eq(server));
+    let query =
+        federation_destinations::table.filter(federation_destinations::server_id.eq(server));
     Ok(diesel_exists!(query, &mut connect()?)?)
 }
 
-/// Get rooms shared with a destination
-pub fn get_destination_rooms(server: &ServerName) -> DataResult<Vec<OwnedRoomId>> {
-    use crate::schema::room_joined_servers;
-    let rooms: Vec<OwnedRoomId> = room_joined_servers::table
-        .filter(room_joined_servers::server_id.eq(server))
-        .select(room_joined_servers::room_id)
-        .load(&mut connect()?)?;
-    Ok(rooms)
+/// Records `event_id` as the latest PDU of the room meant for each of the destinations.
+pub fn set_destination_rooms(
+    servers: &[OwnedServerName],
+    room_id: &RoomId,
+    event_id: &EventId,
+    event_sn: i64,
+) -> DataResult<()> {
+    if servers.is_empty() {
+        return Ok(());
+    }
+    let rows = servers
+        .iter()
+        .map(|server| {
+            (
+                federation_destination_rooms::server_id.eq(server),
+                federation_destination_rooms::room_id.eq(room_id),
+                federation_destination_rooms::event_id.eq(event_id),
+                federation_destination_rooms::event_sn.eq(event_sn),
+            )
+        })
+        .collect::<Vec<_>>();
+    diesel::insert_into(federation_destination_rooms::table)
+        .values(&rows)
+        .on_conflict((
+            federation_destination_rooms::server_id,
+            federation_destination_rooms::room_id,
+        ))
+        .do_update()
+        .set((
+            federation_destination_rooms::event_id
+                .eq(excluded(federation_destination_rooms::event_id)),
+            federation_destination_rooms::event_sn
+                .eq(excluded(federation_destination_rooms::event_sn)),
+        ))
+        .execute(&mut connect()?)?;
+    Ok(())
 }
 
-/// Reset retry timings for a destination
-pub fn reset_destination_retry(_server: &ServerName) -> DataResult<()> {
-    // TODO: Implement retry timing reset when retry tracking is added
+/// Get rooms shared with a destination, with the sn of the latest PDU meant for it.
+pub fn get_destination_rooms(server: &ServerName) -> DataResult<Vec<(OwnedRoomId, i64)>> {
+    federation_destination_rooms::table
+        .filter(federation_destination_rooms::server_id.eq(server))
+        .order(federation_destination_rooms::room_id.asc())
+        .select((
+            federation_destination_rooms::room_id,
+            federation_destination_rooms::event_sn,
+        ))
+        .load(&mut connect_read()?)
+        .map_err(Into::into)
+}
+
+/// Get the latest PDU of every room the destination has missed since `since_sn`, oldest first.
+pub fn destination_rooms_since(
+    server: &ServerName,
+    since_sn: Option<i64>,
+) -> DataResult<Vec<(OwnedEventId, i64)>> {
+    let mut query = federation_destination_rooms::table
+        .filter(federation_destination_rooms::server_id.eq(server))
+        .into_boxed();
+    if let Some(since_sn) = since_sn {
+        query = query.filter(federation_destination_rooms::event_sn.gt(since_sn));
+    }
+    query
+        .order(federation_destination_rooms::event_sn.asc())
+        .select((
+            federation_destination_rooms::event_id,
+            federation_destination_rooms::event_sn,
+        ))
+        .load(&mut connect()?)
+        .map_err(Into::into)
+}
+
+/// Reset retry timings for a destination, so that it is retried right away.
+pub fn reset_destination_retry(server: &ServerName) -> DataResult<()> {
+    diesel::update(federation_destinations::table.find(server))
+        .set((
+            federation_destinations::retry_last_ts.eq(0),
+            federation_destinations::retry_interval.eq(0),
+        ))
+        .execute(&mut connect()?)?;
     Ok(())
 }
+
+/// Count queued and in flight requests per kind and destination server.
+pub fn count_requests_by_destination() -> DataResult<Vec<(String, Option<OwnedServerName>, i64)>> {
+    outgoing_requests::table
+        .group_by((outgoing_requests::kind, outgoing_requests::server_id))
+        .select((
+            outgoing_requests::kind,
+            outgoing_requests::server_id,
+            diesel::dsl::count_star(),
+        ))
+        .load::<(String, Option<OwnedServerName>, i64)>(&mut connect()?)
+        .map_err(Into::into)
+}
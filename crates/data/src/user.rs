@@ -25,6 +25,7 @@ pub mod external_id;
 pub mod presence;
 pub mod registration_token;
 pub use registration_token::*;
+pub mod threepid;
 use std::mem;
 
 use diesel::dsl;
@@ -37,7 +38,7 @@ use crate::core::identifiers::*;
 use crate::core::serde::{JsonValue, RawJson};
 use crate::core::{OwnedMxcUri, UnixMillis};
 use crate::schema::*;
-use crate::{DataError, DataResult, connect};
+use crate::{DataError, DataResult, connect, connect_read};
 
 #[derive(Insertable, Identifiable, Queryable, Debug, Clone)]
 #[diesel(table_name = users)]
@@ -82,6 +83,10 @@ impl DbUser {
     pub fn is_deactivated(&self) -> bool {
         self.deactivated_at.is_some()
     }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended_at.is_some()
+    }
 }
 
 #[derive(Insertable, AsChangeset, Debug, Clone)]
@@ -494,6 +499,16 @@ pub fn set_user_type(user_id: &UserId, user_type: Option<&str>) -> DataResult<()
     Ok(())
 }
 
+/// Lists the users of a type (e.g. users provisioned from ldap) that are not deactivated.
+pub fn active_users_of_type(user_type: &str) -> DataResult<Vec<OwnedUserId>> {
+    users::table
+        .filter(users::ty.eq(user_type))
+        .filter(users::deactivated_at.is_null())
+        .select(users::id)
+        .load::<OwnedUserId>(&mut connect()?)
+        .map_err(Into::into)
+}
+
 /// Set locked status for a user
 pub fn set_locked(user_id: &UserId, locked: bool, locker_id: Option<&UserId>) -> DataResult<()> {
     if locked {
@@ -596,7 +611,7 @@ pub fn list_users(filter: &ListUsersFilter) -> DataResult<(Vec<DbUser>, i64)> {
     }
 
     // Get total count with filters applied
-    let total: i64 = count_query.count().get_result(&mut connect()?)?;
+    let total: i64 = count_query.count().get_result(&mut connect_read()?)?;
 
     // Apply ordering
     let dir_asc = filter.dir.as_ref().map(|d| d == "f").unwrap_or(true);
@@ -653,7 +668,7 @@ pub fn list_users(filter: &ListUsersFilter) -> DataResult<(Vec<DbUser>, i64)> {
     let limit = filter.limit.unwrap_or(100).min(1000);
     query = query.limit(limit);
 
-    let users = query.load::<DbUser>(&mut connect()?)?;
+    let users = query.load::<DbUser>(&mut connect_read()?)?;
 
     Ok((users, total))
 }
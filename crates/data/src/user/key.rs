@@ -4,7 +4,7 @@ use diesel::prelude::*;
 
 use crate::core::encryption::DeviceKeys;
 use crate::core::identifiers::*;
-use crate::core::serde::JsonValue;
+use crate::core::serde::{JsonValue, RawJson};
 use crate::core::{DeviceKeyAlgorithm, Seqnum, UnixMillis};
 use crate::schema::*;
 use crate::{DataResult, connect};
@@ -50,7 +50,7 @@ pub struct NewDbCrossSignature {
 #[derive(Identifiable, Queryable, Debug, Clone)]
 #[diesel(table_name = e2e_fallback_keys)]
 pub struct DbFallbackKey {
-    pub id: String,
+    pub id: i64,
 
     pub user_id: OwnedUserId,
     pub device_id: OwnedDeviceId,
@@ -154,17 +154,32 @@ pub fn count_one_time_keys(
     ))
 }
 
+pub fn unused_fallback_key_types(
+    user_id: &UserId,
+    device_id: &DeviceId,
+) -> DataResult<Vec<DeviceKeyAlgorithm>> {
+    let list = e2e_fallback_keys::table
+        .filter(e2e_fallback_keys::user_id.eq(user_id))
+        .filter(e2e_fallback_keys::device_id.eq(device_id))
+        .filter(e2e_fallback_keys::used_at.is_null())
+        .select(e2e_fallback_keys::algorithm)
+        .load::<String>(&mut connect()?)?;
+    Ok(list.into_iter().map(DeviceKeyAlgorithm::from).collect())
+}
+
+/// Stores the device keys as uploaded, so the fields covered by their
+/// signature are kept even when `DeviceKeys` doesn't know about them.
 pub fn add_device_keys(
     user_id: &UserId,
     device_id: &DeviceId,
-    device_keys: &DeviceKeys,
+    device_keys: &RawJson<DeviceKeys>,
 ) -> DataResult<()> {
     let new_device_key = NewDbDeviceKey {
         user_id: user_id.to_owned(),
         device_id: device_id.to_owned(),
         stream_id: 0,
-        display_name: device_keys.unsigned.device_display_name.clone(),
-        key_data: serde_json::to_value(device_keys).unwrap(),
+        display_name: device_keys.deserialize()?.unsigned.device_display_name,
+        key_data: device_keys.deserialize_as()?,
         created_at: UnixMillis::now(),
     };
     diesel::insert_into(e2e_device_keys::table)
@@ -187,6 +202,21 @@ pub fn get_device_keys(user_id: &UserId, device_id: &DeviceId) -> DataResult<Opt
         .transpose()
 }
 
+/// Returns the device keys as they were uploaded, with any field the typed
+/// `DeviceKeys` does not know about.
+pub fn get_device_keys_json(
+    user_id: &UserId,
+    device_id: &DeviceId,
+) -> DataResult<Option<JsonValue>> {
+    e2e_device_keys::table
+        .filter(e2e_device_keys::user_id.eq(user_id))
+        .filter(e2e_device_keys::device_id.eq(device_id))
+        .select(e2e_device_keys::key_data)
+        .first::<JsonValue>(&mut connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
 pub fn get_device_keys_and_sigs(
     user_id: &UserId,
     device_id: &DeviceId,
@@ -246,6 +276,67 @@ pub fn keys_changed_users(
     }
 }
 
+/// Users who stopped sharing any room with the user between the positions,
+/// because they or the user left or were banned, so the user no longer needs
+/// to track their device lists.
+pub fn device_list_left_users(
+    user_id: &UserId,
+    since_sn: Seqnum,
+    until_sn: Option<Seqnum>,
+) -> DataResult<Vec<OwnedUserId>> {
+    let joined_room_ids = crate::user::joined_rooms(user_id)?;
+    let mut query = room_users::table
+        .filter(room_users::user_id.eq(user_id))
+        .filter(room_users::membership.eq_any(["leave", "ban"]))
+        .filter(room_users::event_sn.ge(since_sn))
+        .select(room_users::room_id)
+        .into_boxed();
+    if let Some(until_sn) = until_sn {
+        query = query.filter(room_users::event_sn.le(until_sn));
+    }
+    let left_room_ids = query.load::<OwnedRoomId>(&mut connect()?)?;
+
+    // Members of the rooms the user left, and those who left the rooms the
+    // user was in.
+    let room_ids = joined_room_ids.iter().chain(&left_room_ids);
+    let mut query = room_users::table
+        .filter(room_users::user_id.ne(user_id))
+        .filter(room_users::room_id.eq_any(room_ids))
+        .filter(room_users::membership.eq_any(["leave", "ban"]))
+        .filter(room_users::event_sn.ge(since_sn))
+        .select(room_users::user_id)
+        .into_boxed();
+    if let Some(until_sn) = until_sn {
+        query = query.filter(room_users::event_sn.le(until_sn));
+    }
+    let mut candidates = query.load::<OwnedUserId>(&mut connect()?)?;
+    candidates.extend(
+        room_users::table
+            .filter(room_users::user_id.ne(user_id))
+            .filter(room_users::room_id.eq_any(&left_room_ids))
+            .filter(room_users::membership.eq("join"))
+            .select(room_users::user_id)
+            .load::<OwnedUserId>(&mut connect()?)?,
+    );
+    candidates.sort_unstable();
+    candidates.dedup();
+    if candidates.is_empty() {
+        return Ok(candidates);
+    }
+
+    let still_shared = room_users::table
+        .filter(room_users::room_id.eq_any(&joined_room_ids))
+        .filter(room_users::user_id.eq_any(&candidates))
+        .filter(room_users::membership.eq("join"))
+        .select(room_users::user_id)
+        .distinct()
+        .load::<OwnedUserId>(&mut connect()?)?;
+    Ok(candidates
+        .into_iter()
+        .filter(|user_id| !still_shared.contains(user_id))
+        .collect())
+}
+
 /// Check if user has a master cross-signing key
 pub fn has_master_cross_signing_key(user_id: &UserId) -> DataResult<bool> {
     let count = e2e_cross_signing_keys::table
@@ -100,6 +100,68 @@ pub fn get_pushers(user_id: &UserId) -> DataResult<Vec<DbPusher>> {
         .map_err(Into::into)
 }
 
+/// Returns the enabled email pushers of all users.
+pub fn get_email_pushers() -> DataResult<Vec<DbPusher>> {
+    user_pushers::table
+        .filter(user_pushers::kind.eq("email"))
+        .filter(user_pushers::enabled.eq(true))
+        .order_by(user_pushers::id.asc())
+        .load::<DbPusher>(&mut connect()?)
+        .map_err(Into::into)
+}
+
+/// Records a successful delivery up to `last_stream_ordering`.
+pub fn set_pusher_success(
+    user_id: &UserId,
+    app_id: &str,
+    pushkey: &str,
+    last_stream_ordering: i64,
+    last_success: UnixMillis,
+) -> DataResult<()> {
+    diesel::update(
+        user_pushers::table
+            .filter(user_pushers::user_id.eq(user_id))
+            .filter(user_pushers::app_id.eq(app_id))
+            .filter(user_pushers::pushkey.eq(pushkey)),
+    )
+    .set((
+        user_pushers::last_stream_ordering.eq(last_stream_ordering),
+        user_pushers::last_success.eq(last_success.get() as i64),
+        user_pushers::failing_since.eq(None::<i64>),
+    ))
+    .execute(&mut connect()?)?;
+    Ok(())
+}
+
+pub fn set_pusher_failing(
+    user_id: &UserId,
+    app_id: &str,
+    pushkey: &str,
+    failing_since: UnixMillis,
+) -> DataResult<()> {
+    diesel::update(
+        user_pushers::table
+            .filter(user_pushers::user_id.eq(user_id))
+            .filter(user_pushers::app_id.eq(app_id))
+            .filter(user_pushers::pushkey.eq(pushkey))
+            .filter(user_pushers::failing_since.is_null()),
+    )
+    .set(user_pushers::failing_since.eq(failing_since.get() as i64))
+    .execute(&mut connect()?)?;
+    Ok(())
+}
+
+pub fn delete_pusher(user_id: &UserId, app_id: &str, pushkey: &str) -> DataResult<usize> {
+    diesel::delete(
+        user_pushers::table
+            .filter(user_pushers::user_id.eq(user_id))
+            .filter(user_pushers::app_id.eq(app_id))
+            .filter(user_pushers::pushkey.eq(pushkey)),
+    )
+    .execute(&mut connect()?)
+    .map_err(Into::into)
+}
+
 pub async fn get_actions<'a>(
     user: &UserId,
     ruleset: &'a Ruleset,
@@ -2,7 +2,7 @@ use diesel::prelude::*;
 
 use crate::core::UnixMillis;
 use crate::schema::*;
-use crate::{DataResult, connect};
+use crate::{DataResult, connect, connect_read};
 
 /// Database model for registration tokens
 #[derive(Identifiable, Queryable, Debug, Clone)]
@@ -107,7 +107,7 @@ pub fn list_registration_tokens(valid: Option<bool>) -> DataResult<Vec<Registrat
 
     let tokens = query
         .order(user_registration_tokens::created_at.desc())
-        .load::<DbRegistrationToken>(&mut connect()?)?;
+        .load::<DbRegistrationToken>(&mut connect_read()?)?;
 
     Ok(tokens.into_iter().map(Into::into).collect())
 }
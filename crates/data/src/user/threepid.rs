@@ -0,0 +1,241 @@
+use diesel::prelude::*;
+
+use crate::core::UnixMillis;
+use crate::core::identifiers::*;
+use crate::schema::*;
+use crate::{DataResult, connect};
+
+#[derive(Identifiable, Queryable, Debug, Clone)]
+#[diesel(table_name = threepid_validation_sessions)]
+pub struct DbThreepidSession {
+    pub id: i64,
+    pub session_id: String,
+    pub medium: String,
+    pub address: String,
+    pub client_secret: String,
+    pub last_send_attempt: i64,
+    pub validated_at: Option<UnixMillis>,
+    pub created_at: UnixMillis,
+    pub purpose: String,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = threepid_validation_sessions)]
+pub struct NewDbThreepidSession {
+    pub session_id: String,
+    pub medium: String,
+    pub address: String,
+    pub client_secret: String,
+    pub last_send_attempt: i64,
+    pub validated_at: Option<UnixMillis>,
+    pub created_at: UnixMillis,
+    pub purpose: String,
+}
+
+#[derive(Identifiable, Queryable, Debug, Clone)]
+#[diesel(table_name = threepid_validation_tokens)]
+pub struct DbThreepidToken {
+    pub id: i64,
+    pub token: String,
+    pub session_id: String,
+    pub next_link: Option<String>,
+    pub expires_at: UnixMillis,
+    pub created_at: UnixMillis,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = threepid_validation_tokens)]
+pub struct NewDbThreepidToken {
+    pub token: String,
+    pub session_id: String,
+    pub next_link: Option<String>,
+    pub expires_at: UnixMillis,
+    pub created_at: UnixMillis,
+}
+
+/// Finds the validation session a client started for an address and purpose.
+pub fn find_validation_session(
+    medium: &str,
+    address: &str,
+    client_secret: &str,
+    purpose: &str,
+) -> DataResult<Option<DbThreepidSession>> {
+    threepid_validation_sessions::table
+        .filter(threepid_validation_sessions::medium.eq(medium))
+        .filter(threepid_validation_sessions::address.eq(address))
+        .filter(threepid_validation_sessions::client_secret.eq(client_secret))
+        .filter(threepid_validation_sessions::purpose.eq(purpose))
+        .order_by(threepid_validation_sessions::id.desc())
+        .first::<DbThreepidSession>(&mut connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+pub fn get_validation_session(
+    session_id: &str,
+    client_secret: &str,
+) -> DataResult<Option<DbThreepidSession>> {
+    threepid_validation_sessions::table
+        .filter(threepid_validation_sessions::session_id.eq(session_id))
+        .filter(threepid_validation_sessions::client_secret.eq(client_secret))
+        .first::<DbThreepidSession>(&mut connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+pub fn add_validation_session(session: &NewDbThreepidSession) -> DataResult<()> {
+    diesel::insert_into(threepid_validation_sessions::table)
+        .values(session)
+        .execute(&mut connect()?)?;
+    Ok(())
+}
+
+/// Removes a session with its tokens once it has been used. Returns whether the
+/// session still existed, so only one caller can use it.
+pub fn delete_validation_session(session_id: &str) -> DataResult<bool> {
+    let mut conn = connect()?;
+    diesel::delete(
+        threepid_validation_tokens::table
+            .filter(threepid_validation_tokens::session_id.eq(session_id)),
+    )
+    .execute(&mut conn)?;
+    let deleted = diesel::delete(
+        threepid_validation_sessions::table
+            .filter(threepid_validation_sessions::session_id.eq(session_id)),
+    )
+    .execute(&mut conn)?;
+    Ok(deleted > 0)
+}
+
+pub fn set_session_send_attempt(session_id: &str, send_attempt: i64) -> DataResult<()> {
+    diesel::update(
+        threepid_validation_sessions::table
+            .filter(threepid_validation_sessions::session_id.eq(session_id)),
+    )
+    .set(threepid_validation_sessions::last_send_attempt.eq(send_attempt))
+    .execute(&mut connect()?)?;
+    Ok(())
+}
+
+pub fn set_session_validated(session_id: &str, validated_at: UnixMillis) -> DataResult<()> {
+    diesel::update(
+        threepid_validation_sessions::table
+            .filter(threepid_validation_sessions::session_id.eq(session_id)),
+    )
+    .set(threepid_validation_sessions::validated_at.eq(validated_at))
+    .execute(&mut connect()?)?;
+    Ok(())
+}
+
+pub fn add_validation_token(token: &NewDbThreepidToken) -> DataResult<()> {
+    diesel::insert_into(threepid_validation_tokens::table)
+        .values(token)
+        .execute(&mut connect()?)?;
+    Ok(())
+}
+
+/// Returns the token if it belongs to the session and has not expired yet.
+pub fn get_valid_token(session_id: &str, token: &str) -> DataResult<Option<DbThreepidToken>> {
+    threepid_validation_tokens::table
+        .filter(threepid_validation_tokens::session_id.eq(session_id))
+        .filter(threepid_validation_tokens::token.eq(token))
+        .filter(threepid_validation_tokens::expires_at.gt(UnixMillis::now()))
+        .first::<DbThreepidToken>(&mut connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+/// Removes expired tokens and the sessions created before `older_than`.
+pub fn prune_validation_sessions(older_than: UnixMillis) -> DataResult<()> {
+    let mut conn = connect()?;
+    diesel::delete(
+        threepid_validation_tokens::table
+            .filter(threepid_validation_tokens::expires_at.lt(UnixMillis::now())),
+    )
+    .execute(&mut conn)?;
+    diesel::delete(
+        threepid_validation_sessions::table
+            .filter(threepid_validation_sessions::created_at.lt(older_than)),
+    )
+    .execute(&mut conn)?;
+    Ok(())
+}
+
+pub fn add_threepid(
+    user_id: &UserId,
+    medium: &str,
+    address: &str,
+    validated_at: UnixMillis,
+) -> DataResult<()> {
+    diesel::insert_into(user_threepids::table)
+        .values(super::NewDbUserThreepid {
+            user_id: user_id.to_owned(),
+            medium: medium.to_owned(),
+            address: address.to_owned(),
+            validated_at,
+            added_at: UnixMillis::now(),
+        })
+        .execute(&mut connect()?)?;
+    Ok(())
+}
+
+pub fn remove_threepid(user_id: &UserId, medium: &str, address: &str) -> DataResult<usize> {
+    diesel::delete(
+        user_threepids::table
+            .filter(user_threepids::user_id.eq(user_id))
+            .filter(user_threepids::medium.eq(medium))
+            .filter(user_threepids::address.eq(address)),
+    )
+    .execute(&mut connect()?)
+    .map_err(Into::into)
+}
+
+/// Records that a threepid of the user was bound on an identity server.
+pub fn add_threepid_id_server(
+    user_id: &UserId,
+    medium: &str,
+    address: &str,
+    id_server: &str,
+) -> DataResult<()> {
+    diesel::insert_into(threepid_id_servers::table)
+        .values((
+            threepid_id_servers::user_id.eq(user_id),
+            threepid_id_servers::medium.eq(medium),
+            threepid_id_servers::address.eq(address),
+            threepid_id_servers::id_server.eq(id_server),
+        ))
+        .on_conflict_do_nothing()
+        .execute(&mut connect()?)?;
+    Ok(())
+}
+
+pub fn get_threepid_id_servers(
+    user_id: &UserId,
+    medium: &str,
+    address: &str,
+) -> DataResult<Vec<String>> {
+    threepid_id_servers::table
+        .filter(threepid_id_servers::user_id.eq(user_id))
+        .filter(threepid_id_servers::medium.eq(medium))
+        .filter(threepid_id_servers::address.eq(address))
+        .select(threepid_id_servers::id_server)
+        .load::<String>(&mut connect()?)
+        .map_err(Into::into)
+}
+
+pub fn remove_threepid_id_server(
+    user_id: &UserId,
+    medium: &str,
+    address: &str,
+    id_server: &str,
+) -> DataResult<()> {
+    diesel::delete(
+        threepid_id_servers::table
+            .filter(threepid_id_servers::user_id.eq(user_id))
+            .filter(threepid_id_servers::medium.eq(medium))
+            .filter(threepid_id_servers::address.eq(address))
+            .filter(threepid_id_servers::id_server.eq(id_server)),
+    )
+    .execute(&mut connect()?)?;
+    Ok(())
+}
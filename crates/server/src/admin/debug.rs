@@ -8,7 +8,6 @@ use std::{
 
 use futures_util::{FutureExt, StreamExt, TryStreamExt};
 use serde::Serialize;
-use tracing_subscriber::EnvFilter;
 
 use crate::core::UnixMillis;
 use crate::core::serde::{CanonicalJsonObject, CanonicalJsonValue, RawJsonValue};
@@ -307,46 +306,26 @@ pub(super) async fn change_log_level(ctx: &Context<'_>, filter: Option<String>,
     let handles = &["console"];
     let conf = config::get();
     if reset {
-        let old_filter_layer = match EnvFilter::try_new(&conf.logger.level) {
-            Ok(s) => s,
-            Err(e) => {
-                return Err(AppError::public(format!(
-                    "Log level from config appears to be invalid now: {e}"
-                )));
-            }
-        };
-
-        // TODO: This is a workaround for the fact that we cannot reload the logger
-        // match crate::config::get().logger.reload(&old_filter_layer, Some(handles)) {
-        //     Err(e) => {
-        //         return Err(AppError::public(format!(
-        //             "Failed to modify and reload the global tracing log level: {e}"
-        //         )));
-        //     }
-        //     Ok(()) => {
-        //         let value = &conf.logger.level;
-        //         let out = format!("Successfully changed log level back to config value {value}");
-        //         return ctx.write_str(&out).await;
-        //     }
-        // }
+        crate::logging::get()
+            .reload
+            .reload(&conf.logger.level, Some(handles))
+            .map_err(|e| {
+                AppError::public(format!("Failed to modify and reload the global tracing log level: {e}"))
+            })?;
+        let value = &conf.logger.level;
+        let out = format!("Successfully changed log level back to config value {value}");
+        return ctx.write_str(&out).await;
     }
 
-    // TODO: This is a workaround for the fact that we cannot reload the logger
-    // if let Some(filter) = filter {
-    //     let new_filter_layer = match EnvFilter::try_new(filter) {
-    //         Ok(s) => s,
-    //         Err(e) => return Err(AppError::public(format!("Invalid log level filter specified: {e}"))),
-    //     };
-
-    //     match self.services.server.log.reload.reload(&new_filter_layer, Some(handles)) {
-    //         Ok(()) => return ctx.write_str("Successfully changed log level").await,
-    //         Err(e) => {
-    //             return Err(AppError::public(format!(
-    //                 "Failed to modify and reload the global tracing log level: {e}"
-    //             )));
-    //         }
-    //     }
-    // }
+    if let Some(filter) = filter {
+        crate::logging::get()
+            .reload
+            .reload(&filter, Some(handles))
+            .map_err(|e| {
+                AppError::public(format!("Failed to modify and reload the global tracing log level: {e}"))
+            })?;
+        return ctx.write_str("Successfully changed log level").await;
+    }
 
     Err(AppError::public("No log level was specified."))
 }
@@ -3,6 +3,7 @@ use clap::Subcommand;
 use cmd::*;
 
 use crate::AppResult;
+use crate::config::MediaStorageKind;
 use crate::core::{OwnedEventId, OwnedMxcUri};
 use crate::macros::admin_command_dispatch;
 
@@ -62,6 +63,18 @@ pub(crate) enum MediaCommand {
         /// The MXC URL to lookup info for.
         mxc: OwnedMxcUri,
     },
+
+    /// - Copies all media files and thumbnails from one storage backend to
+    ///   another in a scheduled task. Files already present in the destination
+    ///   are skipped, so an interrupted migration can be resumed by running it
+    ///   again.
+    MigrateStorage {
+        /// The backend to copy from
+        from: MediaStorageKind,
+
+        /// The backend to copy to
+        to: MediaStorageKind,
+    },
     // GetRemoteFile {
     //     /// The MXC URL to fetch
     //     mxc: OwnedMxcUri,
@@ -2,9 +2,11 @@ use std::time::Duration;
 
 use crate::admin::Context;
 use crate::admin::utils::parse_local_user_id;
+use crate::config::MediaStorageKind;
 use crate::core::{Mxc, OwnedEventId, OwnedMxcUri, OwnedServerName};
-use crate::media::Dimension;
+use crate::media::{Dimension, build_storage};
 use crate::room::timeline;
+use crate::scheduler::{self, MigrateMediaStorageParams};
 use crate::utils::time::parse_timepoint_ago;
 use crate::{AppError, AppResult, IsRemoteOrLocal, config, data};
 
@@ -328,6 +330,35 @@ pub(super) async fn get_file_info(ctx: &Context<'_>, mxc: OwnedMxcUri) -> AppRes
     ctx.write_str(&format!("```\n{metadata:#?}\n```")).await
 }
 
+pub(super) async fn migrate_storage(
+    ctx: &Context<'_>,
+    from: MediaStorageKind,
+    to: MediaStorageKind,
+) -> AppResult<()> {
+    if from == to {
+        return Err(AppError::public(
+            "Source and destination storage backends must differ.",
+        ));
+    }
+    // Fail right away when a backend is not configured.
+    build_storage(from)?;
+    build_storage(to)?;
+
+    let params = MigrateMediaStorageParams { from, to };
+    let task = scheduler::schedule(
+        scheduler::MIGRATE_MEDIA_STORAGE,
+        None,
+        serde_json::to_value(params)?,
+    )?;
+
+    ctx.write_str(&format!(
+        "Scheduled task {} to copy all media to the new storage. Its progress is listed by the \
+         scheduled tasks admin API.",
+        task.id
+    ))
+    .await
+}
+
 // pub(super) async fn fetch_remote_file(
 //     ctx: &Context<'_>,
 //     mxc: OwnedMxcUri,
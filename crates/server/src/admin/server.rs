@@ -15,7 +15,8 @@ pub(crate) enum ServerCommand {
     /// - Show configuration values
     ShowConfig,
 
-    /// - Reload configuration values
+    /// - Reload the configuration values that can change without a restart:
+    ///   log level, rate limits, url previews and forbidden names
     ReloadConfig { path: Option<PathBuf> },
 
     /// - List the features built into the server
@@ -17,10 +17,8 @@ pub(super) async fn show_config(ctx: &Context<'_>) -> AppResult<()> {
     ctx.write_str(&format!("{}", config::get())).await
 }
 
-pub(super) async fn reload_config(ctx: &Context<'_>, _path: Option<PathBuf>) -> AppResult<()> {
-    // TODO: admin
-    // let path = path.as_deref();
-    // config::reload(path)?;
+pub(super) async fn reload_config(ctx: &Context<'_>, path: Option<PathBuf>) -> AppResult<()> {
+    config::reload(path.as_deref())?;
 
     ctx.write_str("Successfully reconfigured.").await
 }
@@ -70,10 +68,11 @@ pub(super) async fn admin_notice(ctx: &Context<'_>, message: Vec<String>) -> App
     ctx.write_str("Notice was sent to #admins").await
 }
 
-pub(super) async fn reload_mods(ctx: &Context<'_>) -> AppResult<()> {
-    // TODO: reload mods
-
-    ctx.write_str("(TODO) Reloading server...").await
+pub(super) async fn reload_mods(_ctx: &Context<'_>) -> AppResult<()> {
+    Err(AppError::public(
+        "Hot-reloading the server is not supported. Use `server reload-config` to apply config \
+         changes, or `server restart`.",
+    ))
 }
 
 pub(super) async fn restart(ctx: &Context<'_>, force: bool) -> AppResult<()> {
@@ -86,14 +85,14 @@ pub(super) async fn restart(ctx: &Context<'_>, force: bool) -> AppResult<()> {
         ));
     }
 
-    // TODO: restart server
-
-    ctx.write_str("(TODO) Restarting server...").await
+    ctx.write_str("Restarting server...").await?;
+    crate::global::restart();
+    Ok(())
 }
 
 pub(super) async fn shutdown(ctx: &Context<'_>) -> AppResult<()> {
     warn!("shutdown command");
-    // TODO: shutdown server
-
-    ctx.write_str("(TODO) Shutting down server...").await
+    ctx.write_str("Shutting down server...").await?;
+    crate::global::shutdown();
+    Ok(())
 }
@@ -3,13 +3,15 @@ use crate::core::events::room::power_levels::RoomPowerLevelsEventContent;
 use crate::core::events::room::redaction::RoomRedactionEventContent;
 use crate::core::events::tag::{TagEventContent, TagInfo};
 use crate::core::events::{RoomAccountDataEventType, StateEventType};
-use crate::core::{OwnedEventId, OwnedRoomId, OwnedRoomOrAliasId, OwnedUserId};
+use crate::core::{
+    OwnedEventId, OwnedRoomId, OwnedRoomOrAliasId, OwnedServerName, OwnedUserId, RoomId,
+};
 use crate::room::timeline;
+use crate::scheduler::{self, ForceJoinUsersParams};
 use crate::user::full_user_deactivate;
 use crate::{AppError, AppResult, IsRemoteOrLocal, PduBuilder, config, data, membership, utils};
 
 const AUTO_GEN_PASSWORD_LENGTH: usize = 25;
-const BULK_JOIN_REASON: &str = "Bulk force joining this room as initiated by the server admin.";
 
 pub(super) async fn list_users(ctx: &Context<'_>) -> AppResult<()> {
     let users: Vec<_> = crate::user::list_local_users()?;
@@ -416,38 +418,7 @@ pub(super) async fn force_join_list_of_local_users(
         }
     }
 
-    let mut failed_joins: usize = 0;
-    let mut successful_joins: usize = 0;
-
-    for user_id in user_ids {
-        let user = data::user::get_user(&user_id)?;
-        match membership::join_room(
-            &user,
-            None,
-            &room_id,
-            Some(String::from(BULK_JOIN_REASON)),
-            &servers,
-            None,
-            None,
-            Default::default(),
-        )
-        .await
-        {
-            Ok(_res) => {
-                successful_joins = successful_joins.saturating_add(1);
-            }
-            Err(e) => {
-                warn!("Failed force joining {user_id} to {room_id} during bulk join: {e}");
-                failed_joins = failed_joins.saturating_add(1);
-            }
-        }
-    }
-
-    ctx.write_str(&format!(
-        "{successful_joins} local users have been joined to {room_id}. {failed_joins} joins \
-		 failed.",
-    ))
-    .await
+    schedule_force_join(ctx, &room_id, user_ids, servers).await
 }
 
 pub(super) async fn force_join_all_local_users(
@@ -485,36 +456,29 @@ pub(super) async fn force_join_all_local_users(
         ));
     }
 
-    let mut failed_joins: usize = 0;
-    let mut successful_joins: usize = 0;
-
-    for user_id in &data::user::list_local_users()? {
-        let user = data::user::get_user(user_id)?;
-        match membership::join_room(
-            &user,
-            None,
-            &room_id,
-            Some(String::from(BULK_JOIN_REASON)),
-            &servers,
-            None,
-            None,
-            Default::default(),
-        )
-        .await
-        {
-            Ok(_res) => {
-                successful_joins = successful_joins.saturating_add(1);
-            }
-            Err(e) => {
-                warn!("Failed force joining {user_id} to {room_id} during bulk join: {e}");
-                failed_joins = failed_joins.saturating_add(1);
-            }
-        }
-    }
+    let user_ids = data::user::list_local_users()?;
+    schedule_force_join(ctx, &room_id, user_ids, servers).await
+}
+
+/// Joins the users in a scheduled task, since joining many users takes long.
+async fn schedule_force_join(
+    ctx: &Context<'_>,
+    room_id: &RoomId,
+    user_ids: Vec<OwnedUserId>,
+    servers: Vec<OwnedServerName>,
+) -> AppResult<()> {
+    let count = user_ids.len();
+    let params = ForceJoinUsersParams { user_ids, servers };
+    let task = scheduler::schedule(
+        scheduler::FORCE_JOIN_USERS,
+        Some(room_id.to_string()),
+        serde_json::to_value(params)?,
+    )?;
 
     ctx.write_str(&format!(
-        "{successful_joins} local users have been joined to {room_id}. {failed_joins} joins \
-		 failed.",
+        "Scheduled task {} to join {count} local users to {room_id}. Its progress is listed \
+		 by the scheduled tasks admin API.",
+        task.id
     ))
     .await
 }
@@ -13,6 +13,9 @@ use crate::data::connect;
 use crate::data::schema::*;
 use crate::{AppError, AppResult, sending};
 
+mod ephemeral;
+pub use ephemeral::*;
+
 /// Compiled regular expressions for a namespace.
 #[derive(Clone, Debug)]
 pub struct NamespaceRegex {
@@ -174,6 +177,12 @@ pub struct DbRegistration {
     /// Defaults to `false`
     #[serde(default, rename = "io.element.msc4190")]
     pub device_management: bool,
+
+    /// Whether the application service wants the MSC3202 transaction extensions.
+    ///
+    /// Defaults to `false`
+    #[serde(default, rename = "org.matrix.msc3202")]
+    pub transaction_extensions: bool,
 }
 
 // Custom Debug implementation to prevent leaking as_token and hs_token
@@ -190,6 +199,7 @@ impl fmt::Debug for DbRegistration {
             .field("protocols", &self.protocols)
             .field("receive_ephemeral", &self.receive_ephemeral)
             .field("device_management", &self.device_management)
+            .field("transaction_extensions", &self.transaction_extensions)
             .finish()
     }
 }
@@ -207,6 +217,7 @@ impl From<Registration> for DbRegistration {
             protocols,
             receive_ephemeral,
             device_management,
+            transaction_extensions,
         } = value;
         Self {
             id,
@@ -220,6 +231,7 @@ impl From<Registration> for DbRegistration {
                 .map(|protocols| serde_json::to_value(protocols).unwrap_or_default()),
             receive_ephemeral,
             device_management,
+            transaction_extensions,
         }
     }
 }
@@ -238,6 +250,7 @@ impl TryFrom<DbRegistration> for Registration {
             protocols,
             receive_ephemeral,
             device_management,
+            transaction_extensions,
         } = value;
         let protocols = if let Some(protocols) = protocols {
             serde_json::from_value(protocols)?
@@ -255,6 +268,7 @@ impl TryFrom<DbRegistration> for Registration {
             protocols,
             receive_ephemeral,
             device_management,
+            transaction_extensions,
         })
     }
 }
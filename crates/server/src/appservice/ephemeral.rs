@@ -0,0 +1,153 @@
+//! Ephemeral data, to-device messages and device list changes pushed to
+//! appservices alongside PDUs (MSC2409 and MSC3202).
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::RegistrationInfo;
+use crate::core::appservice::Registration;
+use crate::core::appservice::event::{EphemeralData, PushEventsReqBody};
+use crate::core::events::presence::PresenceEvent;
+use crate::core::identifiers::*;
+use crate::core::serde::JsonValue;
+use crate::{AppResult, IsRemoteOrLocal, data, sending};
+
+/// A non-PDU item queued for an appservice transaction.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", content = "data", rename_all = "snake_case")]
+pub enum AppserviceEdu {
+    /// A typing, receipt or presence event.
+    Ephemeral(JsonValue),
+    /// A to-device event, including `to_user_id` and `to_device_id`.
+    ToDevice(JsonValue),
+    /// A user whose devices or cross-signing keys changed.
+    DeviceListChanged(OwnedUserId),
+}
+
+/// Queues a typing or receipt event for appservices interested in the room.
+pub fn send_room_ephemeral(room_id: &RoomId, data: &EphemeralData) -> AppResult<()> {
+    let edu = AppserviceEdu::Ephemeral(serde_json::to_value(data)?);
+    for appservice in super::all()?.values() {
+        if appservice.registration.receive_ephemeral && is_interested_in_room(room_id, appservice)?
+        {
+            sending::send_edu_appservice(appservice.registration.id.clone(), &edu)?;
+        }
+    }
+    Ok(())
+}
+
+/// Queues a presence event for appservices owning the user or sharing a room with them.
+pub fn send_presence(event: &PresenceEvent) -> AppResult<()> {
+    let appservices = super::all()?
+        .into_values()
+        .filter(|appservice| appservice.registration.receive_ephemeral)
+        .collect::<Vec<_>>();
+    if appservices.is_empty() {
+        return Ok(());
+    }
+
+    let joined_rooms = data::user::joined_rooms(&event.sender)?;
+    let edu = AppserviceEdu::Ephemeral(serde_json::to_value(EphemeralData::Presence(
+        event.clone(),
+    ))?);
+    for appservice in &appservices {
+        if appservice.is_user_match(&event.sender)
+            || is_interested_in_any_room(&joined_rooms, appservice)?
+        {
+            sending::send_edu_appservice(appservice.registration.id.clone(), &edu)?;
+        }
+    }
+    Ok(())
+}
+
+/// Queues a to-device event for appservices exclusively owning the local recipient, so they
+/// can handle messages for the devices they masquerade as.
+pub fn send_to_device(
+    sender: &UserId,
+    target_user_id: &UserId,
+    target_device_id: &DeviceId,
+    event_type: &str,
+    content: &JsonValue,
+) -> AppResult<()> {
+    if target_user_id.is_remote() {
+        return Ok(());
+    }
+    for appservice in super::all()?.values() {
+        if !appservice.registration.receive_ephemeral
+            || !appservice.is_exclusive_user_match(target_user_id)
+        {
+            continue;
+        }
+        let edu = AppserviceEdu::ToDevice(json!({
+            "type": event_type,
+            "sender": sender,
+            "content": content,
+            "to_user_id": target_user_id,
+            "to_device_id": target_device_id,
+        }));
+        sending::send_edu_appservice(appservice.registration.id.clone(), &edu)?;
+    }
+    Ok(())
+}
+
+/// Queues a device list change for appservices using the MSC3202 transaction extensions that
+/// own the user or share a room with them.
+pub fn send_device_list_update(user_id: &UserId, joined_rooms: &[OwnedRoomId]) -> AppResult<()> {
+    let edu = AppserviceEdu::DeviceListChanged(user_id.to_owned());
+    for appservice in super::all()?.values() {
+        if !appservice.registration.transaction_extensions {
+            continue;
+        }
+        if appservice.is_user_match(user_id) || is_interested_in_any_room(joined_rooms, appservice)?
+        {
+            sending::send_edu_appservice(appservice.registration.id.clone(), &edu)?;
+        }
+    }
+    Ok(())
+}
+
+/// Fills the MSC3202 one-time key counts and unused fallback key types for all devices of the
+/// given users that the appservice owns exclusively.
+pub fn fill_device_keys(
+    body: &mut PushEventsReqBody,
+    registration: &Registration,
+    user_ids: BTreeSet<OwnedUserId>,
+) -> AppResult<()> {
+    let appservice = RegistrationInfo::try_from(registration.clone())?;
+    for user_id in user_ids {
+        if !appservice.is_exclusive_user_match(&user_id) {
+            continue;
+        }
+        for device_id in data::user::all_device_ids(&user_id)? {
+            let counts = data::user::count_one_time_keys(&user_id, &device_id)?;
+            let fallback_types = data::user::unused_fallback_key_types(&user_id, &device_id)?;
+            body.device_one_time_keys_count
+                .entry(user_id.clone())
+                .or_default()
+                .insert(device_id.clone(), counts);
+            body.device_unused_fallback_key_types
+                .entry(user_id.clone())
+                .or_default()
+                .insert(device_id, fallback_types);
+        }
+    }
+    Ok(())
+}
+
+fn is_interested_in_room(room_id: &RoomId, appservice: &RegistrationInfo) -> AppResult<bool> {
+    Ok(appservice.rooms.is_match(room_id.as_str())
+        || crate::room::appservice_in_room(room_id, appservice)?)
+}
+
+fn is_interested_in_any_room(
+    room_ids: &[OwnedRoomId],
+    appservice: &RegistrationInfo,
+) -> AppResult<bool> {
+    for room_id in room_ids {
+        if is_interested_in_room(room_id, appservice)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
@@ -1,8 +1,8 @@
 use std::iter::once;
-use std::ops::Deref;
-use std::path::Path;
-use std::sync::{LazyLock, OnceLock};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, OnceLock};
 
+use arc_swap::ArcSwapOption;
 use base64::Engine as _;
 use base64::engine::general_purpose::STANDARD;
 use figment::Figment;
@@ -11,6 +11,8 @@ use ipaddress::IPAddress;
 
 mod server;
 pub use server::*;
+mod acme;
+pub use acme::*;
 mod admin;
 pub use admin::*;
 // mod appservice;
@@ -23,26 +25,36 @@ pub use blurhash::*;
 // pub use cache::*;
 mod compression;
 pub use compression::*;
+mod coordination;
+pub use coordination::*;
 mod db;
 pub use db::*;
 // mod dns;
 // pub use dns::*;
+mod email;
+pub use email::*;
 mod federation;
 pub use federation::*;
 mod http_client;
 pub use http_client::*;
-// mod ldap;
-// pub use ldap::*;
+mod ldap;
+pub use ldap::*;
 mod logger;
 pub use logger::*;
 mod media;
 pub use media::*;
+mod metrics;
+pub use metrics::*;
 mod presence;
 pub use presence::*;
 mod proxy;
 pub use proxy::*;
+mod rate_limit;
+pub use rate_limit::*;
 mod read_receipt;
 pub use read_receipt::*;
+mod retention;
+pub use retention::*;
 mod turn;
 pub use turn::*;
 mod typing;
@@ -51,15 +63,21 @@ mod url_preview;
 pub use url_preview::*;
 mod well_known;
 pub use well_known::*;
+mod worker;
+pub use worker::*;
 mod oidc;
 pub use oidc::*;
 
-use crate::AppResult;
 use crate::core::client::discovery::capabilities::RoomVersionStability;
 use crate::core::identifiers::*;
 use crate::core::signatures::Ed25519KeyPair;
+use crate::logging::EnvFilter;
+use crate::{AppError, AppResult};
 
-pub static CONFIG: OnceLock<ServerConfig> = OnceLock::new();
+// Replaced as a whole on reload, configs handed out by `get` before that stay
+// valid until they are dropped.
+static CONFIG: ArcSwapOption<ServerConfig> = ArcSwapOption::const_empty();
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
 
 pub static STABLE_ROOM_VERSIONS: LazyLock<Vec<RoomVersionId>> = LazyLock::new(|| {
     vec![
@@ -101,8 +119,7 @@ pub fn init(config_path: impl AsRef<Path>) {
         panic!("config file not found: `{}`", config_path.display());
     }
 
-    let raw_conf = figment_from_path(config_path).merge(Env::prefixed("PALPO_").global());
-    let conf = match raw_conf.extract::<ServerConfig>() {
+    let conf = match load(config_path) {
         Ok(s) => s,
         Err(e) => {
             eprintln!("it looks like your config is invalid. The following error occurred: {e}");
@@ -110,15 +127,56 @@ pub fn init(config_path: impl AsRef<Path>) {
         }
     };
 
-    CONFIG.set(conf).expect("config should be set once");
+    if CONFIG.compare_and_swap(&None::<Arc<_>>, Some(Arc::new(conf))).is_some() {
+        panic!("config should be set once");
+    }
+    let _ = CONFIG_PATH.set(config_path.to_owned());
+}
+
+fn load(config_path: &Path) -> Result<ServerConfig, figment::Error> {
+    figment_from_path(config_path)
+        .merge(Env::prefixed("PALPO_").global())
+        .extract::<ServerConfig>()
 }
-pub fn reload(_path: impl AsRef<Path>) -> AppResult<()> {
-    // TODO: reload config
+
+/// Reloads the settings that can be changed while the server is running: the
+/// log level, rate limits, url preview lists and forbidden name patterns. The
+/// email templates are read again. Changes to other settings need a restart.
+pub fn reload(path: Option<&Path>) -> AppResult<()> {
+    let path = match path {
+        Some(path) => path,
+        None => CONFIG_PATH.get().expect("config path should be set"),
+    };
+    let new_conf = load(path).map_err(|e| {
+        AppError::public(format!("failed to load config `{}`: {e}", path.display()))
+    })?;
+    new_conf.check()?;
+    EnvFilter::try_new(&new_conf.logger.level)
+        .map_err(|e| AppError::public(format!("invalid log level: {e}")))?;
+
+    let mut conf = ServerConfig::clone(&get());
+    conf.logger.level = new_conf.logger.level;
+    conf.rate_limit = new_conf.rate_limit;
+    conf.url_preview = new_conf.url_preview;
+    conf.forbidden_remote_server_names = new_conf.forbidden_remote_server_names;
+    conf.forbidden_remote_room_directory_server_names =
+        new_conf.forbidden_remote_room_directory_server_names;
+    conf.forbidden_alias_names = new_conf.forbidden_alias_names;
+    conf.forbidden_usernames = new_conf.forbidden_usernames;
+    CONFIG.store(Some(Arc::new(conf)));
+
+    crate::logging::get()
+        .reload
+        .reload(&get().logger.level, None)?;
+    crate::user::pusher::mailer::reload_templates();
+    info!("reloaded config from `{}`", path.display());
     Ok(())
 }
 
-pub fn get() -> &'static ServerConfig {
-    CONFIG.get().unwrap()
+/// Returns the current config. Keep it only as long as needed, so a reload
+/// is picked up by the next call.
+pub fn get() -> Arc<ServerConfig> {
+    CONFIG.load_full().expect("config should be initialized")
 }
 
 pub static SERVER_USER_ID: OnceLock<OwnedUserId> = OnceLock::new();
@@ -134,11 +192,15 @@ pub fn server_user() -> crate::data::user::DbUser {
     crate::data::user::get_user(server_user_id()).expect("server user should exist in the database")
 }
 
+// Settings that are not reloaded are cached, so they can be borrowed for the
+// whole lifetime of the process.
 pub fn space_path() -> &'static str {
-    get().space_path.deref()
+    static SPACE_PATH: OnceLock<String> = OnceLock::new();
+    SPACE_PATH.get_or_init(|| get().space_path.clone())
 }
 pub fn server_name() -> &'static ServerName {
-    get().server_name.deref()
+    static SERVER_NAME: OnceLock<OwnedServerName> = OnceLock::new();
+    SERVER_NAME.get_or_init(|| get().server_name.clone())
 }
 
 static ADMIN_ALIAS: OnceLock<OwnedRoomAliasId> = OnceLock::new();
@@ -152,7 +214,10 @@ pub fn admin_alias() -> &'static RoomAliasId {
 }
 
 pub fn appservice_registration_dir() -> Option<&'static str> {
-    get().appservice_registration_dir.as_deref()
+    static APPSERVICE_REGISTRATION_DIR: OnceLock<Option<String>> = OnceLock::new();
+    APPSERVICE_REGISTRATION_DIR
+        .get_or_init(|| get().appservice_registration_dir.clone())
+        .as_deref()
 }
 
 /// Returns this server's keypair.
@@ -1,14 +1,109 @@
-use std::collections::BTreeMap;
-use std::fmt;
-use std::net::IpAddr;
 use std::path::PathBuf;
 
 use serde::Deserialize;
+use url::Url;
 
-use crate::core::serde::{default_false, default_true};
+use crate::core::serde::default_true;
 use crate::macros::config_example;
 
-#[config_example(filename = "palpo-example.toml", section = "acme")]
-#[derive(Clone, Debug, Deserialize, Default)]
+/// Automatic TLS certificates through ACME (e.g. Let's Encrypt).
+///
+/// Listeners with `acme = true` serve TLS with a certificate issued for
+/// `server_name` and the hosts of the well-known client and server endpoints.
+/// Certificates are renewed before they expire and picked up without a
+/// restart.
+#[config_example(filename = "palpo-example.toml", section = "auto_acme")]
+#[derive(Clone, Debug, Deserialize)]
 pub struct AcmeConfig {
+    /// Request certificates through ACME. Setting this to false keeps the
+    /// rest of the section, but listeners with `acme = true` are then not
+    /// started.
+    ///
+    /// default: true
+    #[serde(default = "default_true")]
+    pub enable: bool,
+
+    /// Directory url of the ACME server.
+    ///
+    /// example: "https://localhost:14000/dir"
+    ///
+    /// default: "https://acme-v02.api.letsencrypt.org/directory"
+    #[serde(default = "default_directory_url")]
+    pub directory_url: String,
+
+    /// Additional domains to include in the certificate.
+    ///
+    /// default: []
+    #[serde(default)]
+    pub domains: Vec<String>,
+
+    /// Contacts registered with the ACME account.
+    ///
+    /// example: ["mailto:admin@example.com"]
+    ///
+    /// default: []
+    #[serde(default)]
+    pub contacts: Vec<String>,
+
+    /// Challenge used to prove control of the domains. One of "tls-alpn-01",
+    /// answered by the ACME listeners themselves, or "http-01", which needs a
+    /// plain listener reachable on the port the ACME server validates on.
+    /// That is port 80 for public ACME servers such as Let's Encrypt, and
+    /// port 5002 for a default Pebble test server.
+    ///
+    /// default: "tls-alpn-01"
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+
+    /// Directory where the account key and certificates are stored, so they
+    /// survive restarts.
+    ///
+    /// default: "./acme"
+    #[serde(default = "default_cache_path")]
+    pub cache_path: PathBuf,
+}
+
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            directory_url: default_directory_url(),
+            domains: Vec::new(),
+            contacts: Vec::new(),
+            challenge: Default::default(),
+            cache_path: default_cache_path(),
+        }
+    }
+}
+
+impl AcmeConfig {
+    /// Name of the directory used for the files in `cache_path`, so that
+    /// certificates from different ACME servers are kept apart.
+    pub fn directory_name(&self) -> String {
+        let Ok(url) = Url::parse(&self.directory_url) else {
+            return "default".to_owned();
+        };
+        let host = url.host_str().unwrap_or("default");
+        match url.port() {
+            Some(port) => format!("{host}_{port}"),
+            None => host.to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+pub enum AcmeChallenge {
+    #[serde(rename = "http-01")]
+    Http01,
+    #[default]
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+fn default_directory_url() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_owned()
+}
+
+fn default_cache_path() -> PathBuf {
+    PathBuf::from("./acme")
 }
@@ -0,0 +1,54 @@
+use serde::Deserialize;
+
+use crate::macros::config_example;
+
+#[config_example(filename = "palpo-example.toml", section = "coordination")]
+#[derive(Clone, Debug, Deserialize)]
+pub struct CoordinationConfig {
+    /// How palpo processes sharing the same database coordinate, needed to
+    /// run several of them behind a load balancer.
+    ///
+    /// - "local": a single process, in-memory state like typing
+    ///   notifications is not shared.
+    /// - "postgres": processes exchange typing notifications and sync wake
+    ///   ups through Postgres `LISTEN/NOTIFY`.
+    ///
+    /// default: "local"
+    #[serde(default)]
+    pub backend: CoordinationBackend,
+
+    /// How often the Postgres listener checks for notifications of the other
+    /// processes, in milliseconds.
+    ///
+    /// default: 50
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+}
+
+impl Default for CoordinationConfig {
+    fn default() -> Self {
+        Self {
+            backend: CoordinationBackend::default(),
+            poll_interval: default_poll_interval(),
+        }
+    }
+}
+
+impl CoordinationConfig {
+    /// Whether other processes may share the database with this one.
+    pub fn is_shared(&self) -> bool {
+        self.backend != CoordinationBackend::Local
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CoordinationBackend {
+    #[default]
+    Local,
+    Postgres,
+}
+
+fn default_poll_interval() -> u64 {
+    50
+}
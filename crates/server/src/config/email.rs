@@ -0,0 +1,157 @@
+use serde::Deserialize;
+
+use crate::core::serde::default_true;
+use crate::macros::config_example;
+
+#[config_example(filename = "palpo-example.toml", section = "email")]
+#[derive(Clone, Debug, Deserialize)]
+pub struct EmailConfig {
+    /// Send emails, for validating email addresses and for notifications.
+    /// Setting this to false keeps the rest of the section but turns email
+    /// off.
+    ///
+    /// default: true
+    #[serde(default = "default_true")]
+    pub enable: bool,
+
+    /// Hostname of the SMTP server used to send emails.
+    ///
+    /// default: "localhost"
+    #[serde(default = "default_smtp_host")]
+    pub smtp_host: String,
+
+    /// Port of the SMTP server. Defaults to 25, or to 465 when `smtp_tls` is
+    /// "tls".
+    pub smtp_port: Option<u16>,
+
+    /// Username to authenticate against the SMTP server with. Authentication is
+    /// skipped if this is not set.
+    pub smtp_user: Option<String>,
+
+    /// Password to authenticate against the SMTP server with.
+    ///
+    /// display: sensitive
+    pub smtp_pass: Option<String>,
+
+    /// How to secure the connection to the SMTP server. One of "none",
+    /// "starttls" or "tls".
+    ///
+    /// default: "none"
+    #[serde(default)]
+    pub smtp_tls: SmtpTlsMode,
+
+    /// The address emails are sent from.
+    ///
+    /// example: "Palpo <noreply@example.com>"
+    ///
+    /// default: "Palpo <noreply@localhost>"
+    #[serde(default = "default_notif_from")]
+    pub notif_from: String,
+
+    /// Name of the service shown in email subjects and bodies.
+    ///
+    /// default: "Palpo"
+    #[serde(default = "default_app_name")]
+    pub app_name: String,
+
+    /// How long a validation token sent by email stays valid, in seconds.
+    ///
+    /// default: 3600
+    #[serde(default = "default_validation_token_lifetime")]
+    pub validation_token_lifetime: u64,
+
+    /// Email users that set up an email pusher about messages they have not
+    /// read yet.
+    #[serde(default)]
+    pub enable_notifs: bool,
+
+    /// Only email about unread highlights, such as mentions, instead of every
+    /// unread notification.
+    ///
+    /// default: true
+    #[serde(default = "default_true")]
+    pub notif_highlights_only: bool,
+
+    /// How long to wait after a notification before emailing about it, in
+    /// seconds. This gives the user a chance to read it in a client first.
+    ///
+    /// default: 600
+    #[serde(default = "default_notif_delay")]
+    pub notif_delay: u64,
+
+    /// Minimum time between two notification emails to the same address, in
+    /// seconds. Notifications arriving in between are batched into the next
+    /// email.
+    ///
+    /// default: 3600
+    #[serde(default = "default_notif_throttle")]
+    pub notif_throttle: u64,
+
+    /// Base url of a web client used for room links in notification emails.
+    /// Links point to matrix.to when this is not set.
+    ///
+    /// example: "https://app.element.io"
+    pub client_base_url: Option<String>,
+
+    /// Directory with custom templates for notification emails. A file here
+    /// replaces the built in template of the same name: `notif`, `notif_room`,
+    /// `notif_message` and `notif_more`, each as `.html` and `.txt`.
+    ///
+    /// example: "/etc/palpo/templates"
+    pub template_dir: Option<String>,
+}
+
+impl Default for EmailConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            smtp_host: default_smtp_host(),
+            smtp_port: None,
+            smtp_user: None,
+            smtp_pass: None,
+            smtp_tls: Default::default(),
+            notif_from: default_notif_from(),
+            app_name: default_app_name(),
+            validation_token_lifetime: default_validation_token_lifetime(),
+            enable_notifs: false,
+            notif_highlights_only: true,
+            notif_delay: default_notif_delay(),
+            notif_throttle: default_notif_throttle(),
+            client_base_url: None,
+            template_dir: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTlsMode {
+    #[default]
+    None,
+    StartTls,
+    Tls,
+}
+
+fn default_smtp_host() -> String {
+    "localhost".to_owned()
+}
+
+fn default_notif_from() -> String {
+    "Palpo <noreply@localhost>".to_owned()
+}
+
+fn default_app_name() -> String {
+    "Palpo".to_owned()
+}
+
+fn default_validation_token_lifetime() -> u64 {
+    60 * 60
+}
+
+fn default_notif_delay() -> u64 {
+    10 * 60
+}
+
+fn default_notif_throttle() -> u64 {
+    60 * 60
+}
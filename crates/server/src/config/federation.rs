@@ -38,6 +38,21 @@ pub struct FederationConfig {
     /// This is inherently false if `allow_federation` is disabled
     #[serde(default = "default_true")]
     pub allow_inbound_profile_lookup: bool,
+
+    /// How long to wait before retrying a destination after its first failed
+    /// transaction, in milliseconds. The wait doubles with every further
+    /// failure until a transaction succeeds.
+    ///
+    /// default: 30_000
+    #[serde(default = "default_min_retry_interval")]
+    pub min_retry_interval: u64,
+
+    /// Upper bound for the wait between retries of a failing destination, in
+    /// milliseconds.
+    ///
+    /// default: 86_400_000
+    #[serde(default = "default_max_retry_interval")]
+    pub max_retry_interval: u64,
 }
 
 impl Default for FederationConfig {
@@ -47,6 +62,16 @@ impl Default for FederationConfig {
             allow_loopback: false,
             allow_device_name: false,
             allow_inbound_profile_lookup: true,
+            min_retry_interval: default_min_retry_interval(),
+            max_retry_interval: default_max_retry_interval(),
         }
     }
 }
+
+fn default_min_retry_interval() -> u64 {
+    30_000
+}
+
+fn default_max_retry_interval() -> u64 {
+    86_400_000
+}
@@ -94,12 +94,27 @@ pub struct LdapConfig {
     /// If left blank, administrative state must be configured manually for each
     /// user.
     ///
+    /// The filter may match the user entries of admins, or a group entry.
+    /// Users listed in the `member`, `uniqueMember` or `memberUid` attribute of
+    /// a matched group are admins.
+    ///
     /// You can use the variable `{username}` that will be replaced by the
     /// entered username for more complex filters.
     ///
-    /// example: "(objectClass=palpoAdmin)" or "(uid={username})"
+    /// example: "(objectClass=palpoAdmin)" or "(cn=admins)"
     #[serde(default)]
     pub admin_filter: String,
+
+    /// How often to look up the users created through LDAP, in seconds.
+    /// Accounts of users that can no longer be found in LDAP are deactivated.
+    /// Set to 0 to disable.
+    ///
+    /// This requires a search to be possible, so it does nothing when
+    /// `bind_dn` contains `{username}`.
+    ///
+    /// default: 3600
+    #[serde(default = "default_ldap_deactivation_interval")]
+    pub deactivation_interval: u64,
 }
 
 fn default_ldap_search_filter() -> String {
@@ -117,3 +132,7 @@ fn default_ldap_mail_attribute() -> String {
 fn default_ldap_name_attribute() -> String {
     String::from("givenName")
 }
+
+fn default_ldap_deactivation_interval() -> u64 {
+    60 * 60
+}
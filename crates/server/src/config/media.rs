@@ -1,10 +1,10 @@
 use regex::RegexSet;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::core::serde::default_true;
 use crate::macros::config_example;
 
-#[config_example(filename = "palpo-example.toml", section = "media")]
+#[config_example(filename = "palpo-example.toml", section = "media", ignore = "s3")]
 #[derive(Clone, Debug, Deserialize)]
 pub struct MediaConfig {
     /// Enable the legacy unauthenticated Matrix media repository endpoints.
@@ -25,6 +25,28 @@ pub struct MediaConfig {
     #[serde(default = "default_true")]
     pub freeze_legacy: bool,
 
+    /// Where media files are stored.
+    ///
+    /// - "fs": files are kept under `space_path/media` on the local filesystem.
+    /// - "s3": files are kept in the S3-compatible object store configured in
+    ///   `[media.s3]`.
+    /// - "tiered": files are kept in the object store, with
+    ///   `space_path/media_cache` used as a read-through cache in front of it.
+    ///   Migrate existing media to the object store before switching.
+    ///
+    /// default: "fs"
+    #[serde(default)]
+    pub storage: MediaStorageKind,
+
+    /// Maximum size in bytes of the local cache used by the "tiered"
+    /// storage. Once the cache grows past this, the least recently used
+    /// files are removed from it. Set to 0 to let the cache grow without
+    /// limit.
+    ///
+    /// default: 10737418240
+    #[serde(default = "default_cache_capacity")]
+    pub cache_capacity: u64,
+
     /// Check consistency of the media directory at startup:
     /// 1. When `media_compat_file_link` is enabled, this check will upgrade media when switching
     ///    back and forth between Conduit and palpo. Both options must be enabled to handle this.
@@ -34,6 +56,9 @@ pub struct MediaConfig {
     /// If none of these checks apply to your use cases, and your media
     /// directory is significantly large setting this to false may reduce
     /// startup time.
+    ///
+    /// Only applies to the local filesystem, so it is ignored when `storage`
+    /// is "s3".
     #[serde(default = "default_true")]
     pub startup_check: bool,
 
@@ -69,6 +94,9 @@ pub struct MediaConfig {
     /// default: []
     #[serde(default, with = "serde_regex")]
     pub prevent_downloads_from: RegexSet,
+
+    // external structure; separate section
+    pub s3: Option<S3StorageConfig>,
 }
 
 impl Default for MediaConfig {
@@ -76,10 +104,67 @@ impl Default for MediaConfig {
         Self {
             allow_legacy: true,
             freeze_legacy: true,
+            storage: MediaStorageKind::default(),
+            cache_capacity: default_cache_capacity(),
             startup_check: true,
             compat_file_link: false,
             prune_missing: false,
             prevent_downloads_from: Default::default(),
+            s3: None,
         }
     }
 }
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaStorageKind {
+    #[default]
+    Fs,
+    S3,
+    Tiered,
+}
+
+#[config_example(filename = "palpo-example.toml", section = "media.s3")]
+#[derive(Clone, Debug, Deserialize)]
+pub struct S3StorageConfig {
+    /// Endpoint of the S3-compatible object store, including the scheme.
+    ///
+    /// example: "https://s3.eu-central-1.amazonaws.com"
+    pub endpoint: String,
+
+    /// Name of the bucket media files are stored in.
+    pub bucket: String,
+
+    /// Region used to sign requests. Most S3-compatible stores accept any
+    /// value here.
+    ///
+    /// default: "us-east-1"
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+
+    /// Access key ID used to sign requests.
+    pub access_key: String,
+
+    /// Secret access key used to sign requests.
+    ///
+    /// display: sensitive
+    pub secret_key: String,
+
+    /// Optional prefix prepended to every object key, e.g. "palpo/".
+    #[serde(default)]
+    pub prefix: String,
+
+    /// Address the bucket as `endpoint/bucket/key` instead of
+    /// `bucket.endpoint/key`. Most self-hosted stores such as MinIO need
+    /// this.
+    #[serde(default = "default_true")]
+    pub path_style: bool,
+}
+
+fn default_cache_capacity() -> u64 {
+    10 * 1024 * 1024 * 1024
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_owned()
+}
@@ -0,0 +1,52 @@
+use serde::Deserialize;
+
+use crate::core::serde::default_true;
+use crate::macros::config_example;
+
+/// Prometheus metrics.
+///
+/// Metrics are exposed in the OpenMetrics text format on a dedicated
+/// listener, so they can be kept off the public internet.
+#[config_example(filename = "palpo-example.toml", section = "metrics")]
+#[derive(Clone, Debug, Deserialize)]
+pub struct MetricsConfig {
+    /// Serve the metrics. Setting this to false keeps the rest of the section
+    /// but turns the listener off.
+    ///
+    /// default: true
+    #[serde(default = "default_true")]
+    pub enable: bool,
+
+    /// Address of the listener serving the `/metrics` endpoint.
+    ///
+    /// default: "127.0.0.1:9000"
+    #[serde(default = "default_metrics_address")]
+    pub address: String,
+
+    /// Maximum number of destinations that get their own series in the
+    /// per destination metrics. Further destinations are counted together
+    /// under the "other" label, which keeps the number of series bounded on
+    /// servers that federate widely.
+    ///
+    /// default: 100
+    #[serde(default = "default_max_destination_labels")]
+    pub max_destination_labels: usize,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            address: default_metrics_address(),
+            max_destination_labels: default_max_destination_labels(),
+        }
+    }
+}
+
+fn default_metrics_address() -> String {
+    "127.0.0.1:9000".to_owned()
+}
+
+fn default_max_destination_labels() -> usize {
+    100
+}
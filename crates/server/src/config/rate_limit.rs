@@ -0,0 +1,124 @@
+use serde::Deserialize;
+
+use crate::core::serde::default_true;
+use crate::macros::config_example;
+
+#[config_example(filename = "palpo-example.toml", section = "rate_limit")]
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitConfig {
+    /// Enable rate limiting of client requests. When a client exceeds its
+    /// budget the request is rejected with `M_LIMIT_EXCEEDED` and a
+    /// `retry_after_ms` hint.
+    #[serde(default = "default_true")]
+    pub enable: bool,
+
+    /// Requests from appservices are exempt from rate limiting unless their
+    /// registration sets `rate_limited: true`.
+    #[serde(default = "default_true")]
+    pub exempt_appservices: bool,
+
+    /// Admin users are exempt from rate limiting.
+    #[serde(default = "default_true")]
+    pub exempt_admins: bool,
+
+    /// Default limit for endpoints that are not covered by a more specific
+    /// class below. Keyed by user device for authenticated requests and by
+    /// IP address otherwise.
+    ///
+    /// default: { per_second = 10.0, burst_count = 50 }
+    #[serde(default = "default_rule")]
+    pub default: RateLimitRule,
+
+    /// Limit for login attempts, keyed by IP address.
+    ///
+    /// default: { per_second = 0.17, burst_count = 3 }
+    #[serde(default = "default_login_rule")]
+    pub login: RateLimitRule,
+
+    /// Limit for registration attempts, keyed by IP address.
+    ///
+    /// default: { per_second = 0.17, burst_count = 3 }
+    #[serde(default = "default_register_rule")]
+    pub register: RateLimitRule,
+
+    /// Limit for sending messages, state events and redactions, keyed by
+    /// user. Per-user overrides set through the admin API take precedence.
+    ///
+    /// default: { per_second = 0.2, burst_count = 10 }
+    #[serde(default = "default_message_rule")]
+    pub message: RateLimitRule,
+
+    /// Limit for joining, knocking and creating rooms, keyed by user.
+    ///
+    /// default: { per_second = 0.1, burst_count = 10 }
+    #[serde(default = "default_join_rule")]
+    pub join: RateLimitRule,
+
+    /// Limit for media uploads, keyed by user.
+    ///
+    /// default: { per_second = 1.0, burst_count = 10 }
+    #[serde(default = "default_media_upload_rule")]
+    pub media_upload: RateLimitRule,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            exempt_appservices: true,
+            exempt_admins: true,
+            default: default_rule(),
+            login: default_login_rule(),
+            register: default_register_rule(),
+            message: default_message_rule(),
+            join: default_join_rule(),
+            media_upload: default_media_upload_rule(),
+        }
+    }
+}
+
+/// A token bucket definition: `burst_count` requests may be made at once, and
+/// the bucket refills at `per_second` requests per second. Setting either
+/// value to zero disables the limit.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct RateLimitRule {
+    pub per_second: f64,
+    pub burst_count: u32,
+}
+
+impl RateLimitRule {
+    pub fn new(per_second: f64, burst_count: u32) -> Self {
+        Self {
+            per_second,
+            burst_count,
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.per_second <= 0.0 || self.burst_count == 0
+    }
+}
+
+fn default_rule() -> RateLimitRule {
+    RateLimitRule::new(10.0, 50)
+}
+
+fn default_login_rule() -> RateLimitRule {
+    RateLimitRule::new(0.17, 3)
+}
+
+fn default_register_rule() -> RateLimitRule {
+    RateLimitRule::new(0.17, 3)
+}
+
+fn default_message_rule() -> RateLimitRule {
+    RateLimitRule::new(0.2, 10)
+}
+
+fn default_join_rule() -> RateLimitRule {
+    RateLimitRule::new(0.1, 10)
+}
+
+fn default_media_upload_rule() -> RateLimitRule {
+    RateLimitRule::new(1.0, 10)
+}
@@ -0,0 +1,66 @@
+use serde::Deserialize;
+
+use crate::macros::config_example;
+
+#[config_example(filename = "palpo-example.toml", section = "retention")]
+#[derive(Clone, Debug, Deserialize)]
+pub struct RetentionConfig {
+    /// Enforce `m.room.retention` policies by periodically deleting the events
+    /// that outlived the max lifetime of their room. State events and the
+    /// latest events of a room are always kept.
+    #[serde(default)]
+    pub enable: bool,
+
+    /// Max lifetime of events in rooms without a retention policy, in
+    /// milliseconds. Events of those rooms are kept forever if this is unset.
+    ///
+    /// example: 31_536_000_000
+    pub default_max_lifetime: Option<u64>,
+
+    /// Smallest max lifetime a room may set, shorter ones are raised to it.
+    pub allowed_lifetime_min: Option<u64>,
+
+    /// Largest max lifetime a room may set, longer ones and rooms keeping
+    /// their events forever are capped to it.
+    ///
+    /// example: 31_536_000_000
+    pub allowed_lifetime_max: Option<u64>,
+
+    /// How often expired events are deleted, in milliseconds.
+    ///
+    /// default: 86_400_000
+    #[serde(default = "default_purge_interval")]
+    pub purge_interval: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            default_max_lifetime: None,
+            allowed_lifetime_min: None,
+            allowed_lifetime_max: None,
+            purge_interval: default_purge_interval(),
+        }
+    }
+}
+
+impl RetentionConfig {
+    /// The max lifetime enforced for a room, given the one of its policy.
+    pub fn max_lifetime(&self, room_max_lifetime: Option<u64>) -> Option<u64> {
+        let lifetime = room_max_lifetime
+            .or(self.default_max_lifetime)
+            .or(self.allowed_lifetime_max)?;
+        let lifetime = self
+            .allowed_lifetime_max
+            .map_or(lifetime, |max| lifetime.min(max));
+        Some(
+            self.allowed_lifetime_min
+                .map_or(lifetime, |min| lifetime.max(min)),
+        )
+    }
+}
+
+fn default_purge_interval() -> u64 {
+    86_400_000
+}
@@ -5,11 +5,14 @@ use regex::RegexSet;
 use salvo::http::HeaderValue;
 use serde::Deserialize;
 use serde::de::IgnoredAny;
+use url::Url;
 
 use super::{
-    AdminConfig, BlurhashConfig, CompressionConfig, DbConfig, FederationConfig, HttpClientConfig,
-    JwtConfig, LoggerConfig, MediaConfig, OidcConfig, PresenceConfig, ProxyConfig,
-    ReadReceiptConfig, TurnConfig, TypingConfig, UrlPreviewConfig, WellKnownConfig,
+    AcmeConfig, AdminConfig, BlurhashConfig, CompressionConfig, CoordinationConfig, DbConfig,
+    EmailConfig, FederationConfig, HttpClientConfig, JwtConfig, LdapConfig, LoggerConfig,
+    MediaConfig, MediaStorageKind, MetricsConfig, OidcConfig, PresenceConfig, ProxyConfig,
+    RateLimitConfig, ReadReceiptConfig, RetentionConfig, TurnConfig, TypingConfig,
+    UrlPreviewConfig, WellKnownConfig, WorkerConfig,
 };
 use crate::core::serde::{default_false, default_true};
 use crate::core::{OwnedRoomOrAliasId, OwnedServerName, RoomVersionId};
@@ -35,6 +38,30 @@ pub struct ListenerConfig {
     pub x_forwarded: bool,
     // external structure; separate section
     pub tls: Option<TlsConfig>,
+    /// Serve TLS with a certificate obtained through `auto_acme`.
+    #[serde(default)]
+    pub acme: bool,
+
+    /// The UNIX socket to listen on instead of `address`.
+    ///
+    /// Remember to make sure that your reverse proxy has access to this socket
+    /// file, either through `unix_socket_group` or by granting world R/W
+    /// permissions with `unix_socket_perms` (666 minimum).
+    ///
+    /// example: "/run/palpo/palpo.sock"
+    pub unix_socket_path: Option<PathBuf>,
+
+    /// The permissions (in octal) to create the UNIX socket with.
+    ///
+    /// default: 660
+    #[serde(default = "default_unix_socket_perms")]
+    pub unix_socket_perms: u32,
+
+    /// User id to give the ownership of the UNIX socket to.
+    pub unix_socket_owner: Option<u32>,
+
+    /// Group id to give the ownership of the UNIX socket to.
+    pub unix_socket_group: Option<u32>,
 }
 impl Default for ListenerConfig {
     fn default() -> Self {
@@ -42,6 +69,11 @@ impl Default for ListenerConfig {
             address: default_listen_address(),
             x_forwarded: false,
             tls: None,
+            acme: false,
+            unix_socket_path: None,
+            unix_socket_perms: default_unix_socket_perms(),
+            unix_socket_owner: None,
+            unix_socket_group: None,
         }
     }
 }
@@ -76,7 +108,8 @@ impl ListenerConfig {
 ### https://palpo.im/guide/configuration.html
 "#,
     ignore = "catch_others federation well_known compression typing read_receipt presence \
-        admin url_preview turn media blurhash keypair ldap proxy jwt oidc logger db appservice"
+        admin url_preview turn media blurhash keypair ldap proxy jwt oidc logger db appservice \
+        rate_limit email auto_acme metrics retention coordination worker"
 )]
 #[derive(Clone, Debug, Deserialize)]
 pub struct ServerConfig {
@@ -113,23 +146,6 @@ pub struct ServerConfig {
     #[serde(default = "default_new_user_displayname_suffix")]
     pub new_user_displayname_suffix: String,
 
-    // /// The UNIX socket palpo will listen on.
-    // ///
-    // /// palpo cannot listen on both an IP address and a UNIX socket. If
-    // /// listening on a UNIX socket, you MUST remove/comment the `address` key.
-    // ///
-    // /// Remember to make sure that your reverse proxy has access to this socket
-    // /// file, either by adding your reverse proxy to the 'palpo' group or
-    // /// granting world R/W permissions with `unix_socket_perms` (666 minimum).
-    // ///
-    // /// example: "/run/palpo/palpo.sock"
-    // pub unix_socket_path: Option<PathBuf>,
-
-    // /// The default permissions (in octal) to create the UNIX socket with.
-    // ///
-    // /// default: 660
-    // #[serde(default = "default_unix_socket_perms")]
-    // pub unix_socket_perms: u32,
     /// Enable to query all nameservers until the domain is found. Referred to
     /// as "trust_negative_responses" in hickory_resolver. This can avoid
     /// useless DNS queries if the first nameserver responds with NXDOMAIN or
@@ -361,15 +377,31 @@ pub struct ServerConfig {
     /// Servers listed here will be used to gather public keys of other servers
     /// (notary trusted key servers).
     ///
-    /// Currently, palpo doesn't support inbound batched key requests, so
-    /// this list should only contain other Synapse servers.
-    ///
     /// example: ["matrix.org", "tchncs.de"]
     ///
     /// default: ["matrix.org"]
     #[serde(default = "default_trusted_servers")]
     pub trusted_servers: Vec<OwnedServerName>,
 
+    /// Identity servers the server talks to on behalf of clients, to bind,
+    /// unbind and look up third party identifiers and to check the keys of
+    /// third party invites. Requests naming any other identity server are
+    /// refused.
+    ///
+    /// default: ["matrix.org", "vector.im"]
+    #[serde(default = "default_trusted_identity_servers")]
+    pub trusted_identity_servers: Vec<String>,
+
+    /// Identity servers to reach over plain HTTP instead of HTTPS, when
+    /// inviting users by email address. Only meant for testing against a local
+    /// identity server.
+    ///
+    /// example: ["localhost:8090"]
+    ///
+    /// default: []
+    #[serde(default)]
+    pub insecure_identity_servers: Vec<String>,
+
     /// OpenID token expiration/TTL.
     ///
     /// These are the OpenID tokens that are primarily used for Matrix account
@@ -546,7 +578,12 @@ pub struct ServerConfig {
     #[serde(default = "default_space_path")]
     pub space_path: String,
 
-    // pub auto_acme: Option<AcmeConfig>,
+    // external structure; separate section
+    pub auto_acme: Option<AcmeConfig>,
+
+    // external structure; separate section
+    pub metrics: Option<MetricsConfig>,
+
     /// Whether to query the servers listed in trusted_servers first or query
     /// the origin server first. For best security, querying the origin server
     /// first is advised to minimize the exposure to a compromised trusted
@@ -644,8 +681,8 @@ pub struct ServerConfig {
     // external structure; separate section
     pub proxy: Option<ProxyConfig>,
 
-    // // external structure; separate section
-    // pub ldap: Option<LdapConfig>,
+    // external structure; separate section
+    pub ldap: Option<LdapConfig>,
 
     // external structure; separate section
     // display: hidden
@@ -662,6 +699,9 @@ pub struct ServerConfig {
     // external structure; separate section
     pub turn: Option<TurnConfig>,
 
+    // external structure; separate section
+    pub email: Option<EmailConfig>,
+
     // external structure; separate section
     #[serde(default)]
     pub url_preview: UrlPreviewConfig,
@@ -683,6 +723,10 @@ pub struct ServerConfig {
     #[serde(default)]
     pub typing: TypingConfig,
 
+    // external structure; separate section
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+
     // external structure; separate section
     #[serde(default)]
     pub compression: CompressionConfig,
@@ -699,6 +743,18 @@ pub struct ServerConfig {
     #[serde(default)]
     pub http_client: HttpClientConfig,
 
+    // external structure; separate section
+    #[serde(default)]
+    pub retention: RetentionConfig,
+
+    // external structure; separate section
+    #[serde(default)]
+    pub coordination: CoordinationConfig,
+
+    // external structure; separate section
+    #[serde(default)]
+    pub worker: WorkerConfig,
+
     /// Enables configuration reload when the server receives SIGUSR1 on
     /// supporting platforms.
     ///
@@ -737,13 +793,29 @@ pub struct ServerConfig {
 }
 
 impl ServerConfig {
-    // pub fn enabled_ldap(&self) -> Option<&LdapConfig> {
-    //     if let Some(ldap) = self.ldap.as_ref() {
-    //         if ldap.enable { Some(ldap) } else { None }
-    //     } else {
-    //         None
-    //     }
-    // }
+    pub fn enabled_ldap(&self) -> Option<&LdapConfig> {
+        if let Some(ldap) = self.ldap.as_ref() {
+            if ldap.enable { Some(ldap) } else { None }
+        } else {
+            None
+        }
+    }
+
+    pub fn enabled_acme(&self) -> Option<&AcmeConfig> {
+        if let Some(acme) = self.auto_acme.as_ref() {
+            if acme.enable { Some(acme) } else { None }
+        } else {
+            None
+        }
+    }
+
+    pub fn enabled_metrics(&self) -> Option<&MetricsConfig> {
+        if let Some(metrics) = self.metrics.as_ref() {
+            if metrics.enable { Some(metrics) } else { None }
+        } else {
+            None
+        }
+    }
 
     pub fn enabled_jwt(&self) -> Option<&JwtConfig> {
         if let Some(jwt) = self.jwt.as_ref() {
@@ -769,6 +841,14 @@ impl ServerConfig {
         }
     }
 
+    pub fn enabled_email(&self) -> Option<&EmailConfig> {
+        if let Some(email) = self.email.as_ref() {
+            if email.enable { Some(email) } else { None }
+        } else {
+            None
+        }
+    }
+
     pub fn enabled_federation(&self) -> Option<&FederationConfig> {
         if self.federation.enable {
             Some(&self.federation)
@@ -783,7 +863,7 @@ impl ServerConfig {
         } else {
             // If under proxy, you should set well-known client manually.
             if let Some(listenser) = self.listeners.first() {
-                if listenser.enabled_tls().is_none() {
+                if listenser.enabled_tls().is_none() && !listenser.acme {
                     return format!("http://{}", self.server_name);
                 }
             };
@@ -806,6 +886,30 @@ impl ServerConfig {
         }
     }
 
+    /// Domains the ACME certificate is issued for: the server name, the hosts
+    /// of the well-known endpoints and any configured extra domains.
+    pub fn acme_domains(&self) -> Vec<String> {
+        let mut domains = vec![self.server_name.host().to_owned()];
+        if let Some(host) = self
+            .well_known
+            .client
+            .as_deref()
+            .and_then(|client| Url::parse(client).ok())
+            .and_then(|url| url.host_str().map(ToOwned::to_owned))
+        {
+            domains.push(host);
+        }
+        if let Some(server) = &self.well_known.server {
+            domains.push(server.host().to_owned());
+        }
+        if let Some(acme) = self.enabled_acme() {
+            domains.extend(acme.domains.iter().cloned());
+        }
+        domains.sort();
+        domains.dedup();
+        domains
+    }
+
     pub fn check(&self) -> AppResult<()> {
         if cfg!(debug_assertions) {
             tracing::warn!("Note: palpo was built without optimisations (i.e. debug build)");
@@ -840,12 +944,31 @@ impl ServerConfig {
         //     );
         // }
 
-        // if cfg!(not(unix)) && self.unix_socket_path.is_some() {
-        //     return Err(AppError::internal(
-        //         "UNIX socket support is only available on *nix platforms. Please remove \
-        //          'unix_socket_path' from your config.",
-        //     ));
-        // }
+        for listener in &self.listeners {
+            if listener.unix_socket_path.is_none() {
+                continue;
+            }
+            if cfg!(not(unix)) {
+                return Err(AppError::internal(
+                    "UNIX socket support is only available on *nix platforms. Please remove \
+                     'unix_socket_path' from your config.",
+                ));
+            }
+            if listener.acme || listener.enabled_tls().is_some() {
+                return Err(AppError::internal(
+                    "A listener on a UNIX socket can't use `tls` or `acme`.",
+                ));
+            }
+        }
+
+        if self.worker.federation_sender_instances == 0
+            || self.worker.federation_sender_index >= self.worker.federation_sender_instances
+        {
+            return Err(AppError::internal(
+                "`worker.federation_sender_index` must be lower than \
+                 `worker.federation_sender_instances`.",
+            ));
+        }
 
         // if self.unix_socket_path.is_none() && self.get_bind_hosts().is_empty() {
         //     return Err(AppError::internal("No TCP addresses were specified to listen on"));
@@ -917,6 +1040,31 @@ impl ServerConfig {
             ));
         }
 
+        let acme_listeners = self
+            .listeners
+            .iter()
+            .filter(|listener| listener.acme)
+            .count();
+        if acme_listeners > 1 {
+            return Err(AppError::internal("Only one listener can use `acme`."));
+        }
+        if acme_listeners == 1 {
+            if self.enabled_acme().is_none() {
+                return Err(AppError::internal(
+                    "Listeners with `acme` enabled require the `auto_acme` section to be configured.",
+                ));
+            }
+            if self
+                .listeners
+                .iter()
+                .any(|listener| listener.acme && listener.enabled_tls().is_some())
+            {
+                return Err(AppError::internal(
+                    "A listener can't use both `acme` and a static `tls` certificate.",
+                ));
+            }
+        }
+
         // check if the user specified a registration token as `""`
         if self.registration_token == Some(String::new()) {
             return Err(AppError::internal(
@@ -995,6 +1143,28 @@ impl ServerConfig {
 
         self.url_preview.check();
 
+        if self.retention.purge_interval == 0 {
+            return Err(AppError::internal(
+                "retention.purge_interval must be greater than 0.",
+            ));
+        }
+        if let (Some(min), Some(max)) = (
+            self.retention.allowed_lifetime_min,
+            self.retention.allowed_lifetime_max,
+        ) && min > max
+        {
+            return Err(AppError::internal(
+                "retention.allowed_lifetime_min must not be greater than \
+                 retention.allowed_lifetime_max.",
+            ));
+        }
+
+        if self.media.storage != MediaStorageKind::Fs && self.media.s3.is_none() {
+            return Err(AppError::internal(
+                "The \"s3\" and \"tiered\" media storage backends require a [media.s3] section.",
+            ));
+        }
+
         // if let Some(Either::Right(_)) = self.url_preview_bound_interface.as_ref() {
         //     if !matches!(OS, "android" | "fuchsia" | "linux") {
         //         return Err(AppError::internal(
@@ -1095,6 +1265,9 @@ fn default_listener() -> Vec<ListenerConfig> {
 fn default_listen_address() -> String {
     "0.0.0.0:8008".into()
 }
+fn default_unix_socket_perms() -> u32 {
+    660
+}
 fn default_server_name() -> OwnedServerName {
     OwnedServerName::try_from("change.palpo.im").expect("default server name should be valid")
 }
@@ -1169,6 +1342,10 @@ fn default_trusted_servers() -> Vec<OwnedServerName> {
     vec![OwnedServerName::try_from("matrix.org").unwrap()]
 }
 
+fn default_trusted_identity_servers() -> Vec<String> {
+    vec!["matrix.org".to_owned(), "vector.im".to_owned()]
+}
+
 fn default_rust_log() -> String {
     "warn".to_owned()
 }
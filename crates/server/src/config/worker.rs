@@ -0,0 +1,71 @@
+use serde::Deserialize;
+
+use crate::macros::config_example;
+
+#[config_example(filename = "palpo-example.toml", section = "worker")]
+#[derive(Clone, Debug, Deserialize)]
+pub struct WorkerConfig {
+    /// Subsystems this process runs, so heavy media and federation traffic
+    /// can be served by other processes than the client API. Overridden by
+    /// the `--role` command line option.
+    ///
+    /// - "all": everything, for a single process.
+    /// - "client": the client-server and admin APIs, including `/sync`.
+    /// - "federation": the server-server API, receiving from other servers.
+    /// - "federation_sender": sending to other servers, appservices and
+    ///   push gateways.
+    /// - "media": the media repository.
+    /// - "background": scheduled tasks, retention and other periodic jobs.
+    ///
+    /// Processes with different roles must share the database and use the
+    /// "postgres" coordination backend.
+    ///
+    /// default: ["all"]
+    #[serde(default = "default_roles")]
+    pub roles: Vec<WorkerRole>,
+
+    /// Number of processes with the "federation_sender" role, each sending
+    /// to its own share of the destinations.
+    ///
+    /// default: 1
+    #[serde(default = "default_federation_sender_instances")]
+    pub federation_sender_instances: u32,
+
+    /// Index of this process among the federation senders, from 0 to
+    /// `federation_sender_instances - 1`. Appservices and push gateways are
+    /// sent to by the first one.
+    ///
+    /// default: 0
+    #[serde(default)]
+    pub federation_sender_index: u32,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            roles: default_roles(),
+            federation_sender_instances: default_federation_sender_instances(),
+            federation_sender_index: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum WorkerRole {
+    All,
+    Client,
+    Federation,
+    FederationSender,
+    Media,
+    Background,
+}
+
+fn default_roles() -> Vec<WorkerRole> {
+    vec![WorkerRole::All]
+}
+
+fn default_federation_sender_instances() -> u32 {
+    1
+}
@@ -0,0 +1,208 @@
+//! Coordination between palpo processes sharing the same database.
+//!
+//! Durable state lives in the database, which every process reads, and syncs
+//! notice its changes by polling it. Typing notifications are only kept in
+//! memory, so with a shared backend each process publishes its typing updates
+//! to the others, which also wakes the syncs waiting on them. Processes also
+//! tell the ones with other roles about the work they queued for them, see
+//! `worker`.
+//!
+//! The "postgres" backend publishes with `pg_notify` and receives on a
+//! dedicated connection `LISTEN`ing on the channel.
+
+use std::sync::LazyLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+
+use crate::config::CoordinationBackend;
+use crate::core::UnixMillis;
+use crate::core::identifiers::*;
+use crate::data::connect;
+use crate::sending::OutgoingKind;
+use crate::{AppResult, config, data, utils};
+
+const CHANNEL: &str = "palpo_coordination";
+
+/// How long to wait before listening again after the connection failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How often the listening connection is checked, as a broken one receives
+/// nothing without failing.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often the shared state left behind by clients is pruned.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Members lazily sent in a sync response the client never confirmed are
+/// dropped after this long.
+const LAZY_LOAD_WAITING_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Sliding sync connections unused for this long are dropped, the client
+/// starts a new one.
+const SYNC_CONNECTION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Identifies this process, so it ignores the notifications it published.
+static INSTANCE_ID: LazyLock<String> = LazyLock::new(|| utils::random_string(16));
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Notification {
+    /// The user is typing until the `timeout` timestamp, or stopped typing
+    /// when there is none.
+    Typing {
+        room_id: OwnedRoomId,
+        user_id: OwnedUserId,
+        timeout: Option<u64>,
+    },
+    /// Requests were queued for these destinations, for the federation
+    /// senders owning them.
+    SendingQueued { outgoing_kinds: Vec<OutgoingKind> },
+    /// A task was scheduled, for the process running background jobs.
+    TaskScheduled { task_id: String },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Message {
+    instance_id: String,
+    #[serde(flatten)]
+    notification: Notification,
+}
+
+/// Identifies this process among the ones sharing the database.
+pub fn instance_id() -> &'static str {
+    &INSTANCE_ID
+}
+
+/// Starts listening for the notifications of the other processes.
+///
+/// Notifications published while the listening connection is down are lost.
+/// The database stays the source of truth, so the jobs acting on them also
+/// rescan their tables now and then.
+pub fn start() {
+    let conf = &config::get().coordination;
+    if conf.backend != CoordinationBackend::Postgres {
+        return;
+    }
+    let handle = Handle::current();
+    let poll_interval = Duration::from_millis(conf.poll_interval);
+    // Notifications are polled with blocking calls, so it gets its own thread.
+    let spawned = thread::Builder::new()
+        .name("palpo-coordination".to_owned())
+        .spawn(move || {
+            loop {
+                if let Err(e) = listen(&handle, poll_interval) {
+                    error!("coordination listener failed: {e}");
+                }
+                thread::sleep(RECONNECT_DELAY);
+            }
+        });
+    if let Err(e) = spawned {
+        error!("failed to start coordination listener: {e}");
+    }
+}
+
+/// Starts the job that periodically prunes the state stored for the other
+/// processes, there is none without a shared backend.
+pub fn start_pruning() {
+    if !config::get().coordination.is_shared() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = utils::spawn_blocking(prune).await {
+                error!("failed to prune shared state: {e}");
+            }
+        }
+    });
+}
+
+fn prune() -> AppResult<()> {
+    let now = UnixMillis::now().get();
+    let pruned = crate::room::lazy_loading::prune_lazy_load_waitings(UnixMillis(
+        now.saturating_sub(LAZY_LOAD_WAITING_TTL.as_millis() as u64),
+    ))?;
+    if pruned > 0 {
+        debug!("pruned {pruned} lazy load waitings");
+    }
+    let pruned = crate::sync_v5::prune_sync_connections(UnixMillis(
+        now.saturating_sub(SYNC_CONNECTION_TTL.as_millis() as u64),
+    ))?;
+    if pruned > 0 {
+        debug!("pruned {pruned} sliding sync connections");
+    }
+    Ok(())
+}
+
+/// Sends the notification to the other processes, if there may be any.
+pub fn publish(notification: Notification) -> AppResult<()> {
+    if !config::get().coordination.is_shared() {
+        return Ok(());
+    }
+    let payload = serde_json::to_string(&Message {
+        instance_id: INSTANCE_ID.clone(),
+        notification,
+    })?;
+    diesel::sql_query("SELECT pg_notify($1, $2)")
+        .bind::<Text, _>(CHANNEL)
+        .bind::<Text, _>(payload)
+        .execute(&mut connect()?)?;
+    Ok(())
+}
+
+fn listen(handle: &Handle, poll_interval: Duration) -> AppResult<()> {
+    let mut conn = data::connect_dedicated(&config::get().db.clone().into_data_db_config())?;
+    conn.batch_execute(&format!("LISTEN {CHANNEL}"))?;
+    info!("listening for notifications of other processes");
+
+    let mut checked_at = Instant::now();
+    loop {
+        for notification in conn.notifications_iter() {
+            receive(handle, &notification?.payload);
+        }
+        if checked_at.elapsed() > HEALTH_CHECK_INTERVAL {
+            conn.batch_execute("SELECT 1")?;
+            checked_at = Instant::now();
+        }
+        thread::sleep(poll_interval);
+    }
+}
+
+fn receive(handle: &Handle, payload: &str) {
+    let message = match serde_json::from_str::<Message>(payload) {
+        Ok(message) => message,
+        Err(e) => {
+            warn!("invalid coordination notification {payload}: {e}");
+            return;
+        }
+    };
+    if message.instance_id == *INSTANCE_ID {
+        return;
+    }
+    match message.notification {
+        Notification::Typing {
+            room_id,
+            user_id,
+            timeout,
+        } => {
+            handle.spawn(async move {
+                if let Err(e) =
+                    crate::room::typing::apply_replicated(&user_id, &room_id, timeout).await
+                {
+                    warn!("failed to apply typing update of {user_id} in {room_id}: {e}");
+                }
+            });
+        }
+        Notification::SendingQueued { outgoing_kinds } => {
+            crate::sending::guard::wake(outgoing_kinds);
+        }
+        Notification::TaskScheduled { task_id } => crate::scheduler::enqueue(task_id),
+    }
+}
@@ -0,0 +1,115 @@
+//! Outgoing email over SMTP.
+
+use std::sync::Mutex;
+
+use lettre::message::header::{HeaderName, HeaderValue};
+use lettre::message::{Mailbox, MessageBuilder, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+use crate::config::{self, EmailConfig, SmtpTlsMode};
+use crate::{AppError, AppResult};
+
+/// The transport is rebuilt whenever the SMTP settings change on config reload.
+static TRANSPORT: Mutex<Option<(SmtpSettings, AsyncSmtpTransport<Tokio1Executor>)>> =
+    Mutex::new(None);
+
+/// The parts of the email config the SMTP transport is built from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SmtpSettings {
+    host: String,
+    port: Option<u16>,
+    tls: SmtpTlsMode,
+    user: Option<String>,
+    pass: Option<String>,
+}
+
+impl SmtpSettings {
+    fn new(conf: &EmailConfig) -> Self {
+        Self {
+            host: conf.smtp_host.clone(),
+            port: conf.smtp_port,
+            tls: conf.smtp_tls,
+            user: conf.smtp_user.clone(),
+            pass: conf.smtp_pass.clone(),
+        }
+    }
+}
+
+pub fn config() -> AppResult<EmailConfig> {
+    config::get()
+        .enabled_email()
+        .cloned()
+        .ok_or_else(|| AppError::public("email is not configured on this server"))
+}
+
+fn transport(conf: &EmailConfig) -> AppResult<AsyncSmtpTransport<Tokio1Executor>> {
+    let settings = SmtpSettings::new(conf);
+    let mut cached = TRANSPORT.lock().expect("locking should not fail");
+    if let Some((cached_settings, transport)) = &*cached
+        && *cached_settings == settings
+    {
+        return Ok(transport.clone());
+    }
+
+    let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&settings.host);
+    builder = match settings.tls {
+        SmtpTlsMode::None => builder.port(settings.port.unwrap_or(25)),
+        SmtpTlsMode::StartTls => builder
+            .port(settings.port.unwrap_or(25))
+            .tls(Tls::Required(TlsParameters::new(settings.host.clone())?)),
+        SmtpTlsMode::Tls => builder
+            .port(settings.port.unwrap_or(465))
+            .tls(Tls::Wrapper(TlsParameters::new(settings.host.clone())?)),
+    };
+    if let Some(user) = &settings.user {
+        builder = builder.credentials(Credentials::new(
+            user.clone(),
+            settings.pass.clone().unwrap_or_default(),
+        ));
+    }
+    let transport = builder.build();
+    *cached = Some((settings, transport.clone()));
+    Ok(transport)
+}
+
+/// Sends an email with both a plain text and an html body.
+pub async fn send(to: &str, subject: &str, text: String, html: String) -> AppResult<()> {
+    deliver(message_builder(to, subject)?, text, html).await
+}
+
+/// Sends a notification email carrying the `List-Unsubscribe` headers, so mail
+/// clients can offer a one click way to stop receiving them (RFC 8058).
+pub async fn send_notification(
+    to: &str,
+    subject: &str,
+    text: String,
+    html: String,
+    unsubscribe_url: &str,
+) -> AppResult<()> {
+    let builder = message_builder(to, subject)?
+        .raw_header(HeaderValue::new(
+            HeaderName::new_from_ascii_str("List-Unsubscribe"),
+            format!("<{unsubscribe_url}>"),
+        ))
+        .raw_header(HeaderValue::new(
+            HeaderName::new_from_ascii_str("List-Unsubscribe-Post"),
+            "List-Unsubscribe=One-Click".to_owned(),
+        ));
+    deliver(builder, text, html).await
+}
+
+fn message_builder(to: &str, subject: &str) -> AppResult<MessageBuilder> {
+    let conf = config()?;
+    Ok(Message::builder()
+        .from(conf.notif_from.parse::<Mailbox>()?)
+        .to(to.parse::<Mailbox>()?)
+        .subject(subject))
+}
+
+async fn deliver(builder: MessageBuilder, text: String, html: String) -> AppResult<()> {
+    let message = builder.multipart(MultiPart::alternative_plain_html(text, html))?;
+    transport(&config()?)?.send(message).await?;
+    Ok(())
+}
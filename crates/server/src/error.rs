@@ -92,6 +92,14 @@ pub enum AppError {
     SystemTime(#[from] std::time::SystemTimeError),
     #[error("ReqwestMiddlewareError: `{0}`")]
     ReqwestMiddleware(#[from] reqwest_middleware::Error),
+    #[error("Smtp error: `{0}`")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("Email error: `{0}`")]
+    Email(#[from] lettre::error::Error),
+    #[error("Email address error: `{0}`")]
+    EmailAddress(#[from] lettre::address::AddressError),
+    #[error("Blocking task error: `{0}`")]
+    Join(#[from] tokio::task::JoinError),
 }
 
 impl AppError {
@@ -503,54 +503,61 @@ pub async fn process_to_timeline_pdu(
 
             // 13. Use state resolution to find new room state
             let state_lock = crate::room::lock_state(&incoming_pdu.room_id).await;
-            // Now that the event has passed all auth it is added into the timeline.
-            // We use the `state_at_event` instead of `state_after` so we accurately
-            // represent the state for this event.
-            debug!("compressing state at event");
-            let compressed_state_ids = Arc::new(
-                state_at_incoming_event
-                    .iter()
-                    .map(|(field_id, event_id)| {
-                        state::compress_event(
-                            &incoming_pdu.room_id,
-                            *field_id,
-                            crate::event::ensure_event_sn(&incoming_pdu.room_id, event_id)?.0,
-                        )
-                    })
-                    .collect::<AppResult<_>>()?,
-            );
-            debug!("preparing for stateres to derive new room state");
-
-            // We also add state after incoming event to the fork states
-            // let mut state_after = state_at_incoming_event.clone();
-
-            let state_key_id =
-                state::ensure_field_id(&incoming_pdu.event_ty.to_string().into(), state_key)?;
-
-            let compressed_event =
-                state::compress_event(&incoming_pdu.room_id, state_key_id, incoming_pdu.event_sn)?;
-            let mut new_room_state = CompressedState::new();
-            new_room_state.insert(compressed_event);
-
-            // Set the new room state to the resolved state
-            debug!("forcing new room state");
-            let DeltaInfo {
-                frame_id,
-                appended,
-                disposed,
-            } = state::save_state(&incoming_pdu.room_id, Arc::new(new_room_state))?;
-
-            state::force_state(&incoming_pdu.room_id, frame_id, appended, disposed)?;
-
-            debug!("appended incoming pdu");
-            timeline::append_pdu(&incoming_pdu, json_data, &state_lock).await?;
-            state::set_event_state(
-                &incoming_pdu.event_id,
-                incoming_pdu.event_sn,
-                &incoming_pdu.room_id,
-                compressed_state_ids,
-            )?;
-            drop(state_lock);
+            crate::utils::run_in_place(async {
+                // Now that the event has passed all auth it is added into the timeline.
+                // We use the `state_at_event` instead of `state_after` so we accurately
+                // represent the state for this event.
+                debug!("compressing state at event");
+                let compressed_state_ids = Arc::new(
+                    state_at_incoming_event
+                        .iter()
+                        .map(|(field_id, event_id)| {
+                            state::compress_event(
+                                &incoming_pdu.room_id,
+                                *field_id,
+                                crate::event::ensure_event_sn(&incoming_pdu.room_id, event_id)?.0,
+                            )
+                        })
+                        .collect::<AppResult<_>>()?,
+                );
+                debug!("preparing for stateres to derive new room state");
+
+                // We also add state after incoming event to the fork states
+                // let mut state_after = state_at_incoming_event.clone();
+
+                let state_key_id =
+                    state::ensure_field_id(&incoming_pdu.event_ty.to_string().into(), state_key)?;
+
+                let compressed_event = state::compress_event(
+                    &incoming_pdu.room_id,
+                    state_key_id,
+                    incoming_pdu.event_sn,
+                )?;
+                let mut new_room_state = CompressedState::new();
+                new_room_state.insert(compressed_event);
+
+                // Set the new room state to the resolved state
+                debug!("forcing new room state");
+                let DeltaInfo {
+                    frame_id,
+                    appended,
+                    disposed,
+                } = state::save_state(&incoming_pdu.room_id, Arc::new(new_room_state))?;
+
+                state::force_state(&incoming_pdu.room_id, frame_id, appended, disposed)?;
+
+                debug!("appended incoming pdu");
+                timeline::append_pdu(&incoming_pdu, json_data, &state_lock).await?;
+                state::set_event_state(
+                    &incoming_pdu.event_id,
+                    incoming_pdu.event_sn,
+                    &incoming_pdu.room_id,
+                    compressed_state_ids,
+                )?;
+                drop(state_lock);
+                Ok::<_, AppError>(())
+            })
+            .await?;
         }
         return Ok(());
     }
@@ -597,95 +604,99 @@ pub async fn process_to_timeline_pdu(
 
     // 13. Use state resolution to find new room state
     let state_lock = crate::room::lock_state(&incoming_pdu.room_id).await;
+    // State resolution and the writes below only touch the database.
+    crate::utils::run_in_place(async move {
+        // Only keep those extremities were not referenced yet
+        // extremities.retain(|id| !matches!(crate::room::pdu_metadata::is_event_referenced(room_id,
+        // id), Ok(true)));
+
+        debug!("compressing state at event");
+        let compressed_state_ids = Arc::new(
+            state_at_incoming_event
+                .iter()
+                .map(|(field_id, event_id)| {
+                    state::compress_event(
+                        &incoming_pdu.room_id,
+                        *field_id,
+                        crate::event::ensure_event_sn(&incoming_pdu.room_id, event_id)?.0,
+                    )
+                })
+                .collect::<AppResult<_>>()?,
+        );
 
-    // Only keep those extremities were not referenced yet
-    // extremities.retain(|id| !matches!(crate::room::pdu_metadata::is_event_referenced(room_id,
-    // id), Ok(true)));
-
-    debug!("compressing state at event");
-    let compressed_state_ids = Arc::new(
-        state_at_incoming_event
-            .iter()
-            .map(|(field_id, event_id)| {
-                state::compress_event(
-                    &incoming_pdu.room_id,
-                    *field_id,
-                    crate::event::ensure_event_sn(&incoming_pdu.room_id, event_id)?.0,
-                )
-            })
-            .collect::<AppResult<_>>()?,
-    );
-
-    let guards = if let Some(state_key) = &incoming_pdu.state_key {
-        debug!("preparing for stateres to derive new room state");
+        let guards = if let Some(state_key) = &incoming_pdu.state_key {
+            debug!("preparing for stateres to derive new room state");
 
-        // We also add state after incoming event to the fork states
-        let mut state_after = state_at_incoming_event.clone();
-        let state_key_id =
-            state::ensure_field_id(&incoming_pdu.event_ty.to_string().into(), state_key)?;
-        state_after.insert(state_key_id, incoming_pdu.event_id.clone());
-        let (new_room_state, guards) =
-            resolve_state(&incoming_pdu.room_id, room_version_id, state_after).await?;
+            // We also add state after incoming event to the fork states
+            let mut state_after = state_at_incoming_event.clone();
+            let state_key_id =
+                state::ensure_field_id(&incoming_pdu.event_ty.to_string().into(), state_key)?;
+            state_after.insert(state_key_id, incoming_pdu.event_id.clone());
+            let (new_room_state, guards) =
+                resolve_state(&incoming_pdu.room_id, room_version_id, state_after).await?;
 
-        // Set the new room state to the resolved state
-        debug!("forcing new room state");
+            // Set the new room state to the resolved state
+            debug!("forcing new room state");
 
-        let DeltaInfo {
-            frame_id,
-            appended,
-            disposed,
-        } = state::save_state(&incoming_pdu.room_id, new_room_state)?;
+            let DeltaInfo {
+                frame_id,
+                appended,
+                disposed,
+            } = state::save_state(&incoming_pdu.room_id, new_room_state)?;
 
-        state::force_state(&incoming_pdu.room_id, frame_id, appended, disposed)?;
-        guards
-    } else {
-        vec![]
-    };
+            state::force_state(&incoming_pdu.room_id, frame_id, appended, disposed)?;
+            guards
+        } else {
+            vec![]
+        };
 
-    // Now that the event has passed all auth it is added into the timeline.
-    // We use the `state_at_event` instead of `state_after` so we accurately
-    // represent the state for this event.
-    let event_id = incoming_pdu.event_id.clone();
-    // 14. Check if the event passes auth based on the "current state" of the room, if not soft fail
-    //     it
-    if soft_fail {
-        debug!("starting soft fail auth check");
-        // We start looking at current room state now, so lets lock the room
-        // Now we calculate the set of extremities this room has after the incoming event has been
-        // applied. We start with the previous extremities (aka leaves)
-        debug!("calculating extremities");
-        let mut extremities: BTreeSet<_> = state::get_forward_extremities(&incoming_pdu.room_id)?
-            .into_iter()
-            .collect();
-
-        // Remove any forward extremities that are referenced by this incoming event's prev_events
-        extremities.retain(|event_id| !incoming_pdu.prev_events.contains(event_id));
-
-        let extremities = extremities
-            .iter()
-            .map(Borrow::borrow)
-            .chain(once(event_id.borrow()));
-        state::set_forward_extremities(&incoming_pdu.room_id, extremities, &state_lock)?;
-        state::update_backward_extremities(&incoming_pdu)?;
-        // Soft fail, we keep the event as an outlier but don't add it to the timeline
-        warn!("event was soft failed: {:?}", incoming_pdu);
-        crate::room::pdu_metadata::mark_event_soft_failed(&incoming_pdu.event_id)?;
-        return Err(MatrixError::invalid_param("event has been soft failed").into());
-    } else {
-        debug!("appended incoming pdu");
-        timeline::append_pdu(&incoming_pdu, json_data, &state_lock).await?;
-        state::set_event_state(
-            &incoming_pdu.event_id,
-            incoming_pdu.event_sn,
-            &incoming_pdu.room_id,
-            compressed_state_ids,
-        )?;
-    }
-    drop(guards);
+        // Now that the event has passed all auth it is added into the timeline.
+        // We use the `state_at_event` instead of `state_after` so we accurately
+        // represent the state for this event.
+        let event_id = incoming_pdu.event_id.clone();
+        // 14. Check if the event passes auth based on the "current state" of the room, if not soft fail
+        //     it
+        if soft_fail {
+            debug!("starting soft fail auth check");
+            // We start looking at current room state now, so lets lock the room
+            // Now we calculate the set of extremities this room has after the incoming event has been
+            // applied. We start with the previous extremities (aka leaves)
+            debug!("calculating extremities");
+            let mut extremities: BTreeSet<_> =
+                state::get_forward_extremities(&incoming_pdu.room_id)?
+                    .into_iter()
+                    .collect();
+
+            // Remove any forward extremities that are referenced by this incoming event's prev_events
+            extremities.retain(|event_id| !incoming_pdu.prev_events.contains(event_id));
+
+            let extremities = extremities
+                .iter()
+                .map(Borrow::borrow)
+                .chain(once(event_id.borrow()));
+            state::set_forward_extremities(&incoming_pdu.room_id, extremities, &state_lock)?;
+            state::update_backward_extremities(&incoming_pdu)?;
+            // Soft fail, we keep the event as an outlier but don't add it to the timeline
+            warn!("event was soft failed: {:?}", incoming_pdu);
+            crate::room::pdu_metadata::mark_event_soft_failed(&incoming_pdu.event_id)?;
+            return Err(MatrixError::invalid_param("event has been soft failed").into());
+        } else {
+            debug!("appended incoming pdu");
+            timeline::append_pdu(&incoming_pdu, json_data, &state_lock).await?;
+            state::set_event_state(
+                &incoming_pdu.event_id,
+                incoming_pdu.event_sn,
+                &incoming_pdu.room_id,
+                compressed_state_ids,
+            )?;
+        }
+        drop(guards);
 
-    // Event has passed all auth/stateres checks
-    drop(state_lock);
-    Ok(())
+        // Event has passed all auth/stateres checks
+        drop(state_lock);
+        Ok(())
+    })
+    .await
 }
 
 pub async fn remote_timestamp_to_event(
@@ -102,8 +102,10 @@ impl SnPduEvent {
             .unwrap()
             .get_mut(&(user_id.to_owned(), frame_id))
         {
+            crate::metrics::cache_lookup("user_visibility", true);
             return Ok(*visibility);
         }
+        crate::metrics::cache_lookup("user_visibility", false);
 
         let history_visibility = state::get_state_content::<RoomHistoryVisibilityEventContent>(
             frame_id,
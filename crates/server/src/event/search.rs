@@ -13,7 +13,7 @@ use crate::core::serde::CanonicalJsonObject;
 use crate::core::serde::canonical_json::CanonicalJsonValue;
 use crate::data::full_text_search::*;
 use crate::data::schema::*;
-use crate::data::{self, connect};
+use crate::data::{self, connect, connect_read};
 use crate::event::BatchToken;
 use crate::room::{state, timeline};
 use crate::{AppResult, MatrixError, SnPduEvent};
@@ -70,17 +70,17 @@ pub fn search_pdus(
     let items = if criteria.order_by == Some(OrderBy::Rank) {
         data_query
             .order_by(diesel::dsl::sql::<diesel::sql_types::Int8>("1"))
-            .load::<(f32, OwnedEventId, i64, i64)>(&mut connect()?)?
+            .load::<(f32, OwnedEventId, i64, i64)>(&mut connect_read()?)?
     } else {
         data_query
             .order_by(event_searches::origin_server_ts.desc())
             .then_order_by(event_searches::event_sn.desc())
-            .load::<(f32, OwnedEventId, i64, i64)>(&mut connect()?)?
+            .load::<(f32, OwnedEventId, i64, i64)>(&mut connect_read()?)?
     };
     // let _ids: Vec<i64> = event_searches::table
     //     .select(event_searches::id)
     //     .load(&mut connect()?)?;
-    let count: i64 = base_query.count().first(&mut connect()?)?;
+    let count: i64 = base_query.count().first(&mut connect_read()?)?;
     let next_batch = if items.len() < limit {
         None
     } else if let Some(last) = items.last() {
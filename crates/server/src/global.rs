@@ -3,16 +3,18 @@ use std::future::Future;
 use std::net::IpAddr;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, LazyLock, OnceLock, RwLock};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use diesel::prelude::*;
 use hickory_resolver::Resolver as HickoryResolver;
 use hickory_resolver::config::*;
 use hickory_resolver::name_server::TokioConnectionProvider;
 use salvo::oapi::ToSchema;
+use salvo::server::ServerHandle;
 use serde::Serialize;
 use tokio::sync::{Semaphore, broadcast};
 
+use crate::appservice::DbRegistration;
 use crate::core::appservice::Registration;
 use crate::core::federation::discovery::{OldVerifyKey, ServerSigningKeys};
 use crate::core::identifiers::*;
@@ -21,7 +23,6 @@ use crate::core::{Seqnum, UnixMillis};
 use crate::data::misc::DbServerSigningKeys;
 use crate::data::schema::*;
 use crate::data::user::{NewDbUser, NewDbUserDevice};
-use crate::appservice::DbRegistration;
 use crate::data::{connect, diesel_exists};
 use crate::utils::{MutexMap, MutexMapGuard, SeqnumQueue, SeqnumQueueFuture, SeqnumQueueGuard};
 use crate::{AppResult, SigningKeys};
@@ -32,6 +33,8 @@ pub const TOKEN_LENGTH: usize = 32;
 pub const SESSION_ID_LENGTH: usize = 32;
 pub const AUTO_GEN_PASSWORD_LENGTH: usize = 15;
 pub const RANDOM_USER_ID_LENGTH: usize = 10;
+/// How long in flight requests are given to finish on shutdown.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
 
 pub type TlsNameMap = HashMap<String, (Vec<IpAddr>, u16)>;
 type RateLimitState = (Instant, u32); // Time if last failed try, number of failed tries
@@ -47,6 +50,8 @@ pub static BAD_SIGNATURE_RATE_LIMITER: LazyRwLock<HashMap<Vec<String>, RateLimit
     LazyLock::new(Default::default);
 pub static BAD_QUERY_RATE_LIMITER: LazyRwLock<HashMap<OwnedServerName, RateLimitState>> =
     LazyLock::new(Default::default);
+pub static BAD_NOTARY_RATE_LIMITER: LazyRwLock<HashMap<OwnedServerName, RateLimitState>> =
+    LazyLock::new(Default::default);
 pub static SERVER_NAME_RATE_LIMITER: LazyRwLock<HashMap<OwnedServerName, Arc<Semaphore>>> =
     LazyLock::new(Default::default);
 pub static ROOM_ID_FEDERATION_HANDLE_TIME: LazyRwLock<
@@ -56,6 +61,8 @@ pub static APPSERVICE_IN_ROOM_CACHE: LazyRwLock<HashMap<OwnedRoomId, HashMap<Str
     LazyRwLock::new(Default::default);
 pub static ROTATE: LazyLock<RotationHandler> = LazyLock::new(Default::default);
 pub static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+pub static RESTART: AtomicBool = AtomicBool::new(false);
+static SERVER_HANDLE: OnceLock<ServerHandle> = OnceLock::new();
 pub static SEQNUM_QUEUE: LazyLock<SeqnumQueue> = LazyLock::new(Default::default);
 
 /// Handles "rotation" of long-polling requests. "Rotation" in this context is similar to "rotation"
@@ -190,12 +197,17 @@ pub fn appservices() -> &'static Vec<Registration> {
                     appservice_registrations::url.eq(&db_registration.url),
                     appservice_registrations::as_token.eq(&db_registration.as_token),
                     appservice_registrations::hs_token.eq(&db_registration.hs_token),
-                    appservice_registrations::sender_localpart.eq(&db_registration.sender_localpart),
+                    appservice_registrations::sender_localpart
+                        .eq(&db_registration.sender_localpart),
                     appservice_registrations::namespaces.eq(&db_registration.namespaces),
                     appservice_registrations::rate_limited.eq(&db_registration.rate_limited),
                     appservice_registrations::protocols.eq(&db_registration.protocols),
-                    appservice_registrations::receive_ephemeral.eq(&db_registration.receive_ephemeral),
-                    appservice_registrations::device_management.eq(&db_registration.device_management),
+                    appservice_registrations::receive_ephemeral
+                        .eq(&db_registration.receive_ephemeral),
+                    appservice_registrations::device_management
+                        .eq(&db_registration.device_management),
+                    appservice_registrations::transaction_extensions
+                        .eq(&db_registration.transaction_extensions),
                 ))
                 .execute(&mut conn)
             {
@@ -442,11 +454,26 @@ pub fn filter_keys_single_server(
     }
 }
 
+pub fn set_server_handle(handle: ServerHandle) {
+    let _ = SERVER_HANDLE.set(handle);
+}
+
+/// Stops accepting connections and lets in flight requests finish. The
+/// sending queue is flushed once the server has stopped.
 pub fn shutdown() {
     SHUTDOWN.store(true, std::sync::atomic::Ordering::Relaxed);
     // On shutdown
     info!(target: "shutdown-sync", "received shutdown notification, notifying sync helpers...");
     ROTATE.fire();
+    if let Some(handle) = SERVER_HANDLE.get() {
+        handle.stop_graceful(SHUTDOWN_GRACE_PERIOD);
+    }
+}
+
+/// Shuts the server down, then starts it again with the same arguments.
+pub fn restart() {
+    RESTART.store(true, std::sync::atomic::Ordering::Relaxed);
+    shutdown();
 }
 
 pub fn get_servers_from_users(users: &[OwnedUserId]) -> Vec<OwnedServerName> {
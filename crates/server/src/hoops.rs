@@ -2,11 +2,14 @@ use salvo::http::{ParseError, ResBody};
 use salvo::prelude::*;
 use salvo::size_limiter;
 
-use crate::AppResult;
 use crate::core::MatrixError;
 
 mod auth;
 pub use auth::*;
+mod metrics;
+pub use metrics::*;
+mod rate_limit;
+pub use rate_limit::*;
 
 #[handler]
 pub async fn ensure_accept(req: &mut Request) {
@@ -78,11 +81,6 @@ async fn access_control(
     // headers.insert("Cross-Origin-Opener-Policy", "same-origin".parse().unwrap());
 }
 
-#[handler]
-pub async fn limit_rate() -> AppResult<()> {
-    Ok(())
-}
-
 // utf8 will cause complement testing fail.
 #[handler]
 pub async fn remove_json_utf8(
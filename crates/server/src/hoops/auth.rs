@@ -41,6 +41,18 @@ pub async fn auth_by_access_token_or_signatures(
 pub async fn auth_by_access_token(aa: AuthArgs, depot: &mut Depot) -> AppResult<()> {
     auth_by_access_token_inner(aa, depot).await
 }
+
+/// Authenticates the request if it carries an access token, and lets it
+/// through unauthenticated otherwise.
+#[handler]
+pub async fn auth_by_access_token_if_present(aa: AuthArgs, depot: &mut Depot) -> AppResult<()> {
+    if aa.authorization.is_some() || aa.access_token.is_some() {
+        auth_by_access_token_inner(aa, depot).await
+    } else {
+        Ok(())
+    }
+}
+
 #[handler]
 pub async fn auth_by_signatures(
     _aa: AuthArgs,
@@ -0,0 +1,127 @@
+use std::time::Instant;
+
+use salvo::http::StatusCode;
+use salvo::prelude::*;
+
+/// Put in the depot by [`mark_routed`] once a route matched the request.
+struct Routed;
+
+/// Records how long each request took, labelled with its route.
+#[handler]
+pub async fn record_metrics(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    let start = Instant::now();
+    ctrl.call_next(req, depot, res).await;
+
+    let status = res.status_code.unwrap_or(StatusCode::OK);
+    crate::metrics::HTTP_REQUEST_DURATION
+        .with_label_values(&[
+            req.method().as_str(),
+            &route_label(req, depot),
+            status.as_str(),
+        ])
+        .observe(start.elapsed().as_secs_f64());
+}
+
+/// Marks the request as matched by a route, it must be a hoop of the root
+/// router so it only runs when routing succeeded.
+#[handler]
+pub async fn mark_routed(depot: &mut Depot) {
+    depot.inject(Routed);
+}
+
+/// The pattern of the route that matched the request, so ids don't end up in
+/// labels.
+fn route_label(req: &Request, depot: &Depot) -> String {
+    // Anything can be requested, don't let unknown paths create new series.
+    if depot.obtain::<Routed>().is_err() {
+        return "unmatched".to_owned();
+    }
+    format!("/{}", req.matched_path())
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+
+    use super::*;
+
+    #[handler]
+    async fn add_route_header(
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        ctrl.call_next(req, depot, res).await;
+        let route = route_label(req, depot);
+        res.headers_mut().insert(
+            "x-route",
+            route.parse().expect("label should be a header value"),
+        );
+    }
+
+    #[handler]
+    async fn ok() -> &'static str {
+        "ok"
+    }
+
+    fn service() -> Service {
+        let router = Router::new().hoop(mark_routed).get(ok).push(
+            Router::with_path("_matrix/client/v3/rooms/{room_id}/state/{event_type}").get(ok),
+        );
+        Service::new(router).hoop(add_route_header)
+    }
+
+    async fn route_of(res: Response) -> String {
+        res.headers()["x-route"].to_str().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn labels_matched_routes_with_their_pattern() {
+        let service = service();
+        let res = TestClient::get(
+            "http://127.0.0.1/_matrix/client/v3/rooms/!room%3Aexample.org/state/m.room.name",
+        )
+        .send(&service)
+        .await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        assert_eq!(
+            route_of(res).await,
+            "/_matrix/client/v3/rooms/{room_id}/state/{event_type}"
+        );
+
+        let mut res = TestClient::get("http://127.0.0.1/").send(&service).await;
+        assert_eq!(res.take_string().await.unwrap(), "ok");
+        assert_eq!(route_of(res).await, "/");
+    }
+
+    #[tokio::test]
+    async fn labels_unknown_paths_as_unmatched() {
+        let service = service();
+        let res = TestClient::get("http://127.0.0.1/_matrix/client/v3/unknown/abc")
+            .send(&service)
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::NOT_FOUND));
+        assert_eq!(route_of(res).await, "unmatched");
+
+        let res = TestClient::post("http://127.0.0.1/").send(&service).await;
+        assert_eq!(res.status_code, Some(StatusCode::METHOD_NOT_ALLOWED));
+        assert_eq!(route_of(res).await, "unmatched");
+    }
+
+    #[tokio::test]
+    async fn labels_options_requests_as_unmatched() {
+        let service = service();
+        let res = TestClient::options(
+            "http://127.0.0.1/_matrix/client/v3/rooms/!room%3Aexample.org/state/m.room.name",
+        )
+        .send(&service)
+        .await;
+        assert_eq!(route_of(res).await, "unmatched");
+    }
+}
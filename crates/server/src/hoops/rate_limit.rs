@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use salvo::prelude::*;
+
+use crate::config::{self, RateLimitRule};
+use crate::core::error::RetryAfter;
+use crate::core::identifiers::*;
+use crate::data::user::RateLimitOverride;
+use crate::{AppResult, AuthedInfo, MatrixError, data};
+
+/// Buckets are split over this many maps, so requests rarely wait on the
+/// same lock.
+const SHARD_COUNT: usize = 64;
+/// How often buckets that refilled completely are dropped, they are the same
+/// as a new one.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a per-user override is cached before it is read again.
+const OVERRIDE_TTL: Duration = Duration::from_secs(60);
+
+type BucketMap = HashMap<(RateClass, RateKey), Bucket>;
+
+static BUCKETS: LazyLock<Buckets> = LazyLock::new(Buckets::new);
+static OVERRIDES: LazyLock<Mutex<HashMap<OwnedUserId, (Option<RateLimitOverride>, Instant)>>> =
+    LazyLock::new(Default::default);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RateClass {
+    Default,
+    Login,
+    Register,
+    Message,
+    Join,
+    MediaUpload,
+}
+
+impl RateClass {
+    fn rule(&self) -> RateLimitRule {
+        let conf = &config::get().rate_limit;
+        match self {
+            Self::Default => conf.default,
+            Self::Login => conf.login,
+            Self::Register => conf.register,
+            Self::Message => conf.message,
+            Self::Join => conf.join,
+            Self::MediaUpload => conf.media_upload,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RateKey {
+    User(OwnedUserId),
+    Device(OwnedUserId, OwnedDeviceId),
+    Appservice(String),
+    Ip(IpAddr),
+}
+
+/// The buckets of every class and key, sharded by key.
+struct Buckets {
+    shards: Vec<Mutex<BucketMap>>,
+    hasher: RandomState,
+}
+
+impl Buckets {
+    fn new() -> Self {
+        Self {
+            shards: (0..SHARD_COUNT).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn take(
+        &self,
+        class: RateClass,
+        key: RateKey,
+        rule: &RateLimitRule,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let index = self.hasher.hash_one((class, &key)) as usize % self.shards.len();
+        let mut shard = self.shards[index].lock().expect("locking should work");
+        let bucket = shard
+            .entry((class, key))
+            .or_insert_with(|| Bucket::full(rule, now));
+        // The rule of a user changes when an admin overrides it.
+        bucket.rule = *rule;
+        bucket.take(now)
+    }
+
+    /// Drops the buckets that refilled completely, one shard at a time.
+    fn sweep(&self, now: Instant) {
+        for shard in &self.shards {
+            shard
+                .lock()
+                .expect("locking should work")
+                .retain(|_, bucket| !bucket.is_full(now));
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    /// The rule the bucket was last used with.
+    rule: RateLimitRule,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn full(rule: &RateLimitRule, now: Instant) -> Self {
+        Self {
+            rule: *rule,
+            tokens: rule.burst_count as f64,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.rule.per_second).min(self.rule.burst_count as f64);
+        self.updated_at = now;
+    }
+
+    /// Takes one token from the bucket, or returns how long to wait until one
+    /// is available.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.rule.per_second,
+            ))
+        }
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        let mut bucket = *self;
+        bucket.refill(now);
+        bucket.tokens >= self.rule.burst_count as f64
+    }
+}
+
+/// Periodically drops the buckets that are back to full, which keeps memory
+/// bounded by the clients seen within the last refill period.
+pub fn start_rate_limit_sweep() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            BUCKETS.sweep(Instant::now());
+        }
+    });
+}
+
+#[handler]
+pub async fn limit_rate(req: &mut Request, depot: &mut Depot) -> AppResult<()> {
+    check(RateClass::Default, req, depot)
+}
+
+#[handler]
+pub async fn limit_rate_login(req: &mut Request, depot: &mut Depot) -> AppResult<()> {
+    check(RateClass::Login, req, depot)
+}
+
+#[handler]
+pub async fn limit_rate_register(req: &mut Request, depot: &mut Depot) -> AppResult<()> {
+    check(RateClass::Register, req, depot)
+}
+
+#[handler]
+pub async fn limit_rate_message(req: &mut Request, depot: &mut Depot) -> AppResult<()> {
+    check(RateClass::Message, req, depot)
+}
+
+#[handler]
+pub async fn limit_rate_join(req: &mut Request, depot: &mut Depot) -> AppResult<()> {
+    check(RateClass::Join, req, depot)
+}
+
+#[handler]
+pub async fn limit_rate_media_upload(req: &mut Request, depot: &mut Depot) -> AppResult<()> {
+    check(RateClass::MediaUpload, req, depot)
+}
+
+fn check(class: RateClass, req: &Request, depot: &Depot) -> AppResult<()> {
+    let conf = &config::get().rate_limit;
+    if !conf.enable {
+        return Ok(());
+    }
+
+    let authed = depot.obtain::<AuthedInfo>().ok();
+    let mut rule = class.rule();
+    let key = match authed {
+        Some(authed) => {
+            if let Some(appservice) = authed.appservice() {
+                let rate_limited = appservice
+                    .registration
+                    .rate_limited
+                    .unwrap_or(!conf.exempt_appservices);
+                if !rate_limited {
+                    return Ok(());
+                }
+                RateKey::Appservice(appservice.registration.id.clone())
+            } else {
+                if conf.exempt_admins && authed.is_admin() {
+                    return Ok(());
+                }
+                // Like Synapse, the overrides only apply to sending messages.
+                if class == RateClass::Message
+                    && let Some(over) = user_override(authed.user_id())?
+                {
+                    rule = RateLimitRule::new(
+                        over.messages_per_second
+                            .map(f64::from)
+                            .unwrap_or(rule.per_second),
+                        over.burst_count
+                            .and_then(|c| u32::try_from(c).ok())
+                            .unwrap_or(rule.burst_count),
+                    );
+                }
+                match class {
+                    RateClass::Default => {
+                        RateKey::Device(authed.user_id().to_owned(), authed.device_id().to_owned())
+                    }
+                    _ => RateKey::User(authed.user_id().to_owned()),
+                }
+            }
+        }
+        None => match client_ip(req) {
+            Some(ip) => RateKey::Ip(ip),
+            // Clients behind a UNIX socket proxy without `X-Forwarded-For`
+            // can't be told apart, so they are not limited by address.
+            None => return Ok(()),
+        },
+    };
+
+    if rule.is_unlimited() {
+        return Ok(());
+    }
+    if let Err(wait) = BUCKETS.take(class, key, &rule, Instant::now()) {
+        return Err(MatrixError::limit_exceeded(
+            "Too many requests.",
+            Some(RetryAfter::Delay(wait)),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn user_override(user_id: &UserId) -> AppResult<Option<RateLimitOverride>> {
+    let now = Instant::now();
+    if let Some((over, fetched_at)) = OVERRIDES.lock().expect("locking should work").get(user_id)
+        && now.saturating_duration_since(*fetched_at) < OVERRIDE_TTL
+    {
+        return Ok(over.clone());
+    }
+    let over = data::user::get_ratelimit(user_id)?;
+    OVERRIDES
+        .lock()
+        .expect("locking should work")
+        .insert(user_id.to_owned(), (over.clone(), now));
+    Ok(over)
+}
+
+/// Drops the cached override for a user so admin changes apply immediately.
+pub fn forget_user_override(user_id: &UserId) {
+    OVERRIDES
+        .lock()
+        .expect("locking should work")
+        .remove(user_id);
+}
+
+fn client_ip(req: &Request) -> Option<IpAddr> {
+    let local_addr = req.local_addr();
+    // Only local processes can connect to a UNIX socket, which is always
+    // served behind a reverse proxy.
+    if local_addr.is_unix() || trusts_forwarded_for(local_addr) {
+        let forwarded_ip = req
+            .headers()
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .and_then(|v| v.trim().parse().ok());
+        if forwarded_ip.is_some() || local_addr.is_unix() {
+            return forwarded_ip;
+        }
+    }
+    req.remote_addr().ip()
+}
+
+/// Whether the listener that accepted the connection is configured to sit
+/// behind a proxy setting `X-Forwarded-For`.
+fn trusts_forwarded_for(local_addr: &salvo::conn::SocketAddr) -> bool {
+    config::get()
+        .listeners
+        .iter()
+        .filter(|listener| listener.x_forwarded && listener.unix_socket_path.is_none())
+        .filter_map(|listener| listener.address.parse::<std::net::SocketAddr>().ok())
+        .any(|addr| {
+            local_addr.port() == Some(addr.port())
+                && (addr.ip().is_unspecified() || local_addr.ip() == Some(addr.ip()))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_burst_then_limits() {
+        let rule = RateLimitRule::new(1.0, 3);
+        let now = Instant::now();
+        let mut bucket = Bucket::full(&rule, now);
+        for _ in 0..3 {
+            assert!(bucket.take(now).is_ok());
+        }
+        let wait = bucket.take(now).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let rule = RateLimitRule::new(2.0, 2);
+        let now = Instant::now();
+        let mut bucket = Bucket::full(&rule, now);
+        assert!(bucket.take(now).is_ok());
+        assert!(bucket.take(now).is_ok());
+        assert!(bucket.take(now).is_err());
+        let later = now + Duration::from_millis(500);
+        assert!(bucket.take(later).is_ok());
+        assert!(bucket.take(later).is_err());
+        assert!(bucket.is_full(later + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn sweep_uses_the_rule_of_the_bucket() {
+        let buckets = Buckets::new();
+        let now = Instant::now();
+        let ip = RateKey::Ip(IpAddr::from([127, 0, 0, 1]));
+        let user = RateKey::User(OwnedUserId::try_from("@alice:example.org").unwrap());
+        // A slow override refills long after the class rule would have.
+        assert!(
+            buckets
+                .take(RateClass::Login, ip, &RateLimitRule::new(10.0, 1), now)
+                .is_ok()
+        );
+        assert!(
+            buckets
+                .take(RateClass::Login, user, &RateLimitRule::new(0.01, 1), now)
+                .is_ok()
+        );
+
+        buckets.sweep(now + Duration::from_secs(1));
+        let remaining: usize = buckets
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum();
+        assert_eq!(remaining, 1);
+    }
+}
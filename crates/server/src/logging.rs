@@ -60,11 +60,9 @@ pub fn init() -> AppResult<()> {
         .fmt_fields(ConsoleFormat::new(conf))
         .with_writer(ConsoleWriter::new(conf));
 
-    let (console_reload_filter, _console_reload_handle) =
+    let (console_reload_filter, console_reload_handle) =
         tracing_subscriber::reload::Layer::new(console_filter);
-
-    // TODO: fix https://github.com/tokio-rs/tracing/pull/2956
-    // reload_handles.add("console", Box::new(console_reload_handle));
+    reload_handles.add("console", Box::new(console_reload_handle));
 
     let cap_state = Arc::new(capture::State::new());
     let cap_layer = capture::Layer::new(&cap_state);
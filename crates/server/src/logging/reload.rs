@@ -1,9 +1,10 @@
 use std::collections::HashMap;
+use std::fmt::Display;
 use std::sync::{Arc, Mutex};
 
 use tracing_subscriber::{EnvFilter, reload};
 
-use crate::AppResult;
+use crate::{AppError, AppResult};
 
 /// We need to store a reload::Handle value, but can't name it's type explicitly
 /// because the S type parameter depends on the subscriber's previous layers. In
@@ -19,15 +20,18 @@ use crate::AppResult;
 /// a trait object.
 ///
 /// [1]: <https://github.com/tokio-rs/tracing/pull/1035/commits/8a87ea52425098d3ef8f56d92358c2f6c144a28f>
+///
+/// `EnvFilter` can't be cloned, so the current value is handed out in its
+/// textual form instead.
 pub trait ReloadHandle<L> {
-    fn current(&self) -> Option<L>;
+    fn current(&self) -> Option<String>;
 
     fn reload(&self, new_value: L) -> Result<(), reload::Error>;
 }
 
-impl<L: Clone, S> ReloadHandle<L> for reload::Handle<L, S> {
-    fn current(&self) -> Option<L> {
-        Self::clone_current(self)
+impl<L: Display, S> ReloadHandle<L> for reload::Handle<L, S> {
+    fn current(&self) -> Option<String> {
+        self.with_current(ToString::to_string).ok()
     }
 
     fn reload(&self, new_value: L) -> Result<(), reload::Error> {
@@ -51,22 +55,31 @@ impl LogLevelReloadHandles {
             .insert(name.into(), handle);
     }
 
-    pub fn reload(&self, _new_value: &EnvFilter, names: Option<&[&str]>) -> AppResult<()> {
-        self.handles
+    /// Replaces the filter of the named handles, or of all of them when `names`
+    /// is `None`.
+    pub fn reload(&self, new_value: &str, names: Option<&[&str]>) -> AppResult<()> {
+        let filter_regex = crate::config::get().logger.filter_regex;
+        for (name, handle) in self
+            .handles
             .lock()
             .expect("locked")
             .iter()
-            .filter(|(name, _)| names.is_some_and(|names| names.contains(&name.as_str())))
-            .for_each(|(_, _handle)| {
-                // TODO: EnvFilter clone
-                // _ = handle.reload(new_value.to_owned());
-            });
+            .filter(|(name, _)| names.is_none_or(|names| names.contains(&name.as_str())))
+        {
+            let filter = EnvFilter::builder()
+                .with_regex(filter_regex)
+                .parse(new_value)
+                .map_err(|e| AppError::public(format!("invalid log filter: {e}")))?;
+            handle.reload(filter).map_err(|e| {
+                AppError::internal(format!("failed to reload log filter `{name}`: {e}"))
+            })?;
+        }
 
         Ok(())
     }
 
     #[must_use]
-    pub fn current(&self, name: &str) -> Option<EnvFilter> {
+    pub fn current(&self, name: &str) -> Option<String> {
         self.handles
             .lock()
             .expect("locked")
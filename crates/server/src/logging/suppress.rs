@@ -1,22 +1,19 @@
-use super::EnvFilter;
-
 pub struct Suppress {
-    restore: EnvFilter,
+    restore: String,
 }
 
 impl Default for Suppress {
     fn default() -> Self {
         let handle = "console";
-        let suppress = EnvFilter::default();
         let conf = &crate::config::get().logger;
         let restore = crate::logging::get()
             .reload
             .current(handle)
-            .unwrap_or_else(|| EnvFilter::try_new(&conf.level).unwrap_or_default());
+            .unwrap_or_else(|| conf.level.clone());
 
         crate::logging::get()
             .reload
-            .reload(&suppress, Some(&[handle]))
+            .reload("off", Some(&[handle]))
             .expect("log filter reloaded");
 
         Self { restore }
@@ -24,13 +24,17 @@ pub mod utils;
 pub use auth::{AuthArgs, AuthedInfo};
 pub mod admin;
 pub mod appservice;
+pub mod coordination;
 pub mod directory;
+pub mod email;
 pub mod event;
 pub mod exts;
 pub mod federation;
 pub mod media;
 pub mod membership;
+pub mod metrics;
 pub mod room;
+pub mod scheduler;
 pub mod sending;
 pub mod server_key;
 pub mod state;
@@ -44,6 +48,7 @@ mod signing_keys;
 pub mod sync_v3;
 pub mod sync_v5;
 pub mod watcher;
+pub mod worker;
 pub use event::{PduBuilder, PduEvent, SnPduEvent};
 pub use signing_keys::SigningKeys;
 mod global;
@@ -61,6 +66,7 @@ pub use diesel::result::Error as DieselError;
 use dotenvy::dotenv;
 pub use error::AppError;
 use figment::providers::Env;
+use salvo::acme::ListenerAcmeExt;
 use salvo::catcher::Catcher;
 use salvo::compression::{Compression, CompressionLevel};
 use salvo::conn::rustls::{Keycert, RustlsConfig};
@@ -74,7 +80,7 @@ pub use {
     jsonwebtoken as jwt, palpo_core as core, palpo_data as data, palpo_server_macros as macros,
 };
 
-use crate::config::ServerConfig;
+use crate::config::{ServerConfig, WorkerRole};
 
 pub type AppResult<T> = Result<T, crate::AppError>;
 pub type DieselResult<T> = Result<T, diesel::result::Error>;
@@ -132,6 +138,11 @@ pub(crate) struct Args {
 
     #[arg(long, short, num_args(1), default_value_t = true)]
     pub(crate) server: bool,
+
+    /// Subsystems to run, overriding `roles` of the `[worker]` config
+    /// section, for example `--role client,media`.
+    #[arg(long = "role", value_enum, value_delimiter = ',')]
+    pub(crate) roles: Vec<WorkerRole>,
 }
 
 #[tokio::main]
@@ -159,6 +170,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     crate::logging::init()?;
     crate::data::init(&conf.db.clone().into_data_db_config());
+    crate::worker::init(args.roles.clone())?;
 
     if args.console {
         tracing::info!("starting admin console...");
@@ -185,8 +197,36 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     }
 
     crate::sending::guard::start();
+    crate::coordination::start();
+    crate::hoops::start_rate_limit_sweep();
+    if crate::worker::has_role(WorkerRole::Background) {
+        crate::scheduler::start();
+        crate::room::retention::start();
+        crate::room::push_action::start();
+        crate::coordination::start_pruning();
+        crate::user::pusher::mailer::start();
+        crate::user::start_ldap_sync();
+    }
 
-    let router = routing::root();
+    let mut router = routing::root();
+    // Set up before the service is built, since HTTP-01 challenges are answered by the router.
+    let mut acme_listener = conf
+        .listeners
+        .iter()
+        .find(|listener_conf| listener_conf.acme)
+        .zip(conf.enabled_acme())
+        .map(|(listener_conf, acme_conf)| {
+            let listener = TcpListener::new(listener_conf.address.clone())
+                .acme()
+                .get_directory(acme_conf.directory_name(), &acme_conf.directory_url)
+                .domains(conf.acme_domains())
+                .contacts(acme_conf.contacts.clone())
+                .cache_path(&acme_conf.cache_path);
+            match acme_conf.challenge {
+                crate::config::AcmeChallenge::Http01 => listener.http01_challenge(&mut router),
+                crate::config::AcmeChallenge::TlsAlpn01 => listener.tls_alpn01_challenge(),
+            }
+        });
     // let doc = OpenApi::new("palpo api", "0.0.1").merge_router(&router);
     // let router = router
     //     .unshift(doc.into_router("/api-doc/openapi.json"))
@@ -197,8 +237,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     //     )
     //     .unshift(SwaggerUi::new("/api-doc/openapi.json").into_router("/swagger-ui"));
     let catcher = Catcher::default().hoop(hoops::catch_status_error);
-    let service = Service::new(router)
-        .catcher(catcher)
+    let mut service = Service::new(router).catcher(catcher);
+    if conf.enabled_metrics().is_some() {
+        service = service.hoop(hoops::record_metrics);
+    }
+    let service = service
         .hoop(hoops::default_accept_json)
         .hoop(Logger::new())
         .hoop(
@@ -236,15 +279,53 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     } else {
         service
     };
-    let _ = crate::data::user::unset_all_presences();
+    if crate::worker::has_role(WorkerRole::Background) {
+        let _ = crate::data::user::unset_all_presences();
+    }
 
     salvo::http::request::set_global_secure_max_size(8 * 1024 * 1024);
+    if let Some(metrics_conf) = conf.enabled_metrics() {
+        tracing::info!("Serving metrics on: {}", metrics_conf.address);
+        let acceptor = TcpListener::new(metrics_conf.address.clone()).bind().await;
+        tokio::spawn(async move {
+            Server::new(acceptor)
+                .serve(Router::with_path("metrics").get(metrics::serve))
+                .await;
+        });
+    }
     let conf = crate::config::get();
     let mut acceptors = vec![];
     for listener_conf in &conf.listeners {
-        if let Some(tls_conf) = listener_conf.enabled_tls() {
+        if let Some(path) = &listener_conf.unix_socket_path {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+
+                crate::utils::fs::prepare_unix_socket(path)?;
+                let perms = u32::from_str_radix(&listener_conf.unix_socket_perms.to_string(), 8)
+                    .map_err(|_| "`unix_socket_perms` must be an octal number")?;
+                let mut listener =
+                    UnixListener::new(path.clone()).permissions(std::fs::Permissions::from_mode(perms));
+                if listener_conf.unix_socket_owner.is_some()
+                    || listener_conf.unix_socket_group.is_some()
+                {
+                    listener = listener.owner(
+                        listener_conf.unix_socket_owner,
+                        listener_conf.unix_socket_group,
+                    );
+                }
+                tracing::info!("Listening on: {}", path.display());
+                acceptors.push(listener.bind().await.into_boxed());
+            }
+        } else if listener_conf.acme {
+            let Some(listener) = acme_listener.take() else {
+                continue;
+            };
+            tracing::info!("Listening on: {} with ACME", listener_conf.address);
+            acceptors.push(listener.bind().await.into_boxed());
+        } else if let Some(tls_conf) = listener_conf.enabled_tls() {
             tracing::info!("Listening on: {} with TLS", listener_conf.address);
-            let acceptor = TcpListener::new(&listener_conf.address)
+            let acceptor = TcpListener::new(listener_conf.address.clone())
                 .rustls(RustlsConfig::new(
                     Keycert::new()
                         .cert_from_path(&tls_conf.cert)?
@@ -256,7 +337,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             acceptors.push(acceptor);
         } else {
             tracing::info!("Listening on: {}", listener_conf.address);
-            let acceptor = TcpListener::new(&listener_conf.address)
+            let acceptor = TcpListener::new(listener_conf.address.clone())
                 .bind()
                 .await
                 .into_boxed();
@@ -264,9 +345,60 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         }
     }
 
-    Server::new(DynTcpAcceptors::new(acceptors))
+    let server = Server::new(DynTcpAcceptors::new(acceptors));
+    crate::global::set_server_handle(server.handle());
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        crate::global::shutdown();
+    });
+    server
         .serve(service)
         .instrument(tracing::info_span!("server.serve"))
         .await;
+    crate::sending::guard::stopped().await;
+
+    for path in conf
+        .listeners
+        .iter()
+        .filter_map(|listener_conf| listener_conf.unix_socket_path.as_ref())
+    {
+        if let Err(e) = std::fs::remove_file(path) {
+            tracing::warn!("failed to remove UNIX socket {}: {e}", path.display());
+        }
+    }
+
+    if crate::global::RESTART.load(std::sync::atomic::Ordering::Relaxed) {
+        tracing::info!("restarting");
+        crate::utils::sys::restart()?;
+    }
     Ok(())
 }
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("failed to install ctrl-c handler: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("failed to install SIGTERM handler: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!("shutting down");
+}
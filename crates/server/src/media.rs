@@ -1,21 +1,27 @@
 mod preview;
 mod remote;
+mod storage;
 use std::cmp;
 use std::num::Saturating;
-use std::path::PathBuf;
 
 use diesel::prelude::*;
+use mime::Mime;
 pub use preview::*;
 pub use remote::*;
-use tokio::io::AsyncWriteExt;
+use salvo::fs::NamedFile;
+use salvo::http::HeaderValue;
+use salvo::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use salvo::prelude::*;
+pub use storage::*;
 
-use crate::core::http_headers::ContentDisposition;
+use crate::core::http_headers::{ContentDisposition, ContentDispositionType};
 use crate::core::media::ResizeMethod;
 use crate::core::{Mxc, OwnedMxcUri, ServerName, UnixMillis, UserId};
 use crate::data::connect;
 use crate::data::media::NewDbThumbnail;
 use crate::data::schema::*;
-use crate::{AppResult, config, data};
+use crate::utils::content_disposition::make_content_disposition;
+use crate::{AppResult, data};
 
 #[derive(Debug)]
 pub struct FileMeta {
@@ -109,36 +115,68 @@ impl Default for Dimension {
     }
 }
 
-pub fn get_media_path(server_name: &ServerName, media_id: &str) -> PathBuf {
-    let server_name = if server_name == config::server_name() {
-        "_"
-    } else {
-        server_name.as_str()
-    };
-    let mut r = PathBuf::new();
-    r.push(config::space_path());
-    r.push("media");
-    r.push(server_name);
-    // let extension = extension.unwrap_or_default();
-    // if !extension.is_empty() {
-    //     r.push(format!("{media_id}.{extension}"));
-    // } else {
-    r.push(media_id);
-    // }
-    r
-}
-
 pub async fn delete_media(server_name: &ServerName, media_id: &str) -> AppResult<()> {
     data::media::delete_media(server_name, media_id)?;
-    let path = get_media_path(server_name, media_id);
-    if path.exists()
-        && let Err(e) = tokio::fs::remove_file(path).await
-    {
+    if let Err(e) = storage().delete(&media_key(server_name, media_id)).await {
         tracing::error!("failed to delete media file: {e}");
     }
     Ok(())
 }
 
+/// Sends a stored media object to the client, streaming it from disk when the
+/// storage backend keeps a local copy. Returns `false` if the object is missing.
+pub async fn send_stored(
+    key: &str,
+    content_type: Mime,
+    attached_name: Option<&str>,
+    req: &Request,
+    res: &mut Response,
+) -> AppResult<bool> {
+    if let Some(path) = storage().local_path(key) {
+        let mut builder = NamedFile::builder(path).content_type(content_type);
+        if let Some(name) = attached_name {
+            builder = builder.attached_name(name);
+        }
+        builder.send(req.headers(), res).await;
+        return Ok(true);
+    }
+    let Some(stream) = storage().get_stream(key).await? else {
+        return Ok(false);
+    };
+    set_content_headers(&content_type, attached_name, res);
+    res.stream(stream);
+    Ok(true)
+}
+
+/// Writes media bytes that are already in memory to the response.
+pub fn render_bytes(
+    data: Vec<u8>,
+    content_type: &Mime,
+    attached_name: Option<&str>,
+    res: &mut Response,
+) {
+    set_content_headers(content_type, attached_name, res);
+    res.body(data);
+}
+
+fn set_content_headers(content_type: &Mime, attached_name: Option<&str>, res: &mut Response) {
+    if let Ok(value) = HeaderValue::from_str(content_type.as_ref()) {
+        res.headers_mut().insert(CONTENT_TYPE, value);
+    }
+    if let Some(name) = attached_name
+        && let Ok(value) = HeaderValue::from_str(
+            &make_content_disposition(
+                Some(ContentDispositionType::Attachment),
+                Some(content_type.as_ref()),
+                Some(name),
+            )
+            .to_string(),
+        )
+    {
+        res.headers_mut().insert(CONTENT_DISPOSITION, value);
+    }
+}
+
 /// Returns width, height of the thumbnail and whether it should be cropped. Returns None when
 /// the server should send the original file.
 pub fn thumbnail_properties(width: u32, height: u32) -> Option<(u32, u32, bool)> {
@@ -204,16 +242,8 @@ pub async fn save_thumbnail_file(
     media_id: &str,
     thumbnail_id: i64,
     file: &[u8],
-) -> AppResult<PathBuf> {
-    let thumb_path = get_thumbnail_path(server_name, media_id, thumbnail_id);
-    let mut f = tokio::fs::File::create(&thumb_path).await?;
-    f.write_all(file).await?;
-    Ok(thumb_path)
-}
-
-pub fn get_thumbnail_path(server_name: &ServerName, media_id: &str, thumbnail_id: i64) -> PathBuf {
-    get_media_path(
-        server_name,
-        &format!("{media_id}.thumbnails/{thumbnail_id}"),
-    )
+) -> AppResult<()> {
+    storage()
+        .put(&thumbnail_key(server_name, media_id, thumbnail_id), file)
+        .await
 }
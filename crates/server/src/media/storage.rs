@@ -0,0 +1,464 @@
+mod s3;
+pub use s3::S3Storage;
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::stream::{self, BoxStream};
+use futures_util::{StreamExt, TryStreamExt};
+use lru_cache::LruCache;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+use crate::config::{self, MediaStorageKind};
+use crate::core::ServerName;
+use crate::{AppError, AppResult};
+
+static STORAGE: OnceLock<Box<dyn MediaStorage>> = OnceLock::new();
+
+/// Chunks of a media object read from a storage backend.
+pub type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// A place media bytes are kept.
+///
+/// Keys are relative, slash separated paths such as `media/_/{media_id}`,
+/// which map directly onto the layout of the legacy media directory.
+#[async_trait]
+pub trait MediaStorage: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn put(&self, key: &str, data: &[u8]) -> AppResult<()>;
+
+    async fn get(&self, key: &str) -> AppResult<Option<Vec<u8>>>;
+
+    /// Reads an object chunk by chunk, so large files are never held in
+    /// memory as a whole.
+    async fn get_stream(&self, key: &str) -> AppResult<Option<ByteStream>> {
+        Ok(self
+            .get(key)
+            .await?
+            .map(|data| stream::once(async { Ok(Bytes::from(data)) }).boxed()))
+    }
+
+    /// Writes an object chunk by chunk. Backends that need the whole object
+    /// before sending it collect the chunks first.
+    async fn put_stream(&self, key: &str, mut chunks: ByteStream) -> AppResult<()> {
+        let mut data = Vec::new();
+        while let Some(chunk) = chunks.try_next().await? {
+            data.extend_from_slice(&chunk);
+        }
+        self.put(key, &data).await
+    }
+
+    async fn exists(&self, key: &str) -> AppResult<bool>;
+
+    async fn delete(&self, key: &str) -> AppResult<()>;
+
+    /// Returns the path of the file on the local filesystem if the backend
+    /// currently holds one, so it can be served without buffering.
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Returns the configured media storage backend.
+pub fn storage() -> &'static dyn MediaStorage {
+    STORAGE
+        .get_or_init(|| {
+            let conf = config::get();
+            build_storage(conf.media.storage).expect("media storage should be configured")
+        })
+        .as_ref()
+}
+
+/// Builds a storage backend of the given kind from the current config.
+pub fn build_storage(kind: MediaStorageKind) -> AppResult<Box<dyn MediaStorage>> {
+    let conf = config::get();
+    let s3 = || {
+        conf.media
+            .s3
+            .as_ref()
+            .map(S3Storage::new)
+            .ok_or_else(|| AppError::public("`[media.s3]` must be configured for this backend"))
+    };
+    Ok(match kind {
+        MediaStorageKind::Fs => Box::new(FsStorage::new(config::space_path())),
+        MediaStorageKind::S3 => Box::new(s3()?),
+        MediaStorageKind::Tiered => Box::new(TieredStorage::new(
+            FsStorage::new(Path::new(config::space_path()).join("media_cache")),
+            s3()?,
+            conf.media.cache_capacity,
+        )),
+    })
+}
+
+pub fn media_key(server_name: &ServerName, media_id: &str) -> String {
+    let server_name = if server_name == config::server_name() {
+        "_"
+    } else {
+        server_name.as_str()
+    };
+    format!("media/{server_name}/{media_id}")
+}
+
+pub fn thumbnail_key(server_name: &ServerName, media_id: &str, thumbnail_id: i64) -> String {
+    media_key(
+        server_name,
+        &format!("{media_id}.thumbnails/{thumbnail_id}"),
+    )
+}
+
+/// Stores media files below a root directory on the local filesystem.
+#[derive(Clone, Debug)]
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn path(&self, key: &str) -> PathBuf {
+        let mut path = self.root.clone();
+        path.extend(key.split('/').filter(|s| !s.is_empty() && *s != ".."));
+        path
+    }
+
+    /// Writes the chunks to the file of the key and returns its size. Every
+    /// writer uses its own partial file, concurrent writes of the same key
+    /// would corrupt each other otherwise.
+    async fn write_stream(&self, key: &str, mut chunks: ByteStream) -> AppResult<u64> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut partial = path.clone().into_os_string();
+        partial.push(format!(".{}.part", crate::utils::random_string(8)));
+        let partial = PathBuf::from(partial);
+
+        let mut file = tokio::fs::File::create(&partial).await?;
+        let mut size = 0;
+        let written: AppResult<()> = async {
+            while let Some(chunk) = chunks.try_next().await? {
+                file.write_all(&chunk).await?;
+                size += chunk.len() as u64;
+            }
+            file.flush().await?;
+            Ok(())
+        }
+        .await;
+        drop(file);
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e);
+        }
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(size)
+    }
+}
+
+#[async_trait]
+impl MediaStorage for FsStorage {
+    fn name(&self) -> &'static str {
+        "fs"
+    }
+
+    async fn put(&self, key: &str, data: &[u8]) -> AppResult<()> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, data).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> AppResult<Option<Vec<u8>>> {
+        match tokio::fs::read(self.path(key)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_stream(&self, key: &str) -> AppResult<Option<ByteStream>> {
+        match tokio::fs::File::open(self.path(key)).await {
+            Ok(file) => Ok(Some(ReaderStream::new(file).boxed())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn put_stream(&self, key: &str, chunks: ByteStream) -> AppResult<()> {
+        self.write_stream(key, chunks).await?;
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> AppResult<bool> {
+        Ok(tokio::fs::try_exists(self.path(key)).await?)
+    }
+
+    async fn delete(&self, key: &str) -> AppResult<()> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        let path = self.path(key);
+        path.exists().then_some(path)
+    }
+}
+
+/// Keeps media in a remote backend with a local filesystem cache in front of
+/// it. Reads are served from the cache when possible and populate it on miss.
+///
+/// The cache is bounded by `capacity` bytes and drops the least recently used
+/// files once it grows past that. A capacity of 0 leaves it unbounded. The
+/// cache directory must not be shared with an `FsStorage`, since anything in it
+/// may be evicted.
+pub struct TieredStorage<R> {
+    cache: FsStorage,
+    remote: R,
+    capacity: u64,
+    index: Mutex<CacheIndex>,
+}
+
+/// Sizes of the files in the local cache, in least recently used order.
+struct CacheIndex {
+    entries: LruCache<String, u64>,
+    size: u64,
+}
+
+impl CacheIndex {
+    fn remove(&mut self, key: &str) {
+        if let Some(size) = self.entries.remove(key) {
+            self.size -= size;
+        }
+    }
+}
+
+impl<R: MediaStorage> TieredStorage<R> {
+    /// Creates the storage and indexes the files already present in the cache
+    /// directory, oldest first.
+    pub fn new(cache: FsStorage, remote: R, capacity: u64) -> Self {
+        let mut files = Vec::new();
+        scan_cache_dir(&cache.path("media"), "media", &mut files);
+        files.sort_by_key(|(_, _, modified)| *modified);
+
+        let storage = Self {
+            cache,
+            remote,
+            capacity,
+            index: Mutex::new(CacheIndex {
+                entries: LruCache::new(usize::MAX),
+                size: 0,
+            }),
+        };
+        for (key, size, _) in files {
+            for key in storage.record(key, size) {
+                if let Err(e) = std::fs::remove_file(storage.cache.path(&key)) {
+                    warn!("failed to evict cached media {key}: {e}");
+                }
+            }
+        }
+        storage
+    }
+
+    /// Marks a cached file as most recently used and returns the keys that
+    /// must be evicted to stay within the capacity. The file just recorded is
+    /// always kept, even if it is larger than the whole cache.
+    fn record(&self, key: String, size: u64) -> Vec<String> {
+        let mut index = self.index.lock().expect("locking should not fail");
+        index.remove(&key);
+        index.entries.insert(key, size);
+        index.size += size;
+
+        let mut evicted = Vec::new();
+        while self.capacity > 0 && index.size > self.capacity && index.entries.len() > 1 {
+            if let Some((key, size)) = index.entries.remove_lru() {
+                index.size -= size;
+                evicted.push(key);
+            }
+        }
+        evicted
+    }
+
+    async fn evict(&self, keys: Vec<String>) {
+        for key in keys {
+            if let Err(e) = self.cache.delete(&key).await {
+                warn!("failed to evict cached media {key}: {e}");
+            }
+        }
+    }
+
+    async fn cache_data(&self, key: &str, data: &[u8]) {
+        match self.cache.put(key, data).await {
+            Ok(()) => {
+                let evicted = self.record(key.to_owned(), data.len() as u64);
+                self.evict(evicted).await;
+            }
+            Err(e) => warn!("failed to cache media {key}: {e}"),
+        }
+    }
+
+    /// Streams a remote object into the cache and returns the path of the
+    /// cached file, or `None` if the remote does not have it.
+    async fn fill_cache(&self, key: &str) -> AppResult<Option<PathBuf>> {
+        let Some(chunks) = self.remote.get_stream(key).await? else {
+            return Ok(None);
+        };
+        let size = self.cache.write_stream(key, chunks).await?;
+        let evicted = self.record(key.to_owned(), size);
+        self.evict(evicted).await;
+        Ok(Some(self.cache.path(key)))
+    }
+}
+
+/// Collects `(key, size, modified)` for every file below `dir`.
+fn scan_cache_dir(dir: &Path, prefix: &str, files: &mut Vec<(String, u64, std::time::SystemTime)>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let key = format!("{prefix}/{}", entry.file_name().to_string_lossy());
+        if metadata.is_dir() {
+            scan_cache_dir(&entry.path(), &key, files);
+        } else if key.ends_with(".part") {
+            // Left behind by a download that was interrupted.
+            let _ = std::fs::remove_file(entry.path());
+        } else if metadata.is_file() {
+            let modified = metadata.modified().unwrap_or(std::time::UNIX_EPOCH);
+            files.push((key, metadata.len(), modified));
+        }
+    }
+}
+
+#[async_trait]
+impl<R: MediaStorage> MediaStorage for TieredStorage<R> {
+    fn name(&self) -> &'static str {
+        "tiered"
+    }
+
+    async fn put(&self, key: &str, data: &[u8]) -> AppResult<()> {
+        self.remote.put(key, data).await?;
+        self.cache_data(key, data).await;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> AppResult<Option<Vec<u8>>> {
+        if let Some(path) = self.local_path(key) {
+            return Ok(Some(tokio::fs::read(path).await?));
+        }
+        match self.fill_cache(key).await {
+            Ok(Some(path)) => Ok(Some(tokio::fs::read(path).await?)),
+            Ok(None) => Ok(None),
+            Err(e) => {
+                warn!("failed to cache media {key}: {e}");
+                self.remote.get(key).await
+            }
+        }
+    }
+
+    async fn get_stream(&self, key: &str) -> AppResult<Option<ByteStream>> {
+        if self.local_path(key).is_none()
+            && let Err(e) = self.fill_cache(key).await
+        {
+            warn!("failed to cache media {key}: {e}");
+            return self.remote.get_stream(key).await;
+        }
+        self.cache.get_stream(key).await
+    }
+
+    async fn exists(&self, key: &str) -> AppResult<bool> {
+        Ok(self.cache.exists(key).await? || self.remote.exists(key).await?)
+    }
+
+    async fn delete(&self, key: &str) -> AppResult<()> {
+        self.remote.delete(key).await?;
+        self.index
+            .lock()
+            .expect("locking should not fail")
+            .remove(key);
+        self.cache.delete(key).await
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        let path = self.cache.local_path(key)?;
+        self.index
+            .lock()
+            .expect("locking should not fail")
+            .entries
+            .get_mut(key);
+        Some(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_storage(name: &str) -> FsStorage {
+        let root = std::env::temp_dir().join(format!("palpo-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        FsStorage::new(root)
+    }
+
+    #[tokio::test]
+    async fn tiered_cache_evicts_least_recently_used() {
+        let cache = temp_storage("tiered-cache");
+        let remote = temp_storage("tiered-remote");
+        let storage = TieredStorage::new(cache.clone(), remote.clone(), 10);
+
+        storage.put("media/_/a", b"aaaa").await.unwrap();
+        storage.put("media/_/b", b"bbbb").await.unwrap();
+        // Touch `a` so `b` becomes the least recently used file.
+        assert!(storage.local_path("media/_/a").is_some());
+        storage.put("media/_/c", b"cccc").await.unwrap();
+
+        assert!(cache.local_path("media/_/a").is_some());
+        assert!(cache.local_path("media/_/b").is_none());
+        assert!(cache.local_path("media/_/c").is_some());
+
+        // Evicted files are still read from the remote and cached again.
+        let data = storage
+            .get_stream("media/_/b")
+            .await
+            .unwrap()
+            .unwrap()
+            .map_ok(|chunk| chunk.to_vec())
+            .try_concat()
+            .await
+            .unwrap();
+        assert_eq!(&data[..], b"bbbb");
+        assert!(cache.local_path("media/_/b").is_some());
+        assert!(cache.local_path("media/_/a").is_none());
+
+        let _ = std::fs::remove_dir_all(cache.path(""));
+        let _ = std::fs::remove_dir_all(remote.path(""));
+    }
+
+    #[tokio::test]
+    async fn tiered_cache_fills_concurrently() {
+        let cache = temp_storage("tiered-concurrent-cache");
+        let remote = temp_storage("tiered-concurrent-remote");
+        let data = vec![7u8; 256 * 1024];
+        remote.put("media/_/big", &data).await.unwrap();
+        let storage = TieredStorage::new(cache.clone(), remote.clone(), 0);
+
+        let (a, b) = tokio::join!(storage.get("media/_/big"), storage.get("media/_/big"));
+        assert_eq!(a.unwrap().unwrap(), data);
+        assert_eq!(b.unwrap().unwrap(), data);
+        assert_eq!(std::fs::read(cache.path("media/_/big")).unwrap(), data);
+
+        let _ = std::fs::remove_dir_all(cache.path(""));
+        let _ = std::fs::remove_dir_all(remote.path(""));
+    }
+}
@@ -27,12 +27,14 @@ mod invite;
 mod join;
 mod knock;
 mod leave;
+mod third_party_invite;
 pub use banned::*;
 pub use forget::*;
 pub use invite::*;
 pub use join::*;
 pub use knock::*;
 pub use leave::*;
+pub use third_party_invite::*;
 
 async fn validate_and_add_event_id(
     pdu: &RawJsonValue,
@@ -1,5 +1,7 @@
 use crate::core::events::TimelineEventType;
-use crate::core::events::room::member::{MembershipState, RoomMemberEventContent};
+use crate::core::events::room::member::{
+    MembershipState, RoomMemberEventContent, ThirdPartyInvite,
+};
 use crate::core::federation::membership::InviteUserResBodyV2;
 use crate::core::identifiers::*;
 use crate::core::serde::to_raw_json_value;
@@ -25,7 +27,19 @@ pub async fn invite_user(
     if !room::user_can_invite(room_id, inviter_id, invitee_id).await {
         return Err(MatrixError::forbidden("you are not allowed to invite this user", None).into());
     }
+    send_invite(inviter_id, invitee_id, room_id, reason, is_direct, None).await
+}
 
+/// Creates the invite event and hands it to the server of the invitee, with
+/// the `third_party_invite` it was exchanged for if any.
+pub(super) async fn send_invite(
+    inviter_id: &UserId,
+    invitee_id: &UserId,
+    room_id: &RoomId,
+    reason: Option<String>,
+    is_direct: bool,
+    third_party_invite: Option<ThirdPartyInvite>,
+) -> AppResult<()> {
     let conf = crate::config::get();
     if invitee_id.server_name().is_remote() {
         let (pdu, pdu_json, invite_room_state) = {
@@ -34,7 +48,7 @@ pub async fn invite_user(
                 display_name: None,
                 is_direct: Some(is_direct),
                 membership: MembershipState::Invite,
-                third_party_invite: None,
+                third_party_invite,
                 blurhash: None,
                 reason,
                 join_authorized_via_users_server: None,
@@ -153,7 +167,7 @@ pub async fn invite_user(
                 display_name: data::user::display_name(invitee_id)?,
                 avatar_url: data::user::avatar_url(invitee_id)?,
                 is_direct: Some(is_direct),
-                third_party_invite: None,
+                third_party_invite,
                 blurhash: data::user::blurhash(invitee_id)?,
                 reason,
                 join_authorized_via_users_server: None,
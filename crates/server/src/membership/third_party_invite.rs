@@ -0,0 +1,263 @@
+//! Invites by third party identifier, kept by an identity server until the
+//! identifier is bound to a user.
+//!
+//! The inviter stores the invite on the identity server and sends an
+//! `m.room.third_party_invite` event holding the public keys of the identity
+//! server. Once the identifier is bound, the identity server calls
+//! `3pid/onbind` on the server of the user with the invites signed by those
+//! keys, which the server of the inviter exchanges for regular invites.
+
+use crate::core::client::membership::InviteThreepid;
+use crate::core::events::StateEventType;
+use crate::core::events::room::member::{
+    MembershipState, RoomMemberEventContent, SignedContent, ThirdPartyInvite,
+};
+use crate::core::events::room::third_party_invite::RoomThirdPartyInviteEventContent;
+use crate::core::federation::third_party::{self, ExchangeInviteReqBody};
+use crate::core::identifiers::*;
+use crate::core::serde::base64::Standard;
+use crate::core::serde::{Base64, CanonicalJsonObject};
+use crate::core::signatures;
+use crate::core::third_party_invite::IdentityServerBase64PublicKey;
+use crate::event::PduBuilder;
+use crate::room::timeline;
+use crate::user::threepid::{is_key_valid, lookup_on_id_server, store_invite_on_id_server};
+use crate::{AppResult, GetUrlOrigin, IsRemoteOrLocal, MatrixError, room, sending};
+
+/// Invites the user bound to the threepid, or stores the invite on the
+/// identity server if there is none yet.
+pub async fn invite_threepid(
+    inviter_id: &UserId,
+    room_id: &RoomId,
+    threepid: &InviteThreepid,
+) -> AppResult<()> {
+    if !room::user::is_joined(inviter_id, room_id)? {
+        return Err(MatrixError::forbidden(
+            "you must be joined in the room you are trying to invite from",
+            None,
+        )
+        .into());
+    }
+
+    if let Some(invitee_id) = lookup_on_id_server(
+        &threepid.id_server,
+        &threepid.id_access_token,
+        &threepid.medium,
+        &threepid.address,
+    )
+    .await?
+    {
+        return super::invite_user(inviter_id, &invitee_id, room_id, None, false).await;
+    }
+
+    let (token, content) = store_invite_on_id_server(
+        &threepid.id_server,
+        &threepid.id_access_token,
+        &threepid.medium,
+        &threepid.address,
+        room_id,
+        inviter_id,
+    )
+    .await?;
+    timeline::build_and_append_pdu(
+        PduBuilder::state(token, &content),
+        inviter_id,
+        room_id,
+        &room::get_version(room_id)?,
+        &room::lock_state(room_id).await,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Exchanges the invites the identity server kept for the threepid now bound
+/// to the local user. Failures are only logged, so one bad invite does not
+/// drop the others.
+pub async fn on_bind(user_id: &UserId, invites: Vec<third_party::ThirdPartyInvite>) {
+    for invite in invites {
+        if invite.mxid != user_id || invite.signed.mxid != user_id {
+            warn!(
+                "ignoring third party invite to {} bound to {user_id}",
+                invite.signed.mxid
+            );
+            continue;
+        }
+        let mut content = RoomMemberEventContent::new(MembershipState::Invite);
+        // The server of the room replaces it with the one of its invite event.
+        content.third_party_invite =
+            Some(ThirdPartyInvite::new(invite.address.clone(), invite.signed));
+
+        let result = if invite.sender.server_name().is_local() {
+            exchange_third_party_invite(&invite.room_id, &invite.sender, user_id, content).await
+        } else {
+            send_exchange_request(&invite.room_id, &invite.sender, user_id, content).await
+        };
+        if let Err(e) = result {
+            warn!(
+                "failed to exchange third party invite of {user_id} to {}: {e}",
+                invite.room_id
+            );
+        }
+    }
+}
+
+async fn send_exchange_request(
+    room_id: &RoomId,
+    sender_id: &UserId,
+    invitee_id: &UserId,
+    content: RoomMemberEventContent,
+) -> AppResult<()> {
+    let request = third_party::exchange_invite_request(
+        &sender_id.server_name().origin().await,
+        ExchangeInviteReqBody::new(
+            room_id.to_owned(),
+            sender_id.to_owned(),
+            invitee_id.to_owned(),
+            content,
+        ),
+    )?
+    .into_inner();
+    sending::send_federation_request(sender_id.server_name(), request, None).await?;
+    Ok(())
+}
+
+/// Turns the third party invite signed by the identity server into an invite
+/// of the user, sent by the local user who invited the threepid.
+pub async fn exchange_third_party_invite(
+    room_id: &RoomId,
+    sender_id: &UserId,
+    invitee_id: &UserId,
+    content: RoomMemberEventContent,
+) -> AppResult<()> {
+    let Some(mut third_party_invite) = content.third_party_invite else {
+        return Err(MatrixError::invalid_param("missing third_party_invite").into());
+    };
+    if content.membership != MembershipState::Invite {
+        return Err(MatrixError::invalid_param("membership must be invite").into());
+    }
+    if sender_id.server_name().is_remote() {
+        return Err(MatrixError::invalid_param("sender of the invite is not a local user").into());
+    }
+    if third_party_invite.signed.mxid != invitee_id {
+        return Err(MatrixError::invalid_param("signed mxid does not match the invitee").into());
+    }
+    if !room::user::is_joined(sender_id, room_id)? {
+        return Err(MatrixError::forbidden("sender of the invite left the room", None).into());
+    }
+
+    let invite_event = room::get_state(
+        room_id,
+        &StateEventType::RoomThirdPartyInvite,
+        &third_party_invite.signed.token,
+        None,
+    )
+    .map_err(|_| MatrixError::forbidden("no third party invite matches the token", None))?;
+    if invite_event.sender != sender_id {
+        return Err(
+            MatrixError::forbidden("third party invite was not sent by the sender", None).into(),
+        );
+    }
+    let invite_content = invite_event.get_content::<RoomThirdPartyInviteEventContent>()?;
+    verify_signed(&third_party_invite.signed, &invite_content).await?;
+
+    third_party_invite.display_name = invite_content.display_name;
+    super::send_invite(
+        sender_id,
+        invitee_id,
+        room_id,
+        None,
+        false,
+        Some(third_party_invite),
+    )
+    .await
+}
+
+/// Checks that the signed block of a third party invite is signed with one of
+/// the identity server keys of its `m.room.third_party_invite` event, and that
+/// the identity server did not revoke that key.
+async fn verify_signed(
+    signed: &SignedContent,
+    invite_content: &RoomThirdPartyInviteEventContent,
+) -> AppResult<()> {
+    let mut public_keys = vec![(
+        invite_content.public_key.clone(),
+        Some(invite_content.key_validity_url.clone()).filter(|url| !url.is_empty()),
+    )];
+    for key in invite_content.public_keys.iter().flatten() {
+        public_keys.push((
+            IdentityServerBase64PublicKey::new(key.public_key.as_bytes()),
+            key.key_validity_url.clone(),
+        ));
+    }
+
+    let object: CanonicalJsonObject = serde_json::from_value(serde_json::to_value(signed)?)?;
+    let canonical_json = signatures::canonical_json(&object)?;
+    for (key_id, signature) in signed.signatures.values().flatten() {
+        let Ok(signature) = Base64::<Standard>::parse(signature) else {
+            continue;
+        };
+        for (public_key, key_validity_url) in &public_keys {
+            let Ok(public_key_bytes) = public_key.decode() else {
+                continue;
+            };
+            if signatures::verify_canonical_json_bytes(
+                &key_id.algorithm(),
+                &public_key_bytes,
+                signature.as_bytes(),
+                canonical_json.as_bytes(),
+            )
+            .is_err()
+            {
+                continue;
+            }
+            if let Some(url) = key_validity_url
+                && !is_key_valid(url, public_key).await?
+            {
+                return Err(MatrixError::forbidden(
+                    "identity server revoked the key of the third party invite",
+                    None,
+                )
+                .into());
+            }
+            return Ok(());
+        }
+    }
+    Err(MatrixError::forbidden(
+        "third party invite is not signed by the identity server",
+        None,
+    )
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_invite(mxid: &str) -> (SignedContent, RoomThirdPartyInviteEventContent) {
+        let keypair = crate::utils::generate_keypair();
+        let mut object = CanonicalJsonObject::new();
+        object.insert("mxid".to_owned(), mxid.to_owned().into());
+        object.insert("token".to_owned(), "abc".to_owned().into());
+        signatures::sign_json("id.example.org", &keypair, &mut object).unwrap();
+        let signed = serde_json::from_value(serde_json::to_value(&object).unwrap()).unwrap();
+        let content = RoomThirdPartyInviteEventContent::new(
+            "a...@e...".to_owned(),
+            String::new(),
+            IdentityServerBase64PublicKey::new(&keypair.public_key()),
+        );
+        (signed, content)
+    }
+
+    #[tokio::test]
+    async fn accepts_invite_signed_by_identity_server() {
+        let (signed, content) = signed_invite("@alice:example.org");
+        assert!(verify_signed(&signed, &content).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_invite() {
+        let (mut signed, content) = signed_invite("@alice:example.org");
+        signed.mxid = "@mallory:example.org".try_into().unwrap();
+        assert!(verify_signed(&signed, &content).await.is_err());
+    }
+}
@@ -0,0 +1,277 @@
+//! Prometheus metrics.
+//!
+//! Counters and histograms are updated where the work happens. Gauges that
+//! mirror state kept elsewhere (database pool, sending queue, media storage)
+//! are refreshed when the metrics are scraped, the media storage size at most
+//! every `STORAGE_SIZE_REFRESH_INTERVAL`.
+//!
+//! Per destination series are capped by `metrics.max_destination_labels`,
+//! destinations past it are counted under the "other" label.
+
+use std::collections::HashSet;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use salvo::http::header::CONTENT_TYPE;
+use salvo::prelude::*;
+
+use crate::sending::OutgoingKind;
+use crate::{AppError, AppResult, config, data};
+
+/// Summing the size of all media is a full scan, so it is not redone on every
+/// scrape.
+const STORAGE_SIZE_REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Label of the destinations that did not get a series of their own.
+const OTHER_DESTINATIONS: &str = "other";
+
+static STORAGE_SIZE_REFRESHED_AT: Mutex<Option<Instant>> = Mutex::new(None);
+/// Destinations that have their own failure series.
+static LABELED_DESTINATIONS: LazyLock<Mutex<HashSet<String>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(|| {
+    Registry::new_custom(Some("palpo".to_owned()), None).expect("metrics registry should build")
+});
+
+pub static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time spent handling HTTP requests.",
+            ),
+            &["method", "route", "status"],
+        )
+        .expect("metric should build"),
+    )
+});
+
+static DB_POOL_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(
+        IntGauge::new(
+            "db_pool_connections",
+            "Connections currently open in the database pool.",
+        )
+        .expect("metric should build"),
+    )
+});
+
+static DB_POOL_IDLE_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(
+        IntGauge::new(
+            "db_pool_idle_connections",
+            "Idle connections in the database pool.",
+        )
+        .expect("metric should build"),
+    )
+});
+
+static SENDING_QUEUE_DEPTH: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "sending_queue_depth",
+                "Outgoing requests queued or in flight per destination.",
+            ),
+            &["kind", "destination"],
+        )
+        .expect("metric should build"),
+    )
+});
+
+pub static SENDING_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "sending_failures_total",
+                "Failed attempts to send outgoing requests per destination.",
+            ),
+            &["kind", "destination"],
+        )
+        .expect("metric should build"),
+    )
+});
+
+pub static FEDERATION_TRANSACTION_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "federation_transaction_duration_seconds",
+                "Time taken by other servers to accept federation transactions.",
+            )
+            .buckets(vec![
+                0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+            ]),
+            &["outcome"],
+        )
+        .expect("metric should build"),
+    )
+});
+
+static SYNC_LONG_POLLS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "sync_long_polls",
+                "Sync requests currently waiting for new data.",
+            ),
+            &["endpoint"],
+        )
+        .expect("metric should build"),
+    )
+});
+
+static SYNC_LONG_POLLS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "sync_long_polls_total",
+                "Sync requests that had to wait for new data.",
+            ),
+            &["endpoint"],
+        )
+        .expect("metric should build"),
+    )
+});
+
+static CACHE_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("cache_lookups_total", "Lookups in the in memory caches."),
+            &["cache", "result"],
+        )
+        .expect("metric should build"),
+    )
+});
+
+static MEDIA_STORAGE_BYTES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(
+        IntGauge::new(
+            "media_storage_bytes",
+            "Size of the stored media and thumbnails.",
+        )
+        .expect("metric should build"),
+    )
+});
+
+fn register<T: Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric should only be registered once");
+    metric
+}
+
+/// Records a lookup in one of the in memory caches.
+pub fn cache_lookup(cache: &str, hit: bool) {
+    CACHE_LOOKUPS
+        .with_label_values(&[cache, if hit { "hit" } else { "miss" }])
+        .inc();
+}
+
+/// Records a failed attempt to send to `kind`.
+pub fn sending_failed(kind: &OutgoingKind) {
+    let destination = destination_label(kind);
+    let destination = if destination.is_empty() {
+        destination
+    } else {
+        let mut labeled = LABELED_DESTINATIONS
+            .lock()
+            .expect("locking should not fail");
+        if labeled.contains(&destination) || labeled.len() < max_destination_labels() {
+            labeled.insert(destination.clone());
+            destination
+        } else {
+            OTHER_DESTINATIONS.to_owned()
+        }
+    };
+    SENDING_FAILURES
+        .with_label_values(&[kind.name(), &destination])
+        .inc();
+}
+
+fn max_destination_labels() -> usize {
+    config::get()
+        .enabled_metrics()
+        .map(|conf| conf.max_destination_labels)
+        .unwrap_or_default()
+}
+
+/// Counts a sync request as waiting until the returned guard is dropped.
+pub fn sync_long_poll(endpoint: &'static str) -> SyncLongPollGuard {
+    SYNC_LONG_POLLS_TOTAL.with_label_values(&[endpoint]).inc();
+    SYNC_LONG_POLLS.with_label_values(&[endpoint]).inc();
+    SyncLongPollGuard(endpoint)
+}
+
+pub struct SyncLongPollGuard(&'static str);
+impl Drop for SyncLongPollGuard {
+    fn drop(&mut self) {
+        SYNC_LONG_POLLS.with_label_values(&[self.0]).dec();
+    }
+}
+
+// Push destinations are left out, one series per pusher would grow without bound.
+fn destination_label(kind: &OutgoingKind) -> String {
+    match kind {
+        OutgoingKind::Appservice(id) => id.clone(),
+        OutgoingKind::Push(..) => String::new(),
+        OutgoingKind::Normal(server) => server.to_string(),
+    }
+}
+
+fn refresh_gauges() -> AppResult<()> {
+    let state = data::state();
+    DB_POOL_CONNECTIONS.set(state.connections.into());
+    DB_POOL_IDLE_CONNECTIONS.set(state.idle_connections.into());
+
+    // Only the destinations with the deepest queues get a series of their own.
+    let mut depths = data::sending::count_requests_by_destination()?;
+    depths.sort_by_key(|(_, _, count)| std::cmp::Reverse(*count));
+    let max_labels = max_destination_labels();
+    let mut labeled = 0;
+    SENDING_QUEUE_DEPTH.reset();
+    for (kind, server_id, count) in depths {
+        let destination = match server_id {
+            Some(_) if labeled >= max_labels => OTHER_DESTINATIONS.to_owned(),
+            Some(server_id) => {
+                labeled += 1;
+                server_id.to_string()
+            }
+            None => String::new(),
+        };
+        SENDING_QUEUE_DEPTH
+            .with_label_values(&[kind.as_str(), destination.as_str()])
+            .add(count);
+    }
+
+    let mut refreshed_at = STORAGE_SIZE_REFRESHED_AT
+        .lock()
+        .expect("locking should not fail");
+    if refreshed_at.is_none_or(|at| at.elapsed() >= STORAGE_SIZE_REFRESH_INTERVAL) {
+        MEDIA_STORAGE_BYTES.set(data::media::total_storage_size()?);
+        *refreshed_at = Some(Instant::now());
+    }
+    Ok(())
+}
+
+/// #GET /metrics
+/// Metrics in the Prometheus text format.
+#[handler]
+pub async fn serve(res: &mut Response) -> AppResult<()> {
+    if let Err(e) = refresh_gauges() {
+        warn!("failed to refresh metrics: {e}");
+    }
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .map_err(|e| AppError::internal(format!("failed to encode metrics: {e}")))?;
+    res.add_header(CONTENT_TYPE, encoder.format_type(), true)?;
+    res.write_body(buffer)?;
+    Ok(())
+}
@@ -27,7 +27,7 @@ use crate::core::state::events::RoomCreateEvent;
 use crate::core::{Seqnum, UnixMillis};
 use crate::data::room::{DbRoomCurrent, NewDbRoom};
 use crate::data::schema::*;
-use crate::data::{connect, diesel_exists};
+use crate::data::{connect, connect_read, diesel_exists};
 use crate::{
     APPSERVICE_IN_ROOM_CACHE, AppError, AppResult, IsRemoteOrLocal, RoomMutexGuard, RoomMutexMap,
     SnPduEvent, config, data, membership, room, utils,
@@ -41,6 +41,7 @@ pub mod directory;
 pub mod lazy_loading;
 pub mod pdu_metadata;
 pub mod receipt;
+pub mod retention;
 pub mod space;
 pub mod state;
 pub mod timeline;
@@ -447,13 +448,13 @@ pub fn public_room_ids() -> AppResult<Vec<OwnedRoomId>> {
         .filter(rooms::is_public.eq(true))
         .select(rooms::id)
         .order_by(rooms::sn.desc())
-        .load(&mut connect()?)
+        .load(&mut connect_read()?)
         .map_err(Into::into)
 }
 pub fn all_room_ids() -> AppResult<Vec<OwnedRoomId>> {
     rooms::table
         .select(rooms::id)
-        .load(&mut connect()?)
+        .load(&mut connect_read()?)
         .map_err(Into::into)
 }
 
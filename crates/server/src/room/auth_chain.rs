@@ -157,8 +157,10 @@ fn get_event_auth_chain(room_id: &RoomId, event_id: &EventId) -> AppResult<Vec<S
 fn get_cached_auth_chain(cache_key: &[Seqnum]) -> AppResult<Option<Arc<Vec<Seqnum>>>> {
     // Check RAM cache
     if let Some(result) = AUTH_CHAIN_CACHE.lock().unwrap().get_mut(cache_key) {
+        crate::metrics::cache_lookup("auth_chain", true);
         return Ok(Some(Arc::clone(result)));
     }
+    crate::metrics::cache_lookup("auth_chain", false);
 
     let chain_sns = event_auth_chains::table
         .find(cache_key)
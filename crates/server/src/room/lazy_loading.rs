@@ -4,14 +4,18 @@ use std::sync::{LazyLock, Mutex};
 use diesel::prelude::*;
 use palpo_core::Seqnum;
 
-use crate::AppResult;
-use crate::core::{DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UserId};
+use crate::core::serde::JsonValue;
+use crate::core::{DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UnixMillis, UserId};
 use crate::data::schema::*;
 use crate::data::{connect, diesel_exists};
+use crate::{AppResult, config};
 
-pub static LAZY_LOAD_WAITING: LazyLock<
-    Mutex<HashMap<(OwnedUserId, OwnedDeviceId, OwnedRoomId, Seqnum), HashSet<OwnedUserId>>>,
-> = LazyLock::new(Default::default);
+type WaitingKey = (OwnedUserId, OwnedDeviceId, OwnedRoomId, Seqnum);
+
+/// The members lazily sent and waiting for the client to confirm them, when
+/// no other process shares the database.
+static LAZY_LOAD_WAITING: LazyLock<Mutex<HashMap<WaitingKey, HashSet<OwnedUserId>>>> =
+    LazyLock::new(Default::default);
 
 #[tracing::instrument]
 pub fn lazy_load_was_sent_before(
@@ -28,6 +32,9 @@ pub fn lazy_load_was_sent_before(
     diesel_exists!(query, &mut connect()?).map_err(Into::into)
 }
 
+/// Remembers the members lazily sent until the client confirms it received
+/// them by syncing from `until_sn`. With a shared backend they are stored in
+/// the database so any process can see them.
 #[tracing::instrument]
 pub fn lazy_load_mark_sent(
     user_id: &UserId,
@@ -35,16 +42,39 @@ pub fn lazy_load_mark_sent(
     room_id: &RoomId,
     lazy_load: HashSet<OwnedUserId>,
     until_sn: Seqnum,
-) {
-    LAZY_LOAD_WAITING.lock().unwrap().insert(
-        (
-            user_id.to_owned(),
-            device_id.to_owned(),
-            room_id.to_owned(),
-            until_sn,
-        ),
-        lazy_load,
-    );
+) -> AppResult<()> {
+    if !config::get().coordination.is_shared() {
+        LAZY_LOAD_WAITING.lock().unwrap().insert(
+            (
+                user_id.to_owned(),
+                device_id.to_owned(),
+                room_id.to_owned(),
+                until_sn,
+            ),
+            lazy_load,
+        );
+        return Ok(());
+    }
+    let lazy_load_user_ids = serde_json::to_value(lazy_load)?;
+    diesel::insert_into(lazy_load_waitings::table)
+        .values((
+            lazy_load_waitings::user_id.eq(user_id),
+            lazy_load_waitings::device_id.eq(device_id),
+            lazy_load_waitings::room_id.eq(room_id),
+            lazy_load_waitings::until_sn.eq(until_sn),
+            lazy_load_waitings::lazy_load_user_ids.eq(&lazy_load_user_ids),
+            lazy_load_waitings::created_at.eq(UnixMillis::now().get() as i64),
+        ))
+        .on_conflict((
+            lazy_load_waitings::user_id,
+            lazy_load_waitings::device_id,
+            lazy_load_waitings::room_id,
+            lazy_load_waitings::until_sn,
+        ))
+        .do_update()
+        .set(lazy_load_waitings::lazy_load_user_ids.eq(&lazy_load_user_ids))
+        .execute(&mut connect()?)?;
+    Ok(())
 }
 
 #[tracing::instrument]
@@ -54,28 +84,76 @@ pub fn lazy_load_confirm_delivery(
     room_id: &RoomId,
     occur_sn: Seqnum,
 ) -> AppResult<()> {
-    if let Some(confirmed_user_ids) = LAZY_LOAD_WAITING.lock().unwrap().remove(&(
-        user_id.to_owned(),
-        device_id.to_owned(),
-        room_id.to_owned(),
-        occur_sn,
-    )) {
-        for confirmed_user_id in confirmed_user_ids {
-            diesel::insert_into(lazy_load_deliveries::table)
-                .values((
-                    lazy_load_deliveries::user_id.eq(user_id),
-                    lazy_load_deliveries::device_id.eq(device_id),
-                    lazy_load_deliveries::room_id.eq(room_id),
-                    lazy_load_deliveries::confirmed_user_id.eq(confirmed_user_id),
-                ))
-                .on_conflict_do_nothing()
-                .execute(&mut connect()?)?;
+    // Members sent in earlier responses were not received, as the client
+    // syncs from a later point.
+    if !config::get().coordination.is_shared() {
+        let mut confirmed_user_ids = None;
+        LAZY_LOAD_WAITING.lock().unwrap().retain(
+            |(waiting_user_id, waiting_device_id, waiting_room_id, until_sn), waiting| {
+                if waiting_user_id != user_id
+                    || waiting_device_id != device_id
+                    || waiting_room_id != room_id
+                    || *until_sn > occur_sn
+                {
+                    return true;
+                }
+                if *until_sn == occur_sn {
+                    confirmed_user_ids = Some(std::mem::take(waiting));
+                }
+                false
+            },
+        );
+        if let Some(confirmed_user_ids) = confirmed_user_ids {
+            mark_delivered(user_id, device_id, room_id, confirmed_user_ids)?;
         }
+        return Ok(());
+    }
+
+    let waitings = diesel::delete(
+        lazy_load_waitings::table
+            .filter(lazy_load_waitings::user_id.eq(user_id))
+            .filter(lazy_load_waitings::device_id.eq(device_id))
+            .filter(lazy_load_waitings::room_id.eq(room_id))
+            .filter(lazy_load_waitings::until_sn.le(occur_sn)),
+    )
+    .returning((
+        lazy_load_waitings::until_sn,
+        lazy_load_waitings::lazy_load_user_ids,
+    ))
+    .get_results::<(Seqnum, JsonValue)>(&mut connect()?)?;
+    if let Some((_, waiting)) = waitings.into_iter().find(|(sn, _)| *sn == occur_sn) {
+        mark_delivered(
+            user_id,
+            device_id,
+            room_id,
+            serde_json::from_value(waiting)?,
+        )?;
     }
 
     Ok(())
 }
 
+fn mark_delivered(
+    user_id: &UserId,
+    device_id: &DeviceId,
+    room_id: &RoomId,
+    confirmed_user_ids: HashSet<OwnedUserId>,
+) -> AppResult<()> {
+    let conn = &mut connect()?;
+    for confirmed_user_id in confirmed_user_ids {
+        diesel::insert_into(lazy_load_deliveries::table)
+            .values((
+                lazy_load_deliveries::user_id.eq(user_id),
+                lazy_load_deliveries::device_id.eq(device_id),
+                lazy_load_deliveries::room_id.eq(room_id),
+                lazy_load_deliveries::confirmed_user_id.eq(confirmed_user_id),
+            ))
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
 #[tracing::instrument]
 pub fn lazy_load_reset(user_id: &UserId, device_id: &DeviceId, room_id: &RoomId) -> AppResult<()> {
     diesel::delete(
@@ -85,5 +163,32 @@ pub fn lazy_load_reset(user_id: &UserId, device_id: &DeviceId, room_id: &RoomId)
             .filter(lazy_load_deliveries::room_id.eq(room_id)),
     )
     .execute(&mut connect()?)?;
+    if !config::get().coordination.is_shared() {
+        LAZY_LOAD_WAITING.lock().unwrap().retain(
+            |(waiting_user_id, waiting_device_id, waiting_room_id, _), _| {
+                waiting_user_id != user_id
+                    || waiting_device_id != device_id
+                    || waiting_room_id != room_id
+            },
+        );
+        return Ok(());
+    }
+    diesel::delete(
+        lazy_load_waitings::table
+            .filter(lazy_load_waitings::user_id.eq(user_id))
+            .filter(lazy_load_waitings::device_id.eq(device_id))
+            .filter(lazy_load_waitings::room_id.eq(room_id)),
+    )
+    .execute(&mut connect()?)?;
     Ok(())
 }
+
+/// Deletes the stored members waiting for a confirmation since before
+/// `before`, the client is not coming back for them.
+pub fn prune_lazy_load_waitings(before: UnixMillis) -> AppResult<usize> {
+    let pruned = diesel::delete(
+        lazy_load_waitings::table.filter(lazy_load_waitings::created_at.lt(before.get() as i64)),
+    )
+    .execute(&mut connect()?)?;
+    Ok(pruned)
+}
@@ -1,11 +1,38 @@
+use std::time::Duration;
+
 use diesel::prelude::*;
 
-use crate::AppResult;
-use crate::core::Seqnum;
+use crate::core::client::push::Notification;
 use crate::core::identifiers::*;
+use crate::core::push::Action;
+use crate::core::{Seqnum, UnixMillis};
 use crate::data::connect;
 use crate::data::room::NewDbEventPushAction;
 use crate::data::schema::*;
+use crate::event::SnPduEvent;
+use crate::{AppResult, utils};
+
+/// Read push actions are kept this long so they still show up in
+/// `/notifications`, after which they are pruned.
+const READ_ACTIONS_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Rows deleted per statement, so pruning never holds long locks.
+const PRUNE_BATCH_SIZE: i64 = 1000;
+
+/// Starts the job that periodically prunes old read push actions.
+pub fn start() {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match utils::spawn_blocking(prune_read_actions).await {
+                Ok(0) => {}
+                Ok(pruned) => debug!("pruned {pruned} read push actions"),
+                Err(e) => error!("failed to prune read push actions: {e}"),
+            }
+        }
+    });
+}
 
 pub fn increment_notification_counts(
     event_id: &EventId,
@@ -104,10 +131,10 @@ pub fn upsert_push_action(
     room_id: &RoomId,
     event_id: &EventId,
     user_id: &UserId,
+    actions: &[Action],
     notify: bool,
     highlight: bool,
 ) -> AppResult<()> {
-    let actions: Vec<String> = vec![];
     let (event_sn, thread_id) = event_points::table
         .find(event_id)
         .select((event_points::event_sn, event_points::thread_id))
@@ -135,43 +162,177 @@ pub fn upsert_push_action(
     Ok(())
 }
 
-pub fn remove_actions_until(
+/// Marks the push actions up to and including `event_sn` as read, so they no
+/// longer count as unread but are still listed by `/notifications`.
+pub fn mark_actions_read_until(
     user_id: &UserId,
     room_id: &RoomId,
     event_sn: Seqnum,
     thread_id: Option<&EventId>,
 ) -> AppResult<()> {
     if let Some(thread_id) = thread_id {
-        diesel::delete(
+        diesel::update(
             event_push_actions::table
                 .filter(event_push_actions::user_id.eq(user_id))
                 .filter(event_push_actions::room_id.eq(room_id))
                 .filter(event_push_actions::thread_id.eq(thread_id))
                 .filter(event_push_actions::event_sn.le(event_sn)),
         )
+        .set(event_push_actions::is_read.eq(true))
         .execute(&mut connect()?)?;
     } else {
-        diesel::delete(
+        diesel::update(
             event_push_actions::table
                 .filter(event_push_actions::user_id.eq(user_id))
                 .filter(event_push_actions::room_id.eq(room_id))
                 .filter(event_push_actions::event_sn.le(event_sn)),
         )
+        .set(event_push_actions::is_read.eq(true))
         .execute(&mut connect()?)?;
     }
     Ok(())
 }
 
-pub fn remove_actions_for_room(user_id: &UserId, room_id: &RoomId) -> AppResult<()> {
-    diesel::delete(
+pub fn mark_room_actions_read(user_id: &UserId, room_id: &RoomId) -> AppResult<()> {
+    diesel::update(
         event_push_actions::table
             .filter(event_push_actions::user_id.eq(user_id))
             .filter(event_push_actions::room_id.eq(room_id)),
     )
+    .set(event_push_actions::is_read.eq(true))
     .execute(&mut connect()?)?;
     Ok(())
 }
 
+/// Deletes the read push actions of events older than the retention period,
+/// in batches. Returns how many were deleted.
+fn prune_read_actions() -> AppResult<usize> {
+    let cutoff = UnixMillis::now().get() as i64 - READ_ACTIONS_RETENTION.as_millis() as i64;
+    let mut conn = connect()?;
+    let mut pruned = 0;
+    loop {
+        let old_event_ids = events::table
+            .filter(events::origin_server_ts.lt(cutoff))
+            .select(events::id);
+        let ids = event_push_actions::table
+            .filter(event_push_actions::is_read.eq(true))
+            .filter(event_push_actions::event_id.eq_any(old_event_ids))
+            .select(event_push_actions::id)
+            .limit(PRUNE_BATCH_SIZE)
+            .load::<i64>(&mut conn)?;
+        if ids.is_empty() {
+            return Ok(pruned);
+        }
+        pruned +=
+            diesel::delete(event_push_actions::table.filter(event_push_actions::id.eq_any(&ids)))
+                .execute(&mut conn)?;
+    }
+}
+
+/// Lists the events the user was notified about, newest first. Returns the
+/// notifications along with the token for the next page, if there is one.
+pub fn get_notifications(
+    user_id: &UserId,
+    from: Option<Seqnum>,
+    limit: usize,
+    only_highlight: bool,
+) -> AppResult<(Vec<Notification>, Option<Seqnum>)> {
+    let mut query = event_push_actions::table
+        .filter(event_push_actions::user_id.eq(user_id))
+        .filter(event_push_actions::notify.eq(true))
+        .into_boxed();
+    if let Some(from) = from {
+        query = query.filter(event_push_actions::event_sn.lt(from));
+    }
+    if only_highlight {
+        query = query.filter(event_push_actions::highlight.eq(true));
+    }
+    let rows = query
+        .order_by(event_push_actions::event_sn.desc())
+        .limit(limit as i64 + 1)
+        .select((
+            event_push_actions::event_id,
+            event_push_actions::event_sn,
+            event_push_actions::room_id,
+            event_push_actions::profile_tag,
+            event_push_actions::actions,
+            event_push_actions::is_read,
+        ))
+        .load::<(
+            OwnedEventId,
+            Seqnum,
+            OwnedRoomId,
+            String,
+            serde_json::Value,
+            bool,
+        )>(&mut connect()?)?;
+
+    let next_token = if rows.len() > limit {
+        rows.get(limit - 1).map(|row| row.1)
+    } else {
+        None
+    };
+    let mut notifications = Vec::with_capacity(rows.len().min(limit));
+    for (event_id, _, room_id, profile_tag, actions, read) in rows.into_iter().take(limit) {
+        let Ok(pdu) = super::timeline::get_pdu(&event_id) else {
+            continue;
+        };
+        notifications.push(Notification {
+            actions: serde_json::from_value(actions).unwrap_or_default(),
+            event: pdu.to_sync_room_event(),
+            profile_tag: (!profile_tag.is_empty()).then_some(profile_tag),
+            read,
+            room_id,
+            ts: pdu.origin_server_ts,
+        });
+    }
+    Ok((notifications, next_token))
+}
+
+/// Lists the unread notifications of the user that came after `since`, oldest
+/// first, limited to rooms whose push summary still counts something unread.
+pub fn get_unread_notifications(
+    user_id: &UserId,
+    since: Seqnum,
+    highlights_only: bool,
+) -> AppResult<Vec<SnPduEvent>> {
+    let mut room_query = event_push_summaries::table
+        .filter(event_push_summaries::user_id.eq(user_id))
+        .into_boxed();
+    room_query = if highlights_only {
+        room_query.filter(event_push_summaries::highlight_count.gt(0))
+    } else {
+        room_query.filter(event_push_summaries::notification_count.gt(0))
+    };
+    let room_ids = room_query
+        .select(event_push_summaries::room_id)
+        .distinct()
+        .load::<OwnedRoomId>(&mut connect()?)?;
+    if room_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut query = event_push_actions::table
+        .filter(event_push_actions::user_id.eq(user_id))
+        .filter(event_push_actions::room_id.eq_any(&room_ids))
+        .filter(event_push_actions::is_read.eq(false))
+        .filter(event_push_actions::event_sn.gt(since))
+        .into_boxed();
+    query = if highlights_only {
+        query.filter(event_push_actions::highlight.eq(true))
+    } else {
+        query.filter(event_push_actions::notify.eq(true))
+    };
+    let event_ids = query
+        .order_by(event_push_actions::event_sn.asc())
+        .select(event_push_actions::event_id)
+        .load::<OwnedEventId>(&mut connect()?)?;
+    Ok(event_ids
+        .iter()
+        .filter_map(|event_id| super::timeline::get_pdu(event_id).ok())
+        .collect())
+}
+
 pub fn refresh_notify_summary(user_id: &UserId, room_id: &RoomId) -> AppResult<()> {
     let thread_ids = event_push_actions::table
         .filter(event_push_actions::user_id.eq(user_id))
@@ -204,14 +365,17 @@ pub fn refresh_notify_summary(user_id: &UserId, room_id: &RoomId) -> AppResult<(
             .filter(event_push_actions::room_id.eq(room_id))
             .filter(event_push_actions::thread_id.eq(thread_id));
         let notification_count = query
+            .filter(event_push_actions::is_read.eq(false))
             .filter(event_push_actions::notify.eq(true))
             .count()
             .get_result::<i64>(&mut connect()?)?;
         let highlight_count = query
+            .filter(event_push_actions::is_read.eq(false))
             .filter(event_push_actions::highlight.eq(true))
             .count()
             .get_result::<i64>(&mut connect()?)?;
         let unread_count = query
+            .filter(event_push_actions::is_read.eq(false))
             .filter(event_push_actions::unread.eq(true))
             .count()
             .get_result::<i64>(&mut connect()?)?;
@@ -249,14 +413,17 @@ pub fn refresh_notify_summary(user_id: &UserId, room_id: &RoomId) -> AppResult<(
         .filter(event_push_actions::room_id.eq(room_id))
         .filter(event_push_actions::thread_id.is_null());
     let notification_count = query
+        .filter(event_push_actions::is_read.eq(false))
         .filter(event_push_actions::notify.eq(true))
         .count()
         .get_result::<i64>(&mut connect()?)?;
     let highlight_count = query
+        .filter(event_push_actions::is_read.eq(false))
         .filter(event_push_actions::highlight.eq(true))
         .count()
         .get_result::<i64>(&mut connect()?)?;
     let unread_count = query
+        .filter(event_push_actions::is_read.eq(false))
         .filter(event_push_actions::unread.eq(true))
         .count()
         .get_result::<i64>(&mut connect()?)?;
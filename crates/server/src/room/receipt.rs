@@ -3,6 +3,7 @@ use std::collections::BTreeMap;
 use diesel::prelude::*;
 
 use crate::core::UnixMillis;
+use crate::core::appservice::event::EphemeralData;
 use crate::core::events::receipt::{
     Receipt, ReceiptContent, ReceiptData, ReceiptEvent, ReceiptEventContent, ReceiptMap,
     ReceiptType, Receipts,
@@ -69,6 +70,30 @@ pub fn update_read(
     if broadcast {
         sending::send_edu_room(room_id, &edu)?;
     }
+
+    // Private read receipts are never forwarded to appservices.
+    let public_content = event
+        .content
+        .0
+        .iter()
+        .filter_map(|(event_id, receipts)| {
+            let receipts = receipts
+                .iter()
+                .filter(|(receipt_ty, _)| **receipt_ty != ReceiptType::ReadPrivate)
+                .map(|(receipt_ty, user_receipts)| (receipt_ty.clone(), user_receipts.clone()))
+                .collect::<Receipts>();
+            (!receipts.is_empty()).then(|| (event_id.clone(), receipts))
+        })
+        .collect::<BTreeMap<_, _>>();
+    if !public_content.is_empty() {
+        let data = EphemeralData::Receipt(ReceiptEvent {
+            content: ReceiptEventContent(public_content),
+            room_id: room_id.to_owned(),
+        });
+        if let Err(e) = crate::appservice::send_room_ephemeral(room_id, &data) {
+            warn!("failed to send receipt in {room_id} to appservices: {e}");
+        }
+    }
     Ok(())
 }
 
@@ -0,0 +1,86 @@
+//! Enforcement of `m.room.retention` message retention policies.
+//!
+//! A periodic job deletes the events that outlived the max lifetime of their
+//! room, as set by its policy and bounded by the server configuration.
+
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::core::UnixMillis;
+use crate::core::events::StateEventType;
+use crate::core::identifiers::*;
+use crate::data::room::PurgeHistoryFilter;
+use crate::{AppResult, config, data, utils};
+
+/// Content of an `m.room.retention` state event.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RoomRetentionEventContent {
+    #[serde(default)]
+    pub min_lifetime: Option<u64>,
+    #[serde(default)]
+    pub max_lifetime: Option<u64>,
+}
+
+pub fn start() {
+    let conf = config::get().retention.clone();
+    if !conf.enable {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(conf.purge_interval));
+        loop {
+            interval.tick().await;
+            if let Err(e) = purge_expired_events().await {
+                error!("failed to purge expired events: {e}");
+            }
+        }
+    });
+}
+
+/// The max lifetime of the events of the room, if they expire at all.
+pub fn max_lifetime(room_id: &RoomId) -> Option<u64> {
+    let policy = super::get_state_content::<RoomRetentionEventContent>(
+        room_id,
+        &StateEventType::from("m.room.retention"),
+        "",
+        None,
+    )
+    .unwrap_or_default();
+    config::get().retention.max_lifetime(policy.max_lifetime)
+}
+
+async fn purge_expired_events() -> AppResult<()> {
+    let now = UnixMillis::now().get();
+    for room_id in super::all_room_ids()? {
+        let Some(max_lifetime) = max_lifetime(&room_id) else {
+            continue;
+        };
+        let filter = PurgeHistoryFilter {
+            before_ts: Some(now.saturating_sub(max_lifetime) as i64),
+            ..Default::default()
+        };
+        match purge_history(&room_id, filter).await {
+            Ok(0) => {}
+            Ok(purged) => info!("purged {purged} expired events of {room_id}"),
+            Err(e) => warn!("failed to purge expired events of {room_id}: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the events of the room matching the filter in batches, and
+/// returns how many were deleted.
+pub async fn purge_history(room_id: &RoomId, filter: PurgeHistoryFilter) -> AppResult<usize> {
+    let mut purged_events = 0;
+    loop {
+        let room_id = room_id.to_owned();
+        let filter = filter.clone();
+        let purged =
+            utils::spawn_blocking(move || data::room::purge_history(&room_id, &filter)).await?;
+        if purged == 0 {
+            return Ok(purged_events);
+        }
+        purged_events += purged;
+    }
+}
@@ -24,8 +24,10 @@ pub struct FrameInfo {
 /// selected state_hash and each parent layer.
 pub fn load_frame_info(frame_id: i64) -> AppResult<Vec<FrameInfo>> {
     if let Some(r) = STATE_INFO_CACHE.lock().unwrap().get_mut(&frame_id) {
+        crate::metrics::cache_lookup("state_frame", true);
         return Ok(r.clone());
     }
+    crate::metrics::cache_lookup("state_frame", false);
 
     let StateDiff {
         parent_id,
@@ -343,9 +343,10 @@ pub async fn append_pdu(
 
         let mut highlight = false;
         let mut notify = false;
+        let mut actions = Vec::new();
 
         if let Ok(power_levels) = crate::room::get_power_levels(pdu.room_id()).await {
-            for action in data::user::pusher::get_actions(
+            actions = data::user::pusher::get_actions(
                 user_id,
                 &rules_for_user,
                 &power_levels,
@@ -353,7 +354,8 @@ pub async fn append_pdu(
                 &pdu.room_id,
             )
             .await?
-            {
+            .to_vec();
+            for action in &actions {
                 match action {
                     Action::Notify => notify = true,
                     Action::SetTweak(Tweak::Highlight(true)) => {
@@ -371,9 +373,14 @@ pub async fn append_pdu(
             highlights.push(user_id.clone());
         }
 
-        if let Err(e) =
-            push_action::upsert_push_action(&pdu.room_id, &pdu.event_id, user_id, notify, highlight)
-        {
+        if let Err(e) = push_action::upsert_push_action(
+            &pdu.room_id,
+            &pdu.event_id,
+            user_id,
+            &actions,
+            notify,
+            highlight,
+        ) {
             error!("failed to upsert event push action: {}", e);
         }
         push_action::refresh_notify_summary(&pdu.sender, &pdu.room_id)?;
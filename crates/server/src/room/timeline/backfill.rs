@@ -47,8 +47,14 @@ pub async fn backfill_if_required(
 
     let mut prev_depth = *prev_depth;
     let mut prev_event = prev_event;
-    let last_depth = depths.last().map(|&(_, d)| d).unwrap_or_default() as i64;
-    if prev_depth == last_depth {
+    // Purged events leave gaps which must not be filled again.
+    let purged_depth = crate::data::room::purged_depth(room_id)?;
+    let last_depth = depths
+        .last()
+        .map(|&(_, d)| d)
+        .unwrap_or_default()
+        .max(purged_depth + 1);
+    if prev_depth <= last_depth {
         return Ok(vec![]);
     }
 
@@ -117,7 +123,7 @@ pub async fn backfill_if_required(
                         let val =
                             serde_json::from_str::<BTreeMap<String, JsonValue>>(pdu.get()).ok()?;
                         let depth = val.get("depth")?.as_i64()?;
-                        Some((pdu, depth))
+                        (depth > purged_depth).then_some((pdu, depth))
                     })
                     .sorted_by(|a, b| a.1.cmp(&b.1))
                     .map(|(pdu, _)| pdu)
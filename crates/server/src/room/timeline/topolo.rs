@@ -4,7 +4,7 @@ use indexmap::IndexMap;
 use crate::core::client::filter::{RoomEventFilter, UrlFilter};
 use crate::core::identifiers::*;
 use crate::core::{Direction, Seqnum};
-use crate::data::connect;
+use crate::data::connect_read;
 use crate::data::schema::*;
 use crate::event::BatchToken;
 use crate::{AppResult, SnPduEvent, utils};
@@ -180,7 +180,7 @@ pub fn load_pdus(
                 .offset(offset)
                 .limit(utils::usize_to_i64(limit))
                 .select((events::id, events::sn, events::stream_ordering))
-                .load::<(OwnedEventId, Seqnum, i64)>(&mut connect()?)?
+                .load::<(OwnedEventId, Seqnum, i64)>(&mut connect_read()?)?
                 .into_iter()
                 .rev()
                 .collect()
@@ -195,7 +195,7 @@ pub fn load_pdus(
                 .limit(utils::usize_to_i64(limit));
             query
                 .select((events::id, events::sn, events::stream_ordering))
-                .load::<(OwnedEventId, Seqnum, i64)>(&mut connect()?)?
+                .load::<(OwnedEventId, Seqnum, i64)>(&mut connect_read()?)?
                 .into_iter()
                 .collect()
         };
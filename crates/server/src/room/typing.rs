@@ -3,20 +3,31 @@ use std::sync::LazyLock;
 
 use tokio::sync::{RwLock, broadcast};
 
+use crate::coordination::{self, Notification};
 use crate::core::UnixMillis;
+use crate::core::appservice::event::EphemeralData;
 use crate::core::events::SyncEphemeralRoomEvent;
-use crate::core::events::typing::{TypingContent, TypingEventContent};
+use crate::core::events::typing::{TypingContent, TypingEvent, TypingEventContent};
 use crate::core::federation::transaction::Edu;
 use crate::core::identifiers::*;
 use crate::{AppResult, IsRemoteOrLocal, data, sending};
 
-pub static TYPING: LazyLock<RwLock<BTreeMap<OwnedRoomId, BTreeMap<OwnedUserId, u64>>>> =
-    LazyLock::new(Default::default); // u64 is unix timestamp of timeout
+pub static TYPING: LazyLock<RwLock<BTreeMap<OwnedRoomId, BTreeMap<OwnedUserId, TypingEntry>>>> =
+    LazyLock::new(Default::default);
 pub static LAST_TYPING_UPDATE: LazyLock<RwLock<BTreeMap<OwnedRoomId, i64>>> =
     LazyLock::new(Default::default); // timestamp of the last change to typing users
 pub static TYPING_UPDATE_SENDER: LazyLock<broadcast::Sender<OwnedRoomId>> =
     LazyLock::new(|| broadcast::channel(100).0);
 
+/// A typing user, until the unix timestamp of `timeout`.
+#[derive(Clone, Copy, Debug)]
+pub struct TypingEntry {
+    pub timeout: u64,
+    /// Set when another process received the update, which then notifies
+    /// appservices and federation.
+    pub replicated: bool,
+}
+
 /// Sets a user as typing until the timeout timestamp is reached or roomremove_typing is
 /// called.
 pub async fn add_typing(
@@ -30,7 +41,13 @@ pub async fn add_typing(
         .await
         .entry(room_id.to_owned())
         .or_default()
-        .insert(user_id.to_owned(), timeout);
+        .insert(
+            user_id.to_owned(),
+            TypingEntry {
+                timeout,
+                replicated: false,
+            },
+        );
     let event_sn = data::next_sn()?;
     LAST_TYPING_UPDATE
         .write()
@@ -49,6 +66,8 @@ pub async fn add_typing(
     // state::update_frame_id(point_id, current_frame_id)?;
 
     let _ = TYPING_UPDATE_SENDER.send(room_id.to_owned());
+    publish(room_id, user_id, Some(timeout));
+    appservice_send(room_id).await.ok();
 
     if broadcast && user_id.is_local() {
         federation_send(room_id, user_id, true).await.ok();
@@ -69,6 +88,8 @@ pub async fn remove_typing(user_id: &UserId, room_id: &RoomId, broadcast: bool)
         .await
         .insert(room_id.to_owned(), data::next_sn()?);
     let _ = TYPING_UPDATE_SENDER.send(room_id.to_owned());
+    publish(room_id, user_id, None);
+    appservice_send(room_id).await.ok();
 
     if broadcast && user_id.is_local() {
         federation_send(room_id, user_id, false).await.ok();
@@ -76,6 +97,51 @@ pub async fn remove_typing(user_id: &UserId, room_id: &RoomId, broadcast: bool)
     Ok(())
 }
 
+/// Applies a typing update another process received, which already notified
+/// appservices and federation.
+pub(crate) async fn apply_replicated(
+    user_id: &UserId,
+    room_id: &RoomId,
+    timeout: Option<u64>,
+) -> AppResult<()> {
+    {
+        let mut typing = TYPING.write().await;
+        let room = typing.entry(room_id.to_owned()).or_default();
+        match timeout {
+            Some(timeout) => {
+                room.insert(
+                    user_id.to_owned(),
+                    TypingEntry {
+                        timeout,
+                        replicated: true,
+                    },
+                );
+            }
+            None => {
+                room.remove(user_id);
+            }
+        }
+    }
+    LAST_TYPING_UPDATE
+        .write()
+        .await
+        .insert(room_id.to_owned(), data::next_sn()?);
+    let _ = TYPING_UPDATE_SENDER.send(room_id.to_owned());
+    Ok(())
+}
+
+/// Shares the typing update with the other processes, failures are only logged.
+fn publish(room_id: &RoomId, user_id: &UserId, timeout: Option<u64>) {
+    let notification = Notification::Typing {
+        room_id: room_id.to_owned(),
+        user_id: user_id.to_owned(),
+        timeout,
+    };
+    if let Err(e) = coordination::publish(notification) {
+        warn!("failed to publish typing update of {user_id} in {room_id}: {e}");
+    }
+}
+
 pub async fn wait_for_update(room_id: &RoomId) -> AppResult<()> {
     let mut receiver = TYPING_UPDATE_SENDER.subscribe();
     while let Ok(next) = receiver.recv().await {
@@ -96,18 +162,20 @@ async fn maintain_typings(room_id: &RoomId) -> AppResult<()> {
         let Some(room) = typing.get(room_id) else {
             return Ok(());
         };
-        for (user_id, timeout) in room {
-            if *timeout < current_timestamp.get() {
-                removable.push(user_id.clone());
+        for (user_id, entry) in room {
+            if entry.timeout < current_timestamp.get() {
+                removable.push((user_id.clone(), entry.replicated));
             }
         }
         drop(typing);
     }
     if !removable.is_empty() {
-        let typing = &mut TYPING.write().await;
-        let room = typing.entry(room_id.to_owned()).or_default();
-        for user_id in &removable {
-            room.remove(user_id);
+        {
+            let typing = &mut TYPING.write().await;
+            let room = typing.entry(room_id.to_owned()).or_default();
+            for (user_id, _) in &removable {
+                room.remove(user_id);
+            }
         }
         LAST_TYPING_UPDATE
             .write()
@@ -115,8 +183,13 @@ async fn maintain_typings(room_id: &RoomId) -> AppResult<()> {
             .insert(room_id.to_owned(), data::next_sn()?);
         let _ = TYPING_UPDATE_SENDER.send(room_id.to_owned());
 
-        for user_id in &removable {
-            if user_id.is_local() {
+        // Every process expires the users itself, only the one that received
+        // the update notifies others.
+        if removable.iter().any(|(_, replicated)| !replicated) {
+            appservice_send(room_id).await.ok();
+        }
+        for (user_id, replicated) in &removable {
+            if !replicated && user_id.is_local() {
                 federation_send(room_id, user_id, false).await.ok();
             }
         }
@@ -151,6 +224,15 @@ pub async fn all_typings(
     })
 }
 
+/// Forwards the current typing users of the room to interested appservices.
+async fn appservice_send(room_id: &RoomId) -> AppResult<()> {
+    let data = EphemeralData::Typing(TypingEvent {
+        content: all_typings(room_id).await?.content,
+        room_id: room_id.to_owned(),
+    });
+    crate::appservice::send_room_ephemeral(room_id, &data)
+}
+
 async fn federation_send(room_id: &RoomId, user_id: &UserId, typing: bool) -> AppResult<()> {
     debug_assert!(
         user_id.is_local(),
@@ -7,11 +7,12 @@ mod media;
 use salvo::prelude::*;
 use salvo::serve_static::StaticDir;
 
+use crate::config::WorkerRole;
 use crate::core::MatrixError;
 use crate::core::client::discovery::client::{ClientResBody, HomeServerInfo};
 use crate::core::client::discovery::support::{Contact, SupportResBody};
 use crate::core::federation::directory::ServerResBody;
-use crate::{AppResult, JsonResult, config, hoops, json_ok};
+use crate::{JsonResult, config, hoops, json_ok, worker};
 
 pub mod prelude {
     pub use salvo::prelude::*;
@@ -26,28 +27,45 @@ pub mod prelude {
     };
 }
 
+/// Routes of the subsystems this process runs, see `worker::has_role`.
 pub fn root() -> Router {
-    Router::new()
+    let mut matrix = Router::with_path("_matrix");
+    if worker::has_role(WorkerRole::Client) {
+        matrix = matrix.push(client::router());
+    }
+    if worker::has_role(WorkerRole::Media) {
+        matrix = matrix
+            .push(client::media_router())
+            .push(media::router())
+            .push(federation::media_router());
+    }
+    if worker::has_role(WorkerRole::Federation) {
+        matrix = matrix
+            .push(federation::router())
+            .push(federation::identity_router())
+            .push(federation::key::router());
+    }
+    if worker::has_role(WorkerRole::Client) {
+        matrix = matrix.push(appservice::router());
+    }
+
+    let mut root = Router::new()
+        .hoop(hoops::mark_routed)
         .hoop(hoops::ensure_accept)
         .hoop(hoops::ensure_content_type)
         .hoop(hoops::limit_size)
         .get(home)
-        .push(
-            Router::with_path("_matrix")
-                .push(client::router())
-                .push(media::router())
-                .push(federation::router())
-                .push(federation::key::router())
-                .push(appservice::router()),
-        )
-        .push(admin::router())
-        .push(
-            Router::with_path(".well-known/matrix")
-                .push(Router::with_path("client").get(well_known_client))
-                .push(Router::with_path("support").get(well_known_support))
-                .push(Router::with_path("server").get(well_known_server)),
-        )
-        .push(Router::with_path("{*path}").get(StaticDir::new("./static")))
+        .push(matrix);
+    if worker::has_role(WorkerRole::Client) {
+        root = root.push(admin::router());
+    }
+    root.push(
+        Router::with_path(".well-known/matrix")
+            .push(Router::with_path("client").get(well_known_client))
+            .push(Router::with_path("support").get(well_known_support))
+            .push(Router::with_path("server").get(well_known_server)),
+    )
+    .push(Router::with_path("{*path}").get(StaticDir::new("./static")))
 }
 
 #[handler]
@@ -58,11 +76,6 @@ async fn home(req: &mut Request, res: &mut Response) {
     res.render("Hello Palpo");
 }
 
-#[handler]
-pub async fn limit_rate() -> AppResult<()> {
-    Ok(())
-}
-
 #[endpoint]
 fn well_known_client() -> JsonResult<ClientResBody> {
     let conf = config::get();
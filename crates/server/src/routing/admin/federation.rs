@@ -10,6 +10,7 @@ use salvo::prelude::*;
 use serde::Serialize;
 
 use crate::core::identifiers::*;
+use crate::data::sending::{DbDestination, DestinationFilter};
 use crate::{JsonResult, MatrixError, data, json_ok};
 
 pub fn router() -> Router {
@@ -58,6 +59,18 @@ pub struct DestinationRoom {
     pub stream_ordering: i64,
 }
 
+impl From<DbDestination> for DestinationInfo {
+    fn from(destination: DbDestination) -> Self {
+        Self {
+            destination: destination.server_id.to_string(),
+            retry_last_ts: destination.retry_last_ts,
+            retry_interval: destination.retry_interval,
+            failure_ts: destination.failure_ts,
+            last_successful_stream_ordering: destination.last_successful_sn,
+        }
+    }
+}
+
 /// GET /_synapse/admin/v1/federation/destinations
 ///
 /// List all federation destinations
@@ -65,28 +78,21 @@ pub struct DestinationRoom {
 pub fn list_destinations(
     from: QueryParam<i64, false>,
     limit: QueryParam<i64, false>,
-    _destination: QueryParam<String, false>,
-    _order_by: QueryParam<String, false>,
-    _dir: QueryParam<String, false>,
+    destination: QueryParam<String, false>,
+    order_by: QueryParam<String, false>,
+    dir: QueryParam<String, false>,
 ) -> JsonResult<DestinationsListResponse> {
     let offset = from.into_inner().unwrap_or(0);
-    let limit = limit.into_inner().unwrap_or(100);
+    let filter = DestinationFilter {
+        from: Some(offset),
+        limit: Some(limit.into_inner().unwrap_or(100)),
+        destination: destination.into_inner(),
+        order_by: order_by.into_inner(),
+        direction: dir.into_inner(),
+    };
 
-    let servers = data::sending::get_all_destinations()?;
-    let total = servers.len() as i64;
-
-    let destinations: Vec<DestinationInfo> = servers
-        .into_iter()
-        .skip(offset as usize)
-        .take(limit as usize)
-        .map(|server| DestinationInfo {
-            destination: server.to_string(),
-            retry_last_ts: 0,
-            retry_interval: 0,
-            failure_ts: None,
-            last_successful_stream_ordering: None,
-        })
-        .collect();
+    let (destinations, total) = data::sending::list_destinations(&filter)?;
+    let destinations: Vec<DestinationInfo> = destinations.into_iter().map(Into::into).collect();
 
     let next_token = if (offset + destinations.len() as i64) < total {
         Some((offset + destinations.len() as i64).to_string())
@@ -108,18 +114,11 @@ pub fn list_destinations(
 pub fn get_destination(destination: PathParam<OwnedServerName>) -> JsonResult<DestinationInfo> {
     let destination = destination.into_inner();
 
-    // Check if destination is known
-    if !data::sending::is_destination_known(&destination)? {
+    let Some(destination) = data::sending::get_destination(&destination)? else {
         return Err(MatrixError::not_found("Unknown destination").into());
-    }
+    };
 
-    json_ok(DestinationInfo {
-        destination: destination.to_string(),
-        retry_last_ts: 0,
-        retry_interval: 0,
-        failure_ts: None,
-        last_successful_stream_ordering: None,
-    })
+    json_ok(destination.into())
 }
 
 /// GET /_synapse/admin/v1/federation/destinations/{destination}/rooms
@@ -147,9 +146,9 @@ pub fn destination_rooms(
         .into_iter()
         .skip(offset as usize)
         .take(limit as usize)
-        .map(|room_id| DestinationRoom {
+        .map(|(room_id, event_sn)| DestinationRoom {
             room_id: room_id.to_string(),
-            stream_ordering: 0,
+            stream_ordering: event_sn,
         })
         .collect();
 
@@ -173,13 +172,18 @@ pub fn destination_rooms(
 pub fn reset_connection(destination: PathParam<OwnedServerName>) -> JsonResult<serde_json::Value> {
     let destination = destination.into_inner();
 
-    // Check if destination is known
-    if !data::sending::is_destination_known(&destination)? {
+    let Some(info) = data::sending::get_destination(&destination)? else {
         return Err(MatrixError::not_found("Unknown destination").into());
+    };
+    if !info.is_down() {
+        return Err(MatrixError::invalid_param(
+            "The retry timing does not need to be reset for this destination.",
+        )
+        .into());
     }
 
-    // Reset retry timings
-    data::sending::reset_destination_retry(&destination)?;
+    // Reset retry timings, the sender retries the destination on its next check.
+    crate::sending::retry::reset(&destination)?;
 
     json_ok(serde_json::json!({}))
 }
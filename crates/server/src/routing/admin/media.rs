@@ -13,6 +13,7 @@ use salvo::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use crate::core::identifiers::*;
+use crate::scheduler::{self, DeleteOldLocalMediaParams, PurgeRemoteMediaParams};
 use crate::{JsonResult, MatrixError, config, data, json_ok};
 
 pub fn router() -> Router {
@@ -88,9 +89,11 @@ pub struct DeleteMediaResponse {
     pub total: i64,
 }
 
+/// Id of the scheduled task doing the work, which can be followed through
+/// `/_synapse/admin/v1/scheduled_tasks`.
 #[derive(Debug, Serialize, ToSchema)]
-pub struct PurgeMediaCacheResponse {
-    pub deleted: i64,
+pub struct MediaTaskResponse {
+    pub task_id: String,
 }
 
 #[derive(Debug, Deserialize, ToParameters)]
@@ -154,10 +157,12 @@ pub fn delete_media(
 }
 
 /// POST /_synapse/admin/v1/media/delete
+///
+/// Runs as a scheduled task and returns its id right away.
 #[endpoint(operation_id = "delete_media_by_date_size")]
 pub fn delete_media_by_date_size(
     query: DeleteMediaByDateSizeQuery,
-) -> JsonResult<DeleteMediaResponse> {
+) -> JsonResult<MediaTaskResponse> {
     let before_ts = query.before_ts;
     let size_gt = query.size_gt.unwrap_or(0);
 
@@ -169,13 +174,14 @@ pub fn delete_media_by_date_size(
         .into());
     }
 
-    let local_server = &config::get().server_name;
-    let (deleted_media, total) = data::media::delete_old_local_media(local_server, before_ts, size_gt)?;
+    let params = DeleteOldLocalMediaParams { before_ts, size_gt };
+    let task = scheduler::schedule(
+        scheduler::DELETE_OLD_LOCAL_MEDIA,
+        None,
+        serde_json::to_value(params)?,
+    )?;
 
-    json_ok(DeleteMediaResponse {
-        deleted_media,
-        total,
-    })
+    json_ok(MediaTaskResponse { task_id: task.id })
 }
 
 /// GET /_synapse/admin/v1/room/{room_id}/media
@@ -212,7 +218,8 @@ pub fn list_user_media(
         return Err(MatrixError::not_found("Unknown user").into());
     }
 
-    let (media_list, total) = data::media::list_media_by_user(&user_id, from, limit, order_by, dir)?;
+    let (media_list, total) =
+        data::media::list_media_by_user(&user_id, from, limit, order_by, dir)?;
 
     let media: Vec<MediaInfo> = media_list.into_iter().map(Into::into).collect();
     let next_token = if (from + limit) < total {
@@ -261,15 +268,17 @@ pub fn delete_user_media(
 }
 
 /// POST /_synapse/admin/v1/purge_media_cache
+///
+/// Runs as a scheduled task and returns its id right away.
 #[endpoint(operation_id = "purge_media_cache")]
-pub fn purge_media_cache(before_ts: QueryParam<i64, true>) -> JsonResult<PurgeMediaCacheResponse> {
+pub fn purge_media_cache(before_ts: QueryParam<i64, true>) -> JsonResult<MediaTaskResponse> {
     let before_ts = before_ts.into_inner();
 
     if before_ts < 0 {
-        return Err(
-            MatrixError::invalid_param("Query parameter before_ts must be a positive integer")
-                .into(),
-        );
+        return Err(MatrixError::invalid_param(
+            "Query parameter before_ts must be a positive integer",
+        )
+        .into());
     }
     if before_ts < 30000000000 {
         return Err(MatrixError::invalid_param(
@@ -279,8 +288,12 @@ pub fn purge_media_cache(before_ts: QueryParam<i64, true>) -> JsonResult<PurgeMe
         .into());
     }
 
-    let local_server = &config::get().server_name;
-    let deleted = data::media::purge_remote_media_cache(local_server, before_ts)?;
+    let params = PurgeRemoteMediaParams { before_ts };
+    let task = scheduler::schedule(
+        scheduler::PURGE_REMOTE_MEDIA,
+        None,
+        serde_json::to_value(params)?,
+    )?;
 
-    json_ok(PurgeMediaCacheResponse { deleted })
+    json_ok(MediaTaskResponse { task_id: task.id })
 }
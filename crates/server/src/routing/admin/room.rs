@@ -5,8 +5,9 @@ use serde_json::Value as JsonValue;
 
 use crate::core::client::space::{HierarchyReqArgs, HierarchyResBody};
 use crate::core::identifiers::*;
-use crate::exts::IsRemoteOrLocal;
-use crate::{AuthArgs, DepotExt, JsonResult, MatrixError, admin, json_ok, room};
+use crate::data::scheduled_task::{DbScheduledTask, STATUS_ACTIVE, ScheduledTaskFilter};
+use crate::scheduler::{self, PurgeHistoryParams, ShutdownRoomParams, ShutdownRoomResult};
+use crate::{AppResult, AuthArgs, DepotExt, JsonResult, MatrixError, admin, data, json_ok, room};
 
 pub fn router() -> Router {
     Router::new()
@@ -31,7 +32,25 @@ pub fn router() -> Router {
             ),
         )
         .push(
-            Router::with_path("v2").push(Router::with_path("rooms/{room_id}").delete(delete_room)),
+            Router::with_path("v1")
+                .push(
+                    Router::with_path("purge_history/{room_id}")
+                        .post(purge_history)
+                        .push(Router::with_path("{event_id}").post(purge_history_up_to_event)),
+                )
+                .push(
+                    Router::with_path("purge_history_status/{purge_id}")
+                        .get(get_purge_history_status),
+                ),
+        )
+        .push(
+            Router::with_path("v2")
+                .push(Router::with_path("rooms/delete_status/{delete_id}").get(get_delete_status))
+                .push(
+                    Router::with_path("rooms/{room_id}")
+                        .delete(delete_room)
+                        .push(Router::with_path("delete_status").get(get_delete_status_by_room)),
+                ),
         )
 }
 
@@ -126,12 +145,82 @@ fn default_purge() -> bool {
 /// Delete room response
 #[derive(Debug, Serialize, ToSchema)]
 pub struct DeleteRoomResponse {
+    pub delete_id: String,
+}
+
+/// Room delete statuses response
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeleteStatusesResponse {
+    pub results: Vec<DeleteStatus>,
+}
+
+/// Status of a room deletion
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeleteStatus {
+    pub delete_id: String,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub shutdown_room: ShutdownRoomStatus,
+}
+
+/// Outcome of shutting down a room
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct ShutdownRoomStatus {
     pub kicked_users: Vec<String>,
     pub failed_to_kick_users: Vec<String>,
     pub local_aliases: Vec<String>,
     pub new_room_id: Option<String>,
 }
 
+impl From<DbScheduledTask> for DeleteStatus {
+    fn from(task: DbScheduledTask) -> Self {
+        let shutdown_room = task
+            .result
+            .and_then(|result| serde_json::from_value::<ShutdownRoomResult>(result).ok())
+            .map(|result| ShutdownRoomStatus {
+                kicked_users: result.kicked_users.iter().map(|u| u.to_string()).collect(),
+                failed_to_kick_users: result
+                    .failed_to_kick_users
+                    .iter()
+                    .map(|u| u.to_string())
+                    .collect(),
+                local_aliases: result.local_aliases.iter().map(|a| a.to_string()).collect(),
+                new_room_id: result.new_room_id.map(|r| r.to_string()),
+            })
+            .unwrap_or_default();
+        Self {
+            delete_id: task.id,
+            status: task.status,
+            error: task.error,
+            shutdown_room,
+        }
+    }
+}
+
+/// Purge history request
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct PurgeHistoryReqBody {
+    #[serde(default)]
+    pub delete_local_events: bool,
+    pub purge_up_to_event_id: Option<OwnedEventId>,
+    pub purge_up_to_ts: Option<i64>,
+}
+
+/// Purge history response
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PurgeHistoryResponse {
+    pub purge_id: String,
+}
+
+/// Purge history status response
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PurgeHistoryStatusResponse {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
 /// Forward extremities response
 #[derive(Debug, Serialize, ToSchema)]
 pub struct ForwardExtremitiesResponse {
@@ -452,9 +541,8 @@ pub fn set_room_block(
 
 /// Delete room (v2)
 ///
-/// Note: The `purge` field is currently not fully implemented.
-/// When purge=true, events are not actually deleted from the database.
-/// This is a known limitation - full purge requires additional data layer support.
+/// The room is shut down and purged by a scheduled task, whose progress is
+/// reported by the `delete_status` endpoints.
 #[endpoint]
 pub async fn delete_room(
     room_id: PathParam<OwnedRoomId>,
@@ -466,48 +554,151 @@ pub async fn delete_room(
     if !room::room_exists(&room_id)? {
         return Err(MatrixError::not_found("Room not found").into());
     }
-
-    // Explicitly reject purge requests until full purge is implemented
-    if body.purge {
-        return Err(MatrixError::bad_status(
-            Some(salvo::http::StatusCode::NOT_IMPLEMENTED),
-            "Room purge is not supported yet",
-        )
-        .into());
+    if delete_tasks(&room_id)?
+        .iter()
+        .any(|task| !task.is_finished())
+    {
+        return Err(MatrixError::invalid_param("Room is already being deleted").into());
     }
 
-    let members = room::get_members(&room_id)?;
-    let kicked_users: Vec<String> = members
-        .iter()
-        .filter(|m| m.server_name().is_local())
-        .map(|m| m.to_string())
-        .collect();
+    let params = ShutdownRoomParams {
+        block: body.block,
+        purge: body.purge,
+    };
+    let task = scheduler::schedule(
+        scheduler::SHUTDOWN_AND_PURGE_ROOM,
+        Some(room_id.to_string()),
+        serde_json::to_value(params)?,
+    )?;
 
-    let local_aliases: Vec<String> = room::local_aliases_for_room(&room_id)?
+    json_ok(DeleteRoomResponse { delete_id: task.id })
+}
+
+/// Get the status of all deletions of a room (v2)
+#[endpoint]
+pub fn get_delete_status_by_room(
+    room_id: PathParam<OwnedRoomId>,
+) -> JsonResult<DeleteStatusesResponse> {
+    let room_id = room_id.into_inner();
+
+    let results: Vec<DeleteStatus> = delete_tasks(&room_id)?
         .into_iter()
-        .map(|a| a.to_string())
+        .map(DeleteStatus::from)
         .collect();
+    if results.is_empty() {
+        return Err(MatrixError::not_found("No delete task for room").into());
+    }
+
+    json_ok(DeleteStatusesResponse { results })
+}
+
+/// Get the status of a room deletion (v2)
+#[endpoint]
+pub fn get_delete_status(delete_id: PathParam<String>) -> JsonResult<DeleteStatus> {
+    let delete_id = delete_id.into_inner();
+
+    let task = data::scheduled_task::get_task(&delete_id)?
+        .filter(|task| task.action == scheduler::SHUTDOWN_AND_PURGE_ROOM)
+        .ok_or_else(|| MatrixError::not_found("delete id not found"))?;
+
+    json_ok(DeleteStatus::from(task))
+}
+
+fn delete_tasks(room_id: &RoomId) -> AppResult<Vec<DbScheduledTask>> {
+    data::scheduled_task::list_tasks(&ScheduledTaskFilter {
+        action: Some(scheduler::SHUTDOWN_AND_PURGE_ROOM.to_owned()),
+        resource_id: Some(room_id.to_string()),
+        ..Default::default()
+    })
+    .map_err(Into::into)
+}
+
+/// Purge the history of a room up to an event or a timestamp
+///
+/// State events and the latest events of the room are kept. Events sent by
+/// local users are only deleted if `delete_local_events` is set.
+#[endpoint]
+pub fn purge_history(
+    room_id: PathParam<OwnedRoomId>,
+    body: JsonBody<PurgeHistoryReqBody>,
+) -> JsonResult<PurgeHistoryResponse> {
+    let body = body.into_inner();
+    let purge_up_to_event_id = body.purge_up_to_event_id.clone();
+    schedule_purge_history(room_id.into_inner(), purge_up_to_event_id, body)
+}
 
-    if body.block {
-        room::ban_room(&room_id, true)?;
+/// Purge the history of a room up to the event in the path
+#[endpoint]
+pub fn purge_history_up_to_event(
+    room_id: PathParam<OwnedRoomId>,
+    event_id: PathParam<OwnedEventId>,
+    body: JsonBody<PurgeHistoryReqBody>,
+) -> JsonResult<PurgeHistoryResponse> {
+    schedule_purge_history(
+        room_id.into_inner(),
+        Some(event_id.into_inner()),
+        body.into_inner(),
+    )
+}
+
+fn schedule_purge_history(
+    room_id: OwnedRoomId,
+    purge_up_to_event_id: Option<OwnedEventId>,
+    body: PurgeHistoryReqBody,
+) -> JsonResult<PurgeHistoryResponse> {
+    if !room::room_exists(&room_id)? {
+        return Err(MatrixError::not_found("Room not found").into());
     }
 
-    // Disable the room (prevents new joins/messages)
-    room::disable_room(&room_id, true)?;
-
-    // TODO: Implement actual purge when purge=true
-    // This would require:
-    // 1. Delete events from events table
-    // 2. Delete event data from event_datas table
-    // 3. Delete state from room_state tables
-    // 4. Clean up room_users entries
-    // For now, we only disable the room
-
-    json_ok(DeleteRoomResponse {
-        kicked_users,
-        failed_to_kick_users: Vec::new(),
-        local_aliases,
-        new_room_id: None,
+    let mut params = PurgeHistoryParams {
+        delete_local_events: body.delete_local_events,
+        ..Default::default()
+    };
+    if let Some(event_id) = purge_up_to_event_id {
+        let pdu = room::timeline::get_pdu(&event_id)
+            .map_err(|_| MatrixError::not_found("Event not found"))?;
+        if pdu.room_id != room_id {
+            return Err(MatrixError::invalid_param("Event is for wrong room").into());
+        }
+        params.before_sn = Some(pdu.event_sn);
+    } else if let Some(purge_up_to_ts) = body.purge_up_to_ts {
+        params.before_ts = Some(purge_up_to_ts);
+    } else {
+        return Err(MatrixError::invalid_param(
+            "must specify purge_up_to_event_id or purge_up_to_ts",
+        )
+        .into());
+    }
+
+    let task = scheduler::schedule(
+        scheduler::PURGE_HISTORY,
+        Some(room_id.to_string()),
+        serde_json::to_value(params)?,
+    )?;
+
+    json_ok(PurgeHistoryResponse { purge_id: task.id })
+}
+
+/// Get the status of a history purge
+#[endpoint]
+pub fn get_purge_history_status(
+    purge_id: PathParam<String>,
+) -> JsonResult<PurgeHistoryStatusResponse> {
+    let purge_id = purge_id.into_inner();
+
+    let task = data::scheduled_task::get_task(&purge_id)?
+        .filter(|task| task.action == scheduler::PURGE_HISTORY)
+        .ok_or_else(|| MatrixError::not_found("purge id not found"))?;
+    // Tasks still waiting to run are reported as active, like running ones.
+    let status = if task.is_finished() {
+        task.status
+    } else {
+        STATUS_ACTIVE.to_owned()
+    };
+
+    json_ok(PurgeHistoryStatusResponse {
+        status,
+        error: task.error,
     })
 }
 
@@ -6,7 +6,8 @@ use salvo::oapi::extract::*;
 use salvo::prelude::*;
 use serde::Serialize;
 
-use crate::{JsonResult, json_ok};
+use crate::data::scheduled_task::{DbScheduledTask, ScheduledTaskFilter};
+use crate::{JsonResult, data, json_ok};
 
 pub fn router() -> Router {
     Router::new().push(Router::with_path("v1/scheduled_tasks").get(list_scheduled_tasks))
@@ -26,22 +27,48 @@ pub struct ScheduledTask {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub resource_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub result: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
 }
 
+impl From<DbScheduledTask> for ScheduledTask {
+    fn from(task: DbScheduledTask) -> Self {
+        Self {
+            id: task.id,
+            action: task.action,
+            status: task.status,
+            timestamp_ms: task.timestamp_ms,
+            resource_id: task.resource_id,
+            progress: task.progress,
+            result: task.result,
+            error: task.error,
+        }
+    }
+}
+
 /// GET /_synapse/admin/v1/scheduled_tasks
 ///
-/// List scheduled tasks
+/// List scheduled tasks, newest first
 #[endpoint]
 pub fn list_scheduled_tasks(
-    _action_name: QueryParam<String, false>,
-    _resource_id: QueryParam<String, false>,
-    _job_status: QueryParam<String, false>,
-    _max_timestamp: QueryParam<i64, false>,
+    action_name: QueryParam<String, false>,
+    resource_id: QueryParam<String, false>,
+    job_status: QueryParam<String, false>,
+    max_timestamp: QueryParam<i64, false>,
 ) -> JsonResult<ScheduledTasksResponse> {
-    json_ok(ScheduledTasksResponse {
-        scheduled_tasks: vec![],
-    })
+    let filter = ScheduledTaskFilter {
+        action: action_name.into_inner(),
+        resource_id: resource_id.into_inner(),
+        status: job_status.into_inner(),
+        max_timestamp: max_timestamp.into_inner(),
+    };
+    let scheduled_tasks = data::scheduled_task::list_tasks(&filter)?
+        .into_iter()
+        .map(ScheduledTask::from)
+        .collect();
+
+    json_ok(ScheduledTasksResponse { scheduled_tasks })
 }
@@ -16,8 +16,10 @@ use salvo::oapi::extract::*;
 use salvo::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use crate::core::client::account::{DeactivateResBody, ThirdPartyIdRemovalStatus};
 use crate::core::identifiers::*;
-use crate::{EmptyResult, JsonResult, MatrixError, data, empty_ok, json_ok, user};
+use crate::scheduler::{self, DeactivateUserParams};
+use crate::{EmptyResult, JsonResult, MatrixError, data, empty_ok, hoops, json_ok, user};
 
 // ============================================================================
 // Response/Request Types
@@ -527,12 +529,13 @@ pub async fn allow_cross_signing_replacement(
 
 /// POST /_synapse/admin/v1/deactivate/{user_id}
 ///
-/// Deactivate a user account
+/// Deactivate a user account. The account is locked out right away, leaving
+/// its rooms runs as a scheduled task.
 #[endpoint]
-pub async fn deactivate_user(
+pub fn deactivate_user(
     user_id: PathParam<OwnedUserId>,
     body: JsonBody<DeactivateReqBody>,
-) -> EmptyResult {
+) -> JsonResult<DeactivateResBody> {
     let user_id = user_id.into_inner();
     let body = body.into_inner();
 
@@ -541,19 +544,20 @@ pub async fn deactivate_user(
         return Err(MatrixError::not_found("User not found").into());
     }
 
-    // Get all joined rooms before deactivation
-    let joined_rooms = data::user::joined_rooms(&user_id)?;
-
-    // Perform full deactivation
-    user::full_user_deactivate(&user_id, &joined_rooms).await?;
+    // Stop the account from logging in and sending before returning.
+    data::user::deactivate(&user_id)?;
 
-    // Erase user data if requested
-    if body.erase.unwrap_or(false) {
-        // Delete all media
-        user::delete_all_media(&user_id).await?;
-    }
+    // Leaving every room may take long, so run it as a scheduled task
+    let params = DeactivateUserParams {
+        erase: body.erase.unwrap_or(false),
+    };
+    scheduler::schedule(
+        scheduler::DEACTIVATE_USER,
+        Some(user_id.to_string()),
+        serde_json::to_value(params)?,
+    )?;
 
-    empty_ok()
+    json_ok(DeactivateResBody::new(ThirdPartyIdRemovalStatus::NoSupport))
 }
 
 /// POST /_synapse/admin/v1/reset_password/{user_id}
@@ -838,6 +842,7 @@ pub async fn set_user_ratelimit(
     }
 
     data::user::set_ratelimit(&user_id, body.messages_per_second, body.burst_count)?;
+    hoops::forget_user_override(&user_id);
 
     json_ok(RateLimitResponse {
         messages_per_second: body.messages_per_second,
@@ -858,6 +863,7 @@ pub async fn delete_user_ratelimit(user_id: PathParam<OwnedUserId>) -> EmptyResu
     }
 
     data::user::delete_ratelimit(&user_id)?;
+    hoops::forget_user_override(&user_id);
 
     empty_ok()
 }
@@ -36,9 +36,19 @@ use crate::core::client::discovery::capabilities::{
     RoomVersionStability, RoomVersionsCapability, ThirdPartyIdChangesCapability,
 };
 use crate::core::client::discovery::versions::VersionsResBody;
+use crate::core::client::push::{NotificationsReqArgs, NotificationsResBody};
 use crate::core::client::search::{ResultCategories, SearchReqArgs, SearchReqBody, SearchResBody};
 use crate::routing::prelude::*;
 
+/// Client media endpoints, served by the media repository.
+pub fn media_router() -> Router {
+    let mut client = Router::with_path("client").oapi_tag("client");
+    for v in ["v3", "v1", "r0"] {
+        client = client.push(Router::with_path(v).push(media::self_auth_router()));
+    }
+    client
+}
+
 pub fn router() -> Router {
     let mut client = Router::with_path("client").oapi_tag("client");
     for v in ["v3", "v1", "r0"] {
@@ -51,7 +61,6 @@ pub fn router() -> Router {
                     .push(session::public_router())
                     .push(room::public_router())
                     .push(directory::public_router())
-                    .push(media::self_auth_router())
                     .push(
                         Router::with_path("publicRooms")
                             .get(room::get_public_rooms)
@@ -62,7 +71,6 @@ pub fn router() -> Router {
                 Router::with_path(v)
                     .hoop(hoops::auth_by_access_token)
                     .push(account::authed_router())
-                    .push(register::authed_router())
                     .push(session::authed_router())
                     .push(device::authed_router())
                     .push(room_key::authed_router())
@@ -84,9 +92,14 @@ pub fn router() -> Router {
                     .push(Router::with_path("joined_rooms").get(room::membership::joined_rooms))
                     .push(
                         Router::with_path("join/{room_id_or_alias}")
+                            .hoop(hoops::limit_rate_join)
                             .post(room::membership::join_room_by_id_or_alias),
                     )
-                    .push(Router::with_path("createRoom").post(room::create_room))
+                    .push(
+                        Router::with_path("createRoom")
+                            .hoop(hoops::limit_rate_join)
+                            .post(room::create_room),
+                    )
                     .push(Router::with_path("notifications").get(get_notifications))
                     .push(Router::with_path("sync").get(sync_v3::sync_events_v3))
                     .push(
@@ -102,11 +115,15 @@ pub fn router() -> Router {
             )
             .push(
                 Router::with_path(v)
-                    .hoop(hoops::limit_rate)
                     .hoop(hoops::auth_by_access_token)
+                    .hoop(hoops::limit_rate)
                     .push(Router::with_path("search").post(search))
                     .push(Router::with_path("capabilities").get(get_capabilities))
-                    .push(Router::with_path("knock/{room_id_or_alias}").post(room::knock_room)),
+                    .push(
+                        Router::with_path("knock/{room_id_or_alias}")
+                            .hoop(hoops::limit_rate_join)
+                            .post(room::knock_room),
+                    ),
             )
     }
     client
@@ -118,6 +135,11 @@ pub fn router() -> Router {
                 .push(Router::with_path("callback").get(oidc::oidc_callback))
                 .push(Router::with_path("login").post(oidc::oidc_login)),
         )
+        .push(
+            Router::with_path("unstable/pushers/remove")
+                .get(pusher::unsubscribe_page)
+                .post(pusher::unsubscribe),
+        )
         .push(unstable::router())
 }
 
@@ -217,9 +239,29 @@ fn supported_versions() -> JsonResult<VersionsResBody> {
     })
 }
 
+/// #GET /_matrix/client/r0/notifications
+/// Paginates through the events the user was notified about, newest first.
 #[endpoint]
-fn get_notifications(_aa: AuthArgs, depot: &mut Depot) -> EmptyResult {
-    // TODO: get_notifications
-    let _authed = depot.authed_info()?;
-    empty_ok()
+fn get_notifications(
+    _aa: AuthArgs,
+    args: NotificationsReqArgs,
+    depot: &mut Depot,
+) -> JsonResult<NotificationsResBody> {
+    let authed = depot.authed_info()?;
+
+    let from = args
+        .from
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+        .map_err(|_| MatrixError::invalid_param("Invalid `from` token."))?;
+    let limit = args.limit.unwrap_or(20).clamp(1, 100);
+    let only_highlight = args.only.as_deref() == Some("highlight");
+
+    let (notifications, next_token) =
+        crate::room::push_action::get_notifications(authed.user_id(), from, limit, only_highlight)?;
+    json_ok(NotificationsResBody {
+        next_token: next_token.map(|sn| sn.to_string()),
+        notifications,
+    })
 }
@@ -9,9 +9,7 @@ use crate::core::client::account::{
 };
 use crate::core::client::uiaa::{AuthFlow, AuthType, UiaaInfo};
 use crate::exts::*;
-use crate::{
-    AuthArgs, EmptyResult, JsonResult, MatrixError, SESSION_ID_LENGTH, data, hoops, json_ok, utils,
-};
+use crate::{AuthArgs, JsonResult, MatrixError, SESSION_ID_LENGTH, data, hoops, json_ok, utils};
 
 pub fn public_router() -> Router {
     Router::with_path("account")
@@ -25,8 +23,8 @@ pub fn public_router() -> Router {
         //         .push(Router::with_path("msisdn/requestToken").post(msisdn_request_token))
         //         .push(Router::with_path("email/requestTZoken").post(email_request_token)),
         // )
-        .push(Router::with_path("email/requestToken").post(token_via_email))
-        .push(Router::with_path("msisdn/requestToken").post(token_via_msisdn))
+        .push(password::public_router())
+        .push(threepid::public_router())
 }
 pub fn authed_router() -> Router {
     Router::with_path("account")
@@ -50,29 +48,9 @@ pub fn authed_router() -> Router {
                 .hoop(hoops::limit_rate)
                 .post(deactivate),
         )
-        .push(password::authed_router())
         .push(threepid::authed_router())
 }
 
-/// #POST /_matrix/client/v3/account/3pid/email/requestToken
-/// "This API should be used to request validation tokens when adding an email address to an
-/// account"
-///
-/// - 403 signals that The homeserver does not allow the third party identifier as a contact option.
-#[endpoint]
-async fn token_via_email(_aa: AuthArgs) -> EmptyResult {
-    Err(MatrixError::threepid_denied("Third party identifier is not allowed").into())
-}
-
-/// #POST /_matrix/client/v3/account/3pid/msisdn/requestToken
-/// "This API should be used to request validation tokens when adding an phone number to an account"
-///
-/// - 403 signals that The homeserver does not allow the third party identifier as a contact option.
-#[endpoint]
-async fn token_via_msisdn(_aa: AuthArgs) -> EmptyResult {
-    Err(MatrixError::threepid_denied("Third party identifier is not allowed").into())
-}
-
 /// #GET _matrix/client/r0/account/whoami
 ///
 /// Get user_id of the sender user.
@@ -1,24 +1,40 @@
 use diesel::prelude::*;
-use palpo_core::client::account::ChangePasswordReqBody;
+use palpo_core::client::account::{
+    ChangePasswordReqBody, TokenViaEmailReqBody, TokenViaEmailResBody,
+};
 use salvo::oapi::extract::*;
 use salvo::prelude::*;
 
-use crate::core::client::uiaa::{AuthFlow, AuthType, UiaaInfo};
+use crate::core::client::uiaa::{AuthData, AuthFlow, AuthType, UiaaInfo};
 use crate::data::connect;
 use crate::data::schema::*;
 use crate::exts::*;
-use crate::{AuthArgs, EmptyResult, SESSION_ID_LENGTH, empty_ok, hoops, utils};
+use crate::user::threepid::{ValidationPurpose, consume_validated_session, normalize_email};
+use crate::{
+    AuthArgs, EmptyResult, JsonResult, MatrixError, SESSION_ID_LENGTH, data, empty_ok, hoops,
+    json_ok, utils,
+};
 
-pub fn authed_router() -> Router {
+pub fn public_router() -> Router {
     Router::with_path("password")
-        .hoop(hoops::limit_rate)
-        .post(change_password)
+        .push(
+            Router::with_hoop(hoops::limit_rate)
+                .hoop(hoops::auth_by_access_token_if_present)
+                .post(change_password),
+        )
+        .push(
+            Router::with_path("email/requestToken")
+                .hoop(hoops::limit_rate)
+                .post(token_via_email),
+        )
+        .push(Router::with_path("msisdn/requestToken").post(token_via_msisdn))
 }
 
 /// #POST /_matrix/client/r0/account/password
 /// Changes the password of this account.
 ///
-/// - Requires UIAA to verify user password
+/// - Requires UIAA to verify user password, or an email validated through
+///   `password/email/requestToken` when the request carries no access token
 /// - Changes the password of the sender user
 /// - The password hash is calculated using argon2 with 32 character salt, the plain password is
 /// not saved
@@ -35,60 +51,143 @@ async fn change_password(
     body: JsonBody<ChangePasswordReqBody>,
     depot: &mut Depot,
 ) -> EmptyResult {
-    let authed = depot.authed_info()?;
-
-    let mut uiaa_info = UiaaInfo {
-        flows: vec![AuthFlow {
-            stages: vec![AuthType::Password],
-        }],
-        completed: Vec::new(),
-        params: Default::default(),
-        session: None,
-        auth_error: None,
+    let body = body.into_inner();
+    let (user_id, current_device) = match depot.authed_info() {
+        Ok(authed) => {
+            let mut uiaa_info = UiaaInfo {
+                flows: vec![AuthFlow {
+                    stages: vec![AuthType::Password],
+                }],
+                completed: Vec::new(),
+                params: Default::default(),
+                session: None,
+                auth_error: None,
+            };
+            let Some(auth) = &body.auth else {
+                uiaa_info.session = Some(utils::random_string(SESSION_ID_LENGTH));
+                return Err(uiaa_info.into());
+            };
+            match crate::uiaa::try_auth(authed.user_id(), authed.device_id(), auth, &uiaa_info) {
+                Ok((true, _)) => {}
+                Ok((false, uiaa_info)) => return Err(uiaa_info.into()),
+                Err(_) => {
+                    uiaa_info.session = Some(utils::random_string(SESSION_ID_LENGTH));
+                    return Err(uiaa_info.into());
+                }
+            }
+            (
+                authed.user_id().to_owned(),
+                Some((authed.device_id().to_owned(), authed.access_token_id())),
+            )
+        }
+        Err(_) => {
+            // Password reset for users who can not log in, proven by the email
+            // validated through `password/email/requestToken`.
+            let Some(AuthData::EmailIdentity(email)) = &body.auth else {
+                let uiaa_info = UiaaInfo {
+                    flows: vec![AuthFlow {
+                        stages: vec![AuthType::EmailIdentity],
+                    }],
+                    completed: Vec::new(),
+                    params: Default::default(),
+                    session: Some(utils::random_string(SESSION_ID_LENGTH)),
+                    auth_error: None,
+                };
+                return Err(uiaa_info.into());
+            };
+            let creds = &email.thirdparty_id_creds;
+            let session = consume_validated_session(
+                &creds.sid,
+                &creds.client_secret,
+                ValidationPurpose::PasswordReset,
+            )?;
+            let user_id = data::user::get_user_by_threepid(&session.medium, &session.address)?
+                .ok_or_else(|| {
+                    MatrixError::threepid_not_found("Email is not bound to any account.")
+                })?;
+            (user_id, None)
+        }
     };
-    let Some(auth) = &body.auth else {
-        uiaa_info.session = Some(utils::random_string(SESSION_ID_LENGTH));
-        return Err(uiaa_info.into());
-    };
-    if crate::uiaa::try_auth(authed.user_id(), authed.device_id(), auth, &uiaa_info).is_err() {
-        uiaa_info.session = Some(utils::random_string(SESSION_ID_LENGTH));
-        return Err(uiaa_info.into());
-    }
 
-    crate::user::set_password(authed.user_id(), &body.new_password)?;
-    if let Some(access_token_id) = authed.access_token_id() {
-        diesel::delete(
-            user_pushers::table
-                .filter(user_pushers::user_id.eq(authed.user_id()))
-                .filter(user_pushers::access_token_id.ne(access_token_id)),
-        )
-        .execute(&mut connect()?)?;
+    crate::user::set_password(&user_id, &body.new_password)?;
+    match &current_device {
+        Some((_, Some(access_token_id))) => {
+            diesel::delete(
+                user_pushers::table
+                    .filter(user_pushers::user_id.eq(&user_id))
+                    .filter(user_pushers::access_token_id.ne(access_token_id)),
+            )
+            .execute(&mut connect()?)?;
+        }
+        None if body.logout_devices => {
+            data::user::pusher::delete_user_pushers(&user_id)?;
+        }
+        _ => {}
     }
+
     if body.logout_devices {
         // Logout all devices except the current one
-        diesel::delete(
-            user_devices::table
-                .filter(user_devices::user_id.eq(authed.user_id()))
-                .filter(user_devices::device_id.ne(authed.device_id())),
-        )
-        .execute(&mut connect()?)?;
-        diesel::delete(
-            user_access_tokens::table
-                .filter(user_access_tokens::user_id.eq(authed.user_id()))
-                .filter(user_access_tokens::device_id.ne(authed.device_id())),
-        )
-        .execute(&mut connect()?)?;
-        diesel::delete(
-            user_refresh_tokens::table
-                .filter(user_refresh_tokens::user_id.eq(authed.user_id()))
-                .filter(user_refresh_tokens::device_id.ne(authed.device_id())),
-        )
-        .execute(&mut connect()?)?;
+        let current_device_id = current_device.as_ref().map(|(device_id, _)| device_id);
+        let mut devices = diesel::delete(user_devices::table)
+            .filter(user_devices::user_id.eq(&user_id))
+            .into_boxed();
+        let mut access_tokens = diesel::delete(user_access_tokens::table)
+            .filter(user_access_tokens::user_id.eq(&user_id))
+            .into_boxed();
+        let mut refresh_tokens = diesel::delete(user_refresh_tokens::table)
+            .filter(user_refresh_tokens::user_id.eq(&user_id))
+            .into_boxed();
+        if let Some(device_id) = current_device_id {
+            devices = devices.filter(user_devices::device_id.ne(device_id));
+            access_tokens = access_tokens.filter(user_access_tokens::device_id.ne(device_id));
+            refresh_tokens = refresh_tokens.filter(user_refresh_tokens::device_id.ne(device_id));
+        }
+        let mut conn = connect()?;
+        devices.execute(&mut conn)?;
+        access_tokens.execute(&mut conn)?;
+        refresh_tokens.execute(&mut conn)?;
     }
 
-    info!("User {} changed their password.", authed.user_id());
+    info!("User {user_id} changed their password.");
     // crate::admin::send_message(RoomMessageEventContent::notice_plain(format!("User {user} changed
     // their password.")));
 
     empty_ok()
 }
+
+/// #POST /_matrix/client/v3/account/password/email/requestToken
+/// Sends a validation link to an email bound to an account, so its password can be reset.
+///
+/// - 400 signals that the email is not bound to any account on this server.
+#[endpoint]
+async fn token_via_email(
+    _aa: AuthArgs,
+    body: JsonBody<TokenViaEmailReqBody>,
+) -> JsonResult<TokenViaEmailResBody> {
+    let body = body.into_inner();
+    let email = normalize_email(&body.email);
+    if data::user::get_user_by_threepid("email", &email)?.is_none() {
+        return Err(MatrixError::threepid_not_found("Email not found.").into());
+    }
+
+    let sid = crate::user::threepid::request_email_token(
+        &body.client_secret,
+        &email,
+        body.send_attempt,
+        body.next_link.as_deref(),
+        ValidationPurpose::PasswordReset,
+    )
+    .await?;
+    json_ok(TokenViaEmailResBody {
+        sid,
+        submit_url: None,
+    })
+}
+
+/// #POST /_matrix/client/v3/account/password/msisdn/requestToken
+///
+/// - 403 signals that The homeserver does not allow the third party identifier as a contact option.
+#[endpoint]
+async fn token_via_msisdn(_aa: AuthArgs) -> EmptyResult {
+    Err(MatrixError::threepid_denied("Third party identifier is not allowed").into())
+}
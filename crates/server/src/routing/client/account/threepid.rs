@@ -6,10 +6,38 @@
 //!
 //! [spec]: https://spec.matrix.org/latest/client-server-api/#post_matrixclientv3account3pidadd
 
+use salvo::oapi::extract::*;
 use salvo::prelude::*;
+use serde::Deserialize;
 
-use crate::core::client::account::threepid::ThreepidsResBody;
-use crate::{AuthArgs, EmptyResult, JsonResult, empty_ok, json_ok};
+use crate::core::client::account::ThirdPartyIdRemovalStatus;
+use crate::core::client::account::threepid::*;
+use crate::core::client::uiaa::{AuthFlow, AuthType, UiaaInfo};
+use crate::core::identifiers::*;
+use crate::core::third_party::{Medium, ThirdPartyIdentifier};
+use crate::data::user::threepid::{
+    add_threepid, add_threepid_id_server, get_threepid_id_servers, remove_threepid,
+};
+use crate::exts::*;
+use crate::user::threepid::{
+    ValidationPurpose, ensure_trusted_identity_server, identity_server_url, normalize_email,
+    unbind_from_id_server,
+};
+use crate::{
+    AppResult, AuthArgs, EmptyResult, JsonResult, MatrixError, SESSION_ID_LENGTH, data, empty_ok,
+    hoops, json_ok, utils,
+};
+
+pub fn public_router() -> Router {
+    Router::with_path("3pid")
+        .push(
+            Router::with_path("email/requestToken")
+                .hoop(hoops::limit_rate)
+                .post(token_via_email),
+        )
+        .push(Router::with_path("email/submitToken").get(submit_token))
+        .push(Router::with_path("msisdn/requestToken").post(token_via_msisdn))
+}
 
 pub fn authed_router() -> Router {
     Router::with_path("3pid")
@@ -20,39 +48,268 @@ pub fn authed_router() -> Router {
         // 1.1 => "/_matrix/client/v3/account/3pid/bind",
         .push(Router::with_path("add").post(add))
         .push(Router::with_path("bind").post(bind))
+        .push(Router::with_path("unbind").post(unbind))
         .push(Router::with_path("delete").post(delete))
 }
 
+/// #POST /_matrix/client/v3/account/3pid/email/requestToken
+/// "This API should be used to request validation tokens when adding an email address to an
+/// account"
+///
+/// - 400 signals that the email is already bound to an account.
+#[endpoint]
+async fn token_via_email(
+    _aa: AuthArgs,
+    body: JsonBody<TokenViaEmailReqBody>,
+) -> JsonResult<TokenViaEmailResBody> {
+    let body = body.into_inner();
+    let email = normalize_email(&body.email);
+    if data::user::get_user_by_threepid("email", &email)?.is_some() {
+        return Err(MatrixError::threepid_in_use("Email is already in use.").into());
+    }
+
+    let sid = crate::user::threepid::request_email_token(
+        &body.client_secret,
+        &email,
+        body.send_attempt,
+        body.next_link.as_deref(),
+        ValidationPurpose::AddThreepid,
+    )
+    .await?;
+    json_ok(TokenViaEmailResBody {
+        sid,
+        submit_url: None,
+    })
+}
+
+/// #POST /_matrix/client/v3/account/3pid/msisdn/requestToken
+/// "This API should be used to request validation tokens when adding an phone number to an account"
+///
+/// - 403 signals that The homeserver does not allow the third party identifier as a contact option.
+#[endpoint]
+async fn token_via_msisdn(_aa: AuthArgs) -> EmptyResult {
+    Err(MatrixError::threepid_denied("Third party identifier is not allowed").into())
+}
+
+#[derive(ToParameters, Deserialize, Debug)]
+#[salvo(parameters(default_parameter_in = Query))]
+struct SubmitTokenReqArgs {
+    sid: OwnedSessionId,
+    client_secret: OwnedClientSecret,
+    token: String,
+}
+
+/// #GET /_matrix/client/v3/account/3pid/email/submitToken
+/// Target of the link sent in validation emails.
+///
+/// Redirects to the `next_link` given when the token was requested, if any.
+#[endpoint]
+async fn submit_token(args: SubmitTokenReqArgs, res: &mut Response) -> AppResult<()> {
+    let next_link =
+        crate::user::threepid::submit_token(&args.sid, &args.client_secret, &args.token)?;
+    if let Some(next_link) = next_link {
+        res.render(Redirect::found(next_link));
+    } else {
+        res.render(Text::Html(
+            "<html><body><p>Your email has been validated, you can now return to your client.</p></body></html>",
+        ));
+    }
+    Ok(())
+}
+
 /// #GET _matrix/client/v3/account/3pid
 /// Get a list of third party identifiers associated with this account.
-///
-/// - Currently always returns empty list
 #[endpoint]
-async fn get(_aa: AuthArgs) -> JsonResult<ThreepidsResBody> {
-    // TODO: later
-    json_ok(ThreepidsResBody::new(Vec::new()))
+async fn get(_aa: AuthArgs, depot: &mut Depot) -> JsonResult<ThreepidsResBody> {
+    let authed = depot.authed_info()?;
+    let threepids = data::user::get_threepids(authed.user_id())?
+        .into_iter()
+        .map(|threepid| ThirdPartyIdentifier {
+            address: threepid.address,
+            medium: Medium::from(threepid.medium),
+            validated_at: threepid.validated_at,
+            added_at: threepid.added_at,
+        })
+        .collect();
+    json_ok(ThreepidsResBody::new(threepids))
 }
 
+/// #POST /_matrix/client/v3/account/3pid/add
+/// Adds a third party identifier validated through `requestToken` to the account.
+///
+/// - Requires UIAA to verify user password
 #[endpoint]
-async fn add(_aa: AuthArgs) -> EmptyResult {
-    // TODO: later
+async fn add(_aa: AuthArgs, body: JsonBody<AddThreepidReqBody>, depot: &mut Depot) -> EmptyResult {
+    let authed = depot.authed_info()?;
+    let body = body.into_inner();
+
+    let mut uiaa_info = UiaaInfo {
+        flows: vec![AuthFlow {
+            stages: vec![AuthType::Password],
+        }],
+        completed: Vec::new(),
+        params: Default::default(),
+        session: None,
+        auth_error: None,
+    };
+    let Some(auth) = &body.auth else {
+        uiaa_info.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        return Err(uiaa_info.into());
+    };
+    match crate::uiaa::try_auth(authed.user_id(), authed.device_id(), auth, &uiaa_info) {
+        Ok((true, _)) => {}
+        Ok((false, uiaa_info)) => return Err(uiaa_info.into()),
+        Err(_) => {
+            uiaa_info.session = Some(utils::random_string(SESSION_ID_LENGTH));
+            return Err(uiaa_info.into());
+        }
+    }
+
+    let session = crate::user::threepid::consume_validated_session(
+        &body.sid,
+        &body.client_secret,
+        ValidationPurpose::AddThreepid,
+    )?;
+    match data::user::get_user_by_threepid(&session.medium, &session.address)? {
+        Some(user_id) if user_id == authed.user_id() => return empty_ok(),
+        Some(_) => {
+            return Err(
+                MatrixError::threepid_in_use("Third party identifier is already in use.").into(),
+            );
+        }
+        None => {}
+    }
+    add_threepid(
+        authed.user_id(),
+        &session.medium,
+        &session.address,
+        session.validated_at.unwrap_or_default(),
+    )?;
+    info!(
+        "User {} added {} {}.",
+        authed.user_id(),
+        session.medium,
+        session.address
+    );
     empty_ok()
 }
 
+#[derive(Deserialize, Debug)]
+struct BoundThreepid {
+    medium: String,
+    address: String,
+}
+
+/// #POST /_matrix/client/v3/account/3pid/bind
+/// Binds a third party identifier validated by an identity server to the user on that server.
 #[endpoint]
-async fn bind(_aa: AuthArgs) -> EmptyResult {
-    // TODO: later
+async fn bind(
+    _aa: AuthArgs,
+    body: JsonBody<BindThreepidReqBody>,
+    depot: &mut Depot,
+) -> EmptyResult {
+    let authed = depot.authed_info()?;
+    let body = body.into_inner();
+    let id_server = &body.identity_server_info.id_server;
+    ensure_trusted_identity_server(id_server)?;
+
+    let response = crate::sending::default_client()
+        .post(format!(
+            "{}/_matrix/identity/v2/3pid/bind",
+            identity_server_url(id_server)
+        ))
+        .bearer_auth(&body.identity_server_info.id_access_token)
+        .json(&serde_json::json!({
+            "sid": body.sid,
+            "client_secret": body.client_secret,
+            "mxid": authed.user_id(),
+        }))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        warn!(
+            "identity server {id_server} refused to bind {}: {}",
+            authed.user_id(),
+            response.status()
+        );
+        return Err(
+            MatrixError::threepid_auth_failed("Identity server refused the binding.").into(),
+        );
+    }
+    let bound = response.json::<BoundThreepid>().await?;
+    add_threepid_id_server(authed.user_id(), &bound.medium, &bound.address, id_server)?;
     empty_ok()
 }
 
+/// #POST /_matrix/client/v3/account/3pid/unbind
+/// Removes a binding of a third party identifier from identity servers without removing it
+/// from the account.
 #[endpoint]
-async fn unbind(_aa: AuthArgs) -> EmptyResult {
-    // TODO: later
-    empty_ok()
+async fn unbind(
+    _aa: AuthArgs,
+    body: JsonBody<UnbindThreepidReqBody>,
+    depot: &mut Depot,
+) -> JsonResult<UnbindThreepidResBody> {
+    let authed = depot.authed_info()?;
+    let body = body.into_inner();
+    let id_server_unbind_result = unbind_id_servers(
+        authed.user_id(),
+        &body.medium,
+        &body.address,
+        body.id_server,
+    )
+    .await?;
+    json_ok(UnbindThreepidResBody {
+        id_server_unbind_result,
+    })
 }
 
+/// #POST /_matrix/client/v3/account/3pid/delete
+/// Removes a third party identifier from the account and unbinds it from identity servers.
 #[endpoint]
-async fn delete(_aa: AuthArgs) -> EmptyResult {
-    // TODO: later
-    empty_ok()
+async fn delete(
+    _aa: AuthArgs,
+    body: JsonBody<DeleteThreepidReqBody>,
+    depot: &mut Depot,
+) -> JsonResult<DeleteThreepidResBody> {
+    let authed = depot.authed_info()?;
+    let body = body.into_inner();
+    let address = if matches!(body.medium, Medium::Email) {
+        normalize_email(&body.address)
+    } else {
+        body.address
+    };
+    if remove_threepid(authed.user_id(), body.medium.as_str(), &address)? == 0 {
+        return Err(MatrixError::threepid_not_found("Third party identifier not found.").into());
+    }
+    let id_server_unbind_result =
+        unbind_id_servers(authed.user_id(), &body.medium, &address, body.id_server).await?;
+    json_ok(DeleteThreepidResBody {
+        id_server_unbind_result,
+    })
+}
+
+async fn unbind_id_servers(
+    user_id: &UserId,
+    medium: &Medium,
+    address: &str,
+    id_server: Option<String>,
+) -> AppResult<ThirdPartyIdRemovalStatus> {
+    let id_servers = match id_server {
+        Some(id_server) => vec![id_server],
+        None => get_threepid_id_servers(user_id, medium.as_str(), address)?,
+    };
+    if id_servers.is_empty() {
+        return Ok(ThirdPartyIdRemovalStatus::NoSupport);
+    }
+
+    let mut success = true;
+    for id_server in id_servers {
+        success &= unbind_from_id_server(user_id, medium, address, &id_server).await?;
+    }
+    Ok(if success {
+        ThirdPartyIdRemovalStatus::Success
+    } else {
+        ThirdPartyIdRemovalStatus::NoSupport
+    })
 }
@@ -28,7 +28,7 @@ async fn uiaa_fallback(
 ) -> Result<(), crate::AppError> {
     let auth_type = auth_type.into_inner();
     let session_id = session.into_inner();
-    let server_name = config::get().server_name.as_str();
+    let server_name = config::server_name().as_str();
     let complete = complete.into_inner().unwrap_or(false);
     let accepted = accepted.into_inner().unwrap_or(false);
 
@@ -61,8 +61,9 @@ async fn query_keys(
 /// #POST /_matrix/client/r0/keys/upload
 /// Publish end-to-end encryption keys for the sender device.
 ///
-/// - Adds one time keys
-/// - If there are no device keys yet: Adds device keys (TODO: merge with existing keys?)
+/// - Adds one time keys, reusing the id of another key is an error
+/// - Replaces the device keys if they changed, after checking their signature
+/// - Replaces the fallback key of each algorithm
 #[endpoint]
 async fn upload_keys(
     _aa: AuthArgs,
@@ -71,25 +72,26 @@ async fn upload_keys(
 ) -> JsonResult<UploadKeysResBody> {
     let authed = depot.authed_info()?;
 
+    if let Some(device_keys) = &body.device_keys {
+        crate::user::add_device_keys(authed.user_id(), authed.device_id(), device_keys)?;
+    }
+
     for (key_id, one_time_key) in &body.one_time_keys {
         crate::user::add_one_time_key(authed.user_id(), authed.device_id(), key_id, one_time_key)?;
     }
 
-    if let Some(device_keys) = &body.device_keys {
-        crate::user::add_device_keys(authed.user_id(), authed.device_id(), device_keys)?;
+    for (key_id, fallback_key) in &body.fallback_keys {
+        crate::user::add_fallback_key(authed.user_id(), authed.device_id(), key_id, fallback_key)?;
     }
 
-    // TODO: fallback keys. e2e_keys.py 848
-
     json_ok(UploadKeysResBody {
         one_time_key_counts: data::user::count_one_time_keys(authed.user_id(), authed.device_id())?,
     })
 }
 
 /// #POST /_matrix/client/r0/keys/changes
-/// Gets a list of users who have updated their device identity keys since the previous sync token.
-///
-/// - TODO: left users
+/// Gets a list of users who have updated their device identity keys since the previous sync token,
+/// and of those who no longer share a room with the sender.
 #[endpoint]
 async fn get_key_changes(
     _aa: AuthArgs,
@@ -117,6 +119,10 @@ async fn get_key_changes(
     }
     json_ok(KeyChangesResBody {
         changed: device_list_updates.into_iter().collect(),
-        left: Vec::new(), // TODO
+        left: data::user::device_list_left_users(
+            sender_id,
+            from_tk.event_sn(),
+            Some(to_tk.event_sn()),
+        )?,
     })
 }
@@ -72,14 +72,21 @@ pub(super) async fn upload(_aa: AuthArgs, req: &mut Request, depot: &mut Depot)
         crate::uiaa::try_auth(sender_id, authed.device_id(), auth, &uiaa_info)?;
     }
 
-    if let Some(master_key) = &body.master_key {
-        crate::user::add_cross_signing_keys(
-            sender_id,
-            master_key,
-            &body.self_signing_key,
-            &body.user_signing_key,
-            true, // notify so that other users see the new keys
-        )?;
-    }
+    // Self and user signing keys may be replaced alone, signed by the current master key.
+    let master_key = match body.master_key {
+        Some(master_key) => master_key,
+        None if body.self_signing_key.is_none() && body.user_signing_key.is_none() => {
+            return empty_ok();
+        }
+        None => crate::user::key::get_master_key(sender_id)?
+            .ok_or_else(|| MatrixError::invalid_param("no master key to sign the keys with"))?,
+    };
+    crate::user::add_cross_signing_keys(
+        sender_id,
+        &master_key,
+        &body.self_signing_key,
+        &body.user_signing_key,
+        true, // notify so that other users see the new keys
+    )?;
     empty_ok()
 }
@@ -1,18 +1,13 @@
-use std::fs;
 use std::io::Cursor;
-use std::path::Path;
 use std::str::FromStr;
 
 use diesel::prelude::*;
 use image::imageops::FilterType;
 use mime::Mime;
 use reqwest::Url;
-use salvo::fs::NamedFile;
 use salvo::http::header::CONTENT_TYPE;
 use salvo::http::{HeaderValue, ResBody};
 use salvo::prelude::*;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
 
 use crate::core::UnixMillis;
@@ -38,8 +33,8 @@ pub fn self_auth_router() -> Router {
                 .push(Router::with_path("{filename}").get(get_content_with_filename)),
         )
         .push(
-            Router::with_hoop(hoops::limit_rate)
-                .hoop(hoops::auth_by_access_token)
+            Router::with_hoop(hoops::auth_by_access_token)
+                .hoop(hoops::limit_rate)
                 .push(Router::with_path("config").get(get_config))
                 .push(Router::with_path("preview_url").get(preview_url))
                 .push(Router::with_path("thumbnail/{server_name}/{media_id}").get(get_thumbnail)),
@@ -69,16 +64,8 @@ pub async fn get_content(
                     .unwrap_or(mime::APPLICATION_OCTET_STREAM)
             });
 
-        let path = get_media_path(&args.server_name, &args.media_id);
-        if Path::new(&path).exists() {
-            if let Some(file_name) = &metadata.file_name {
-                NamedFile::builder(path).attached_name(file_name)
-            } else {
-                NamedFile::builder(path)
-            }
-            .content_type(content_type)
-            .send(req.headers(), res)
-            .await;
+        let key = media_key(&args.server_name, &args.media_id);
+        if send_stored(&key, content_type, metadata.file_name.as_deref(), req, res).await? {
             Ok(())
         } else {
             Err(MatrixError::not_yet_uploaded("Media has not been uploaded yet").into())
@@ -119,25 +106,13 @@ pub async fn get_content_with_filename(
         res.headers_mut().insert(CONTENT_TYPE, content_type);
     }
 
-    let path = get_media_path(&args.server_name, &args.media_id);
-    if Path::new(&path).exists() {
-        let file = NamedFile::builder(path)
-            .content_type(
-                metadata
-                    .content_type
-                    .as_deref()
-                    .and_then(|c| Mime::from_str(c).ok())
-                    .unwrap_or(mime::APPLICATION_OCTET_STREAM),
-            )
-            .attached_name(args.filename)
-            .build()
-            .await?;
-        // if let Some(Ok(content_disposition)) =
-        // metadata.content_disposition.as_deref().map(HeaderValue::from_str) {
-        //     file.set_content_disposition(content_disposition);
-        // }
-        file.send(req.headers(), res).await;
-
+    let key = media_key(&args.server_name, &args.media_id);
+    let content_type = metadata
+        .content_type
+        .as_deref()
+        .and_then(|c| Mime::from_str(c).ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    if send_stored(&key, content_type, Some(&args.filename), req, res).await? {
         Ok(())
     } else if *args.server_name != config::get().server_name && args.allow_remote {
         let mxc = format!("mxc://{}/{}", args.server_name, args.media_id);
@@ -186,23 +161,9 @@ pub async fn create_content(
     };
 
     let conf = crate::config::get();
-    let dest_path = get_media_path(&conf.server_name, &media_id);
-
-    // let dest_path = Path::new(&dest_path);
-    // if dest_path.exists() {
-    //     let metadata = fs::metadata(dest_path)?;
-    //     if metadata.len() != payload.len() as u64 {
-    //         if let Err(e) = fs::remove_file(dest_path) {
-    //             tracing::error!(error = ?e, "remove media file failed");
-    //         }
-    //     }
-    // }
-    if !dest_path.exists() {
-        let parent_dir = utils::fs::get_parent_dir(&dest_path);
-        fs::create_dir_all(&parent_dir)?;
-
-        let mut file = File::create(dest_path).await?;
-        file.write_all(payload).await?;
+    let key = media_key(&conf.server_name, &media_id);
+    if !storage().exists(&key).await? {
+        storage().put(&key, payload).await?;
 
         let metadata = NewDbMetadata {
             media_id: media_id.clone(),
@@ -252,22 +213,9 @@ pub async fn upload_content(
 
     let conf = crate::config::get();
 
-    let dest_path = get_media_path(&conf.server_name, &args.media_id);
-    let dest_path = Path::new(&dest_path);
-    // if dest_path.exists() {
-    //     let metadata = fs::metadata(dest_path)?;
-    //     if metadata.len() != payload.len() as u64 {
-    //         if let Err(e) = fs::remove_file(dest_path) {
-    //             tracing::error!(error = ?e, "remove media file failed");
-    //         }
-    //     }
-    // }
-    if !dest_path.exists() {
-        let parent_dir = utils::fs::get_parent_dir(dest_path);
-        fs::create_dir_all(&parent_dir)?;
-
-        let mut file = File::create(dest_path).await?;
-        file.write_all(payload).await?;
+    let key = media_key(&conf.server_name, &args.media_id);
+    if !storage().exists(&key).await? {
+        storage().put(&key, payload).await?;
 
         let metadata = NewDbMetadata {
             media_id: args.media_id.clone(),
@@ -368,13 +316,11 @@ pub async fn get_thumbnail(
         *res.headers_mut() = response.headers().clone();
         let bytes = response.bytes().await?;
 
-        let thumb_path = get_media_path(
+        let thumb_key = media_key(
             &args.server_name,
             &format!("{}.{}x{}", args.media_id, args.width, args.height),
         );
-        std::fs::create_dir_all(utils::fs::get_parent_dir(&thumb_path))?;
-        let mut f = File::create(&thumb_path).await?;
-        f.write_all(&bytes).await?;
+        storage().put(&thumb_key, &bytes).await?;
 
         res.body = ResBody::Once(bytes);
         return Ok(());
@@ -392,25 +338,17 @@ pub async fn get_thumbnail(
             content_type,
             ..
         })) => {
-            let thumbnail_path = get_thumbnail_path(&args.server_name, &args.media_id, id);
+            let key = thumbnail_key(&args.server_name, &args.media_id, id);
 
             res.add_header("Cross-Origin-Resource-Policy", "cross-origin", true)?;
-            let _file = NamedFile::builder(&thumbnail_path)
-                .content_type(if let Some(content_type) = &content_type {
-                    Mime::from_str(content_type)
-                        .ok()
-                        .unwrap_or(mime::APPLICATION_OCTET_STREAM)
-                } else {
-                    mime::APPLICATION_OCTET_STREAM
-                })
-                .build()
-                .await?;
-            // if let Some(Ok(content_disposition)) =
-            // content_disposition.as_deref().map(HeaderValue::from_str) {
-            //     file.set_content_disposition(content_disposition);
-            // }
-
-            return Ok(());
+            let content_type = content_type
+                .as_deref()
+                .and_then(|c| Mime::from_str(c).ok())
+                .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+            if send_stored(&key, content_type, None, req, res).await? {
+                return Ok(());
+            }
+            return Err(MatrixError::not_found("Media not found.").into());
         }
         Err(e) => {
             tracing::error!(error = ?e, "get_thumbnail error");
@@ -430,25 +368,17 @@ pub async fn get_thumbnail(
         width,
         height,
     )? {
-        let thumbnail_path = get_thumbnail_path(&args.server_name, &args.media_id, id);
         // Using saved thumbnail
-        let file = NamedFile::builder(&thumbnail_path)
-            .content_type(if let Some(content_type) = &content_type {
-                Mime::from_str(content_type)
-                    .ok()
-                    .unwrap_or(mime::APPLICATION_OCTET_STREAM)
-            } else {
-                mime::APPLICATION_OCTET_STREAM
-            })
-            .build()
-            .await?;
-        // if let Some(Ok(content_disposition)) =
-        // content_disposition.as_deref().map(HeaderValue::from_str) {
-        //     file.set_content_disposition(content_disposition);
-        // }
-        file.send(req.headers(), res).await;
-
-        Ok(())
+        let content_type = content_type
+            .as_deref()
+            .and_then(|c| Mime::from_str(c).ok())
+            .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+        let key = thumbnail_key(&args.server_name, &args.media_id, id);
+        if send_stored(&key, content_type, None, req, res).await? {
+            Ok(())
+        } else {
+            Err(MatrixError::not_found("Media not found.").into())
+        }
     } else if let Ok(Some(DbMetadata {
         disposition_type: _,
         content_type,
@@ -456,25 +386,21 @@ pub async fn get_thumbnail(
     })) = crate::data::media::get_metadata(&args.server_name, &args.media_id)
     {
         // Generate a thumbnail
-        let image_path = get_media_path(&args.server_name, &args.media_id);
-        if let Ok(image) = image::open(&image_path) {
+        let content_type = content_type
+            .as_deref()
+            .and_then(|c| Mime::from_str(c).ok())
+            .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+        let Some(image_data) = storage()
+            .get(&media_key(&args.server_name, &args.media_id))
+            .await?
+        else {
+            return Err(MatrixError::not_found("file not found").into());
+        };
+        if let Ok(image) = image::load_from_memory(&image_data) {
             let original_width = image.width();
             let original_height = image.height();
             if width > original_width || height > original_height {
-                let file = NamedFile::builder(&image_path)
-                    .content_type(
-                        content_type
-                            .as_deref()
-                            .and_then(|c| Mime::from_str(c).ok())
-                            .unwrap_or(mime::APPLICATION_OCTET_STREAM),
-                    )
-                    .build()
-                    .await?;
-                // if let Some(Ok(content_disposition)) =
-                // content_disposition.as_deref().map(HeaderValue::from_str) {
-                //     file.set_content_disposition(content_disposition);
-                // }
-                file.send(req.headers(), res).await;
+                render_bytes(image_data, &content_type, None, res);
                 return Ok(());
             }
 
@@ -537,7 +463,7 @@ pub async fn get_thumbnail(
                 .returning(media_thumbnails::id)
                 .get_result::<i64>(&mut connect()?)
                 .optional()?;
-            let thumbnail_id = if let Some(thumbnail_id) = thumbnail_id {
+            if let Some(thumbnail_id) = thumbnail_id {
                 crate::media::save_thumbnail_file(
                     &args.server_name,
                     &args.media_id,
@@ -545,56 +471,13 @@ pub async fn get_thumbnail(
                     &thumbnail_bytes,
                 )
                 .await?;
-                thumbnail_id
-            } else {
-                media_thumbnails::table
-                    .filter(media_thumbnails::media_id.eq(&args.media_id))
-                    .filter(media_thumbnails::width.eq(args.width as i32))
-                    .filter(media_thumbnails::height.eq(args.height as i32))
-                    .filter(
-                        media_thumbnails::resize_method.eq(&args
-                            .method
-                            .clone()
-                            .unwrap_or_default()
-                            .to_string()),
-                    )
-                    .select(media_thumbnails::id)
-                    .first::<i64>(&mut connect()?)?
-            };
+            }
 
-            let thumbnail_path =
-                get_thumbnail_path(&args.server_name, &args.media_id, thumbnail_id);
-            let file = NamedFile::builder(&thumbnail_path)
-                .content_type(
-                    content_type
-                        .as_deref()
-                        .and_then(|c| Mime::from_str(c).ok())
-                        .unwrap_or(mime::APPLICATION_OCTET_STREAM),
-                )
-                .build()
-                .await?;
-            // if let Some(Ok(content_disposition)) =
-            // content_disposition.as_deref().map(HeaderValue::from_str) {
-            //     file.set_content_disposition(content_disposition);
-            // }
-            file.send(req.headers(), res).await;
+            render_bytes(thumbnail_bytes, &mime::IMAGE_PNG, None, res);
             Ok(())
         } else {
             // Couldn't parse file to generate thumbnail, send original
-            let file = NamedFile::builder(&image_path)
-                .content_type(
-                    content_type
-                        .as_deref()
-                        .and_then(|c| Mime::from_str(c).ok())
-                        .unwrap_or(mime::APPLICATION_OCTET_STREAM),
-                )
-                .build()
-                .await?;
-            // if let Some(Ok(content_disposition)) =
-            // content_disposition.as_deref().map(HeaderValue::from_str) {
-            //     file.set_content_disposition(content_disposition);
-            // }
-            file.send(req.headers(), res).await;
+            render_bytes(image_data, &content_type, None, res);
             Ok(())
         }
     } else {
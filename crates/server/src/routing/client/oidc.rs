@@ -371,7 +371,7 @@ fn generate_pkce_challenge() -> (String, String) {
 }
 
 /// Get provider configuration by name
-fn get_provider_config(provider_name: &str) -> Result<&'static OidcProviderConfig, MatrixError> {
+fn get_provider_config(provider_name: &str) -> Result<OidcProviderConfig, MatrixError> {
     let config = config::get();
     let oidc_config = config
         .enabled_oidc()
@@ -380,6 +380,7 @@ fn get_provider_config(provider_name: &str) -> Result<&'static OidcProviderConfi
     oidc_config
         .providers
         .get(provider_name)
+        .cloned()
         .ok_or_else(|| MatrixError::not_found("Unknown OIDC provider"))
 }
 
@@ -151,6 +151,7 @@ async fn set_avatar_url(
     if !is_allowed {
         return Err(MatrixError::forbidden("forbidden", None).into());
     }
+    crate::user::ensure_not_suspended(authed.user())?;
 
     let SetAvatarUrlReqBody {
         avatar_url,
@@ -179,6 +180,11 @@ async fn set_avatar_url(
         return Err(StatusError::not_found().brief("Profile not found.").into());
     }
 
+    // Others don't see profile changes of shadow-banned users in rooms
+    if authed.user().shadow_banned {
+        return empty_ok();
+    }
+
     // Send a new membership event and presence update into all joined rooms
     let all_joined_rooms: Vec<_> = data::user::joined_rooms(&user_id)?
         .into_iter()
@@ -290,12 +296,18 @@ async fn set_display_name(
     if !is_allowed {
         return Err(MatrixError::forbidden("forbidden", None).into());
     }
+    crate::user::ensure_not_suspended(authed.user())?;
     let SetDisplayNameReqBody { display_name } = body.into_inner();
 
     if let Some(display_name) = display_name.as_deref() {
         data::user::set_display_name(&user_id, display_name)?;
     }
 
+    // Others don't see profile changes of shadow-banned users in rooms
+    if authed.user().shadow_banned {
+        return empty_ok();
+    }
+
     // Send a new membership event and presence update into all joined rooms
     let all_joined_rooms: Vec<_> = data::user::joined_rooms(&user_id)?
         .into_iter()
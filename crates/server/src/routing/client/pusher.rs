@@ -1,10 +1,15 @@
 use salvo::oapi::extract::JsonBody;
 use salvo::prelude::*;
+use serde::Deserialize;
 
 use crate::core::client::push::{PushersResBody, SetPusherReqBody};
+use crate::core::identifiers::*;
 use crate::core::push::Pusher;
 use crate::data::DataError;
-use crate::{DepotExt, EmptyResult, JsonResult, data, empty_ok, hoops, json_ok};
+use crate::user::pusher::mailer::verify_unsubscribe_token;
+use crate::{
+    AppResult, DepotExt, EmptyResult, JsonResult, MatrixError, data, empty_ok, hoops, json_ok,
+};
 
 pub fn authed_router() -> Router {
     Router::with_path("pushers")
@@ -36,3 +41,50 @@ async fn set_pusher(body: JsonBody<SetPusherReqBody>, depot: &mut Depot) -> Empt
     crate::user::pusher::set_pusher(authed, body.into_inner().0)?;
     empty_ok()
 }
+
+#[derive(ToParameters, Deserialize, Debug)]
+#[salvo(parameters(default_parameter_in = Query))]
+struct UnsubscribeReqArgs {
+    user_id: OwnedUserId,
+    app_id: String,
+    pushkey: String,
+    token: String,
+}
+
+/// #GET /_matrix/client/unstable/pushers/remove
+/// Target of the unsubscribe link in notification emails, asks to confirm the
+/// removal of the email pusher.
+///
+/// Nothing is removed here, as mail scanners follow the links of the emails.
+#[endpoint]
+pub(super) async fn unsubscribe_page(
+    args: UnsubscribeReqArgs,
+    res: &mut Response,
+) -> AppResult<()> {
+    if !verify_unsubscribe_token(&args.user_id, &args.app_id, &args.pushkey, &args.token) {
+        return Err(MatrixError::forbidden("Invalid unsubscribe token.", None).into());
+    }
+    // Without an action, the form is posted to this url, query included.
+    res.render(Text::Html(
+        "<html><body><form method=\"post\">\
+         <p>Stop receiving these notification emails?</p>\
+         <button type=\"submit\">Unsubscribe</button>\
+         </form></body></html>",
+    ));
+    Ok(())
+}
+
+/// #POST /_matrix/client/unstable/pushers/remove
+/// Removes the email pusher, from the confirmation page or the one click
+/// unsubscribe of mail clients.
+#[endpoint]
+pub(super) async fn unsubscribe(args: UnsubscribeReqArgs, res: &mut Response) -> AppResult<()> {
+    if !verify_unsubscribe_token(&args.user_id, &args.app_id, &args.pushkey, &args.token) {
+        return Err(MatrixError::forbidden("Invalid unsubscribe token.", None).into());
+    }
+    data::user::pusher::delete_pusher(&args.user_id, &args.app_id, &args.pushkey)?;
+    res.render(Text::Html(
+        "<html><body><p>You have been unsubscribed from these notification emails.</p></body></html>",
+    ));
+    Ok(())
+}
@@ -6,35 +6,34 @@ use salvo::prelude::*;
 use crate::core::UnixMillis;
 use crate::core::client::account::{LoginType, RegistrationKind};
 use crate::core::client::register::*;
-use crate::core::client::uiaa::{AuthFlow, AuthType, UiaaInfo};
+use crate::core::client::uiaa::{AuthData, AuthFlow, AuthType, UiaaInfo};
 use crate::core::events::GlobalAccountDataEventType;
 use crate::core::events::push_rules::PushRulesEventContent;
 use crate::core::identifiers::*;
 use crate::core::push::Ruleset;
 use crate::core::serde::JsonValue;
 use crate::data::schema::*;
+use crate::data::user::threepid::add_threepid;
 use crate::data::user::{NewDbPresence, NewDbProfile};
 use crate::data::{connect, diesel_exists};
 use crate::exts::*;
+use crate::user::threepid::{ValidationPurpose, consume_validated_session, normalize_email};
 use crate::{
     AppError, AuthArgs, DEVICE_ID_LENGTH, EmptyResult, JsonResult, MatrixError,
-    RANDOM_USER_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH, config, data, empty_ok, hoops,
+    RANDOM_USER_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH, config, data, empty_ok, hoops, json_ok,
     membership, room, utils,
 };
 
 pub fn public_router() -> Router {
-    Router::with_path("register").push(
-        Router::with_hoop(hoops::limit_rate)
-            .push(Router::with_path("available").get(available))
-            .post(register)
-            .push(Router::with_path("m.login.registration_token/validity").get(validate_token)),
-    )
-}
-
-pub fn authed_router() -> Router {
     Router::with_path("register")
-        .push(Router::with_path("email/requestToken").post(token_via_email))
-        .push(Router::with_path("msisdn/requestToken").post(token_via_msisdn))
+        .push(Router::with_hoop(hoops::limit_rate_register).post(register))
+        .push(
+            Router::with_hoop(hoops::limit_rate)
+                .push(Router::with_path("available").get(available))
+                .push(Router::with_path("m.login.registration_token/validity").get(validate_token))
+                .push(Router::with_path("email/requestToken").post(token_via_email))
+                .push(Router::with_path("msisdn/requestToken").post(token_via_msisdn)),
+        )
 }
 
 /// `POST /_matrix/client/*/register`
@@ -113,14 +112,22 @@ async fn register(
     }
 
     // UIAA
+    let mut flows = vec![AuthFlow {
+        stages: if conf.registration_token.is_some() {
+            vec![AuthType::RegistrationToken]
+        } else {
+            vec![AuthType::Dummy]
+        },
+    }];
+    // Registering with an email validated through `register/email/requestToken`
+    // binds it to the new account.
+    if conf.registration_token.is_none() && conf.enabled_email().is_some() {
+        flows.push(AuthFlow {
+            stages: vec![AuthType::EmailIdentity],
+        });
+    }
     let mut uiaa_info = UiaaInfo {
-        flows: vec![AuthFlow {
-            stages: if conf.registration_token.is_some() {
-                vec![AuthType::RegistrationToken]
-            } else {
-                vec![AuthType::Dummy]
-            },
-        }],
+        flows,
         completed: Vec::new(),
         params: Default::default(),
         session: None,
@@ -158,9 +165,42 @@ async fn register(
         body.password.as_deref()
     };
 
+    // Use up the validated email before the account exists, so a session can
+    // only register one account.
+    let threepid_session = match &body.auth {
+        Some(AuthData::EmailIdentity(email))
+            if !is_guest && body.login_type != Some(LoginType::Appservice) =>
+        {
+            let creds = &email.thirdparty_id_creds;
+            let session = consume_validated_session(
+                &creds.sid,
+                &creds.client_secret,
+                ValidationPurpose::Registration,
+            )?;
+            if data::user::get_user_by_threepid(&session.medium, &session.address)?.is_some() {
+                return Err(MatrixError::threepid_in_use("Email is already in use.").into());
+            }
+            Some(session)
+        }
+        _ => None,
+    };
+
     // Create user
     let db_user = crate::user::create_user(user_id.clone(), password)?;
 
+    if let Some(session) = threepid_session {
+        add_threepid(
+            &user_id,
+            &session.medium,
+            &session.address,
+            session.validated_at.unwrap_or_default(),
+        )?;
+        info!(
+            "User {user_id} registered with {} {}.",
+            session.medium, session.address
+        );
+    }
+
     // Default to pretty display_name
     let display_name = user_id.localpart().to_owned();
     // // If enabled append lightning bolt to display name (default true)
@@ -368,9 +408,28 @@ async fn validate_token(_aa: AuthArgs, depot: &mut Depot) -> EmptyResult {
 /// [spec]: https://spec.matrix.org/latest/client-server-api/#post_matrixclientv3registeremailrequesttoken
 
 #[endpoint]
-async fn token_via_email(_aa: AuthArgs, depot: &mut Depot) -> EmptyResult {
-    let _authed = depot.authed_info()?;
-    empty_ok()
+async fn token_via_email(
+    _aa: AuthArgs,
+    body: JsonBody<TokenVisEmailReqBody>,
+) -> JsonResult<TokenVisEmailResBody> {
+    let body = body.into_inner();
+    let email = normalize_email(&body.email);
+    if data::user::get_user_by_threepid("email", &email)?.is_some() {
+        return Err(MatrixError::threepid_in_use("Email is already in use.").into());
+    }
+
+    let sid = crate::user::threepid::request_email_token(
+        &body.client_secret,
+        &email,
+        body.send_attempt,
+        body.next_link.as_deref(),
+        ValidationPurpose::Registration,
+    )
+    .await?;
+    json_ok(TokenVisEmailResBody {
+        sid,
+        submit_url: None,
+    })
 }
 
 /// `POST /_matrix/client/*/register/msisdn/requestToken`
@@ -389,7 +448,6 @@ async fn token_via_email(_aa: AuthArgs, depot: &mut Depot) -> EmptyResult {
 //     }
 // };
 #[endpoint]
-async fn token_via_msisdn(_aa: AuthArgs, depot: &mut Depot) -> EmptyResult {
-    let _authed = depot.authed_info()?;
-    empty_ok()
+async fn token_via_msisdn(_aa: AuthArgs) -> EmptyResult {
+    Err(MatrixError::threepid_denied("Third party identifier is not allowed").into())
 }
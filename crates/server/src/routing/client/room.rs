@@ -70,7 +70,11 @@ pub fn authed_router() -> Router {
                 Router::with_path("{room_id}")
                     .push(Router::with_path("forget").post(membership::forget_room))
                     .push(Router::with_path("leave").post(membership::leave_room))
-                    .push(Router::with_path("join").post(membership::join_room_by_id))
+                    .push(
+                        Router::with_path("join")
+                            .hoop(hoops::limit_rate_join)
+                            .post(membership::join_room_by_id),
+                    )
                     .push(Router::with_path("invite").post(membership::invite_user))
                     .push(Router::with_path("read_markers").post(set_read_markers))
                     .push(Router::with_path("aliases").get(get_aliases))
@@ -96,12 +100,18 @@ pub fn authed_router() -> Router {
                     .push(
                         Router::with_path("state").get(state::get_state).push(
                             Router::with_path("{event_type}")
-                                .put(state::send_state_for_empty_key)
                                 .get(state::state_for_empty_key)
+                                .push(
+                                    Router::with_hoop(hoops::limit_rate_message)
+                                        .put(state::send_state_for_empty_key),
+                                )
                                 .push(
                                     Router::with_path("{state_key}")
-                                        .put(state::send_state_for_key)
-                                        .get(state::state_for_key),
+                                        .get(state::state_for_key)
+                                        .push(
+                                            Router::with_hoop(hoops::limit_rate_message)
+                                                .put(state::send_state_for_key),
+                                        ),
                                 ),
                         ),
                     )
@@ -124,11 +134,20 @@ pub fn authed_router() -> Router {
                     )
                     .push(Router::with_path("upgrade").post(upgrade))
                     .push(Router::with_path("messages").get(message::get_messages))
-                    .push(Router::with_path("send/{event_type}").post(message::post_message))
                     .push(
-                        Router::with_path("send/{event_type}/{txn_id}").put(message::send_message),
+                        Router::with_hoop(hoops::limit_rate_message)
+                            .push(
+                                Router::with_path("send/{event_type}").post(message::post_message),
+                            )
+                            .push(
+                                Router::with_path("send/{event_type}/{txn_id}")
+                                    .put(message::send_message),
+                            )
+                            .push(
+                                Router::with_path("redact/{event_id}/{txn_id}")
+                                    .put(event::send_redact),
+                            ),
                     )
-                    .push(Router::with_path("redact/{event_id}/{txn_id}").put(event::send_redact))
                     .push(
                         Router::with_path("tags").get(tag::list_tags).push(
                             Router::with_path("{tag}")
@@ -231,13 +250,13 @@ fn set_read_markers(
             &RoomAccountDataEventType::FullyRead.to_string(),
             serde_json::to_value(fully_read_event.content).expect("to json value always works"),
         )?;
-        push_action::remove_actions_for_room(sender_id, &room_id)?;
+        push_action::mark_room_actions_read(sender_id, &room_id)?;
     }
 
     if let Some(event_id) = &body.private_read_receipt {
         let (event_sn, _event_guard) = crate::event::ensure_event_sn(&room_id, event_id)?;
         data::room::receipt::set_private_read(&room_id, sender_id, event_id, event_sn)?;
-        push_action::remove_actions_until(sender_id, &room_id, event_sn, None)?;
+        push_action::mark_actions_read_until(sender_id, &room_id, event_sn, None)?;
         push_action::refresh_notify_summary(sender_id, &room_id)?;
     }
 
@@ -267,7 +286,7 @@ fn set_read_markers(
             true,
         )?;
         let event_sn = crate::event::get_event_sn(event)?;
-        push_action::remove_actions_until(sender_id, &room_id, event_sn, None)?;
+        push_action::mark_actions_read_until(sender_id, &room_id, event_sn, None)?;
         push_action::refresh_notify_summary(sender_id, &room_id)?;
     }
     empty_ok()
@@ -316,6 +335,7 @@ async fn upgrade(
     let authed = depot.authed_info()?;
     let sender_id = authed.user_id();
     let room_id = room_id.into_inner();
+    crate::user::ensure_not_suspended(authed.user())?;
 
     if !config::supported_room_versions().contains(&body.new_version) {
         return Err(MatrixError::unsupported_room_version(
@@ -632,6 +652,13 @@ pub(super) async fn create_room(
 ) -> JsonResult<CreateRoomResBody> {
     let authed = depot.authed_info()?;
     let sender_id = authed.user_id();
+    crate::user::ensure_not_suspended(authed.user())?;
+    let mut body = body.into_inner();
+    // Rooms of shadow-banned users are created, but their invites are dropped
+    if authed.user().shadow_banned {
+        body.invite.clear();
+        body.invite_3pid.clear();
+    }
 
     let conf = config::get();
     // let room_version =   conf.default_room_version.clone();
@@ -905,7 +932,7 @@ pub(super) async fn create_room(
     }
     drop(state_lock);
 
-    // 8. Events implied by invite (and TODO: invite_3pid)
+    // 8. Events implied by invite and invite_3pid
     for user_id in &body.invite {
         if let Err(e) =
             crate::membership::invite_user(sender_id, user_id, &room_id, None, body.is_direct).await
@@ -913,6 +940,11 @@ pub(super) async fn create_room(
             tracing::error!("Failed to invite user {}: {:?}", user_id, e);
         }
     }
+    for threepid in &body.invite_3pid {
+        if let Err(e) = crate::membership::invite_threepid(sender_id, &room_id, threepid).await {
+            tracing::error!("Failed to invite {}: {:?}", threepid.address, e);
+        }
+    }
 
     // Homeserver specific stuff
     if let Some(alias) = alias {
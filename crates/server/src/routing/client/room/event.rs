@@ -284,6 +284,17 @@ pub(super) async fn send_redact(
     depot: &mut Depot,
 ) -> JsonResult<RedactEventResBody> {
     let authed = depot.authed_info()?;
+    // Suspended users may still redact their own events
+    if authed.user().is_suspended()
+        && timeline::get_pdu(&args.event_id)?.sender != *authed.user_id()
+    {
+        crate::user::ensure_not_suspended(authed.user())?;
+    }
+    if authed.user().shadow_banned {
+        return json_ok(RedactEventResBody {
+            event_id: crate::user::shadow_ban_event_id(),
+        });
+    }
 
     let state_lock = crate::room::lock_state(&args.room_id).await;
     let event_id = timeline::build_and_append_pdu(
@@ -258,6 +258,7 @@ pub(super) async fn join_room_by_id(
     depot: &mut Depot,
 ) -> JsonResult<JoinRoomResBody> {
     let authed = depot.authed_info()?;
+    crate::user::ensure_not_suspended(authed.user())?;
     let room_id = room_id.into_inner();
     let body = body.into_inner();
 
@@ -301,23 +302,33 @@ pub(super) async fn invite_user(
     depot: &mut Depot,
 ) -> EmptyResult {
     let authed = depot.authed_info()?;
+    crate::user::ensure_not_suspended(authed.user())?;
 
     let conf = config::get();
     if conf.block_non_admin_invites && !authed.user.is_admin {
         return Err(MatrixError::forbidden("you are not allowed to invite users", None).into());
     }
 
-    let InvitationRecipient::UserId { user_id } = &body.recipient else {
-        return Err(MatrixError::not_found("user not found").into());
-    };
-    crate::membership::invite_user(
-        authed.user_id(),
-        user_id,
-        &room_id.into_inner(),
-        body.reason.clone(),
-        false,
-    )
-    .await?;
+    // Invites of shadow-banned users are dropped, but look sent to them
+    if authed.user.shadow_banned {
+        return empty_ok();
+    }
+    match &body.recipient {
+        InvitationRecipient::UserId { user_id } => {
+            crate::membership::invite_user(
+                authed.user_id(),
+                user_id,
+                &room_id.into_inner(),
+                body.reason.clone(),
+                false,
+            )
+            .await?;
+        }
+        InvitationRecipient::ThirdPartyId(threepid) => {
+            crate::membership::invite_threepid(authed.user_id(), &room_id.into_inner(), threepid)
+                .await?;
+        }
+    }
     empty_ok()
 }
 
@@ -337,6 +348,7 @@ pub(crate) async fn join_room_by_id_or_alias(
     depot: &mut Depot,
 ) -> JsonResult<JoinRoomResBody> {
     let authed = depot.authed_info()?;
+    crate::user::ensure_not_suspended(authed.user())?;
     let sender_id = authed.user_id();
     let room_id_or_alias = room_id_or_alias.into_inner();
     let body = body.into_inner().unwrap_or_default();
@@ -461,6 +473,7 @@ pub(super) async fn ban_user(
     depot: &mut Depot,
 ) -> EmptyResult {
     let authed = depot.authed_info()?;
+    crate::user::ensure_not_suspended(authed.user())?;
     let room_id = room_id.into_inner();
 
     let state_lock = room::lock_state(&room_id).await;
@@ -572,6 +585,7 @@ pub(super) async fn unban_user(
     depot: &mut Depot,
 ) -> EmptyResult {
     let authed = depot.authed_info()?;
+    crate::user::ensure_not_suspended(authed.user())?;
     let room_id = room_id.into_inner();
 
     let state_lock = room::lock_state(&room_id).await;
@@ -628,6 +642,7 @@ pub(super) async fn kick_user(
     depot: &mut Depot,
 ) -> EmptyResult {
     let authed = depot.authed_info()?;
+    crate::user::ensure_not_suspended(authed.user())?;
     let room_id = room_id.into_inner();
 
     let state_lock = room::lock_state(&room_id).await;
@@ -704,6 +719,7 @@ pub(crate) async fn knock_room(
     depot: &mut Depot,
 ) -> EmptyResult {
     let authed = depot.authed_info()?;
+    crate::user::ensure_not_suspended(authed.user())?;
     let sender_id = authed.user_id();
     let (room_id, servers) = match OwnedRoomId::try_from(args.room_id_or_alias) {
         Ok(room_id) => {
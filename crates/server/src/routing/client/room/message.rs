@@ -14,7 +14,7 @@ use crate::data::{connect, diesel_exists};
 use crate::event::BatchToken;
 use crate::room::timeline::{self, topolo};
 use crate::routing::prelude::*;
-use crate::{PduBuilder, room};
+use crate::{AuthedInfo, PduBuilder, room};
 
 /// #GET /_matrix/client/r0/rooms/{room_id}/messages
 /// Allows paginating through room history.
@@ -41,7 +41,8 @@ pub(super) async fn get_messages(
             .filter(room_users::membership.eq("leave"))
             .select((room_users::event_sn, room_users::forgotten))
             .first::<(i64, bool)>(&mut connect()?);
-        let Some((_event_sn, forgotten)) = diesel::OptionalExtension::optional(forgotten_row)? else {
+        let Some((_event_sn, forgotten)) = diesel::OptionalExtension::optional(forgotten_row)?
+        else {
             return Err(MatrixError::forbidden("you aren't a member of the room", None).into());
         };
         if forgotten {
@@ -218,6 +219,7 @@ pub(super) async fn send_message(
     depot: &mut Depot,
 ) -> JsonResult<SendMessageResBody> {
     let authed = depot.authed_info()?;
+    crate::user::ensure_not_suspended(authed.user())?;
 
     let conf = config::get();
     // Forbid m.room.encrypted if encryption is disabled
@@ -234,12 +236,26 @@ pub(super) async fn send_message(
 
     let state_lock = room::lock_state(&args.room_id).await;
     // Check if this is a new transaction id
-    if let Some(event_id) = crate::transaction_id::get_event_id(
-        &args.txn_id,
-        authed.user_id(),
-        Some(authed.device_id()),
-        Some(&args.room_id),
-    )? {
+    let existing = {
+        let (txn_id, user_id, device_id, room_id) = (
+            args.txn_id.clone(),
+            authed.user_id().to_owned(),
+            authed.device_id().to_owned(),
+            args.room_id.clone(),
+        );
+        crate::utils::spawn_blocking(move || {
+            crate::transaction_id::get_event_id(&txn_id, &user_id, Some(&device_id), Some(&room_id))
+        })
+        .await?
+    };
+    if let Some(event_id) = existing {
+        return json_ok(SendMessageResBody::new(event_id));
+    }
+
+    // Shadow-banned users' events are dropped, but look sent to them
+    if authed.user().shadow_banned {
+        let event_id = crate::user::shadow_ban_event_id();
+        add_txn_id(authed, &args, &event_id).await?;
         return json_ok(SendMessageResBody::new(event_id));
     }
 
@@ -249,7 +265,13 @@ pub(super) async fn send_message(
         to_raw_value(&args.txn_id).expect("TxnId is valid json"),
     );
 
-    let event_id = timeline::build_and_append_pdu(
+    let room_version = {
+        let room_id = args.room_id.clone();
+        crate::utils::spawn_blocking(move || crate::room::get_version(&room_id)).await?
+    };
+    // Building the event and appending it to the timeline only touch the
+    // database.
+    let event_id = crate::utils::run_in_place(timeline::build_and_append_pdu(
         PduBuilder {
             event_type: args.event_type.to_string().into(),
             content: serde_json::from_slice(payload)
@@ -264,24 +286,42 @@ pub(super) async fn send_message(
         },
         authed.user_id(),
         &args.room_id,
-        &crate::room::get_version(&args.room_id)?,
+        &room_version,
         &state_lock,
-    )
+    ))
     .await?
     .pdu
     .event_id;
 
-    crate::transaction_id::add_txn_id(
-        &args.txn_id,
-        authed.user_id(),
-        Some(authed.device_id()),
-        Some(&args.room_id),
-        Some(&event_id),
-    )?;
-
+    add_txn_id(authed, &args, &event_id).await?;
     json_ok(SendMessageResBody::new((*event_id).to_owned()))
 }
 
+/// Records the event sent for the transaction id of the request.
+async fn add_txn_id(
+    authed: &AuthedInfo,
+    args: &CreateMessageWithTxnReqArgs,
+    event_id: &EventId,
+) -> AppResult<()> {
+    let (txn_id, user_id, device_id, room_id, event_id) = (
+        args.txn_id.clone(),
+        authed.user_id().to_owned(),
+        authed.device_id().to_owned(),
+        args.room_id.clone(),
+        event_id.to_owned(),
+    );
+    crate::utils::spawn_blocking(move || {
+        crate::transaction_id::add_txn_id(
+            &txn_id,
+            &user_id,
+            Some(&device_id),
+            Some(&room_id),
+            Some(&event_id),
+        )
+    })
+    .await
+}
+
 /// #POST /_matrix/client/r0/rooms/{room_id}/send/{event_type}
 /// Send a message event into the room.
 ///
@@ -296,6 +336,7 @@ pub(super) async fn post_message(
     depot: &mut Depot,
 ) -> JsonResult<SendMessageResBody> {
     let authed = depot.authed_info()?;
+    crate::user::ensure_not_suspended(authed.user())?;
 
     let conf = config::get();
     let state_lock = room::lock_state(&args.room_id).await;
@@ -313,6 +354,9 @@ pub(super) async fn post_message(
     if !content.is_object() {
         return Err(MatrixError::bad_json("json body is not object").into());
     }
+    if authed.user().shadow_banned {
+        return json_ok(SendMessageResBody::new(crate::user::shadow_ban_event_id()));
+    }
 
     let event_id = timeline::build_and_append_pdu(
         PduBuilder {
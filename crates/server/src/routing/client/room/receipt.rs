@@ -46,7 +46,7 @@ pub(super) fn send_receipt(
                 &RoomAccountDataEventType::FullyRead.to_string(),
                 serde_json::to_value(fully_read_event.content).expect("to json value always works"),
             )?;
-            push_action::remove_actions_for_room(sender_id, &args.room_id)?;
+            push_action::mark_room_actions_read(sender_id, &args.room_id)?;
         }
         ReceiptType::Read => {
             let mut user_receipts = BTreeMap::new();
@@ -72,7 +72,7 @@ pub(super) fn send_receipt(
                 },
                 true,
             )?;
-            push_action::remove_actions_until(sender_id, &args.room_id, event_sn, thread_id)?;
+            push_action::mark_actions_read_until(sender_id, &args.room_id, event_sn, thread_id)?;
         }
         ReceiptType::ReadPrivate => {
             // let count = timeline::get_event_sn(&args.event_id)?
@@ -83,7 +83,7 @@ pub(super) fn send_receipt(
                 &args.event_id,
                 event_sn,
             )?;
-            push_action::remove_actions_until(sender_id, &args.room_id, event_sn, thread_id)?;
+            push_action::mark_actions_read_until(sender_id, &args.room_id, event_sn, thread_id)?;
         }
         _ => return Err(AppError::internal("unsupported receipt type")),
     }
@@ -8,12 +8,17 @@ use crate::core::client::state::{
     StateEventsForKeyReqArgs, StateEventsForKeyResBody, StateEventsResBody,
 };
 use crate::core::client::typing::{CreateTypingEventReqBody, Typing};
+use crate::core::events::StateEventType;
+use crate::core::events::room::member::MembershipState;
 use crate::core::events::room::message::RoomMessageEventContent;
 use crate::core::identifiers::*;
 use crate::core::room::{RoomEventReqArgs, RoomEventTypeReqArgs, RoomTypingReqArgs};
+use crate::data::user::DbUser;
 use crate::room::{state, timeline};
 use crate::utils::HtmlEscape;
-use crate::{AuthArgs, DepotExt, EmptyResult, JsonResult, MatrixError, empty_ok, json_ok, room};
+use crate::{
+    AppResult, AuthArgs, DepotExt, EmptyResult, JsonResult, MatrixError, empty_ok, json_ok, room,
+};
 
 /// #GET /_matrix/client/r0/rooms/{room_id}/state
 /// Get all state events for a room.
@@ -211,6 +216,11 @@ pub(super) async fn send_state_for_key(
 ) -> JsonResult<SendStateEventResBody> {
     let authed = depot.authed_info()?;
     let body = body.into_inner();
+    if let Some(event_id) =
+        restrict_state_event(authed.user(), &args.event_type, &args.state_key, &body)?
+    {
+        return json_ok(SendStateEventResBody { event_id });
+    }
 
     let event_id = crate::state::send_state_event_for_key(
         authed.user_id(),
@@ -242,11 +252,15 @@ pub(super) async fn send_state_for_empty_key(
 ) -> JsonResult<SendStateEventResBody> {
     let authed = depot.authed_info()?;
     let body = body.into_inner();
+    let event_type = args.event_type.to_string().into();
+    if let Some(event_id) = restrict_state_event(authed.user(), &event_type, "", &body)? {
+        return json_ok(SendStateEventResBody { event_id });
+    }
     let event_id = crate::state::send_state_event_for_key(
         authed.user_id(),
         &args.room_id,
         &crate::room::get_version(&args.room_id)?,
-        &args.event_type.to_string().into(),
+        &event_type,
         body.0,
         "".into(),
     )
@@ -257,6 +271,34 @@ pub(super) async fn send_state_for_empty_key(
     })
 }
 
+/// Rejects state events of suspended users other than leaving, and returns
+/// the fake event id to answer with if the event of a shadow-banned user is
+/// dropped. Shadow-banned users can still join and leave rooms, but neither
+/// can kick others, as that is a leave for another user.
+fn restrict_state_event(
+    user: &DbUser,
+    event_type: &StateEventType,
+    state_key: &str,
+    body: &SendStateEventReqBody,
+) -> AppResult<Option<OwnedEventId>> {
+    let membership = if *event_type == StateEventType::RoomMember && state_key == user.id.as_str() {
+        body.0.get_field::<MembershipState>("membership")?
+    } else {
+        None
+    };
+    if membership != Some(MembershipState::Leave) {
+        crate::user::ensure_not_suspended(user)?;
+    }
+    let passes_shadow_ban = matches!(
+        membership,
+        Some(MembershipState::Join | MembershipState::Leave | MembershipState::Knock)
+    );
+    if user.shadow_banned && !passes_shadow_ban {
+        return Ok(Some(crate::user::shadow_ban_event_id()));
+    }
+    Ok(None)
+}
+
 /// #PUT /_matrix/client/r0/rooms/{room_id}/typing/{user_id}
 /// Sets the typing state of the sender user.
 #[endpoint]
@@ -271,6 +313,10 @@ pub async fn send_typing(
     if !room::user::is_joined(authed.user_id(), &args.room_id)? {
         return Err(MatrixError::forbidden("You are not in this room.", None).into());
     }
+    // Others don't see shadow-banned users typing
+    if authed.user().shadow_banned {
+        return empty_ok();
+    }
 
     if let Typing::Yes(duration) = body.state {
         room::typing::add_typing(
@@ -22,13 +22,13 @@ use crate::{
 pub fn public_router() -> Router {
     Router::new().push(
         Router::with_path("login")
-            .hoop(hoops::limit_rate)
-            .get(login_types)
-            .post(login)
+            .push(Router::with_hoop(hoops::limit_rate_login).post(login))
             .push(
-                Router::with_path("sso/redirect")
-                    .get(redirect)
-                    .push(Router::with_path("idpId").get(provider_url)),
+                Router::with_hoop(hoops::limit_rate).get(login_types).push(
+                    Router::with_path("sso/redirect")
+                        .get(redirect)
+                        .push(Router::with_path("idpId").get(provider_url)),
+                ),
             ),
     )
 }
@@ -88,77 +88,32 @@ async fn login(
             let user_id = UserId::parse_with_server_name(username, &config::get().server_name)
                 .map_err(|_| MatrixError::invalid_username("Username is invalid."))?;
 
-            // if let Some(ldap) = config::enabled_ldap() {
-            //     let (user_dn, is_ldap_admin) = match ldap.bind_dn.as_ref() {
-            //         Some(bind_dn) if bind_dn.contains("{username}") => {
-            //             (bind_dn.replace("{username}", user_id.localpart()), false)
-            //         }
-            //         _ => {
-            //             debug!("searching user in LDAP");
-
-            //             let dns = user::search_ldap(&user_id).await?;
-            //             if dns.len() >= 2 {
-            //                 return Err(MatrixError::forbidden("LDAP search returned two or more
-            // results", None).into());             }
-
-            //             if let Some((user_dn, is_admin)) = dns.first() {
-            //                 (user_dn.clone(), *is_admin)
-            //             } else {
-            //                 let Ok(user) = data::user::get_user(&user_id)? else {
-            //                     return Err(MatrixError::forbidden("user not found.",
-            // None).into());                 };
-            //                 if let Err(_e) = user::vertify_password(&user, password) {
-            //                     res.status_code(StatusCode::FORBIDDEN); //for complement testing:
-            // TestLogin/parallel/POST_/login_wrong_password_is_rejected
-            // return Err(MatrixError::forbidden("wrong username or password.", None).into());
-            //                 }
-            //                 (user_id.to_string(), false)
-            //             }
-            //         }
-            //     };
-
-            //     let user_id = user::auth_ldap(&user_dn, password).await.map(|()|
-            // user_id.to_owned())?;
-
-            //     // LDAP users are automatically created on first login attempt. This is a very
-            //     // common feature that can be seen on many services using a LDAP provider for
-            //     // their users (synapse, Nextcloud, Jellyfin, ...).
-            //     //
-            //     // LDAP users are crated with a dummy password but non empty because an empty
-            //     // password is reserved for deactivated accounts. The palpo password field
-            //     // will never be read to login a LDAP user so it's not an issue.
-            //     if !data::user::user_exists(&user_id)? {
-            //         let new_user = NewDbUser {
-            //             id: user_id.clone(),
-            //             ty: Some("ldap".to_owned()),
-            //             is_admin: false,
-            //             is_guest: false,
-            //             appservice_id: None,
-            //             created_at: UnixMillis::now(),
-            //         };
-            //         let user = diesel::insert_into(users::table)
-            //             .values(&new_user)
-            //             .on_conflict(users::id)
-            //             .do_update()
-            //             .set(&new_user)
-            //             .get_result::<DbUser>(&mut connect()?)?;
-            //     }
-
-            //     let is_palpo_admin = data::user::is_admin(&user_id)?;
-            //     if is_ldap_admin && !is_palpo_admin {
-            //         admin::make_admin(&user_id).await?;
-            //     } else if !is_ldap_admin && is_palpo_admin {
-            //         admin::revoke_admin(&user_id).await?;
-            //     }
-            // } else {
-            let Ok(user) = data::user::get_user(&user_id) else {
-                return Err(MatrixError::forbidden("User not found.", None).into());
+            let ldap_authed = if config::get().enabled_ldap().is_some() {
+                match user::login_ldap(&user_id, password).await {
+                    Ok(authed) => authed,
+                    Err(e) => {
+                        // Accounts of this server keep working while the directory is down.
+                        let local_user = data::user::get_user(&user_id)
+                            .is_ok_and(|user| !user::is_ldap_user(&user));
+                        if !local_user {
+                            return Err(e);
+                        }
+                        warn!("LDAP login of {user_id} failed, trying its local password: {e}");
+                        false
+                    }
+                }
+            } else {
+                false
             };
-            if let Err(_e) = user::verify_password(&user, password) {
-                res.status_code(StatusCode::FORBIDDEN); //for complement testing: TestLogin/parallel/POST_/login_wrong_password_is_rejected
-                return Err(MatrixError::forbidden("Wrong username or password.", None).into());
+            if !ldap_authed {
+                let Ok(user) = data::user::get_user(&user_id) else {
+                    return Err(MatrixError::forbidden("User not found.", None).into());
+                };
+                if let Err(_e) = user::verify_password(&user, password) {
+                    res.status_code(StatusCode::FORBIDDEN); //for complement testing: TestLogin/parallel/POST_/login_wrong_password_is_rejected
+                    return Err(MatrixError::forbidden("Wrong username or password.", None).into());
+                }
             }
-            // }
 
             user_id
         }
@@ -1,4 +1,5 @@
 use std::cmp;
+use std::sync::Arc;
 use std::time::Duration;
 
 use salvo::oapi::extract::*;
@@ -37,14 +38,14 @@ pub(super) async fn sync_events_v5(
 
     let mut req_body = req_body.into_inner();
 
-    let _conn_id = req_body.conn_id.clone();
+    let conn_id = req_body.conn_id.clone();
 
     if since_sn == 0 {
         crate::sync_v5::forget_sync_request_connection(
             sender_id.to_owned(),
             device_id.to_owned(),
             req_body.conn_id.to_owned(),
-        )
+        )?;
     }
 
     // Get sticky parameters from cache
@@ -52,11 +53,18 @@ pub(super) async fn sync_events_v5(
         sender_id.to_owned(),
         device_id.to_owned(),
         &mut req_body,
-    );
+    )?;
 
-    let mut res_body =
-        crate::sync_v5::sync_events(sender_id, device_id, since_sn, &req_body, &known_rooms)
-            .await?;
+    let req_body = Arc::new(req_body);
+    let known_rooms = Arc::new(known_rooms);
+    let mut res_body = crate::sync_v5::sync_events(
+        sender_id,
+        device_id,
+        since_sn,
+        req_body.clone(),
+        known_rooms.clone(),
+    )
+    .await?;
 
     if since_sn > data::curr_sn()? || (args.pos.is_some() && res_body.is_empty()) {
         // Hang a few seconds so requests are not spammed
@@ -64,12 +72,14 @@ pub(super) async fn sync_events_v5(
         let default = Duration::from_secs(30);
         let duration = cmp::min(args.timeout.unwrap_or(default), default);
         // Setup watchers, so if there's no response, we can wait for them
+        let _long_poll = crate::metrics::sync_long_poll("msc4186");
         let watcher = crate::watcher::watch(sender_id, device_id);
         _ = tokio::time::timeout(duration, watcher).await;
         res_body =
-            crate::sync_v5::sync_events(sender_id, device_id, since_sn, &req_body, &known_rooms)
+            crate::sync_v5::sync_events(sender_id, device_id, since_sn, req_body, known_rooms)
                 .await?;
     }
+    crate::sync_v5::save_sync_connection(sender_id.to_owned(), device_id.to_owned(), conn_id)?;
 
     trace!(
         rooms=?res_body.rooms.len(),
@@ -1,3 +1,4 @@
+use std::sync::Arc;
 use std::time::Duration;
 
 use salvo::prelude::*;
@@ -52,7 +53,8 @@ pub(super) async fn sync_events_v3(
     let device_id = authed.device_id();
 
     crate::user::ping_presence(sender_id, &args.set_presence)?;
-    let mut body = crate::sync_v3::sync_events(sender_id, device_id, &args).await?;
+    let args = Arc::new(args);
+    let mut body = crate::sync_v3::sync_events(sender_id, device_id, args.clone()).await?;
 
     if !args.full_state
         && body.rooms.is_empty()
@@ -66,11 +68,12 @@ pub(super) async fn sync_events_v3(
         let default = Duration::from_secs(30);
         let duration = std::cmp::min(args.timeout.unwrap_or(default), default);
         // Setup watchers, so if there's no response, we can wait for them
+        let _long_poll = crate::metrics::sync_long_poll("v3");
         let watcher = crate::watcher::watch(sender_id, device_id);
         _ = tokio::time::timeout(duration, watcher).await;
 
         // Retry returning data
-        body = crate::sync_v3::sync_events(sender_id, device_id, &args).await?;
+        body = crate::sync_v3::sync_events(sender_id, device_id, args).await?;
     }
     json_ok(body)
 }
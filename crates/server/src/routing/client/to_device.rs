@@ -59,7 +59,7 @@ fn send_to_device(
 
             match target_device_id_maybe {
                 DeviceIdOrAllDevices::DeviceId(target_device_id) => {
-                    data::user::device::add_to_device_event(
+                    crate::user::add_to_device_event(
                         authed.user_id(),
                         target_user_id,
                         target_device_id,
@@ -72,7 +72,7 @@ fn send_to_device(
 
                 DeviceIdOrAllDevices::AllDevices => {
                     for target_device_id in data::user::all_device_ids(target_user_id)? {
-                        data::user::device::add_to_device_event(
+                        crate::user::add_to_device_event(
                             authed.user_id(),
                             target_user_id,
                             &target_device_id,
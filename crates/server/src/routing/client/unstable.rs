@@ -4,8 +4,8 @@ use crate::hoops;
 
 pub(super) fn router() -> Router {
     Router::with_path("unstable")
-        .hoop(hoops::limit_rate)
         .hoop(hoops::auth_by_access_token)
+        .hoop(hoops::limit_rate)
         .push(
             Router::with_path("org.matrix.msc3391/user/{user_id}/account_data/{account_type}")
                 .delete(super::account::delete_account_data_msc3391),
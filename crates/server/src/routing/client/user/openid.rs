@@ -1,18 +1,12 @@
 use std::time::Duration;
 
-use diesel::prelude::*;
 use salvo::oapi::extract::PathParam;
 use salvo::prelude::*;
 
 use crate::core::authentication::TokenType;
 use crate::core::client::user::RequstOpenidTokenResBody;
 use crate::core::identifiers::*;
-use crate::core::UnixMillis;
-use crate::data::connect;
-use crate::data::schema::*;
-use crate::{config, utils, AuthArgs, DepotExt, JsonResult, MatrixError, json_ok};
-
-const OPENID_TOKEN_LENGTH: usize = 64;
+use crate::{config, AuthArgs, DepotExt, JsonResult, MatrixError, json_ok};
 
 /// Request an OpenID 1.0 token to verify identity with a third party.
 ///
@@ -36,25 +30,11 @@ pub(super) async fn request_token(
         .into());
     }
 
-    let conf = config::get();
-    let expires_in_secs = conf.openid_token_ttl;
-    let expires_at = UnixMillis::now().get() as i64 + (expires_in_secs as i64 * 1000);
-
-    let access_token = utils::random_string(OPENID_TOKEN_LENGTH);
-
-    // Store the token in the database
-    diesel::insert_into(user_openid_tokens::table)
-        .values((
-            user_openid_tokens::user_id.eq(authed.user_id()),
-            user_openid_tokens::token.eq(&access_token),
-            user_openid_tokens::expires_at.eq(expires_at),
-        ))
-        .execute(&mut connect()?)?;
-
+    let (access_token, expires_in) = crate::user::create_openid_token(&user_id)?;
     json_ok(RequstOpenidTokenResBody::new(
         access_token,
         TokenType::Bearer,
-        conf.server_name.clone(),
-        Duration::from_secs(expires_in_secs),
+        config::server_name().to_owned(),
+        Duration::from_secs(expires_in),
     ))
 }
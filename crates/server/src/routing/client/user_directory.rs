@@ -9,7 +9,7 @@ use crate::core::events::StateEventType;
 use crate::core::events::room::join_rule::RoomJoinRulesEventContent;
 use crate::core::identifiers::*;
 use crate::core::room::JoinRule;
-use crate::data::connect;
+use crate::data::connect_read;
 use crate::data::schema::*;
 use crate::{AuthArgs, DepotExt, JsonResult, data, hoops, json_ok, room};
 
@@ -42,7 +42,7 @@ fn search(
         )
         .filter(user_profiles::user_id.ne(authed.user_id()))
         .select(user_profiles::user_id)
-        .load::<OwnedUserId>(&mut connect()?)?;
+        .load::<OwnedUserId>(&mut connect_read()?)?;
 
     let mut users = user_ids.into_iter().filter_map(|user_id| {
         let user = SearchedUser {
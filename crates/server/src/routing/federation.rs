@@ -38,7 +38,6 @@ pub fn router() -> Router {
                 .push(query::router())
                 .push(room::router())
                 .push(space::router())
-                .push(threepid::router())
                 .push(transaction::router())
                 .push(user::router())
                 .push(Router::with_path("version").post(version)),
@@ -52,15 +51,31 @@ pub fn router() -> Router {
                 .push(query::router())
                 .push(room::router())
                 .push(space::router())
-                .push(threepid::router())
                 .push(transaction::router())
                 .push(user::router())
-                .push(media::router())
                 .push(Router::with_path("version").post(version)),
         )
         .push(Router::with_path("versions").get(get_versions))
 }
 
+/// Federation endpoints called by identity servers, which do not sign their
+/// requests.
+pub fn identity_router() -> Router {
+    Router::with_path("federation")
+        .hoop(check_federation_enabled)
+        .oapi_tag("federation")
+        .push(Router::with_path("v1").push(threepid::router()))
+}
+
+/// Federation media endpoints, served by the media repository.
+pub fn media_router() -> Router {
+    Router::with_path("federation")
+        .hoop(check_federation_enabled)
+        .hoop(hoops::auth_by_access_token_or_signatures)
+        .oapi_tag("federation")
+        .push(Router::with_path("v1").push(media::router()))
+}
+
 #[handler]
 async fn check_federation_enabled() -> AppResult<()> {
     let conf = config::get();
@@ -2,10 +2,12 @@ use salvo::oapi::extract::*;
 use salvo::prelude::*;
 
 use crate::core::UnixMillis;
+use crate::core::events::StateEventType;
 use crate::core::federation::authorization::{EventAuthReqArgs, EventAuthResBody};
 use crate::core::federation::event::{
     EventReqArgs, EventResBody, MissingEventsReqBody, MissingEventsResBody,
 };
+use crate::core::federation::third_party::ExchangeInviteReqBody;
 use crate::core::identifiers::*;
 use crate::core::room::{TimestampToEventReqArgs, TimestampToEventResBody};
 use crate::data::room::DbEvent;
@@ -191,8 +193,35 @@ fn missing_events(
     json_ok(MissingEventsResBody { events })
 }
 
+/// #PUT /_matrix/federation/v1/exchange_third_party_invite/{room_id}
+/// Exchanges a third party invite, signed by the identity server for the user
+/// the threepid got bound to, for an invite of that user.
 #[endpoint]
-async fn exchange_third_party_invite(_aa: AuthArgs) -> EmptyResult {
-    // TODO: todo
+async fn exchange_third_party_invite(
+    _aa: AuthArgs,
+    room_id: PathParam<OwnedRoomId>,
+    body: JsonBody<ExchangeInviteReqBody>,
+    depot: &mut Depot,
+) -> EmptyResult {
+    let origin = depot.origin()?;
+    let body = body.into_inner();
+    if body.room_id != *room_id {
+        return Err(MatrixError::invalid_param("room id does not match the path").into());
+    }
+    if body.kind != StateEventType::RoomMember {
+        return Err(MatrixError::invalid_param("event type must be m.room.member").into());
+    }
+    if body.state_key.server_name() != origin {
+        return Err(
+            MatrixError::forbidden("invitee is not a user of the origin server", None).into(),
+        );
+    }
+    crate::membership::exchange_third_party_invite(
+        &body.room_id,
+        &body.sender,
+        &body.state_key,
+        body.content,
+    )
+    .await?;
     empty_ok()
 }
@@ -1,14 +1,17 @@
 //! Endpoints for handling keys for end-to-end encryption
-use std::collections::BTreeMap;
-use std::time::{Duration, SystemTime};
-
+use futures_util::future::join_all;
+use salvo::oapi::extract::JsonBody;
 use salvo::prelude::*;
 
-use crate::core::federation::directory::ServerKeysResBody;
-use crate::core::federation::discovery::{ServerSigningKeys, VerifyKey};
-use crate::core::serde::{Base64, CanonicalJsonObject};
-use crate::core::{OwnedServerSigningKeyId, UnixMillis};
-use crate::{AuthArgs, EmptyResult, JsonResult, config, empty_ok, json_ok};
+use crate::core::federation::directory::{
+    RemoteServerKeysBatchReqBody, RemoteServerKeysBatchResBody, RemoteServerKeysReqArgs,
+    RemoteServerKeysResBody, ServerKeysResBody,
+};
+use crate::core::{MatrixError, UnixMillis};
+use crate::{AuthArgs, JsonResult, json_ok, server_key};
+
+/// Most servers whose keys can be queried in one batch request.
+const QUERY_SERVER_LIMIT: usize = 100;
 
 pub fn router() -> Router {
     Router::with_path("key").oapi_tag("federation").push(
@@ -22,16 +25,56 @@ pub fn router() -> Router {
     )
 }
 
+/// #POST /_matrix/key/v2/query
+/// Gets the keys of several servers, signed by them and by this server as a
+/// notary.
 #[endpoint]
-async fn query_keys(_aa: AuthArgs) -> EmptyResult {
-    // TODO: todo
-    empty_ok()
+async fn query_keys(
+    _aa: AuthArgs,
+    body: JsonBody<RemoteServerKeysBatchReqBody>,
+) -> JsonResult<RemoteServerKeysBatchResBody> {
+    let body = body.into_inner();
+    if body.server_keys.len() > QUERY_SERVER_LIMIT {
+        return Err(MatrixError::invalid_param(format!(
+            "at most {QUERY_SERVER_LIMIT} servers can be queried at once"
+        ))
+        .into());
+    }
+
+    let now = UnixMillis::now();
+    let server_keys = join_all(body.server_keys.into_iter().map(
+        |(server_name, criteria)| async move {
+            let key_ids = criteria.keys().cloned().collect::<Vec<_>>();
+            // The keys must be valid until the latest timestamp of the query.
+            let minimum_valid_until_ts = criteria
+                .values()
+                .filter_map(|criteria| criteria.minimum_valid_until_ts)
+                .max()
+                .unwrap_or(now);
+            match server_key::notary_keys(&server_name, &key_ids, minimum_valid_until_ts).await {
+                Ok(keys) => keys,
+                Err(e) => {
+                    warn!("failed to get keys of {server_name} for notary request: {e}");
+                    None
+                }
+            }
+        },
+    ))
+    .await;
+    json_ok(RemoteServerKeysBatchResBody::new(
+        server_keys.into_iter().flatten().collect(),
+    ))
 }
 
+/// #GET /_matrix/key/v2/query/{server_name}
+/// Gets the keys of a server, signed by it and by this server as a notary.
 #[endpoint]
-async fn query_keys_from_server(_aa: AuthArgs) -> EmptyResult {
-    // TODO: todo
-    empty_ok()
+async fn query_keys_from_server(
+    _aa: AuthArgs,
+    args: RemoteServerKeysReqArgs,
+) -> JsonResult<RemoteServerKeysResBody> {
+    let keys = server_key::notary_keys(&args.server_name, &[], args.minimum_valid_until_ts).await?;
+    json_ok(RemoteServerKeysResBody::new(keys.into_iter().collect()))
 }
 
 /// #GET /_matrix/key/v2/server
@@ -39,39 +82,7 @@ async fn query_keys_from_server(_aa: AuthArgs) -> EmptyResult {
 ///
 /// - Matrix does not support invalidating public keys, so the key returned by this will be valid
 /// forever.
-// Response type for this endpoint is Json because we need to calculate a signature for the response
 #[endpoint]
 async fn server_signing_keys(_aa: AuthArgs) -> JsonResult<ServerKeysResBody> {
-    let conf = crate::config::get();
-    let mut verify_keys: BTreeMap<OwnedServerSigningKeyId, VerifyKey> = BTreeMap::new();
-    verify_keys.insert(
-        format!("ed25519:{}", config::keypair().version())
-            .try_into()
-            .expect("found invalid server signing keys in DB"),
-        VerifyKey {
-            key: Base64::new(config::keypair().public_key().to_vec()),
-        },
-    );
-    let server_keys = ServerSigningKeys {
-        server_name: conf.server_name.clone(),
-        verify_keys,
-        old_verify_keys: BTreeMap::new(),
-        signatures: BTreeMap::new(),
-        valid_until_ts: UnixMillis::from_system_time(
-            SystemTime::now() + Duration::from_secs(86400 * 7),
-        )
-        .expect("time is valid"),
-    };
-    let buf: Vec<u8> = crate::core::serde::json_to_buf(&server_keys)?;
-    let mut server_keys: CanonicalJsonObject = serde_json::from_slice(&buf)?;
-
-    crate::core::signatures::sign_json(
-        conf.server_name.as_str(),
-        config::keypair(),
-        &mut server_keys,
-    )?;
-    let server_keys: ServerSigningKeys =
-        serde_json::from_slice(&serde_json::to_vec(&server_keys).unwrap())?;
-
-    json_ok(ServerKeysResBody::new(server_keys))
+    json_ok(ServerKeysResBody::new(server_key::local_signing_keys()?))
 }
@@ -1,23 +1,18 @@
-use std::fs;
 use std::io::Cursor;
-use std::path::Path;
 use std::str::FromStr;
 
 use diesel::prelude::*;
 use image::imageops::FilterType;
 use mime::Mime;
 use palpo_core::http_headers::ContentDispositionType;
-use salvo::fs::NamedFile;
 use salvo::prelude::*;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
 
 use crate::core::UnixMillis;
 use crate::core::federation::media::*;
 use crate::data::connect;
 use crate::data::media::*;
 use crate::data::schema::*;
-use crate::media::get_media_path;
+use crate::media::{media_key, send_stored, storage};
 use crate::utils::content_disposition::make_content_disposition;
 use crate::{AppResult, AuthArgs, MatrixError, config, hoops};
 
@@ -52,12 +47,8 @@ pub async fn get_content(
                     .unwrap_or(mime::APPLICATION_OCTET_STREAM)
             });
 
-        let path = get_media_path(server_name, &args.media_id);
-        if Path::new(&path).exists() {
-            NamedFile::builder(path)
-                .content_type(content_type)
-                .send(req.headers(), res)
-                .await;
+        let key = media_key(server_name, &args.media_id);
+        if send_stored(&key, content_type, None, req, res).await? {
             Ok(())
         } else {
             Err(MatrixError::not_yet_uploaded("Media has not been uploaded yet").into())
@@ -82,10 +73,13 @@ pub async fn get_thumbnail(
         args.width,
         args.height,
     )? {
-        let thumb_path = get_media_path(
+        let thumb_key = media_key(
             server_name,
             &format!("{}.{}x{}", args.media_id, args.width, args.height),
         );
+        let Some(file) = storage().get(&thumb_key).await? else {
+            return Err(MatrixError::not_found("file not found").into());
+        };
 
         let content_disposition = make_content_disposition(
             Some(ContentDispositionType::Inline),
@@ -93,7 +87,7 @@ pub async fn get_thumbnail(
             None,
         );
         let content = Content {
-            file: fs::read(&thumb_path)?,
+            file,
             content_type,
             content_disposition: Some(content_disposition),
         };
@@ -108,10 +102,13 @@ pub async fn get_thumbnail(
     let (width, height, crop) =
         crate::media::thumbnail_properties(args.width, args.height).unwrap_or((0, 0, false)); // 0, 0 because that's the original file
 
-    let thumb_path = get_media_path(server_name, &format!("{}.{width}x{height}", &args.media_id));
+    let thumb_key = media_key(server_name, &format!("{}.{width}x{height}", &args.media_id));
     if let Some(DbThumbnail { content_type, .. }) =
         crate::data::media::get_thumbnail_by_dimension(server_name, &args.media_id, width, height)?
     {
+        let Some(file) = storage().get(&thumb_key).await? else {
+            return Err(MatrixError::not_found("file not found").into());
+        };
         // Using saved thumbnail
         let content_disposition = make_content_disposition(
             Some(ContentDispositionType::Inline),
@@ -119,7 +116,7 @@ pub async fn get_thumbnail(
             None,
         );
         let content = Content {
-            file: fs::read(&thumb_path)?,
+            file,
             content_type,
             content_disposition: Some(content_disposition),
         };
@@ -136,8 +133,13 @@ pub async fn get_thumbnail(
     })) = crate::data::media::get_metadata(server_name, &args.media_id)
     {
         // Generate a thumbnail
-        let image_path = get_media_path(server_name, &args.media_id);
-        if let Ok(image) = image::open(&image_path) {
+        let Some(image_data) = storage()
+            .get(&media_key(server_name, &args.media_id))
+            .await?
+        else {
+            return Err(MatrixError::not_found("file not found").into());
+        };
+        if let Ok(image) = image::load_from_memory(&image_data) {
             let original_width = image.width();
             let original_height = image.height();
             if width > original_width || height > original_height {
@@ -147,7 +149,7 @@ pub async fn get_thumbnail(
                     None,
                 );
                 let content = Content {
-                    file: fs::read(&image_path)?,
+                    file: image_data,
                     content_type,
                     content_disposition: Some(content_disposition),
                 };
@@ -215,8 +217,7 @@ pub async fn get_thumbnail(
                     created_at: UnixMillis::now(),
                 })
                 .execute(&mut connect()?)?;
-            let mut f = File::create(&thumb_path).await?;
-            f.write_all(&thumbnail_bytes).await?;
+            storage().put(&thumb_key, &thumbnail_bytes).await?;
 
             let content_disposition = make_content_disposition(
                 Some(ContentDispositionType::Inline),
@@ -237,7 +238,7 @@ pub async fn get_thumbnail(
         } else {
             let content_disposition = make_content_disposition(None, content_type.as_deref(), None);
             let content = Content {
-                file: fs::read(&image_path)?,
+                file: image_data,
                 content_type,
                 content_disposition: Some(content_disposition),
             };
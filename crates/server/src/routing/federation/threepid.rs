@@ -1,13 +1,22 @@
+use salvo::oapi::extract::JsonBody;
 use salvo::prelude::*;
 
-use crate::{AuthArgs, EmptyResult, empty_ok};
+use crate::core::federation::third_party::BindCallbackReqBody;
+use crate::{AuthArgs, EmptyResult, IsRemoteOrLocal, MatrixError, data, empty_ok};
 
 pub fn router() -> Router {
     Router::with_path("3pid/onbind").put(on_bind)
 }
 
+/// #PUT /_matrix/federation/v1/3pid/onbind
+/// Called by identity servers when a threepid with pending invites got bound
+/// to one of our users.
 #[endpoint]
-async fn on_bind(_aa: AuthArgs) -> EmptyResult {
-    // TODO: todo
+async fn on_bind(_aa: AuthArgs, body: JsonBody<BindCallbackReqBody>) -> EmptyResult {
+    let body = body.into_inner();
+    if body.mxid.server_name().is_remote() || !data::user::user_exists(&body.mxid)? {
+        return Err(MatrixError::invalid_param("user is not a local user").into());
+    }
+    crate::membership::on_bind(&body.mxid, body.invites).await;
     empty_ok()
 }
@@ -20,7 +20,9 @@ use crate::core::to_device::DeviceIdOrAllDevices;
 use crate::data::user::NewDbPresence;
 use crate::event::{handler, parse_incoming_pdu};
 use crate::sending::{EDU_LIMIT, PDU_LIMIT};
-use crate::{AppError, AppResult, DepotExt, JsonResult, MatrixError, data, json_ok, room};
+use crate::{
+    AppError, AppResult, DepotExt, IsRemoteOrLocal, JsonResult, MatrixError, data, json_ok, room,
+};
 
 pub fn router() -> Router {
     Router::with_path("send/{txn_id}").put(send_message)
@@ -61,7 +63,7 @@ async fn send_message(
     }
 
     let txn_start_time = Instant::now();
-    let resolved_map = process_pdus(&body.pdus, &body.origin, &txn_start_time).await?;
+    let resolved_map = process_pdus(body.pdus, &body.origin, &txn_start_time).await?;
     process_edus(body.edus, &body.origin).await;
 
     json_ok(SendMessageResBody {
@@ -73,20 +75,21 @@ async fn send_message(
 }
 
 async fn process_pdus(
-    pdus: &[Box<RawJsonValue>],
+    pdus: Vec<Box<RawJsonValue>>,
     origin: &ServerName,
     txn_start_time: &Instant,
 ) -> AppResult<BTreeMap<OwnedEventId, AppResult<()>>> {
-    let mut parsed_pdus = Vec::with_capacity(pdus.len());
-    for pdu in pdus {
-        parsed_pdus.push(match parse_incoming_pdu(pdu) {
-            Ok(t) => t,
-            Err(e) => {
-                warn!("could not parse pdu: {e}");
-                continue;
-            }
-        });
-    }
+    // Parsing looks up the room versions in the database
+    let parsed_pdus = crate::utils::spawn_blocking(move || {
+        pdus.iter()
+            .filter_map(|pdu| {
+                parse_incoming_pdu(pdu)
+                    .inspect_err(|e| warn!("could not parse pdu: {e}"))
+                    .ok()
+            })
+            .collect::<Vec<_>>()
+    })
+    .await;
     let mut resolved_map = BTreeMap::new();
     for (event_id, value, room_id, room_version_id) in parsed_pdus {
         // crate::server::check_running()?;
@@ -166,6 +169,9 @@ async fn process_edu_presence(origin: &ServerName, presence: PresenceContent) {
             true,
         )
         .ok();
+        if let Ok(presence) = crate::data::user::last_presence(&update.user_id) {
+            crate::appservice::send_presence(&presence).ok();
+        }
     }
 }
 
@@ -311,7 +317,7 @@ async fn process_edu_direct_to_device(origin: &ServerName, content: DirectDevice
             let ev_type = ev_type.to_string();
             match target_device_id_maybe {
                 DeviceIdOrAllDevices::DeviceId(target_device_id) => {
-                    let _ = data::user::device::add_to_device_event(
+                    let _ = crate::user::add_to_device_event(
                         &sender,
                         target_user_id,
                         target_device_id,
@@ -326,7 +332,7 @@ async fn process_edu_direct_to_device(origin: &ServerName, content: DirectDevice
                         .unwrap_or_default()
                         .iter()
                         .for_each(|target_device_id| {
-                            let _ = data::user::device::add_to_device_event(
+                            let _ = crate::user::add_to_device_event(
                                 sender,
                                 target_user_id,
                                 target_device_id,
@@ -351,6 +357,16 @@ async fn process_edu_signing_key_update(origin: &ServerName, content: SigningKey
     } = content;
 
     if user_id.server_name() != origin {
+        // Users of the origin signed the master key of a local user.
+        if user_id.is_local()
+            && let Some(master_key) = master_key
+            && master_key.user_id == user_id
+        {
+            if let Err(e) = crate::user::add_remote_master_key_signatures(origin, &master_key) {
+                warn!(%user_id, %origin, "failed to store master key signatures: {e}");
+            }
+            return;
+        }
         warn!(
             %user_id, %origin,
             "received signing key update EDU from server that does not belong to user's server"
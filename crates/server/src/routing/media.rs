@@ -10,11 +10,12 @@ pub fn router() -> Router {
             .push(
                 Router::with_path(v)
                     .hoop(hoops::auth_by_access_token)
-                    .push(Router::with_path("create").post(create_mxc_uri))
                     .push(
-                        Router::with_path("upload").post(create_content).push(
-                            Router::with_path("{server_name}/{media_id}").put(upload_content),
-                        ),
+                        Router::with_hoop(hoops::limit_rate_media_upload)
+                            .push(Router::with_path("create").post(create_mxc_uri))
+                            .push(Router::with_path("upload").post(create_content).push(
+                                Router::with_path("{server_name}/{media_id}").put(upload_content),
+                            )),
                     )
                     .push(
                         Router::with_hoop(hoops::limit_rate)
@@ -0,0 +1,231 @@
+//! Persistent scheduler for long running admin operations.
+//!
+//! Tasks are stored in the `scheduled_tasks` table before they run and record
+//! their progress, result and error there, so they can be listed through the
+//! admin API. A running task is leased to the process running it, which renews
+//! the lease while the task runs. Tasks whose process stopped renewing it are
+//! taken over by another process, or on the next start, so every action must
+//! be safe to re-run.
+
+mod action;
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use tokio::sync::{Semaphore, mpsc};
+
+use crate::coordination::{self, Notification};
+use crate::core::UnixMillis;
+use crate::core::serde::JsonValue;
+use crate::data::scheduled_task::{DbScheduledTask, STATUS_ACTIVE};
+use crate::{AppResult, data, utils};
+pub use action::*;
+
+/// How many tasks run at the same time, the others wait for a free slot.
+const MAX_RUNNING_TASKS: usize = 4;
+
+/// How often finished tasks are checked for pruning.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long finished tasks are kept around to be listed.
+const FINISHED_TASK_RETENTION: u64 = 7 * 24 * 60 * 60 * 1000;
+
+/// How often the table is checked for tasks other processes scheduled, in
+/// case their notification was lost.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a running task stays claimed without its lease being renewed.
+const TASK_LEASE: Duration = Duration::from_secs(2 * 60);
+
+/// How often the lease of a running task is renewed.
+const LEASE_RENEW_INTERVAL: Duration = Duration::from_secs(30);
+
+static TASK_SENDER: OnceLock<mpsc::UnboundedSender<String>> = OnceLock::new();
+
+pub fn start() {
+    // Load the tasks to resume before accepting new ones, so none runs twice.
+    let unfinished = match claimable_tasks() {
+        Ok(tasks) => tasks,
+        Err(e) => {
+            error!("failed to load unfinished scheduled tasks: {e}");
+            Vec::new()
+        }
+    };
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let _ = TASK_SENDER.set(sender);
+
+    tokio::spawn(async move {
+        let runner = Runner {
+            slots: Arc::new(Semaphore::new(MAX_RUNNING_TASKS)),
+            pending: Arc::new(Mutex::new(HashSet::new())),
+        };
+        for task in unfinished {
+            runner.resume_task(task);
+        }
+
+        let mut prune_interval = tokio::time::interval(PRUNE_INTERVAL);
+        let mut rescan_interval = tokio::time::interval(RESCAN_INTERVAL);
+        rescan_interval.reset();
+        loop {
+            tokio::select! {
+                Some(task_id) = receiver.recv() => runner.spawn_task(task_id),
+                _ = rescan_interval.tick() => {
+                    // Catches up on lost notifications, see
+                    // `coordination::start`, and takes over the tasks of
+                    // processes that stopped.
+                    match claimable_tasks() {
+                        Ok(tasks) => {
+                            for task in tasks {
+                                runner.resume_task(task);
+                            }
+                        }
+                        Err(e) => warn!("failed to check for scheduled tasks: {e}"),
+                    }
+                }
+                _ = prune_interval.tick() => {
+                    let before_ts = UnixMillis::now().get().saturating_sub(FINISHED_TASK_RETENTION);
+                    if let Err(e) = data::scheduled_task::delete_finished_tasks(before_ts as i64) {
+                        warn!("failed to prune finished scheduled tasks: {e}");
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Tasks waiting to run and those whose lease expired.
+fn claimable_tasks() -> AppResult<Vec<DbScheduledTask>> {
+    Ok(data::scheduled_task::claimable_tasks(
+        UnixMillis::now().get() as i64,
+    )?)
+}
+
+/// Stores a new task and hands it to the runner.
+pub fn schedule(
+    action: &str,
+    resource_id: Option<String>,
+    params: JsonValue,
+) -> AppResult<DbScheduledTask> {
+    let task = DbScheduledTask::new(
+        utils::random_string(16),
+        action.to_owned(),
+        resource_id,
+        Some(params),
+    );
+    data::scheduled_task::create_task(&task)?;
+    // Without a runner, as before it is started or in processes without the
+    // background role, the task is left to the process running them.
+    if let Some(sender) = TASK_SENDER.get() {
+        let _ = sender.send(task.id.clone());
+    } else if let Err(e) = coordination::publish(Notification::TaskScheduled {
+        task_id: task.id.clone(),
+    }) {
+        warn!("failed to notify the task runner of task {}: {e}", task.id);
+    }
+    Ok(task)
+}
+
+/// Runs a task another process scheduled, if this one runs tasks.
+pub(crate) fn enqueue(task_id: String) {
+    if let Some(sender) = TASK_SENDER.get() {
+        let _ = sender.send(task_id);
+    }
+}
+
+/// Records the progress of a running task, failures are only logged.
+pub(crate) fn set_progress(task_id: &str, progress: JsonValue) {
+    if let Err(e) = data::scheduled_task::set_task_progress(task_id, &progress) {
+        warn!("failed to record progress of scheduled task {task_id}: {e}");
+    }
+}
+
+struct Runner {
+    slots: Arc<Semaphore>,
+    /// Tasks waiting for a slot or running in this process.
+    pending: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Runner {
+    /// Runs a task that is waiting or whose process stopped running it.
+    fn resume_task(&self, task: DbScheduledTask) {
+        if task.status == STATUS_ACTIVE {
+            info!("resuming scheduled task {} ({})", task.id, task.action);
+        }
+        self.spawn_task(task.id);
+    }
+
+    /// Runs the task once a slot is free.
+    fn spawn_task(&self, task_id: String) {
+        if !self
+            .pending
+            .lock()
+            .expect("locking should not fail")
+            .insert(task_id.clone())
+        {
+            return;
+        }
+        let slots = self.slots.clone();
+        let pending = self.pending.clone();
+        tokio::spawn(async move {
+            if let Ok(_slot) = slots.acquire_owned().await
+                && let Err(e) = run_task(&task_id).await
+            {
+                error!("failed to run scheduled task {task_id}: {e}");
+            }
+            pending
+                .lock()
+                .expect("locking should not fail")
+                .remove(&task_id);
+        });
+    }
+}
+
+/// Runs the task if this process claims it.
+async fn run_task(task_id: &str) -> AppResult<()> {
+    let owner = coordination::instance_id();
+    let now = UnixMillis::now().get() as i64;
+    // Claimed in one statement, so another process can't run it as well.
+    let Some(task) = data::scheduled_task::claim_task(task_id, owner, now, lease_until())? else {
+        return Ok(());
+    };
+
+    // Keep the task ours while it runs, so other processes don't take it over.
+    let lease_task_id = task_id.to_owned();
+    let lease = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(LEASE_RENEW_INTERVAL);
+        interval.reset();
+        loop {
+            interval.tick().await;
+            match data::scheduled_task::renew_task_lease(&lease_task_id, owner, lease_until()) {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!("lost the lease of scheduled task {lease_task_id}");
+                    break;
+                }
+                Err(e) => warn!("failed to renew the lease of scheduled task {lease_task_id}: {e}"),
+            }
+        }
+    });
+
+    // Run on its own task so a panicking action only fails itself.
+    let action = task.action.clone();
+    let result = match tokio::spawn(action::run(task)).await {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(e)) => {
+            warn!("scheduled task {task_id} ({action}) failed: {e}");
+            Err(e.to_string())
+        }
+        Err(e) => {
+            error!("scheduled task {task_id} ({action}) panicked: {e}");
+            Err(format!("task aborted: {e}"))
+        }
+    };
+    lease.abort();
+    data::scheduled_task::finish_task(task_id, owner, result)?;
+    Ok(())
+}
+
+fn lease_until() -> i64 {
+    (UnixMillis::now().get() + TASK_LEASE.as_millis() as u64) as i64
+}
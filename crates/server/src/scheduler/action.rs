@@ -0,0 +1,368 @@
+//! The actions scheduled tasks run, with their parameters and results.
+
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::config::MediaStorageKind;
+use crate::core::Mxc;
+use crate::core::identifiers::*;
+use crate::core::serde::JsonValue;
+use crate::data::connect;
+use crate::data::room::PurgeHistoryFilter;
+use crate::data::scheduled_task::DbScheduledTask;
+use crate::data::schema::*;
+use crate::exts::IsRemoteOrLocal;
+use crate::media::{build_storage, media_key, thumbnail_key};
+use crate::{AppError, AppResult, config, data, media, membership, room, user, utils};
+
+pub const SHUTDOWN_AND_PURGE_ROOM: &str = "shutdown_and_purge_room";
+pub const PURGE_REMOTE_MEDIA: &str = "purge_remote_media";
+pub const DELETE_OLD_LOCAL_MEDIA: &str = "delete_old_local_media";
+pub const DEACTIVATE_USER: &str = "deactivate_user";
+pub const FORCE_JOIN_USERS: &str = "force_join_users";
+pub const PURGE_HISTORY: &str = "purge_history";
+pub const MIGRATE_MEDIA_STORAGE: &str = "migrate_media_storage";
+
+const SHUTDOWN_LEAVE_REASON: &str = "This room has been shut down by the server admin.";
+const BULK_JOIN_REASON: &str = "Bulk force joining this room as initiated by the server admin.";
+
+/// How many media files are copied between two progress updates.
+const MIGRATE_BATCH_SIZE: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ShutdownRoomParams {
+    #[serde(default)]
+    pub block: bool,
+    #[serde(default)]
+    pub purge: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ShutdownRoomResult {
+    pub kicked_users: Vec<OwnedUserId>,
+    pub failed_to_kick_users: Vec<OwnedUserId>,
+    pub local_aliases: Vec<OwnedRoomAliasId>,
+    pub new_room_id: Option<OwnedRoomId>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PurgeRemoteMediaParams {
+    pub before_ts: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PurgeRemoteMediaResult {
+    pub deleted: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteOldLocalMediaParams {
+    pub before_ts: i64,
+    #[serde(default)]
+    pub size_gt: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteOldLocalMediaResult {
+    pub deleted_media: Vec<String>,
+    pub total: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DeactivateUserParams {
+    #[serde(default)]
+    pub erase: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForceJoinUsersParams {
+    pub user_ids: Vec<OwnedUserId>,
+    #[serde(default)]
+    pub servers: Vec<OwnedServerName>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ForceJoinUsersResult {
+    pub successful_joins: usize,
+    pub failed_joins: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PurgeHistoryParams {
+    #[serde(default)]
+    pub before_sn: Option<i64>,
+    #[serde(default)]
+    pub before_ts: Option<i64>,
+    #[serde(default)]
+    pub delete_local_events: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PurgeHistoryResult {
+    pub purged_events: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MigrateMediaStorageParams {
+    pub from: MediaStorageKind,
+    pub to: MediaStorageKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MigrateMediaStorageResult {
+    pub total: usize,
+    pub copied: usize,
+    pub present: usize,
+    pub missing: usize,
+    pub failed: usize,
+}
+
+pub(super) async fn run(task: DbScheduledTask) -> AppResult<Option<JsonValue>> {
+    let params = task.params.clone().unwrap_or_else(|| json!({}));
+    let result = match &*task.action {
+        SHUTDOWN_AND_PURGE_ROOM => {
+            let room_id = OwnedRoomId::try_from(resource_id(&task)?)?;
+            let result =
+                shutdown_and_purge_room(&task.id, &room_id, serde_json::from_value(params)?)
+                    .await?;
+            serde_json::to_value(result)?
+        }
+        PURGE_REMOTE_MEDIA => {
+            let params: PurgeRemoteMediaParams = serde_json::from_value(params)?;
+            let deleted =
+                data::media::purge_remote_media_cache(config::server_name(), params.before_ts)?;
+            serde_json::to_value(PurgeRemoteMediaResult { deleted })?
+        }
+        DELETE_OLD_LOCAL_MEDIA => {
+            let params: DeleteOldLocalMediaParams = serde_json::from_value(params)?;
+            let (deleted_media, total) = data::media::delete_old_local_media(
+                config::server_name(),
+                params.before_ts,
+                params.size_gt,
+            )?;
+            serde_json::to_value(DeleteOldLocalMediaResult {
+                deleted_media,
+                total,
+            })?
+        }
+        DEACTIVATE_USER => {
+            let user_id = OwnedUserId::try_from(resource_id(&task)?)?;
+            deactivate_user(&user_id, serde_json::from_value(params)?).await?;
+            return Ok(None);
+        }
+        FORCE_JOIN_USERS => {
+            let room_id = OwnedRoomId::try_from(resource_id(&task)?)?;
+            let result =
+                force_join_users(&task.id, &room_id, serde_json::from_value(params)?).await?;
+            serde_json::to_value(result)?
+        }
+        PURGE_HISTORY => {
+            let room_id = OwnedRoomId::try_from(resource_id(&task)?)?;
+            let params: PurgeHistoryParams = serde_json::from_value(params)?;
+            let filter = PurgeHistoryFilter {
+                before_sn: params.before_sn,
+                before_ts: params.before_ts,
+                keep_server: (!params.delete_local_events)
+                    .then(|| config::server_name().to_owned()),
+            };
+            let purged_events = room::retention::purge_history(&room_id, filter).await?;
+            serde_json::to_value(PurgeHistoryResult { purged_events })?
+        }
+        MIGRATE_MEDIA_STORAGE => {
+            let result = migrate_media_storage(&task.id, serde_json::from_value(params)?).await?;
+            serde_json::to_value(result)?
+        }
+        action => {
+            return Err(AppError::internal(format!(
+                "unknown scheduled task action: {action}"
+            )));
+        }
+    };
+    Ok(Some(result))
+}
+
+fn resource_id(task: &DbScheduledTask) -> AppResult<&str> {
+    task.resource_id
+        .as_deref()
+        .ok_or_else(|| AppError::internal(format!("{} task without resource id", task.action)))
+}
+
+/// Makes the local members leave the room, disables it, and removes its
+/// events and state if `purge` is set.
+async fn shutdown_and_purge_room(
+    task_id: &str,
+    room_id: &RoomId,
+    params: ShutdownRoomParams,
+) -> AppResult<ShutdownRoomResult> {
+    super::set_progress(task_id, json!({ "phase": "shutting_down" }));
+
+    if params.block {
+        room::ban_room(room_id, true)?;
+    }
+
+    let mut result = ShutdownRoomResult::default();
+    for user_id in room::joined_users(room_id, None)? {
+        if user_id.server_name().is_remote() {
+            continue;
+        }
+        match membership::leave_room(&user_id, room_id, Some(SHUTDOWN_LEAVE_REASON.to_owned()))
+            .await
+        {
+            Ok(_) => result.kicked_users.push(user_id),
+            Err(e) => {
+                warn!("failed to remove {user_id} from shut down room {room_id}: {e}");
+                result.failed_to_kick_users.push(user_id);
+            }
+        }
+    }
+
+    result.local_aliases = room::local_aliases_for_room(room_id)?;
+    diesel::delete(room_aliases::table.filter(room_aliases::room_id.eq(room_id)))
+        .execute(&mut connect()?)?;
+
+    if room::room_exists(room_id)? {
+        room::disable_room(room_id, true)?;
+    }
+
+    if params.purge {
+        // Events are purged in batches, so a restarted task continues where it stopped.
+        let mut purged_events = 0;
+        loop {
+            super::set_progress(
+                task_id,
+                json!({ "phase": "purging", "purged_events": purged_events }),
+            );
+            let batch_room_id = room_id.to_owned();
+            let purged =
+                utils::spawn_blocking(move || data::room::purge_room_events(&batch_room_id))
+                    .await?;
+            if purged == 0 {
+                break;
+            }
+            purged_events += purged;
+        }
+        let room_id = room_id.to_owned();
+        utils::spawn_blocking(move || data::room::purge_room(&room_id)).await?;
+    }
+    Ok(result)
+}
+
+async fn deactivate_user(user_id: &UserId, params: DeactivateUserParams) -> AppResult<()> {
+    let joined_rooms = data::user::joined_rooms(user_id)?;
+    user::full_user_deactivate(user_id, &joined_rooms).await?;
+
+    if params.erase {
+        user::delete_all_media(user_id).await?;
+    }
+    Ok(())
+}
+
+/// Joins the users to the room, skipping those that are joined already.
+async fn force_join_users(
+    task_id: &str,
+    room_id: &RoomId,
+    params: ForceJoinUsersParams,
+) -> AppResult<ForceJoinUsersResult> {
+    let mut result = ForceJoinUsersResult::default();
+    for user_id in &params.user_ids {
+        if room::user::is_joined(user_id, room_id)? {
+            result.successful_joins = result.successful_joins.saturating_add(1);
+            continue;
+        }
+        let user = data::user::get_user(user_id)?;
+        match membership::join_room(
+            &user,
+            None,
+            room_id,
+            Some(BULK_JOIN_REASON.to_owned()),
+            &params.servers,
+            None,
+            None,
+            Default::default(),
+        )
+        .await
+        {
+            Ok(_) => result.successful_joins = result.successful_joins.saturating_add(1),
+            Err(e) => {
+                warn!("Failed force joining {user_id} to {room_id} during bulk join: {e}");
+                result.failed_joins = result.failed_joins.saturating_add(1);
+            }
+        }
+        super::set_progress(task_id, serde_json::to_value(&result)?);
+    }
+    Ok(result)
+}
+
+/// Copies all media files and thumbnails from one storage backend to another,
+/// one file at a time. Files already present in the destination are skipped,
+/// so a restarted task continues where it stopped.
+async fn migrate_media_storage(
+    task_id: &str,
+    params: MigrateMediaStorageParams,
+) -> AppResult<MigrateMediaStorageResult> {
+    let source = build_storage(params.from)?;
+    let dest = build_storage(params.to)?;
+
+    let mut keys = Vec::new();
+    for mxc in media::get_all_mxcs()? {
+        let Mxc {
+            server_name,
+            media_id,
+        } = mxc.as_str().try_into()?;
+        keys.push(media_key(server_name, media_id));
+    }
+    for thumbnail in data::media::get_all_thumbnails()? {
+        let server_name = &thumbnail.origin_server;
+        keys.push(thumbnail_key(
+            server_name,
+            &thumbnail.media_id,
+            thumbnail.id,
+        ));
+        keys.push(media_key(
+            server_name,
+            &format!(
+                "{}.{}x{}",
+                thumbnail.media_id, thumbnail.width, thumbnail.height
+            ),
+        ));
+    }
+
+    let mut result = MigrateMediaStorageResult {
+        total: keys.len(),
+        ..Default::default()
+    };
+    for batch in keys.chunks(MIGRATE_BATCH_SIZE) {
+        super::set_progress(task_id, serde_json::to_value(&result)?);
+        for key in batch {
+            match dest.exists(key).await {
+                Ok(true) => {
+                    result.present += 1;
+                    continue;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    warn!("failed to check {key} in destination storage: {e}");
+                    result.failed += 1;
+                    continue;
+                }
+            }
+            match source.get_stream(key).await {
+                Ok(Some(chunks)) => {
+                    if let Err(e) = dest.put_stream(key, chunks).await {
+                        warn!("failed to copy {key}: {e}");
+                        result.failed += 1;
+                    } else {
+                        result.copied += 1;
+                    }
+                }
+                Ok(None) => result.missing += 1,
+                Err(e) => {
+                    warn!("failed to read {key} from source storage: {e}");
+                    result.failed += 1;
+                }
+            }
+        }
+    }
+    Ok(result)
+}
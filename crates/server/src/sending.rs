@@ -1,3 +1,4 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Debug;
 use std::sync::{Arc, OnceLock, RwLock};
 use std::time::{Duration, Instant};
@@ -8,12 +9,13 @@ use diesel::prelude::*;
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
 use reqwest_retry::RetryTransientMiddleware;
 use reqwest_retry::policies::ExponentialBackoff;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::value::to_raw_value;
 use tokio::sync::{Mutex, Semaphore, mpsc};
 
+use crate::appservice::AppserviceEdu;
 use crate::core::appservice::Registration;
-use crate::core::appservice::event::{PushEventsReqBody, push_events_request};
+use crate::core::appservice::event::{DeviceLists, PushEventsReqBody, push_events_request};
 use crate::core::events::GlobalAccountDataEventType;
 use crate::core::events::push_rules::PushRulesEventContent;
 use crate::core::federation::transaction::{
@@ -21,18 +23,22 @@ use crate::core::federation::transaction::{
 };
 use crate::core::identifiers::*;
 pub use crate::core::sending::*;
-use crate::core::serde::{CanonicalJsonObject, RawJsonValue};
+use crate::core::serde::{CanonicalJsonObject, RawJson, RawJsonValue};
 use crate::core::{UnixMillis, push};
 use crate::data::connect;
 use crate::data::schema::*;
 use crate::data::sending::{DbOutgoingRequest, NewDbOutgoingRequest};
 use crate::room::timeline;
-use crate::{AppError, AppResult, GetUrlOrigin, ServerConfig, TlsNameMap, config, data, utils};
+use crate::{
+    AppError, AppResult, GetUrlOrigin, IsRemoteOrLocal, ServerConfig, TlsNameMap, config, data,
+    utils,
+};
 
 mod dest;
 pub use dest::*;
 pub mod guard;
 pub mod resolver;
+pub mod retry;
 
 const SELECT_PRESENCE_LIMIT: usize = 256;
 const SELECT_RECEIPT_LIMIT: usize = 256;
@@ -62,7 +68,7 @@ pub fn sender() -> mpsc::UnboundedSender<(OutgoingKind, SendingEventType, i64)>
     MPSC_SENDER.get().expect("sender should set").clone()
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum OutgoingKind {
     Appservice(String),
     Push(OwnedUserId, String), // user and pushkey
@@ -110,7 +116,7 @@ pub fn default_client() -> reqwest::Client {
     static DEFAULT_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
     DEFAULT_CLIENT
         .get_or_init(|| {
-            reqwest_client_builder(crate::config::get())
+            reqwest_client_builder(&crate::config::get())
                 .expect("failed to build request clinet")
                 .build()
                 .expect("failed to build request clinet")
@@ -139,7 +145,7 @@ pub fn federation_client() -> ClientWithMiddleware {
                 )
                 .build_with_max_retries(conf.http_client.federation_retries);
 
-            let client = reqwest_client_builder(conf)
+            let client = reqwest_client_builder(&conf)
                 .expect("build reqwest client failed")
                 // .dns_resolver(Arc::new(Resolver::new(tls_name_override.clone())))
                 .timeout(Duration::from_secs(2 * 60))
@@ -192,18 +198,22 @@ pub fn send_pdu_servers<S: Iterator<Item = OwnedServerName>>(
     servers: S,
     pdu_id: &EventId,
 ) -> AppResult<()> {
-    let requests = servers
+    let servers = servers.filter(|server| {
+        if *server == config::get().server_name {
+            warn!("not sending pdu to ourself: {server}");
+            false
+        } else {
+            true
+        }
+    });
+    // Destinations that are down get the PDUs they missed once they are back.
+    let requests = retry::track_pdu(servers, pdu_id)?
         .into_iter()
-        .filter_map(|server| {
-            if server == config::get().server_name {
-                warn!("not sending pdu to ourself: {server}");
-                None
-            } else {
-                Some((
-                    OutgoingKind::Normal(server),
-                    SendingEventType::Pdu(pdu_id.to_owned()),
-                ))
-            }
+        .map(|server| {
+            (
+                OutgoingKind::Normal(server),
+                SendingEventType::Pdu(pdu_id.to_owned()),
+            )
         })
         .collect::<Vec<_>>();
 
@@ -232,6 +242,12 @@ pub fn send_edu_room(room_id: &RoomId, edu: &Edu) -> AppResult<()> {
     send_edu_servers(servers.into_iter(), edu)
 }
 
+/// Whether the EDU is stale by the time a down destination is back, so it is
+/// not queued for it. Device list and signing key updates are kept.
+fn is_ephemeral(edu: &Edu) -> bool {
+    matches!(edu, Edu::Presence(_) | Edu::Receipt(_) | Edu::Typing(_))
+}
+
 #[tracing::instrument(skip(servers, edu), level = "debug")]
 pub fn send_edu_servers<S: Iterator<Item = OwnedServerName>>(
     servers: S,
@@ -240,15 +256,16 @@ pub fn send_edu_servers<S: Iterator<Item = OwnedServerName>>(
     let mut serialized = EduBuf::new();
     serde_json::to_writer(&mut serialized, &edu).expect("serialized edu");
 
-    let requests = servers
-        .into_iter()
-        .map(|server| {
-            (
-                OutgoingKind::Normal(server),
-                SendingEventType::Edu(serialized.to_owned()),
-            )
-        })
-        .collect::<Vec<_>>();
+    let mut requests = Vec::new();
+    for server in servers {
+        if is_ephemeral(edu) && retry::is_down(&server)? {
+            continue;
+        }
+        requests.push((
+            OutgoingKind::Normal(server),
+            SendingEventType::Edu(serialized.to_owned()),
+        ));
+    }
     let keys = queue_requests(
         &requests
             .iter()
@@ -265,6 +282,9 @@ pub fn send_edu_servers<S: Iterator<Item = OwnedServerName>>(
 }
 #[tracing::instrument(skip(server, edu), level = "debug")]
 pub fn send_edu_server(server: &ServerName, edu: &Edu) -> AppResult<()> {
+    if is_ephemeral(edu) && retry::is_down(server)? {
+        return Ok(());
+    }
     let mut serialized = EduBuf::new();
     serde_json::to_writer(&mut serialized, &edu).expect("serialized edu");
 
@@ -305,6 +325,18 @@ pub fn send_pdu_appservice(appservice_id: String, pdu_id: &EventId) -> AppResult
     Ok(())
 }
 
+#[tracing::instrument(skip(edu))]
+pub fn send_edu_appservice(appservice_id: String, edu: &AppserviceEdu) -> AppResult<()> {
+    let outgoing_kind = OutgoingKind::Appservice(appservice_id);
+    let event = SendingEventType::Edu(serde_json::to_vec(edu)?);
+    let key = queue_request(&outgoing_kind, &event)?;
+    sender()
+        .send((outgoing_kind, event, key))
+        .map_err(|e| AppError::internal(e.to_string()))?;
+
+    Ok(())
+}
+
 #[tracing::instrument(skip(events, kind))]
 async fn send_events(
     kind: OutgoingKind,
@@ -312,26 +344,6 @@ async fn send_events(
 ) -> Result<OutgoingKind, (OutgoingKind, AppError)> {
     match &kind {
         OutgoingKind::Appservice(id) => {
-            let mut pdu_jsons = Vec::new();
-            for event in &events {
-                match event {
-                    SendingEventType::Pdu(event_id) => {
-                        pdu_jsons.push(
-                            timeline::get_pdu(event_id)
-                                .map_err(|e| (kind.clone(), e))?
-                                .to_room_event(),
-                        );
-                    }
-                    SendingEventType::Edu(_) => {
-                        // Appservices don't need EDUs (?)
-                    }
-                    SendingEventType::Flush => {}
-                }
-            }
-
-            let max_request = crate::sending::max_request();
-            let permit = max_request.acquire().await;
-
             let registration = crate::appservice::get_registration(id)
                 .map_err(|e| (kind.clone(), e))?
                 .ok_or_else(|| {
@@ -342,10 +354,59 @@ async fn send_events(
                         ),
                     )
                 })?;
-            let req_body = PushEventsReqBody {
-                events: pdu_jsons.clone(),
-                to_device: vec![],
+
+            let mut req_body = PushEventsReqBody {
+                events: Vec::new(),
+                device_lists: DeviceLists::new(),
+                device_one_time_keys_count: BTreeMap::new(),
+                device_unused_fallback_key_types: BTreeMap::new(),
+                ephemeral: Vec::new(),
+                to_device: Vec::new(),
             };
+            // Local users whose device keys are reported with MSC3202.
+            let mut key_user_ids = BTreeSet::new();
+            for event in &events {
+                match event {
+                    SendingEventType::Pdu(event_id) => {
+                        let pdu = timeline::get_pdu(event_id).map_err(|e| (kind.clone(), e))?;
+                        if pdu.sender.is_local() {
+                            key_user_ids.insert(pdu.sender.clone());
+                        }
+                        req_body.events.push(pdu.to_room_event());
+                    }
+                    SendingEventType::Edu(edu) => match serde_json::from_slice(edu) {
+                        Ok(AppserviceEdu::Ephemeral(data)) => match serde_json::from_value(data) {
+                            Ok(data) => req_body.ephemeral.push(data),
+                            Err(e) => warn!("invalid ephemeral data for appservice {id}: {e}"),
+                        },
+                        Ok(AppserviceEdu::ToDevice(event)) => match RawJson::from_value(&event) {
+                            Ok(event) => {
+                                if let Ok(Some(user_id)) =
+                                    event.get_field::<OwnedUserId>("to_user_id")
+                                {
+                                    key_user_ids.insert(user_id);
+                                }
+                                req_body.to_device.push(event);
+                            }
+                            Err(e) => warn!("invalid to-device event for appservice {id}: {e}"),
+                        },
+                        Ok(AppserviceEdu::DeviceListChanged(user_id)) => {
+                            if !req_body.device_lists.changed.contains(&user_id) {
+                                req_body.device_lists.changed.push(user_id);
+                            }
+                        }
+                        Err(e) => warn!("invalid edu queued for appservice {id}: {e}"),
+                    },
+                    SendingEventType::Flush => {}
+                }
+            }
+            if registration.transaction_extensions {
+                crate::appservice::fill_device_keys(&mut req_body, &registration, key_user_ids)
+                    .map_err(|e| (kind.clone(), e))?;
+            }
+
+            let max_request = crate::sending::max_request();
+            let permit = max_request.acquire().await;
 
             let txn_id = &*general_purpose::URL_SAFE_NO_PAD.encode(utils::hash_keys(
                 events.iter().filter_map(|e| match e {
@@ -432,10 +493,12 @@ async fn send_events(
         OutgoingKind::Normal(server) => {
             let mut edu_jsons = Vec::new();
             let mut pdu_jsons = Vec::new();
+            let mut pdu_ids = Vec::new();
 
             for event in &events {
                 match event {
                     SendingEventType::Pdu(pdu_id) => {
+                        pdu_ids.push(pdu_id.clone());
                         // TODO: check room version and remove event_id if needed
                         let raw = crate::sending::convert_to_outgoing_federation_event(
                             timeline::get_pdu_json(pdu_id)
@@ -484,23 +547,44 @@ async fn send_events(
             )
             .map_err(|e| (kind.clone(), e.into()))?
             .into_inner();
+            let started = Instant::now();
             let response = crate::sending::send_federation_request(server, request, None)
                 .await
-                .map_err(|e| (kind.clone(), e))?
-                .json::<SendMessageResBody>()
-                .await
-                .map(|response| {
-                    for pdu in response.pdus {
-                        if pdu.1.is_err() {
-                            warn!("failed to send to {}: {:?}", server, pdu);
+                .map_err(|e| (kind.clone(), e));
+            let response = match response {
+                Ok(response) => response
+                    .json::<SendMessageResBody>()
+                    .await
+                    .map(|response| {
+                        for pdu in response.pdus {
+                            if pdu.1.is_err() {
+                                warn!("failed to send to {}: {:?}", server, pdu);
+                            }
                         }
-                    }
-                    kind.clone()
-                })
-                .map_err(|e| (kind, e.into()));
+                        kind.clone()
+                    })
+                    .map_err(|e| (kind.clone(), e.into())),
+                Err(e) => Err(e),
+            };
+            crate::metrics::FEDERATION_TRANSACTION_DURATION
+                .with_label_values(&[if response.is_ok() {
+                    "success"
+                } else {
+                    "failure"
+                }])
+                .observe(started.elapsed().as_secs_f64());
 
             drop(permit);
 
+            let recorded = if response.is_ok() {
+                retry::record_success(server, &pdu_ids)
+            } else {
+                retry::record_failure(server)
+            };
+            if let Err(e) = recorded {
+                error!("failed to record retry state of {server}: {e}");
+            }
+
             response
         }
     }
@@ -591,6 +675,38 @@ fn active_requests() -> AppResult<Vec<(i64, OutgoingKind, SendingEventType)>> {
         .collect())
 }
 
+/// The destinations with requests waiting to be sent.
+fn queued_kinds() -> AppResult<Vec<OutgoingKind>> {
+    let kinds = outgoing_requests::table
+        .filter(outgoing_requests::state.ne("pending"))
+        .select((
+            outgoing_requests::kind,
+            outgoing_requests::appservice_id,
+            outgoing_requests::user_id,
+            outgoing_requests::pushkey,
+            outgoing_requests::server_id,
+        ))
+        .distinct()
+        .load::<(
+            String,
+            Option<String>,
+            Option<OwnedUserId>,
+            Option<String>,
+            Option<OwnedServerName>,
+        )>(&mut connect()?)?;
+    Ok(kinds
+        .into_iter()
+        .filter_map(
+            |(kind, appservice_id, user_id, pushkey, server_id)| match kind.as_str() {
+                "appservice" => appservice_id.map(OutgoingKind::Appservice),
+                "push" => Some(OutgoingKind::Push(user_id?, pushkey?)),
+                "normal" => server_id.map(OutgoingKind::Normal),
+                _ => None,
+            },
+        )
+        .collect())
+}
+
 fn delete_request(id: i64) -> AppResult<()> {
     diesel::delete(outgoing_requests::table.find(id)).execute(&mut connect()?)?;
     Ok(())
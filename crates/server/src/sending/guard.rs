@@ -1,14 +1,18 @@
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::OnceLock;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 
 use futures_util::stream::{FuturesUnordered, StreamExt};
 use tokio::sync::{Mutex, mpsc};
+use tokio::task::JoinHandle;
 
 use super::{
     EduBuf, EduVec, MPSC_RECEIVER, MPSC_SENDER, OutgoingKind, SELECT_EDU_LIMIT,
     SELECT_PRESENCE_LIMIT, SELECT_RECEIPT_LIMIT, SendingEventType, TransactionStatus,
 };
+use crate::config::WorkerRole;
+use crate::coordination::{self, Notification};
 use crate::core::device::DeviceListUpdateContent;
 use crate::core::events::receipt::{ReceiptContent, ReceiptData, ReceiptMap, ReceiptType};
 use crate::core::federation::transaction::Edu;
@@ -17,30 +21,137 @@ use crate::core::presence::{PresenceContent, PresenceUpdate};
 use crate::core::{Seqnum, device_id};
 use crate::exts::*;
 use crate::room::state;
-use crate::{AppResult, data, room};
+use crate::{AppResult, config, data, room, worker};
 
+/// How long transactions in flight are waited for on shutdown. Those that
+/// don't finish in time are retried on the next start.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often down federation destinations are checked for an elapsed backoff.
+const RETRY_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often the queue is checked for requests other processes queued, in
+/// case their notification was lost.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many destinations are published in one notification, to stay below
+/// the size limit of notifications.
+const PUBLISH_CHUNK_SIZE: usize = 50;
+
+static PROCESS: std::sync::Mutex<Option<JoinHandle<()>>> = std::sync::Mutex::new(None);
+static WAKE_SENDER: OnceLock<mpsc::UnboundedSender<OutgoingKind>> = OnceLock::new();
+
+/// Starts sending the queued requests, or handing them to the processes with
+/// the federation sender role when this one doesn't have it.
 pub fn start() {
     let (sender, receiver) = mpsc::unbounded_channel();
     let _ = MPSC_SENDER.set(sender);
     let _ = MPSC_RECEIVER.set(Mutex::new(receiver));
-    tokio::spawn(async move {
-        process().await.unwrap();
+    if !worker::has_role(WorkerRole::FederationSender) {
+        tokio::spawn(forward());
+        return;
+    }
+    let (wake_sender, wake_receiver) = mpsc::unbounded_channel();
+    let _ = WAKE_SENDER.set(wake_sender);
+    let handle = tokio::spawn(async move {
+        process(wake_receiver).await.unwrap();
     });
+    *PROCESS.lock().expect("should locked") = Some(handle);
 }
 
-async fn process() -> AppResult<()> {
+/// Whether this process sends to the destination. Federation destinations
+/// are spread over the federation senders by the hash of their name, the
+/// first one sends to appservices and push gateways.
+pub fn is_owned(outgoing_kind: &OutgoingKind) -> bool {
+    if !worker::has_role(WorkerRole::FederationSender) {
+        return false;
+    }
+    let conf = &config::get().worker;
+    let partition = match outgoing_kind {
+        OutgoingKind::Normal(server) => fnv1a(server.as_bytes()) % conf.federation_sender_instances,
+        OutgoingKind::Appservice(_) | OutgoingKind::Push(..) => 0,
+    };
+    partition == conf.federation_sender_index
+}
+
+/// Sends the requests other processes queued for the destinations this one
+/// owns.
+pub fn wake(outgoing_kinds: Vec<OutgoingKind>) {
+    let Some(sender) = WAKE_SENDER.get() else {
+        return;
+    };
+    for outgoing_kind in outgoing_kinds {
+        if is_owned(&outgoing_kind) {
+            let _ = sender.send(outgoing_kind);
+        }
+    }
+}
+
+/// Stable across processes and builds, unlike the std hasher.
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Tells the federation senders about the requests queued by this process.
+async fn forward() {
+    let mut receiver = MPSC_RECEIVER
+        .get()
+        .expect("receiver should exist")
+        .lock()
+        .await;
+    while let Some((outgoing_kind, _, _)) = receiver.recv().await {
+        let mut outgoing_kinds = vec![outgoing_kind];
+        while let Ok((outgoing_kind, _, _)) = receiver.try_recv() {
+            outgoing_kinds.push(outgoing_kind);
+        }
+        publish_queued(outgoing_kinds);
+    }
+}
+
+fn publish_queued(outgoing_kinds: Vec<OutgoingKind>) {
+    let outgoing_kinds = outgoing_kinds
+        .into_iter()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    for chunk in outgoing_kinds.chunks(PUBLISH_CHUNK_SIZE) {
+        let notification = Notification::SendingQueued {
+            outgoing_kinds: chunk.to_vec(),
+        };
+        if let Err(e) = coordination::publish(notification) {
+            error!("failed to notify federation senders of queued requests: {e}");
+        }
+    }
+}
+
+/// Waits until the transactions in flight at shutdown have been flushed.
+pub async fn stopped() {
+    let handle = PROCESS.lock().expect("should locked").take();
+    if let Some(handle) = handle {
+        let _ = handle.await;
+    }
+}
+
+async fn process(mut wake_receiver: mpsc::UnboundedReceiver<OutgoingKind>) -> AppResult<()> {
     let mut receiver = MPSC_RECEIVER
         .get()
         .expect("receiver should exist")
         .lock()
         .await;
     let mut futures = FuturesUnordered::new();
+    let shutdown = crate::ROTATE.watch();
+    tokio::pin!(shutdown);
     let mut current_transaction_status = HashMap::<OutgoingKind, TransactionStatus>::new();
 
     // Retry requests we could not finish yet
     let mut initial_transactions = HashMap::<OutgoingKind, Vec<SendingEventType>>::new();
 
     for (id, outgoing_kind, event) in super::active_requests()? {
+        if !is_owned(&outgoing_kind) {
+            continue;
+        }
         let entry = initial_transactions
             .entry(outgoing_kind.clone())
             .or_default();
@@ -58,12 +169,57 @@ async fn process() -> AppResult<()> {
     }
 
     for (outgoing_kind, events) in initial_transactions {
+        if let OutgoingKind::Normal(server) = &outgoing_kind
+            && !super::retry::is_due(server)?
+        {
+            // Still backing off, the retry check picks it up once due.
+            current_transaction_status.insert(
+                outgoing_kind.clone(),
+                TransactionStatus::Failed(0, Instant::now()),
+            );
+            continue;
+        }
         current_transaction_status.insert(outgoing_kind.clone(), TransactionStatus::Running);
         futures.push(super::send_events(outgoing_kind.clone(), events));
     }
 
+    let mut retry_check = tokio::time::interval(RETRY_CHECK_INTERVAL);
+    let mut rescan = tokio::time::interval(RESCAN_INTERVAL);
     loop {
         tokio::select! {
+            _ = &mut shutdown => break,
+            _ = rescan.tick() => {
+                // Catches up on lost notifications, see `coordination::start`.
+                match super::queued_kinds() {
+                    Ok(outgoing_kinds) => wake(outgoing_kinds),
+                    Err(e) => error!("failed to check queued requests: {e}"),
+                }
+            }
+            _ = retry_check.tick() => {
+                for server in super::retry::due_destinations().unwrap_or_default() {
+                    let outgoing_kind = OutgoingKind::Normal(server.clone());
+                    if !is_owned(&outgoing_kind) || matches!(
+                        current_transaction_status.get(&outgoing_kind),
+                        Some(TransactionStatus::Running | TransactionStatus::Retrying(_))
+                    ) {
+                        continue;
+                    }
+                    let new_events = match super::retry::wake(&server) {
+                        Ok(new_events) => new_events,
+                        Err(e) => {
+                            error!("failed to wake destination {server}: {e}");
+                            continue;
+                        }
+                    };
+                    if let Ok(Some(events)) = select_events(
+                        &outgoing_kind,
+                        new_events,
+                        &mut current_transaction_status,
+                    ) {
+                        futures.push(super::send_events(outgoing_kind, events));
+                    }
+                }
+            }
             Some(response) = futures.next() => {
                 match response {
                     Ok(outgoing_kind) => {
@@ -88,6 +244,7 @@ async fn process() -> AppResult<()> {
                     }
                     Err((outgoing_kind, event)) => {
                         error!("failed to send event: {event:?}  outgoing_kind:{outgoing_kind:?}");
+                        crate::metrics::sending_failed(&outgoing_kind);
                         current_transaction_status.entry(outgoing_kind).and_modify(|e| *e = match e {
                             TransactionStatus::Running => {
                                 TransactionStatus::Failed(1, Instant::now())
@@ -104,6 +261,10 @@ async fn process() -> AppResult<()> {
                 };
             },
             Some((outgoing_kind, event, id)) = receiver.recv() => {
+                if !is_owned(&outgoing_kind) {
+                    publish_queued(vec![outgoing_kind]);
+                    continue;
+                }
                 if let Ok(Some(events)) = select_events(
                     &outgoing_kind,
                     vec![(id, event)],
@@ -112,6 +273,53 @@ async fn process() -> AppResult<()> {
                     futures.push(super::send_events(outgoing_kind, events));
                 }
             }
+            Some(outgoing_kind) = wake_receiver.recv() => {
+                // Requests stay queued while a transaction is running, and are
+                // picked up once it finished.
+                if matches!(
+                    current_transaction_status.get(&outgoing_kind),
+                    Some(TransactionStatus::Running | TransactionStatus::Retrying(_))
+                ) {
+                    continue;
+                }
+                let new_events = super::queued_requests(&outgoing_kind)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .take(30)
+                    .collect::<Vec<_>>();
+                if new_events.is_empty() {
+                    continue;
+                }
+                if let Ok(Some(events)) = select_events(
+                    &outgoing_kind,
+                    new_events,
+                    &mut current_transaction_status,
+                ) {
+                    futures.push(super::send_events(outgoing_kind, events));
+                }
+            }
+        }
+    }
+
+    info!(
+        "flushing {} outgoing transactions before shutdown",
+        futures.len()
+    );
+    let flush = async {
+        while let Some(response) = futures.next().await {
+            if let Ok(outgoing_kind) = response {
+                super::delete_all_active_requests_for(&outgoing_kind)?;
+            }
+        }
+        AppResult::Ok(())
+    };
+    match tokio::time::timeout(FLUSH_TIMEOUT, flush).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!(
+                "outgoing transactions did not finish in time, they will be retried on next start"
+            );
+            Ok(())
         }
     }
 }
@@ -125,6 +333,14 @@ fn select_events(
     let mut retry = false;
     let mut allow = true;
 
+    // Federation destinations keep their backoff in the database so it survives restarts.
+    let destination_due = match (outgoing_kind, current_transaction_status.get(outgoing_kind)) {
+        (OutgoingKind::Normal(server), Some(TransactionStatus::Failed(..))) => {
+            Some(super::retry::is_due(server)?)
+        }
+        _ => None,
+    };
+
     let entry = current_transaction_status.entry(outgoing_kind.clone());
 
     entry
@@ -139,7 +355,7 @@ fn select_events(
                     min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
                 }
 
-                if time.elapsed() < min_elapsed_duration {
+                if !destination_due.unwrap_or_else(|| time.elapsed() >= min_elapsed_duration) {
                     allow = false;
                 } else {
                     retry = true;
@@ -160,7 +376,9 @@ fn select_events(
         for (_, e) in super::active_requests_for(outgoing_kind)? {
             events.push(e);
         }
-    } else {
+    }
+    // Nothing to retry means the destination was woken up with new events.
+    if events.is_empty() {
         super::mark_as_active(&new_events)?;
         for (_, e) in new_events {
             events.push(e);
@@ -173,6 +391,10 @@ fn select_events(
         }
     }
 
+    if events.is_empty() {
+        current_transaction_status.remove(outgoing_kind);
+        return Ok(None);
+    }
     Ok(Some(events))
 }
 
@@ -397,3 +619,16 @@ pub fn select_edus(server_name: &ServerName) -> AppResult<(EduVec, i64)> {
 
     Ok((events, max_edu_sn))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destination_hash_is_stable() {
+        // Reference values of 32-bit FNV-1a, every sender must agree on them.
+        assert_eq!(fnv1a(b""), 0x811c_9dc5);
+        assert_eq!(fnv1a(b"a"), 0xe40c_292c);
+        assert_eq!(fnv1a(b"foobar"), 0xbf9c_f968);
+    }
+}
@@ -0,0 +1,281 @@
+//! Persistent retry state of federation destinations.
+//!
+//! A destination whose transaction failed is "down" until a later transaction
+//! succeeds. While it is down, new PDUs for it are not queued; only the latest
+//! PDU of each room is remembered, and once the destination is back it is sent
+//! those so it can fetch anything else it missed itself. Typing, receipt and
+//! presence EDUs are dropped for it, other EDUs wait in the queue.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{LazyLock, RwLock};
+use std::time::{Duration, Instant};
+
+use diesel::prelude::*;
+
+use super::{OutgoingKind, SendingEventType};
+use crate::core::UnixMillis;
+use crate::core::identifiers::*;
+use crate::data::connect;
+use crate::data::schema::*;
+use crate::data::sending::DbDestination;
+use crate::{AppResult, config, data};
+
+/// How long the cached down destinations are used before they are loaded
+/// again, so destinations marked by other processes are picked up.
+const DOWN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Destinations that are down, with when they were loaded from the database.
+static DOWN: LazyLock<RwLock<(HashMap<OwnedServerName, DbDestination>, Option<Instant>)>> =
+    LazyLock::new(Default::default);
+
+/// Get the retry state of the destination if it is down.
+fn down_destination(server: &ServerName) -> AppResult<Option<DbDestination>> {
+    {
+        let down = DOWN.read().expect("locking should not fail");
+        if down
+            .1
+            .is_some_and(|loaded_at| loaded_at.elapsed() < DOWN_REFRESH_INTERVAL)
+        {
+            return Ok(down.0.get(server).cloned());
+        }
+    }
+    let destinations = data::sending::down_destinations()?;
+    let mut down = DOWN.write().expect("locking should not fail");
+    down.0 = destinations
+        .into_iter()
+        .map(|destination| (destination.server_id.clone(), destination))
+        .collect();
+    down.1 = Some(Instant::now());
+    Ok(down.0.get(server).cloned())
+}
+
+/// Updates the cached state of the destination after it was saved.
+fn cache_destination(destination: &DbDestination) {
+    let mut down = DOWN.write().expect("locking should not fail");
+    if destination.is_down() {
+        down.0
+            .insert(destination.server_id.clone(), destination.clone());
+    } else {
+        down.0.remove(&destination.server_id);
+    }
+}
+
+/// Whether the last transaction to the destination failed.
+pub fn is_down(server: &ServerName) -> AppResult<bool> {
+    Ok(down_destination(server)?.is_some())
+}
+
+/// Whether the destination may be sent to now.
+pub fn is_due(server: &ServerName) -> AppResult<bool> {
+    let now = UnixMillis::now().get() as i64;
+    Ok(down_destination(server)?.is_none_or(|d| d.is_due(now)))
+}
+
+/// Get the destinations that are down and whose backoff has elapsed.
+pub fn due_destinations() -> AppResult<Vec<OwnedServerName>> {
+    let now = UnixMillis::now().get() as i64;
+    Ok(data::sending::down_destinations()?
+        .into_iter()
+        .filter(|d| d.is_due(now))
+        .map(|d| d.server_id)
+        .collect())
+}
+
+/// Resets the backoff of the destination, so it is retried on the next check.
+pub fn reset(server: &ServerName) -> AppResult<()> {
+    data::sending::reset_destination_retry(server)?;
+    if let Some(destination) = data::sending::get_destination(server)? {
+        cache_destination(&destination);
+    }
+    Ok(())
+}
+
+/// Remembers the PDU as the latest of its room for each destination, and
+/// returns the destinations it should be queued for right away.
+pub fn track_pdu<S: Iterator<Item = OwnedServerName>>(
+    servers: S,
+    pdu_id: &EventId,
+) -> AppResult<Vec<OwnedServerName>> {
+    let (room_id, event_sn) = events::table
+        .find(pdu_id)
+        .select((events::room_id, events::sn))
+        .first::<(OwnedRoomId, i64)>(&mut connect()?)?;
+
+    let servers = servers.collect::<Vec<_>>();
+    data::sending::set_destination_rooms(&servers, &room_id, pdu_id, event_sn)?;
+    let mut up_servers = Vec::with_capacity(servers.len());
+    for server in servers {
+        if !is_down(&server)? {
+            up_servers.push(server);
+        }
+    }
+    Ok(up_servers)
+}
+
+/// Records a failed transaction and extends the backoff of the destination.
+pub(super) fn record_failure(server: &ServerName) -> AppResult<()> {
+    let conf = &config::get().federation;
+    let min_interval = conf.min_retry_interval as i64;
+    let max_interval = (conf.max_retry_interval as i64).max(min_interval);
+    let now = UnixMillis::now().get() as i64;
+
+    let mut destination = data::sending::get_destination(server)?
+        .unwrap_or_else(|| DbDestination::new(server.to_owned()));
+    back_off(&mut destination, now, min_interval, max_interval);
+    data::sending::save_destination(&destination)?;
+    cache_destination(&destination);
+
+    warn!(
+        "destination {server} failed {} times in a row, retrying in {}s",
+        destination.failure_count,
+        destination.retry_interval / 1000
+    );
+    Ok(())
+}
+
+/// Records a successful transaction with the given PDUs. If the destination
+/// was down, the PDUs it missed in the meantime are queued.
+pub(super) fn record_success(server: &ServerName, pdu_ids: &[OwnedEventId]) -> AppResult<()> {
+    let max_sn = events::table
+        .filter(events::id.eq_any(pdu_ids))
+        .select(diesel::dsl::max(events::sn))
+        .first::<Option<i64>>(&mut connect()?)?;
+
+    let mut destination = data::sending::get_destination(server)?
+        .unwrap_or_else(|| DbDestination::new(server.to_owned()));
+    let was_down = destination.is_down();
+    let since_sn = destination.last_successful_sn;
+    clear_backoff(&mut destination, max_sn);
+    data::sending::save_destination(&destination)?;
+    cache_destination(&destination);
+
+    if was_down {
+        info!("destination {server} is reachable again, catching up");
+        queue_catch_up(server, since_sn)?;
+    }
+    Ok(())
+}
+
+/// Doubles the wait before the next retry of the destination, within
+/// `min_interval` and `max_interval`.
+fn back_off(destination: &mut DbDestination, now: i64, min_interval: i64, max_interval: i64) {
+    destination.failure_count = destination.failure_count.saturating_add(1);
+    destination.failure_ts.get_or_insert(now);
+    destination.retry_last_ts = now;
+    destination.retry_interval = destination
+        .retry_interval
+        .saturating_mul(2)
+        .clamp(min_interval, max_interval);
+}
+
+/// Marks the destination as up, having received the PDUs up to `max_sn`.
+fn clear_backoff(destination: &mut DbDestination, max_sn: Option<i64>) {
+    destination.failure_count = 0;
+    destination.failure_ts = None;
+    destination.retry_last_ts = 0;
+    destination.retry_interval = 0;
+    destination.last_successful_sn = destination.last_successful_sn.max(max_sn);
+}
+
+/// Queues the latest PDU of each room the destination missed since `since_sn`
+/// that isn't queued for it already.
+pub(super) fn queue_catch_up(server: &ServerName, since_sn: Option<i64>) -> AppResult<()> {
+    let queued = outgoing_requests::table
+        .filter(outgoing_requests::kind.eq("normal"))
+        .filter(outgoing_requests::server_id.eq(server))
+        .filter(outgoing_requests::pdu_id.is_not_null())
+        .select(outgoing_requests::pdu_id)
+        .load::<Option<OwnedEventId>>(&mut connect()?)?
+        .into_iter()
+        .flatten()
+        .collect::<HashSet<_>>();
+
+    let kind = OutgoingKind::Normal(server.to_owned());
+    for (event_id, _) in data::sending::destination_rooms_since(server, since_sn)? {
+        if !queued.contains(&event_id) {
+            super::queue_request(&kind, &SendingEventType::Pdu(event_id))?;
+        }
+    }
+    Ok(())
+}
+
+/// Queues the PDUs a due destination missed and returns the requests to send it next.
+pub(super) fn wake(server: &ServerName) -> AppResult<Vec<(i64, SendingEventType)>> {
+    let since_sn = data::sending::get_destination(server)?.and_then(|d| d.last_successful_sn);
+    queue_catch_up(server, since_sn)?;
+    Ok(
+        super::queued_requests(&OutgoingKind::Normal(server.to_owned()))?
+            .into_iter()
+            .take(30)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN: i64 = 30_000;
+    const MAX: i64 = 86_400_000;
+
+    fn destination() -> DbDestination {
+        DbDestination::new(ServerName::parse("remote.test").unwrap().to_owned())
+    }
+
+    #[test]
+    fn backoff_doubles_from_the_minimum() {
+        let mut destination = destination();
+        back_off(&mut destination, 1_000, MIN, MAX);
+        assert!(destination.is_down());
+        assert_eq!(destination.failure_count, 1);
+        assert_eq!(destination.retry_interval, MIN);
+        assert!(!destination.is_due(1_000 + MIN - 1));
+        assert!(destination.is_due(1_000 + MIN));
+
+        back_off(&mut destination, 2_000, MIN, MAX);
+        back_off(&mut destination, 3_000, MIN, MAX);
+        assert_eq!(destination.failure_count, 3);
+        assert_eq!(destination.retry_interval, MIN * 4);
+        assert_eq!(destination.retry_last_ts, 3_000);
+        // The destination stays down since its first failure.
+        assert_eq!(destination.failure_ts, Some(1_000));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let mut destination = destination();
+        for now in 0..64 {
+            back_off(&mut destination, now, MIN, MAX);
+        }
+        assert_eq!(destination.retry_interval, MAX);
+        assert_eq!(destination.failure_count, 64);
+    }
+
+    #[test]
+    fn success_resets_backoff() {
+        let mut destination = destination();
+        destination.last_successful_sn = Some(10);
+        back_off(&mut destination, 1_000, MIN, MAX);
+        back_off(&mut destination, 2_000, MIN, MAX);
+
+        clear_backoff(&mut destination, Some(20));
+        assert!(!destination.is_down());
+        assert!(destination.is_due(0));
+        assert_eq!(destination.failure_count, 0);
+        assert_eq!(destination.last_successful_sn, Some(20));
+
+        // The next failure starts from the minimum again.
+        back_off(&mut destination, 3_000, MIN, MAX);
+        assert_eq!(destination.retry_interval, MIN);
+    }
+
+    #[test]
+    fn success_keeps_the_latest_sent_sn() {
+        let mut destination = destination();
+        destination.last_successful_sn = Some(20);
+        clear_backoff(&mut destination, Some(10));
+        assert_eq!(destination.last_successful_sn, Some(20));
+        clear_backoff(&mut destination, None);
+        assert_eq!(destination.last_successful_sn, Some(20));
+    }
+}
@@ -2,8 +2,8 @@ mod acquire;
 mod request;
 mod verify;
 use std::borrow::Borrow;
-use std::collections::BTreeMap;
-use std::time::Duration;
+use std::collections::{BTreeMap, hash_map};
+use std::time::{Duration, Instant};
 
 pub use acquire::*;
 use diesel::prelude::*;
@@ -13,7 +13,7 @@ pub use verify::*;
 
 use crate::core::federation::discovery::{ServerSigningKeys, VerifyKey};
 use crate::core::room_version_rules::RoomVersionRules;
-use crate::core::serde::{Base64, CanonicalJsonObject, JsonValue, RawJson};
+use crate::core::serde::{Base64, CanonicalJsonObject, CanonicalJsonValue, JsonValue, RawJson};
 use crate::core::signatures::{self, PublicKeyMap, PublicKeySet};
 use crate::core::{
     OwnedServerSigningKeyId, RoomVersionId, ServerName, ServerSigningKeyId, UnixMillis,
@@ -23,13 +23,22 @@ use crate::data::misc::DbServerSigningKeys;
 use crate::data::schema::*;
 use crate::exts::*;
 use crate::utils::timepoint_from_now;
-use crate::{AppError, AppResult, config};
+use crate::{AppError, AppResult, BAD_NOTARY_RATE_LIMITER, config};
 
 pub type VerifyKeys = BTreeMap<OwnedServerSigningKeyId, VerifyKey>;
 pub type PubKeyMap = PublicKeyMap;
 pub type PubKeys = PublicKeySet;
 
-fn add_signing_keys(new_keys: ServerSigningKeys) -> AppResult<()> {
+/// How long a notary request waits for the keys of a server.
+const NOTARY_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest time a server that failed to answer is not asked again.
+const NOTARY_MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// Servers in backoff from which the expired ones get dropped.
+const NOTARY_BACKOFF_CAPACITY: usize = 10_000;
+
+/// Stores the keys fetched from the server or a notary and returns them.
+fn add_signing_keys(signed_keys: &RawJson<ServerSigningKeys>) -> AppResult<ServerSigningKeys> {
+    let new_keys = signed_keys.deserialize()?;
     let server: &palpo_core::OwnedServerName = &new_keys.server_name;
 
     // (timo) Not atomic, but this is not critical
@@ -45,8 +54,8 @@ fn add_signing_keys(new_keys: ServerSigningKeys) -> AppResult<()> {
         ServerSigningKeys::new(server.to_owned(), UnixMillis::now())
     };
 
-    keys.verify_keys.extend(new_keys.verify_keys);
-    keys.old_verify_keys.extend(new_keys.old_verify_keys);
+    keys.verify_keys.extend(new_keys.verify_keys.clone());
+    keys.old_verify_keys.extend(new_keys.old_verify_keys.clone());
     diesel::insert_into(server_signing_keys::table)
         .values(DbServerSigningKeys {
             server_id: server.to_owned(),
@@ -61,9 +70,160 @@ fn add_signing_keys(new_keys: ServerSigningKeys) -> AppResult<()> {
             server_signing_keys::updated_at.eq(UnixMillis::now()),
         ))
         .execute(&mut connect()?)?;
+    add_self_signed_keys(&new_keys, signed_keys.deserialize_as()?)?;
+    Ok(new_keys)
+}
+
+/// Keeps the keys if the server signed them itself and they are valid for
+/// longer than the stored ones, to serve them to those using us as a notary.
+///
+/// The JSON is kept as the server signed it, fields unknown to us included.
+fn add_self_signed_keys(
+    new_keys: &ServerSigningKeys,
+    mut object: CanonicalJsonObject,
+) -> AppResult<()> {
+    let server = &new_keys.server_name;
+    let own_signatures = match object.get("signatures") {
+        Some(CanonicalJsonValue::Object(signatures)) => signatures.get(server.as_str()).cloned(),
+        _ => None,
+    };
+    let Some(own_signatures) = own_signatures else {
+        return Ok(());
+    };
+    // Signatures of the notaries that relayed the keys are dropped.
+    object.insert(
+        "signatures".to_owned(),
+        CanonicalJsonValue::Object([(server.to_string(), own_signatures)].into()),
+    );
+
+    let public_keys: PubKeys = new_keys
+        .verify_keys
+        .iter()
+        .map(|(key_id, key)| (key_id.to_string(), key.key.clone()))
+        .collect();
+    if let Err(e) = signatures::verify_json(&[(server.to_string(), public_keys)].into(), &object) {
+        warn!("keys of {server} are not signed by {server}: {e}");
+        return Ok(());
+    }
+
+    if let Some(stored) = self_signed_keys(server)?
+        && stored.deserialize()?.valid_until_ts > new_keys.valid_until_ts
+    {
+        return Ok(());
+    }
+    diesel::update(server_signing_keys::table.find(server))
+        .set(server_signing_keys::signed_keys.eq(serde_json::to_value(&object)?))
+        .execute(&mut connect()?)?;
     Ok(())
 }
 
+/// The latest keys the server signed itself.
+fn self_signed_keys(server: &ServerName) -> AppResult<Option<RawJson<ServerSigningKeys>>> {
+    let signed_keys = server_signing_keys::table
+        .find(server)
+        .select(server_signing_keys::signed_keys)
+        .first::<Option<JsonValue>>(&mut connect()?)
+        .optional()?
+        .flatten();
+    signed_keys
+        .map(|keys| RawJson::from_value(&keys).map_err(Into::into))
+        .transpose()
+}
+
+/// The current keys of this server, signed with its key.
+pub fn local_signing_keys() -> AppResult<ServerSigningKeys> {
+    let keypair = config::keypair();
+    let mut keys = ServerSigningKeys::new(
+        config::server_name().to_owned(),
+        UnixMillis::from_system_time(timepoint_from_now(Duration::from_secs(86400 * 7))?)
+            .expect("time is valid"),
+    );
+    keys.verify_keys.insert(
+        format!("ed25519:{}", keypair.version())
+            .try_into()
+            .expect("found invalid server signing keys in DB"),
+        VerifyKey {
+            key: Base64::new(keypair.public_key().to_vec()),
+        },
+    );
+    let mut object: CanonicalJsonObject = serde_json::from_value(serde_json::to_value(&keys)?)?;
+    sign_json(&mut object)?;
+    Ok(serde_json::from_value(serde_json::to_value(&object)?)?)
+}
+
+/// Keys of the server for the requests made to us as a notary, signed by us.
+///
+/// The stored keys are refreshed from the server when they expire before
+/// `minimum_valid_until_ts` or miss one of `key_ids`, the stored ones are
+/// served if that fails. A server that failed to answer is not asked again
+/// until its backoff elapsed.
+pub async fn notary_keys(
+    server: &ServerName,
+    key_ids: &[OwnedServerSigningKeyId],
+    minimum_valid_until_ts: UnixMillis,
+) -> AppResult<Option<RawJson<ServerSigningKeys>>> {
+    if !server.is_remote() {
+        return Ok(Some(RawJson::new(&local_signing_keys()?)?));
+    }
+
+    let mut signed_keys = self_signed_keys(server)?;
+    let outdated = match &signed_keys {
+        Some(signed_keys) => {
+            let keys = signed_keys.deserialize()?;
+            keys.valid_until_ts < minimum_valid_until_ts
+                || key_ids.iter().any(|key_id| !key_exists(&keys, key_id))
+        }
+        None => true,
+    };
+    if outdated && !is_notary_backing_off(server) {
+        match tokio::time::timeout(NOTARY_FETCH_TIMEOUT, server_request(server)).await {
+            Ok(Ok(server_keys)) => {
+                add_signing_keys(&server_keys)?;
+                BAD_NOTARY_RATE_LIMITER.write().unwrap().remove(server);
+                signed_keys = self_signed_keys(server)?;
+            }
+            Ok(Err(e)) => {
+                debug!("failed to refresh keys of {server} for notary request: {e}");
+                notary_back_off(server);
+            }
+            Err(_) => {
+                debug!("timed out refreshing keys of {server} for notary request");
+                notary_back_off(server);
+            }
+        }
+    }
+
+    let Some(signed_keys) = signed_keys else {
+        return Ok(None);
+    };
+    let mut object: CanonicalJsonObject = signed_keys.deserialize_as()?;
+    sign_json(&mut object)?;
+    Ok(Some(RawJson::new(&object)?.cast()))
+}
+
+fn is_notary_backing_off(server: &ServerName) -> bool {
+    let limiter = BAD_NOTARY_RATE_LIMITER.read().unwrap();
+    let Some((time, tries)) = limiter.get(server) else {
+        return false;
+    };
+    // Exponential backoff
+    let min_elapsed_duration = (Duration::from_secs(30) * (*tries) * (*tries)).min(NOTARY_MAX_BACKOFF);
+    time.elapsed() < min_elapsed_duration
+}
+
+fn notary_back_off(server: &ServerName) {
+    let mut limiter = BAD_NOTARY_RATE_LIMITER.write().unwrap();
+    if limiter.len() >= NOTARY_BACKOFF_CAPACITY {
+        limiter.retain(|_, (time, _)| time.elapsed() < NOTARY_MAX_BACKOFF);
+    }
+    match limiter.entry(server.to_owned()) {
+        hash_map::Entry::Vacant(e) => {
+            e.insert((Instant::now(), 1));
+        }
+        hash_map::Entry::Occupied(mut e) => *e.get_mut() = (Instant::now(), e.get().1 + 1),
+    }
+}
+
 pub fn verify_key_exists(server: &ServerName, key_id: &ServerSigningKeyId) -> AppResult<bool> {
     type KeysMap<'a> = BTreeMap<&'a str, &'a RawJsonValue>;
 
@@ -231,9 +391,9 @@ async fn get_verify_key_from_notaries(
 ) -> AppResult<VerifyKey> {
     for notary in &crate::config::get().trusted_servers {
         if let Ok(server_keys) = notary_request(notary, origin).await {
-            for server_key in server_keys.clone() {
-                add_signing_keys(server_key)?;
-            }
+            let server_keys = server_keys
+                .map(|server_key| add_signing_keys(&server_key))
+                .collect::<AppResult<Vec<_>>>()?;
 
             for server_key in server_keys {
                 if let Some(result) = extract_key(server_key, key_id) {
@@ -253,7 +413,7 @@ async fn get_verify_key_from_origin(
     key_id: &ServerSigningKeyId,
 ) -> AppResult<VerifyKey> {
     if let Ok(server_key) = server_request(origin).await {
-        add_signing_keys(server_key.clone())?;
+        let server_key = add_signing_keys(&server_key)?;
         if let Some(result) = extract_key(server_key, key_id) {
             return Ok(result);
         }
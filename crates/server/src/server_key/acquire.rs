@@ -169,8 +169,10 @@ async fn acquire_origin(
                 "received server_keys"
             );
 
-            let _ = add_signing_keys(server_keys.clone());
-            key_ids.retain(|key_id| !key_exists(&server_keys, key_id));
+            match add_signing_keys(&server_keys) {
+                Ok(server_keys) => key_ids.retain(|key_id| !key_exists(&server_keys, key_id)),
+                Err(e) => tracing::warn!(?origin, "failed to add server keys: {e}"),
+            }
         }
     }
 
@@ -207,9 +209,15 @@ where
     missing
 }
 
-async fn acquire_notary_result(missing: &mut Batch, server_keys: ServerSigningKeys) {
+async fn acquire_notary_result(missing: &mut Batch, server_keys: RawJson<ServerSigningKeys>) {
+    let server_keys = match add_signing_keys(&server_keys) {
+        Ok(server_keys) => server_keys,
+        Err(e) => {
+            warn!("failed to add server keys from notary: {e}");
+            return;
+        }
+    };
     let server = &server_keys.server_name;
-    let _ = add_signing_keys(server_keys.clone());
 
     if let Some(key_ids) = missing.get_mut(server) {
         key_ids.retain(|key_id| key_exists(&server_keys, key_id));
@@ -6,10 +6,11 @@ use crate::AppResult;
 use crate::core::directory::QueryCriteria;
 use crate::core::federation::directory::{
     RemoteServerKeysBatchReqBody, RemoteServerKeysBatchResBody, RemoteServerKeysReqArgs,
-    RemoteServerKeysResBody, ServerKeysResBody, remote_server_keys_batch_request,
+    RemoteServerKeysResBody, remote_server_keys_batch_request,
     remote_server_keys_request, server_keys_request,
 };
 use crate::core::federation::discovery::ServerSigningKeys;
+use crate::core::serde::RawJson;
 use crate::core::{
     MatrixError, OwnedServerName, OwnedServerSigningKeyId, ServerName, ServerSigningKeyId,
 };
@@ -19,7 +20,7 @@ type Batch = BTreeMap<OwnedServerName, BTreeMap<OwnedServerSigningKeyId, QueryCr
 pub(super) async fn batch_notary_request<'a, S, K>(
     notary: &ServerName,
     batch: S,
-) -> AppResult<Vec<ServerSigningKeys>>
+) -> AppResult<Vec<RawJson<ServerSigningKeys>>>
 where
     S: Iterator<Item = (&'a ServerName, K)> + Send,
     K: Iterator<Item = &'a ServerSigningKeyId> + Send,
@@ -78,7 +79,7 @@ where
 pub async fn notary_request(
     notary: &ServerName,
     target: &ServerName,
-) -> AppResult<impl Iterator<Item = ServerSigningKeys> + Clone + Debug + Send> {
+) -> AppResult<impl Iterator<Item = RawJson<ServerSigningKeys>> + Debug + Send> {
     let request = remote_server_keys_request(
         &notary.origin().await,
         RemoteServerKeysReqArgs {
@@ -97,17 +98,17 @@ pub async fn notary_request(
     Ok(response.into_iter())
 }
 
-pub async fn server_request(target: &ServerName) -> AppResult<ServerSigningKeys> {
+pub async fn server_request(target: &ServerName) -> AppResult<RawJson<ServerSigningKeys>> {
     let request = server_keys_request(&target.origin().await)?.into_inner();
     let server_signing_key = crate::sending::send_federation_request(target, request, None)
         .await?
-        .json::<ServerKeysResBody>()
-        .await?
-        .0;
+        .json::<RawJson<ServerSigningKeys>>()
+        .await?;
 
-    if server_signing_key.server_name != target {
+    let server_name = server_signing_key.get_field::<OwnedServerName>("server_name")?;
+    if server_name.as_deref() != Some(target) {
         tracing::warn!(  requested = ?target,
-            response = ?server_signing_key.server_name,
+            response = ?server_name,
             "Server responded with bogus server_name");
         return Err(MatrixError::unknown("Server responded with bogus server_name").into());
     }
@@ -1,5 +1,6 @@
 use std::collections::hash_map::Entry;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
 
 use indexmap::IndexMap;
 use state::DbRoomStateField;
@@ -14,15 +15,17 @@ use crate::core::client::sync_events::v3::{
 use crate::core::device::DeviceLists;
 use crate::core::events::receipt::SyncReceiptEvent;
 use crate::core::events::room::member::{MembershipState, RoomMemberEventContent};
+use crate::core::events::typing::TypingEventContent;
 use crate::core::events::{
-    AnyRawAccountDataEvent, AnySyncEphemeralRoomEvent, StateEventType, TimelineEventType,
+    AnyRawAccountDataEvent, AnySyncEphemeralRoomEvent, StateEventType, SyncEphemeralRoomEvent,
+    TimelineEventType,
 };
 use crate::core::identifiers::*;
 use crate::core::serde::RawJson;
 use crate::core::{Seqnum, UnixMillis};
 use crate::event::{BatchToken, EventHash, PduEvent, SnPduEvent};
 use crate::room::{state, timeline};
-use crate::{AppError, AppResult, config, data, extract_variant, room};
+use crate::{AppError, AppResult, config, data, extract_variant, room, utils};
 
 pub const DEFAULT_BUMP_TYPES: &[TimelineEventType; 6] = &[
     TimelineEventType::CallInvite,
@@ -33,14 +36,54 @@ pub const DEFAULT_BUMP_TYPES: &[TimelineEventType; 6] = &[
     TimelineEventType::Sticker,
 ];
 
+/// Latest typing update of each joined room, along with its typing users.
+type Typings = HashMap<OwnedRoomId, (Seqnum, SyncEphemeralRoomEvent<TypingEventContent>)>;
+
 #[tracing::instrument(skip_all)]
 pub async fn sync_events(
     sender_id: &UserId,
     device_id: &DeviceId,
-    args: &SyncEventsReqArgs,
+    args: Arc<SyncEventsReqArgs>,
 ) -> AppResult<SyncEventsResBody> {
     let curr_sn = data::curr_sn()?;
     crate::seqnum_reach(curr_sn).await;
+
+    let all_joined_rooms = {
+        let sender_id = sender_id.to_owned();
+        utils::spawn_blocking(move || data::user::joined_rooms(&sender_id)).await?
+    };
+    // Typing is kept in memory behind async locks, so it is collected before the
+    // database work moves to the blocking pool.
+    let mut typings = Typings::new();
+    for room_id in &all_joined_rooms {
+        let last_update = room::typing::last_typing_update(room_id).await?;
+        let typing = room::typing::all_typings(room_id).await?;
+        typings.insert(room_id.clone(), (last_update, typing));
+    }
+
+    let sender_id = sender_id.to_owned();
+    let device_id = device_id.to_owned();
+    utils::spawn_blocking(move || {
+        load_sync(
+            &sender_id,
+            &device_id,
+            &args,
+            curr_sn,
+            &all_joined_rooms,
+            &typings,
+        )
+    })
+    .await
+}
+
+fn load_sync(
+    sender_id: &UserId,
+    device_id: &DeviceId,
+    args: &SyncEventsReqArgs,
+    curr_sn: Seqnum,
+    all_joined_rooms: &[OwnedRoomId],
+    typings: &Typings,
+) -> AppResult<SyncEventsResBody> {
     let since_tk = if let Some(since_str) = args.since.as_ref() {
         let since_tk: BatchToken = since_str.parse()?;
         if since_tk.stream_ordering() > curr_sn {
@@ -69,8 +112,6 @@ pub async fn sync_events(
     let mut presence_updates = HashMap::new();
     // Users that have joined any encrypted rooms the sender was in
     let mut joined_users = HashSet::new();
-    // Users that have left any encrypted rooms the sender was in
-    let mut left_users = HashSet::new();
     let mut device_list_updates = HashSet::new();
     let mut device_list_left = HashSet::new();
 
@@ -81,8 +122,7 @@ pub async fn sync_events(
         None,
     )?);
 
-    let all_joined_rooms = data::user::joined_rooms(sender_id)?;
-    for room_id in &all_joined_rooms {
+    for room_id in all_joined_rooms {
         let joined_room = match load_joined_room(
             sender_id,
             device_id,
@@ -93,12 +133,10 @@ pub async fn sync_events(
             full_state,
             &filter,
             args.use_state_after,
+            typings.get(room_id),
             &mut device_list_updates,
             &mut joined_users,
-            &mut left_users,
-        )
-        .await
-        {
+        ) {
             Ok((joined_room, nb)) => {
                 if let Some(nb) = nb
                     && nb.stream_ordering() < next_batch.stream_ordering()
@@ -141,10 +179,7 @@ pub async fn sync_events(
             &filter,
             &mut device_list_updates,
             &mut joined_users,
-            &mut left_users,
-        )
-        .await
-        {
+        ) {
             Ok(left_room) => left_room,
             Err(e) => {
                 tracing::error!(error = ?e, "load left room failed");
@@ -167,37 +202,13 @@ pub async fn sync_events(
     })
     .collect();
 
-    for left_room in left_rooms.keys() {
-        for user_id in room::joined_users(left_room, None)? {
-            let dont_share_encrypted_room =
-                room::user::shared_rooms(vec![sender_id.to_owned(), user_id.clone()])?
-                    .into_iter()
-                    .map(|other_room_id| {
-                        room::get_state(&other_room_id, &StateEventType::RoomEncryption, "", None)
-                            .is_ok()
-                    })
-                    .all(|encrypted| !encrypted);
-            // If the user doesn't share an encrypted room with the target anymore, we need to tell
-            // them.
-            if dont_share_encrypted_room {
-                device_list_left.insert(user_id);
-            }
-        }
-    }
-    for user_id in left_users {
-        let dont_share_encrypted_room =
-            room::user::shared_rooms(vec![sender_id.to_owned(), user_id.clone()])?
-                .into_iter()
-                .map(|other_room_id| {
-                    room::get_state(&other_room_id, &StateEventType::RoomEncryption, "", None)
-                        .is_ok()
-                })
-                .all(|encrypted| !encrypted);
-        // If the user doesn't share an encrypted room with the target anymore, we need to tell
-        // them.
-        if dont_share_encrypted_room {
-            device_list_left.insert(user_id);
-        }
+    // Users who no longer share any room with the sender since the last sync
+    if let Some(since_tk) = since_tk {
+        device_list_left.extend(data::user::device_list_left_users(
+            sender_id,
+            since_tk.event_sn(),
+            None,
+        )?);
     }
 
     let knocked_rooms = data::user::knocked_rooms(sender_id, 0)?.into_iter().fold(
@@ -320,14 +331,15 @@ pub async fn sync_events(
             data::user::count_one_time_keys(sender_id, device_id).unwrap_or_default()
         },
         to_device,
-        // Fallback keys are not yet supported
-        device_unused_fallback_key_types: None,
+        device_unused_fallback_key_types: Some(data::user::unused_fallback_key_types(
+            sender_id, device_id,
+        )?),
     };
     Ok(res_body)
 }
 
 #[tracing::instrument(skip_all)]
-async fn load_joined_room(
+fn load_joined_room(
     sender_id: &UserId,
     device_id: &DeviceId,
     room_id: &RoomId,
@@ -337,9 +349,9 @@ async fn load_joined_room(
     full_state: bool,
     filter: &FilterDefinition,
     _use_state_after: bool, // TODO
+    typing: Option<&(Seqnum, SyncEphemeralRoomEvent<TypingEventContent>)>,
     device_list_updates: &mut HashSet<OwnedUserId>,
     joined_users: &mut HashSet<OwnedUserId>,
-    left_users: &mut HashSet<OwnedUserId>,
 ) -> AppResult<(JoinedRoom, Option<BatchToken>)> {
     if since_tk.map(|s| s.event_sn()) > Some(data::curr_sn()?) {
         return Ok((JoinedRoom::default(), None));
@@ -524,7 +536,7 @@ async fn load_joined_room(
                     room_id,
                     lazy_loaded,
                     next_batch.event_sn(),
-                );
+                )?;
 
                 // && encrypted_room || new_encrypted_room {
                 // If the user is in a new encrypted room, give them all joined users
@@ -628,7 +640,7 @@ async fn load_joined_room(
                     room_id,
                     lazy_loaded,
                     next_batch.event_sn(),
-                );
+                )?;
 
                 let encrypted_room =
                     state::get_state(current_frame_id, &StateEventType::RoomEncryption, "").is_ok();
@@ -659,29 +671,21 @@ async fn load_joined_room(
                             .map_err(|_| AppError::public("invalid pdu in database"))?
                             .membership;
 
-                        match new_membership {
-                            MembershipState::Join => {
-                                // A new user joined an encrypted room
-                                // if !share_encrypted_room(sender_id, &user_id, &room_id)? {
-                                if since_tk.event_sn() <= state_event.event_sn
-                                    && !room::user::shared_rooms(vec![
-                                        sender_id.to_owned(),
-                                        user_id.to_owned(),
-                                    ])?
-                                    .is_empty()
-                                {
-                                    // if user_id.is_local() {
-                                    // check for test TestDeviceListsUpdateOverFederation
-                                    // device_list_updates.insert(user_id.clone());
-                                    // }
-                                    joined_users.insert(user_id);
-                                }
-                            }
-                            MembershipState::Leave => {
-                                // Write down users that have left encrypted rooms we are in
-                                left_users.insert(user_id);
-                            }
-                            _ => {}
+                        // A new user joined an encrypted room
+                        // if !share_encrypted_room(sender_id, &user_id, &room_id)? {
+                        if new_membership == MembershipState::Join
+                            && since_tk.event_sn() <= state_event.event_sn
+                            && !room::user::shared_rooms(vec![
+                                sender_id.to_owned(),
+                                user_id.to_owned(),
+                            ])?
+                            .is_empty()
+                        {
+                            // if user_id.is_local() {
+                            // check for test TestDeviceListsUpdateOverFederation
+                            // device_list_updates.insert(user_id.clone());
+                            // }
+                            joined_users.insert(user_id);
                         }
                     }
                 }
@@ -742,12 +746,12 @@ async fn load_joined_room(
         let receipt = SyncReceiptEvent { content };
         edus.push(RawJson::new(&receipt)?.cast());
     }
-    if room::typing::last_typing_update(room_id).await? >= since_tk.event_sn() {
+    if let Some((last_update, typing)) = typing
+        && *last_update >= since_tk.event_sn()
+    {
         edus.push(
-            serde_json::from_str(&serde_json::to_string(
-                &room::typing::all_typings(room_id).await?,
-            )?)
-            .expect("event is valid, we just created it"),
+            serde_json::from_str(&serde_json::to_string(typing)?)
+                .expect("event is valid, we just created it"),
         );
     }
 
@@ -824,7 +828,7 @@ async fn load_joined_room(
 }
 
 #[tracing::instrument(skip_all)]
-async fn load_left_room(
+fn load_left_room(
     sender_id: &UserId,
     _device_id: &DeviceId,
     room_id: &RoomId,
@@ -836,7 +840,6 @@ async fn load_left_room(
     filter: &FilterDefinition,
     _device_list_updates: &mut HashSet<OwnedUserId>,
     _joined_users: &mut HashSet<OwnedUserId>,
-    _left_users: &mut HashSet<OwnedUserId>,
 ) -> AppResult<LeftRoom> {
     let conf = crate::config::get();
     if !room::room_exists(room_id)? {
@@ -2,7 +2,9 @@ use std::cmp::Ordering;
 use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::sync::{Arc, LazyLock, Mutex};
 
-use crate::core::Seqnum;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
 use crate::core::client::filter::RoomEventFilter;
 use crate::core::client::sync_events::v5::*;
 use crate::core::client::sync_events::{self};
@@ -11,12 +13,17 @@ use crate::core::events::receipt::{SyncReceiptEvent, combine_receipt_event_conte
 use crate::core::events::room::member::{MembershipState, RoomMemberEventContent};
 use crate::core::events::{AnyRawAccountDataEvent, StateEventType, TimelineEventType};
 use crate::core::identifiers::*;
+use crate::core::serde::JsonValue;
+use crate::core::{Seqnum, UnixMillis};
+use crate::data::connect;
+use crate::data::schema::*;
 use crate::event::{BatchToken, ignored_filter};
 use crate::room::{self, filter_rooms, state, timeline};
 use crate::sync_v3::{DEFAULT_BUMP_TYPES, TimelineData, share_encrypted_room};
-use crate::{AppResult, data, extract_variant};
+use crate::{AppResult, config, data, extract_variant, utils};
 
-#[derive(Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 struct SlidingSyncCache {
     lists: BTreeMap<String, sync_events::v5::ReqList>,
     subscriptions: BTreeMap<OwnedRoomId, sync_events::v5::RoomSubscription>,
@@ -25,17 +32,20 @@ struct SlidingSyncCache {
     required_state: BTreeSet<Seqnum>,
 }
 
-static CONNECTIONS: LazyLock<
-    Mutex<BTreeMap<(OwnedUserId, OwnedDeviceId, Option<String>), Arc<Mutex<SlidingSyncCache>>>>,
-> = LazyLock::new(Default::default);
+type ConnectionKey = (OwnedUserId, OwnedDeviceId, Option<String>);
+
+/// Caches of the connections, also stored in the `sliding_sync_connections`
+/// table so they survive restarts and can be used by other processes.
+static CONNECTIONS: LazyLock<Mutex<BTreeMap<ConnectionKey, Arc<Mutex<SlidingSyncCache>>>>> =
+    LazyLock::new(Default::default);
 
 #[tracing::instrument(skip_all)]
 pub async fn sync_events(
     sender_id: &UserId,
     device_id: &DeviceId,
     since_sn: Seqnum,
-    req_body: &SyncEventsReqBody,
-    known_rooms: &KnownRooms,
+    req_body: Arc<SyncEventsReqBody>,
+    known_rooms: Arc<KnownRooms>,
 ) -> AppResult<SyncEventsResBody> {
     let curr_sn = data::curr_sn()?;
     crate::seqnum_reach(curr_sn).await;
@@ -44,19 +54,71 @@ pub async fn sync_events(
         return Ok(SyncEventsResBody::new(next_batch.to_string()));
     }
 
-    let all_joined_rooms = data::user::joined_rooms(sender_id)?;
-
-    let all_invited_rooms = data::user::invited_rooms(sender_id, 0)?;
-    let all_invited_rooms: Vec<&RoomId> = all_invited_rooms.iter().map(|r| r.0.as_ref()).collect();
+    let (all_joined_rooms, all_invited_rooms, all_knocked_rooms) = {
+        let sender_id = sender_id.to_owned();
+        utils::spawn_blocking(move || {
+            AppResult::Ok((
+                data::user::joined_rooms(&sender_id)?,
+                data::user::invited_rooms(&sender_id, 0)?
+                    .into_iter()
+                    .map(|(room_id, _)| room_id)
+                    .collect::<Vec<_>>(),
+                data::user::knocked_rooms(&sender_id, 0)?
+                    .into_iter()
+                    .map(|(room_id, _)| room_id)
+                    .collect::<Vec<_>>(),
+            ))
+        })
+        .await?
+    };
+    // Typing is kept in memory behind async locks, so it is collected before the
+    // database work moves to the blocking pool.
+    let typing = collect_typing(
+        &req_body,
+        all_joined_rooms
+            .iter()
+            .chain(&all_invited_rooms)
+            .chain(&all_knocked_rooms)
+            .map(AsRef::as_ref),
+    )
+    .await?;
 
-    let all_knocked_rooms = data::user::knocked_rooms(sender_id, 0)?;
-    let all_knocked_rooms: Vec<&RoomId> = all_knocked_rooms.iter().map(|r| r.0.as_ref()).collect();
+    let sender_id = sender_id.to_owned();
+    let device_id = device_id.to_owned();
+    utils::spawn_blocking(move || {
+        let sync_info = SyncInfo {
+            sender_id: &sender_id,
+            device_id: &device_id,
+            since_sn,
+            req_body: &req_body,
+        };
+        load_sync(
+            sync_info,
+            next_batch,
+            &known_rooms,
+            &all_joined_rooms,
+            &all_invited_rooms,
+            &all_knocked_rooms,
+            typing,
+        )
+    })
+    .await
+}
 
+fn load_sync(
+    sync_info: SyncInfo<'_>,
+    next_batch: Seqnum,
+    known_rooms: &KnownRooms,
+    all_joined_rooms: &[OwnedRoomId],
+    all_invited_rooms: &[OwnedRoomId],
+    all_knocked_rooms: &[OwnedRoomId],
+    typing: sync_events::v5::Typing,
+) -> AppResult<SyncEventsResBody> {
     let all_rooms: Vec<&RoomId> = all_joined_rooms
         .iter()
+        .chain(all_invited_rooms)
+        .chain(all_knocked_rooms)
         .map(AsRef::as_ref)
-        .chain(all_invited_rooms.iter().map(AsRef::as_ref))
-        .chain(all_knocked_rooms.iter().map(AsRef::as_ref))
         .collect();
 
     let all_joined_rooms = all_joined_rooms.iter().map(AsRef::as_ref).collect();
@@ -64,14 +126,8 @@ pub async fn sync_events(
 
     let mut todo_rooms: TodoRooms = BTreeMap::new();
 
-    let sync_info = SyncInfo {
-        sender_id,
-        device_id,
-        since_sn,
-        req_body,
-    };
     let mut res_body = SyncEventsResBody {
-        txn_id: req_body.txn_id.clone(),
+        txn_id: sync_info.req_body.txn_id.clone(),
         pos: next_batch.to_string(),
         lists: BTreeMap::new(),
         rooms: BTreeMap::new(),
@@ -80,7 +136,7 @@ pub async fn sync_events(
             e2ee: collect_e2ee(sync_info, &all_joined_rooms)?,
             to_device: collect_to_device(sync_info, next_batch),
             receipts: collect_receipts(),
-            typing: collect_typing(sync_info, next_batch, all_rooms.iter().cloned()).await?,
+            typing,
         },
     };
 
@@ -92,8 +148,7 @@ pub async fn sync_events(
         &mut todo_rooms,
         known_rooms,
         &mut res_body,
-    )
-    .await;
+    );
 
     fetch_subscriptions(sync_info, &mut todo_rooms, known_rooms)?;
 
@@ -103,13 +158,12 @@ pub async fn sync_events(
         &todo_rooms,
         known_rooms,
         &mut res_body,
-    )
-    .await?;
+    )?;
     Ok(res_body)
 }
 
 #[allow(clippy::too_many_arguments)]
-async fn process_lists(
+fn process_lists(
     SyncInfo {
         sender_id,
         device_id,
@@ -254,7 +308,7 @@ fn fetch_subscriptions(
     Ok(())
 }
 
-async fn process_rooms(
+fn process_rooms(
     SyncInfo {
         sender_id,
         req_body,
@@ -547,9 +601,7 @@ fn collect_e2ee(
     if !req_body.extensions.e2ee.enabled.unwrap_or(false) {
         return Ok(sync_events::v5::E2ee::default());
     }
-    let mut left_encrypted_users = HashSet::new(); // Users that have left any encrypted rooms the sender was in
     let mut device_list_changes = HashSet::new();
-    let mut device_list_left = HashSet::new();
     // Look for device list updates of this account
     device_list_changes.extend(data::user::keys_changed_users(sender_id, since_sn, None)?);
 
@@ -595,19 +647,11 @@ fn collect_e2ee(
                             }
 
                             let content: RoomMemberEventContent = pdu.get_content()?;
-                            match content.membership {
-                                MembershipState::Join => {
-                                    // A new user joined an encrypted room
-                                    if !share_encrypted_room(sender_id, &user_id, Some(room_id))? {
-                                        device_list_changes.insert(user_id.to_owned());
-                                    }
-                                }
-                                MembershipState::Leave => {
-                                    // Write down users that have left encrypted rooms we
-                                    // are in
-                                    left_encrypted_users.insert(user_id.to_owned());
-                                }
-                                _ => {}
+                            // A new user joined an encrypted room
+                            if content.membership == MembershipState::Join
+                                && !share_encrypted_room(sender_id, &user_id, Some(room_id))?
+                            {
+                                device_list_changes.insert(user_id.to_owned());
                             }
                         }
                     }
@@ -639,25 +683,18 @@ fn collect_e2ee(
         device_list_changes.extend(crate::room::keys_changed_users(room_id, since_sn, None)?);
     }
 
-    for user_id in left_encrypted_users {
-        let Ok(share_encrypted_room) = share_encrypted_room(sender_id, &user_id, None) else {
-            continue;
-        };
-
-        // If the user doesn't share an encrypted room with the target anymore, we need
-        // to tell them
-        if !share_encrypted_room {
-            device_list_left.insert(user_id);
-        }
-    }
+    // Users who no longer share any room with the sender since the last sync
+    let device_list_left = data::user::device_list_left_users(sender_id, since_sn, None)?;
 
     Ok(E2ee {
         device_lists: DeviceLists {
             changed: device_list_changes.into_iter().collect(),
-            left: device_list_left.into_iter().collect(),
+            left: device_list_left,
         },
         device_one_time_keys_count: data::user::count_one_time_keys(sender_id, device_id)?,
-        device_unused_fallback_key_types: None,
+        device_unused_fallback_key_types: Some(data::user::unused_fallback_key_types(
+            sender_id, device_id,
+        )?),
     })
 }
 
@@ -694,8 +731,7 @@ fn collect_receipts() -> sync_events::v5::Receipts {
 }
 
 async fn collect_typing<'a, Rooms>(
-    SyncInfo { req_body, .. }: SyncInfo<'_>,
-    _next_batch: Seqnum,
+    req_body: &SyncEventsReqBody,
     rooms: Rooms,
 ) -> AppResult<sync_events::v5::Typing>
 where
@@ -722,11 +758,99 @@ pub fn forget_sync_request_connection(
     user_id: OwnedUserId,
     device_id: OwnedDeviceId,
     conn_id: Option<String>,
-) {
+) -> AppResult<()> {
+    if config::get().coordination.is_shared() {
+        diesel::delete(
+            sliding_sync_connections::table
+                .filter(sliding_sync_connections::user_id.eq(&user_id))
+                .filter(sliding_sync_connections::device_id.eq(&device_id))
+                .filter(
+                    sliding_sync_connections::conn_id.eq(conn_id.as_deref().unwrap_or_default()),
+                ),
+        )
+        .execute(&mut connect()?)?;
+    }
     CONNECTIONS
         .lock()
         .unwrap()
         .remove(&(user_id, device_id, conn_id));
+    Ok(())
+}
+
+/// Stores the cache of the connection, so the next request of the client can
+/// use it whichever process serves it. A single process keeps it in memory.
+pub fn save_sync_connection(
+    user_id: OwnedUserId,
+    device_id: OwnedDeviceId,
+    conn_id: Option<String>,
+) -> AppResult<()> {
+    if !config::get().coordination.is_shared() {
+        return Ok(());
+    }
+    let key = (user_id, device_id, conn_id);
+    let Some(cached) = CONNECTIONS.lock().unwrap().get(&key).cloned() else {
+        return Ok(());
+    };
+    let cache = serde_json::to_value(&*cached.lock().unwrap())?;
+    let (user_id, device_id, conn_id) = key;
+    let updated_at = UnixMillis::now().get() as i64;
+    diesel::insert_into(sliding_sync_connections::table)
+        .values((
+            sliding_sync_connections::user_id.eq(&user_id),
+            sliding_sync_connections::device_id.eq(&device_id),
+            sliding_sync_connections::conn_id.eq(conn_id.as_deref().unwrap_or_default()),
+            sliding_sync_connections::cache.eq(&cache),
+            sliding_sync_connections::updated_at.eq(updated_at),
+        ))
+        .on_conflict((
+            sliding_sync_connections::user_id,
+            sliding_sync_connections::device_id,
+            sliding_sync_connections::conn_id,
+        ))
+        .do_update()
+        .set((
+            sliding_sync_connections::cache.eq(&cache),
+            sliding_sync_connections::updated_at.eq(updated_at),
+        ))
+        .execute(&mut connect()?)?;
+    Ok(())
+}
+
+/// Deletes the stored connections not used since before `before`.
+pub fn prune_sync_connections(before: UnixMillis) -> AppResult<usize> {
+    let pruned = diesel::delete(
+        sliding_sync_connections::table
+            .filter(sliding_sync_connections::updated_at.lt(before.get() as i64)),
+    )
+    .execute(&mut connect()?)?;
+    Ok(pruned)
+}
+
+/// The cache of the connection, loaded from the database when another process
+/// may have served the connection since.
+fn connection_cache(key: ConnectionKey) -> AppResult<Arc<Mutex<SlidingSyncCache>>> {
+    if !config::get().coordination.is_shared() {
+        return Ok(Arc::clone(
+            CONNECTIONS.lock().unwrap().entry(key).or_default(),
+        ));
+    }
+    let cache = sliding_sync_connections::table
+        .filter(sliding_sync_connections::user_id.eq(&key.0))
+        .filter(sliding_sync_connections::device_id.eq(&key.1))
+        .filter(sliding_sync_connections::conn_id.eq(key.2.as_deref().unwrap_or_default()))
+        .select(sliding_sync_connections::cache)
+        .first::<JsonValue>(&mut connect()?)
+        .optional()?;
+    let cache = match cache {
+        Some(cache) => serde_json::from_value(cache).unwrap_or_else(|e| {
+            warn!("invalid sliding sync connection cache, starting over: {e}");
+            SlidingSyncCache::default()
+        }),
+        None => SlidingSyncCache::default(),
+    };
+    let cached = Arc::new(Mutex::new(cache));
+    CONNECTIONS.lock().unwrap().insert(key, Arc::clone(&cached));
+    Ok(cached)
 }
 /// load params from cache if body doesn't contain it, as long as it's allowed
 /// in some cases we may need to allow an empty list as an actual value
@@ -744,15 +868,9 @@ pub fn update_sync_request_with_cache(
     user_id: OwnedUserId,
     device_id: OwnedDeviceId,
     req_body: &mut sync_events::v5::SyncEventsReqBody,
-) -> BTreeMap<String, BTreeMap<OwnedRoomId, i64>> {
-    let mut cache = CONNECTIONS.lock().unwrap();
-    let cached = Arc::clone(
-        cache
-            .entry((user_id, device_id, req_body.conn_id.clone()))
-            .or_default(),
-    );
+) -> AppResult<BTreeMap<String, BTreeMap<OwnedRoomId, i64>>> {
+    let cached = connection_cache((user_id, device_id, req_body.conn_id.clone()))?;
     let cached = &mut cached.lock().unwrap();
-    drop(cache);
 
     for (list_id, list) in &mut req_body.lists {
         if let Some(cached_list) = cached.lists.get(list_id) {
@@ -840,7 +958,7 @@ pub fn update_sync_request_with_cache(
     );
 
     cached.extensions = req_body.extensions.clone();
-    cached.known_rooms.clone()
+    Ok(cached.known_rooms.clone())
 }
 
 pub fn update_sync_subscriptions(
@@ -1,32 +1,21 @@
-use std::collections::BTreeMap;
-use std::sync::LazyLock;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
 use diesel::prelude::*;
 
-use super::LazyRwLock;
+use crate::core::UnixMillis;
 use crate::core::client::uiaa::{
-    AuthData, AuthError, AuthType, Password, UiaaInfo, UserIdentifier,
+    AuthData, AuthError, AuthType, ErrorKind, Password, UiaaInfo, UserIdentifier,
 };
 use crate::core::identifiers::*;
 use crate::core::serde::{CanonicalJsonValue, JsonValue};
 use crate::data::connect;
 use crate::data::schema::*;
+use crate::user::threepid::ValidationPurpose;
 use crate::{AppResult, MatrixError, SESSION_ID_LENGTH, data, utils};
 
 /// Default UIAA session timeout: 15 minutes
 const UIAA_SESSION_TIMEOUT: Duration = Duration::from_secs(15 * 60);
 
-/// UIAA request with timestamp for timeout tracking
-struct UiaaRequest {
-    request: CanonicalJsonValue,
-    created_at: Instant,
-}
-
-static UIAA_REQUESTS: LazyRwLock<
-    BTreeMap<(OwnedUserId, OwnedDeviceId, String), UiaaRequest>,
-> = LazyLock::new(Default::default);
-
 /// Creates a new Uiaa session. Make sure the session token is unique.
 pub fn create_session(
     user_id: &UserId,
@@ -34,20 +23,10 @@ pub fn create_session(
     uiaa_info: &UiaaInfo,
     json_body: CanonicalJsonValue,
 ) -> AppResult<()> {
-    set_uiaa_request(
-        user_id,
-        device_id,
-        uiaa_info.session.as_ref().expect("session should be set"), /* TODO: better session
-                                                                     * error handling (why is it
-                                                                     * optional in palpo?) */
-        json_body,
-    );
-    update_session(
-        user_id,
-        device_id,
-        uiaa_info.session.as_ref().expect("session should be set"),
-        Some(uiaa_info),
-    )
+    // TODO: better session error handling (why is it optional in palpo?)
+    let session = uiaa_info.session.as_ref().expect("session should be set");
+    update_session(user_id, device_id, session, Some(uiaa_info))?;
+    set_uiaa_request(user_id, device_id, session, json_body)
 }
 
 pub fn update_session(
@@ -64,6 +43,7 @@ pub fn update_session(
                 user_uiaa_datas::device_id.eq(device_id),
                 user_uiaa_datas::session.eq(session),
                 user_uiaa_datas::uiaa_info.eq(&uiaa_info),
+                user_uiaa_datas::created_at.eq(UnixMillis::now().get() as i64),
             ))
             .on_conflict((
                 user_uiaa_datas::user_id,
@@ -77,7 +57,7 @@ pub fn update_session(
         diesel::delete(
             user_uiaa_datas::table
                 .filter(user_uiaa_datas::user_id.eq(user_id))
-                .filter(user_uiaa_datas::device_id.eq(user_id))
+                .filter(user_uiaa_datas::device_id.eq(device_id))
                 .filter(user_uiaa_datas::session.eq(session)),
         )
         .execute(&mut connect()?)?;
@@ -133,6 +113,7 @@ pub fn try_auth(
                 return Err(MatrixError::unauthorized("user not found.").into());
             };
             crate::user::verify_password(&user, password)?;
+            uiaa_info.completed.push(AuthType::Password);
         }
         AuthData::RegistrationToken(t) => {
             if Some(t.token.trim()) == conf.registration_token.as_deref() {
@@ -143,6 +124,45 @@ pub fn try_auth(
                 return Ok((false, uiaa_info));
             }
         }
+        AuthData::EmailIdentity(e) => {
+            let creds = &e.thirdparty_id_creds;
+            // Registration authenticates as the user with an empty localpart
+            // and uses the session up when the account is created. Any other
+            // stage uses it up here, so it can only complete one stage.
+            let session = if user_id.localpart().is_empty() {
+                crate::user::threepid::get_validated_session(
+                    &creds.sid,
+                    &creds.client_secret,
+                    ValidationPurpose::Registration,
+                )
+            } else {
+                crate::user::threepid::consume_validated_session(
+                    &creds.sid,
+                    &creds.client_secret,
+                    ValidationPurpose::PasswordReset,
+                )
+            };
+            let bound = session.ok().map(|session| {
+                data::user::get_user_by_threepid(&session.medium, &session.address)
+                    .ok()
+                    .flatten()
+            });
+            // Registration needs an email that no account uses yet.
+            let valid = if user_id.localpart().is_empty() {
+                bound == Some(None)
+            } else {
+                bound.flatten().as_deref() == Some(user_id)
+            };
+            if valid {
+                uiaa_info.completed.push(AuthType::EmailIdentity);
+            } else {
+                uiaa_info.auth_error = Some(AuthError::new(
+                    ErrorKind::ThreepidAuthFailed,
+                    "Email has not been validated for this user.",
+                ));
+                return Ok((false, uiaa_info));
+            }
+        }
         AuthData::Dummy(_) => {
             uiaa_info.completed.push(AuthType::Dummy);
         }
@@ -181,48 +201,52 @@ pub fn try_auth(
     Ok((true, uiaa_info))
 }
 
+/// Stores the original request of the session, kept in the database so any
+/// process can complete it.
 pub fn set_uiaa_request(
     user_id: &UserId,
     device_id: &DeviceId,
     session: &str,
     request: CanonicalJsonValue,
-) {
+) -> AppResult<()> {
     // Clean up expired sessions before adding new one
-    cleanup_expired_sessions();
-
-    UIAA_REQUESTS
-        .write()
-        .expect("write UIAA_REQUESTS failed")
-        .insert(
-            (user_id.to_owned(), device_id.to_owned(), session.to_owned()),
-            UiaaRequest {
-                request,
-                created_at: Instant::now(),
-            },
-        );
+    cleanup_expired_sessions()?;
+
+    diesel::update(
+        user_uiaa_datas::table
+            .filter(user_uiaa_datas::user_id.eq(user_id))
+            .filter(user_uiaa_datas::device_id.eq(device_id))
+            .filter(user_uiaa_datas::session.eq(session)),
+    )
+    .set(user_uiaa_datas::request.eq(serde_json::to_value(request)?))
+    .execute(&mut connect()?)?;
+    Ok(())
 }
 
 pub fn get_uiaa_request(
     user_id: &UserId,
     device_id: &DeviceId,
     session: &str,
-) -> Option<CanonicalJsonValue> {
-    let key = (user_id.to_owned(), device_id.to_owned(), session.to_owned());
-    let requests = UIAA_REQUESTS.read().expect("read UIAA_REQUESTS failed");
-
-    requests.get(&key).and_then(|uiaa_request| {
-        // Check if the session has expired
-        if uiaa_request.created_at.elapsed() > UIAA_SESSION_TIMEOUT {
-            // Session expired, will be cleaned up later
-            None
-        } else {
-            Some(uiaa_request.request.clone())
-        }
-    })
+) -> AppResult<Option<CanonicalJsonValue>> {
+    let request = user_uiaa_datas::table
+        .filter(user_uiaa_datas::user_id.eq(user_id))
+        .filter(user_uiaa_datas::device_id.eq(device_id))
+        .filter(user_uiaa_datas::session.eq(session))
+        .filter(user_uiaa_datas::created_at.ge(expired_before()))
+        .select(user_uiaa_datas::request)
+        .first::<Option<JsonValue>>(&mut connect()?)
+        .optional()?
+        .flatten();
+    Ok(request.map(serde_json::from_value).transpose()?)
+}
+
+/// Remove expired UIAA sessions
+fn cleanup_expired_sessions() -> AppResult<()> {
+    diesel::delete(user_uiaa_datas::table.filter(user_uiaa_datas::created_at.lt(expired_before())))
+        .execute(&mut connect()?)?;
+    Ok(())
 }
 
-/// Remove expired UIAA sessions from memory
-fn cleanup_expired_sessions() {
-    let mut requests = UIAA_REQUESTS.write().expect("write UIAA_REQUESTS failed");
-    requests.retain(|_, uiaa_request| uiaa_request.created_at.elapsed() <= UIAA_SESSION_TIMEOUT);
+fn expired_before() -> i64 {
+    UnixMillis::now().get() as i64 - UIAA_SESSION_TIMEOUT.as_millis() as i64
 }
@@ -4,10 +4,11 @@ pub use password::*;
 pub mod key;
 pub mod pusher;
 pub use key::*;
+mod ldap;
 pub mod presence;
-// mod ldap;
-// pub use ldap::*;
+pub use ldap::*;
 pub mod session;
+pub mod threepid;
 use std::mem;
 
 use diesel::prelude::*;
@@ -24,7 +25,11 @@ use crate::data::schema::*;
 use crate::data::user::{DbUser, DbUserData, NewDbUser};
 use crate::data::{DataResult, connect};
 use crate::room::timeline;
-use crate::{AppError, AppResult, IsRemoteOrLocal, MatrixError, PduBuilder, config, data, room};
+use crate::{
+    AppError, AppResult, IsRemoteOrLocal, MatrixError, PduBuilder, config, data, room, utils,
+};
+
+const OPENID_TOKEN_LENGTH: usize = 64;
 
 pub fn is_username_available(username: &str) -> AppResult<bool> {
     let user_id = UserId::parse(format!("@{}:{}", username, config::server_name()))
@@ -114,6 +119,23 @@ pub fn user_is_ignored(sender_id: &UserId, recipient_id: &UserId) -> bool {
     }
 }
 
+/// Rejects users suspended by an admin (MSC3823). Suspended users can still
+/// read and leave rooms, but not send events or join rooms.
+pub fn ensure_not_suspended(user: &DbUser) -> AppResult<()> {
+    if user.is_suspended() {
+        Err(MatrixError::user_suspended("Your account has been suspended.").into())
+    } else {
+        Ok(())
+    }
+}
+
+/// An event id to answer shadow-banned users with for events that are
+/// silently dropped, so they can't tell them apart from sent ones.
+pub fn shadow_ban_event_id() -> OwnedEventId {
+    OwnedEventId::try_from(format!("${}", crate::utils::random_string(43)))
+        .expect("random event id is valid")
+}
+
 /// Runs through all the deactivation steps:
 ///
 /// - Mark as deactivated
@@ -181,6 +203,23 @@ pub async fn full_user_deactivate(
     Ok(())
 }
 
+/// Creates an OpenID token for the user, which third parties can check
+/// through `/_matrix/federation/v1/openid/userinfo`. Returns the token and
+/// its lifetime in seconds.
+pub fn create_openid_token(user_id: &UserId) -> AppResult<(String, u64)> {
+    let expires_in = config::get().openid_token_ttl;
+    let token = utils::random_string(OPENID_TOKEN_LENGTH);
+    diesel::insert_into(user_openid_tokens::table)
+        .values((
+            user_openid_tokens::user_id.eq(user_id),
+            user_openid_tokens::token.eq(&token),
+            user_openid_tokens::expires_at
+                .eq(UnixMillis::now().get() as i64 + expires_in as i64 * 1000),
+        ))
+        .execute(&mut connect()?)?;
+    Ok((token, expires_in))
+}
+
 /// Find out which user an OpenID access token belongs to.
 pub async fn find_from_openid_token(token: &str) -> AppResult<OwnedUserId> {
     let Ok((user_id, expires_at)) = user_openid_tokens::table
@@ -320,3 +359,32 @@ pub async fn deactivate_account(user_id: &UserId) -> AppResult<()> {
         .execute(&mut connect()?)?;
     Ok(())
 }
+
+/// Stores a to-device event in the target device's inbox and forwards it to appservices
+/// owning the target user.
+pub fn add_to_device_event(
+    sender: &UserId,
+    target_user_id: &UserId,
+    target_device_id: &DeviceId,
+    event_type: &str,
+    content: JsonValue,
+) -> AppResult<()> {
+    data::user::device::add_to_device_event(
+        sender,
+        target_user_id,
+        target_device_id,
+        event_type,
+        content.clone(),
+    )?;
+    // The event is delivered through the inbox, appservices only get a copy.
+    if let Err(e) = crate::appservice::send_to_device(
+        sender,
+        target_user_id,
+        target_device_id,
+        event_type,
+        &content,
+    ) {
+        warn!("failed to send to-device event to appservices: {e}");
+    }
+    Ok(())
+}
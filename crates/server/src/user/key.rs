@@ -2,10 +2,12 @@ use std::collections::{BTreeMap, HashMap, hash_map};
 use std::time::Instant;
 
 use diesel::prelude::*;
+use diesel::upsert::excluded;
 use futures_util::stream::{FuturesUnordered, StreamExt};
+use serde::Serialize;
 use serde_json::json;
 
-use crate::core::client::key::ClaimKeysResBody;
+use crate::core::client::key::{ClaimKeysResBody, Failure, FailureErrorCode, SignedKeys};
 use crate::core::device::DeviceListUpdateContent;
 use crate::core::encryption::{CrossSigningKey, DeviceKeys, OneTimeKey};
 use crate::core::federation::key::{
@@ -13,12 +15,16 @@ use crate::core::federation::key::{
 };
 use crate::core::federation::transaction::{Edu, SigningKeyUpdateContent};
 use crate::core::identifiers::*;
-use crate::core::serde::JsonValue;
+use crate::core::serde::{
+    Base64, CanonicalJsonObject, CanonicalJsonValue, JsonValue, RawJson, RawJsonValue,
+};
+use crate::core::signatures::{self, PublicKeyMap, PublicKeySet};
 use crate::core::{DeviceKeyAlgorithm, UnixMillis, client, federation};
 use crate::data::connect;
 use crate::data::schema::*;
 use crate::data::user::{
-    DbOneTimeKey, NewDbCrossSignature, NewDbCrossSigningKey, NewDbKeyChange, NewDbOneTimeKey,
+    DbFallbackKey, DbOneTimeKey, NewDbCrossSignature, NewDbCrossSigningKey, NewDbFallbackKey,
+    NewDbKeyChange, NewDbOneTimeKey,
 };
 use crate::exts::*;
 use crate::user::clean_signatures;
@@ -233,19 +239,25 @@ pub async fn claim_one_time_keys(
 }
 
 pub fn get_master_key(user_id: &UserId) -> AppResult<Option<CrossSigningKey>> {
-    let key_data = e2e_cross_signing_keys::table
-        .filter(e2e_cross_signing_keys::user_id.eq(user_id))
-        .filter(e2e_cross_signing_keys::key_type.eq("master"))
-        .select(e2e_cross_signing_keys::key_data)
-        .first::<JsonValue>(&mut connect()?)
-        .optional()?;
-    if let Some(key_data) = key_data {
+    if let Some(key_data) = get_master_key_json(user_id)? {
         Ok(serde_json::from_value(key_data).ok())
     } else {
         Ok(None)
     }
 }
 
+/// Returns the master key as stored, with every signature made of it.
+fn get_master_key_json(user_id: &UserId) -> AppResult<Option<JsonValue>> {
+    e2e_cross_signing_keys::table
+        .filter(e2e_cross_signing_keys::user_id.eq(user_id))
+        .filter(e2e_cross_signing_keys::key_type.eq("master"))
+        .order_by(e2e_cross_signing_keys::id.desc())
+        .select(e2e_cross_signing_keys::key_data)
+        .first::<JsonValue>(&mut connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
 pub fn get_allowed_master_key(
     sender_id: Option<&UserId>,
     user_id: &UserId,
@@ -319,23 +331,69 @@ pub fn add_one_time_key(
     key_id: &DeviceKeyId,
     one_time_key: &OneTimeKey,
 ) -> AppResult<()> {
+    let key_data = serde_json::to_value(one_time_key)?;
+    let existing = e2e_one_time_keys::table
+        .filter(e2e_one_time_keys::user_id.eq(user_id))
+        .filter(e2e_one_time_keys::device_id.eq(device_id))
+        .filter(e2e_one_time_keys::algorithm.eq(key_id.algorithm().as_ref()))
+        .filter(e2e_one_time_keys::key_id.eq(key_id.as_str()))
+        .select(e2e_one_time_keys::key_data)
+        .first::<JsonValue>(&mut connect()?)
+        .optional()?;
+    if let Some(existing) = existing {
+        // Clients may upload the same key again, but not reuse its id.
+        if existing != key_data {
+            return Err(MatrixError::invalid_param(format!(
+                "one time key {key_id} already exists"
+            ))
+            .into());
+        }
+        return Ok(());
+    }
     diesel::insert_into(e2e_one_time_keys::table)
         .values(&NewDbOneTimeKey {
             user_id: user_id.to_owned(),
             device_id: device_id.to_owned(),
             algorithm: key_id.algorithm().to_string(),
             key_id: key_id.to_owned(),
-            key_data: serde_json::to_value(one_time_key).unwrap(),
+            key_data,
+            created_at: UnixMillis::now(),
+        })
+        .on_conflict_do_nothing()
+        .execute(&mut connect()?)?;
+    Ok(())
+}
+
+/// Stores the fallback key of its algorithm for the device, replacing the previous one.
+pub fn add_fallback_key(
+    user_id: &UserId,
+    device_id: &DeviceId,
+    key_id: &DeviceKeyId,
+    fallback_key: &OneTimeKey,
+) -> AppResult<()> {
+    let key_data = serde_json::to_value(fallback_key)?;
+    diesel::insert_into(e2e_fallback_keys::table)
+        .values(&NewDbFallbackKey {
+            user_id: user_id.to_owned(),
+            device_id: device_id.to_owned(),
+            algorithm: key_id.algorithm().to_string(),
+            key_id: key_id.to_owned(),
+            key_data: key_data.clone(),
+            used_at: None,
             created_at: UnixMillis::now(),
         })
         .on_conflict((
-            e2e_one_time_keys::user_id,
-            e2e_one_time_keys::device_id,
-            e2e_one_time_keys::algorithm,
-            e2e_one_time_keys::key_id,
+            e2e_fallback_keys::user_id,
+            e2e_fallback_keys::device_id,
+            e2e_fallback_keys::algorithm,
         ))
         .do_update()
-        .set(e2e_one_time_keys::key_data.eq(serde_json::to_value(one_time_key).unwrap()))
+        .set((
+            e2e_fallback_keys::key_id.eq(key_id.as_str()),
+            e2e_fallback_keys::key_data.eq(key_data),
+            e2e_fallback_keys::used_at.eq(None::<i64>),
+            e2e_fallback_keys::created_at.eq(UnixMillis::now()),
+        ))
         .execute(&mut connect()?)?;
     Ok(())
 }
@@ -360,20 +418,47 @@ pub fn claim_one_time_key(
     }) = one_time_key
     {
         diesel::delete(e2e_one_time_keys::table.find(id)).execute(&mut connect()?)?;
-        Ok(Some((
+        return Ok(Some((
             key_id,
             serde_json::from_value::<OneTimeKey>(key_data)?,
-        )))
-    } else {
-        Ok(None)
+        )));
+    }
+
+    // Without one-time keys left, the fallback key is handed out until the
+    // device replaces it.
+    let fallback_key = e2e_fallback_keys::table
+        .filter(e2e_fallback_keys::user_id.eq(user_id))
+        .filter(e2e_fallback_keys::device_id.eq(device_id))
+        .filter(e2e_fallback_keys::algorithm.eq(key_algorithm.as_ref()))
+        .first::<DbFallbackKey>(&mut connect()?)
+        .optional()?;
+    let Some(fallback_key) = fallback_key else {
+        return Ok(None);
+    };
+    if fallback_key.used_at.is_none() {
+        diesel::update(e2e_fallback_keys::table.find(fallback_key.id))
+            .set(e2e_fallback_keys::used_at.eq(UnixMillis::now().get() as i64))
+            .execute(&mut connect()?)?;
     }
+    Ok(Some((
+        fallback_key.key_id,
+        serde_json::from_value::<OneTimeKey>(fallback_key.key_data)?,
+    )))
 }
 
 pub fn add_device_keys(
     user_id: &UserId,
     device_id: &DeviceId,
-    device_keys: &DeviceKeys,
+    device_keys: &RawJson<DeviceKeys>,
 ) -> AppResult<()> {
+    verify_device_keys(user_id, device_id, device_keys)?;
+
+    // Uploading the same keys again is not a change other devices need to know about.
+    if let Some(existing) = data::user::get_device_keys_json(user_id, device_id)?
+        && existing == device_keys.deserialize_as::<JsonValue>()?
+    {
+        return Ok(());
+    }
     data::user::add_device_keys(user_id, device_id, device_keys)?;
     mark_device_key_update(user_id, device_id)?;
     send_device_key_update(user_id, device_id)?;
@@ -387,7 +472,26 @@ pub fn add_cross_signing_keys(
     user_signing_key: &Option<CrossSigningKey>,
     notify: bool,
 ) -> AppResult<()> {
-    // TODO: Check signatures
+    let mut master_key_ids = master_key.keys.iter();
+    let (master_key_id, master_public_key) = master_key_ids
+        .next()
+        .ok_or(MatrixError::invalid_param("Master key contained no key."))?;
+    if master_key_ids.next().is_some() {
+        return Err(MatrixError::invalid_param("Master key contained more than one key.").into());
+    }
+    if master_key.user_id != user_id {
+        return Err(MatrixError::invalid_param("Master key is not the one of the user.").into());
+    }
+    // The other keys of the user are only trusted when signed by the master key.
+    for key in [self_signing_key, user_signing_key].into_iter().flatten() {
+        if key.user_id != user_id {
+            return Err(
+                MatrixError::invalid_param("Signing key is not the one of the user.").into(),
+            );
+        }
+        verify_key_signature(key, user_id, master_key_id, master_public_key)?;
+    }
+
     diesel::insert_into(e2e_cross_signing_keys::table)
         .values(NewDbCrossSigningKey {
             user_id: user_id.to_owned(),
@@ -457,37 +561,326 @@ pub fn add_cross_signing_keys(
     Ok(())
 }
 
-pub fn sign_key(
-    target_user_id: &UserId,
-    target_device_id: &str,
-    signature: (String, String),
+/// Checks that the device keys are the ones of the uploading device and are
+/// signed with its ed25519 key.
+///
+/// The signature is checked against the uploaded JSON, as it also covers the
+/// fields `DeviceKeys` drops, like `dehydrated`.
+fn verify_device_keys(
+    user_id: &UserId,
+    device_id: &DeviceId,
+    device_keys: &RawJson<DeviceKeys>,
+) -> AppResult<()> {
+    let typed_keys = device_keys
+        .deserialize()
+        .map_err(|e| MatrixError::bad_json(format!("invalid device keys: {e}")))?;
+    if typed_keys.user_id != user_id || typed_keys.device_id != device_id {
+        return Err(MatrixError::invalid_param(
+            "device keys are not the ones of the device uploading them",
+        )
+        .into());
+    }
+    let signing_key_id = DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, device_id);
+    let signing_key = typed_keys
+        .keys
+        .get(&signing_key_id)
+        .ok_or_else(|| MatrixError::invalid_param("device keys have no ed25519 key"))?;
+    let object: CanonicalJsonObject = device_keys
+        .deserialize_as()
+        .map_err(|e| MatrixError::bad_json(format!("invalid device keys: {e}")))?;
+    verify_key_signature(&object, user_id, &signing_key_id, signing_key)
+}
+
+/// Checks that the key object carries a valid signature of the user made with
+/// the ed25519 key `signing_key_id`, whose public key is `signing_key`.
+fn verify_key_signature<T: Serialize>(
+    key: &T,
+    user_id: &UserId,
+    signing_key_id: &DeviceKeyId,
+    signing_key: &str,
+) -> AppResult<()> {
+    let mut object: CanonicalJsonObject = serde_json::from_value(serde_json::to_value(key)?)?;
+    let signature = match object.get("signatures") {
+        Some(CanonicalJsonValue::Object(signatures)) => match signatures.get(user_id.as_str()) {
+            Some(CanonicalJsonValue::Object(user_signatures)) => {
+                user_signatures.get(signing_key_id.as_str()).cloned()
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+    .ok_or_else(|| MatrixError::invalid_param(format!("key is not signed by {signing_key_id}")))?;
+    let public_key = Base64::parse(signing_key)
+        .map_err(|_| MatrixError::invalid_param(format!("invalid key {signing_key_id}")))?;
+
+    // Other signatures may be made with keys unknown here, only this one is checked.
+    let user_signatures = CanonicalJsonObject::from([(signing_key_id.to_string(), signature)]);
+    object.insert(
+        "signatures".to_owned(),
+        CanonicalJsonValue::Object(CanonicalJsonObject::from([(
+            user_id.to_string(),
+            CanonicalJsonValue::Object(user_signatures),
+        )])),
+    );
+    let public_keys = PublicKeyMap::from([(
+        user_id.to_string(),
+        PublicKeySet::from([(signing_key_id.to_string(), public_key)]),
+    )]);
+    signatures::verify_json(&public_keys, &object).map_err(|e| {
+        MatrixError::invalid_param(format!("invalid signature of {signing_key_id}: {e}"))
+    })?;
+    Ok(())
+}
+
+/// Those allowed to sign a key with the uploaded signatures.
+enum KeySigner {
+    /// The self-signing key of the user, for the keys of their devices.
+    SelfSigningKey,
+    /// The devices of the user, for their own master key.
+    Devices,
+    /// The user-signing key of the user, for the master key of other users.
+    UserSigningKey,
+}
+
+/// Checks and stores the signatures the sender made of the keys, returning
+/// why those not stored failed, by user and key.
+pub fn upload_signatures(
+    sender_id: &UserId,
+    signed_keys: &BTreeMap<OwnedUserId, SignedKeys>,
+) -> AppResult<BTreeMap<OwnedUserId, BTreeMap<String, Failure>>> {
+    let mut failures: BTreeMap<OwnedUserId, BTreeMap<String, Failure>> = BTreeMap::new();
+    for (user_id, keys) in signed_keys {
+        let mut signed = false;
+        for (key_id, key) in keys.iter() {
+            match sign_key(sender_id, user_id, key_id, key)? {
+                Some(failure) => {
+                    failures
+                        .entry(user_id.clone())
+                        .or_default()
+                        .insert(key_id.to_owned(), failure);
+                }
+                None => signed = true,
+            }
+        }
+        // Users seeing the keys refetch them, and for local users the servers
+        // sharing rooms with them get their updated keys. The server of a
+        // remote user gets the signatures the sender made of its master key.
+        if signed {
+            mark_signing_key_update(user_id)?;
+            if !user_id.is_local() {
+                send_master_key_signatures(sender_id, user_id);
+            }
+        }
+    }
+    Ok(failures)
+}
+
+/// Stores the signatures of the sender in the uploaded key, if they all sign
+/// the key as stored with a key allowed to sign it, or returns why they do not.
+fn sign_key(
+    sender_id: &UserId,
+    user_id: &UserId,
+    key_id: &str,
+    key: &RawJsonValue,
+) -> AppResult<Option<Failure>> {
+    let invalid = |error: &str| {
+        Ok(Some(Failure::new(
+            FailureErrorCode::InvalidSignature,
+            error,
+        )))
+    };
+
+    let Ok(mut uploaded_key) = serde_json::from_str::<CanonicalJsonObject>(key.get()) else {
+        return invalid("Invalid key JSON.");
+    };
+    let mut signatures = BTreeMap::new();
+    if let Some(CanonicalJsonValue::Object(all_signatures)) = uploaded_key.get("signatures")
+        && let Some(CanonicalJsonValue::Object(sender_signatures)) =
+            all_signatures.get(sender_id.as_str())
+    {
+        for (signing_key_id, signature) in sender_signatures {
+            let (Ok(signing_key_id), CanonicalJsonValue::String(signature)) =
+                (DeviceKeyId::parse(signing_key_id), signature)
+            else {
+                return invalid("Invalid signature.");
+            };
+            signatures.insert(signing_key_id, signature.clone());
+        }
+    }
+    if signatures.is_empty() {
+        return Ok(Some(Failure::new(
+            FailureErrorCode::MissingParam,
+            "Key is not signed by the sender.",
+        )));
+    }
+
+    let device_keys = if user_id == sender_id {
+        data::user::get_device_keys_json(user_id, key_id.into())?
+    } else {
+        None
+    };
+    let is_master_key = |master_key: &JsonValue| {
+        master_key
+            .get("keys")
+            .and_then(JsonValue::as_object)
+            .is_some_and(|keys| {
+                keys.keys()
+                    .any(|id| id.split_once(':').is_some_and(|(_, name)| name == key_id))
+            })
+    };
+    // Compared as stored, so fields unknown to the typed keys are kept.
+    let (stored_key, signer) = if let Some(device_keys) = device_keys {
+        (device_keys, KeySigner::SelfSigningKey)
+    } else if let Some(master_key) = get_master_key_json(user_id)?.filter(is_master_key) {
+        let signer = if user_id == sender_id {
+            KeySigner::Devices
+        } else {
+            KeySigner::UserSigningKey
+        };
+        (master_key, signer)
+    } else {
+        return invalid("Unknown key.");
+    };
+
+    // Signatures are only valid for the key as stored, other changes are not allowed.
+    let Ok(mut stored_key) = serde_json::from_value::<CanonicalJsonObject>(stored_key) else {
+        return invalid("Stored key is invalid.");
+    };
+    for object in [&mut stored_key, &mut uploaded_key] {
+        object.remove("signatures");
+        object.remove("unsigned");
+    }
+    if stored_key != uploaded_key {
+        return invalid("Key does not match the stored one.");
+    }
+    let mut signed_key = stored_key;
+    signed_key.insert(
+        "signatures".to_owned(),
+        serde_json::from_value(json!({ sender_id.as_str(): &signatures }))?,
+    );
+
+    for signing_key_id in signatures.keys() {
+        let signing_key = match signer {
+            KeySigner::SelfSigningKey => get_self_signing_key(sender_id)?
+                .and_then(|key| key.keys.get(signing_key_id).cloned()),
+            KeySigner::UserSigningKey => get_user_signing_key(sender_id)?
+                .and_then(|key| key.keys.get(signing_key_id).cloned()),
+            KeySigner::Devices => {
+                data::user::get_device_keys(sender_id, signing_key_id.key_name())?
+                    .and_then(|keys| keys.keys.get(signing_key_id).cloned())
+            }
+        };
+        let Some(signing_key) = signing_key else {
+            return invalid("Signed with a key not allowed to sign it.");
+        };
+        if verify_key_signature(&signed_key, sender_id, signing_key_id, &signing_key).is_err() {
+            return invalid("Invalid signature.");
+        }
+    }
+
+    for (signing_key_id, signature) in signatures {
+        match signer {
+            KeySigner::SelfSigningKey => {
+                diesel::insert_into(e2e_cross_signing_sigs::table)
+                    .values(NewDbCrossSignature {
+                        origin_user_id: sender_id.to_owned(),
+                        origin_key_id: signing_key_id,
+                        target_user_id: user_id.to_owned(),
+                        target_device_id: OwnedDeviceId::from(key_id),
+                        signature,
+                    })
+                    .on_conflict((
+                        e2e_cross_signing_sigs::origin_user_id,
+                        e2e_cross_signing_sigs::origin_key_id,
+                        e2e_cross_signing_sigs::target_user_id,
+                        e2e_cross_signing_sigs::target_device_id,
+                    ))
+                    .do_update()
+                    .set(
+                        e2e_cross_signing_sigs::signature
+                            .eq(excluded(e2e_cross_signing_sigs::signature)),
+                    )
+                    .execute(&mut connect()?)?;
+            }
+            // Signatures of master keys are kept with the key, they are shown
+            // to those allowed to see them when the key is queried.
+            KeySigner::Devices | KeySigner::UserSigningKey => {
+                add_master_key_signature(user_id, sender_id, &signing_key_id, signature)?;
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Sends the signatures the sender made of the master key of a remote user to
+/// the server of that user with `m.signing_key_update`.
+fn send_master_key_signatures(sender_id: &UserId, user_id: &UserId) {
+    let result = get_allowed_master_key(None, user_id, &|signer| signer == sender_id).and_then(
+        |master_key| {
+            let mut content = SigningKeyUpdateContent::new(user_id.to_owned());
+            content.master_key = master_key;
+            sending::send_edu_server(user_id.server_name(), &Edu::SigningKeyUpdate(content))
+        },
+    );
+    if let Err(e) = result {
+        warn!("failed to send the signatures of {sender_id} on the master key of {user_id}: {e}");
+    }
+}
+
+/// Stores the signatures users of `origin` made of the master key of a local
+/// user, if the key is the one stored. They are only shown to their signer.
+pub fn add_remote_master_key_signatures(
+    origin: &ServerName,
+    master_key: &CrossSigningKey,
+) -> AppResult<()> {
+    let Some(stored_key) = get_master_key(&master_key.user_id)? else {
+        return Ok(());
+    };
+    if stored_key.keys != master_key.keys {
+        return Err(MatrixError::invalid_param("Master key does not match the stored one.").into());
+    }
+    for (signer_id, signatures) in &master_key.signatures {
+        if signer_id.server_name() != origin {
+            continue;
+        }
+        for (signing_key_id, signature) in signatures {
+            add_master_key_signature(
+                &master_key.user_id,
+                signer_id,
+                signing_key_id,
+                signature.clone(),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn add_master_key_signature(
+    user_id: &UserId,
     sender_id: &UserId,
+    signing_key_id: &DeviceKeyId,
+    signature: String,
 ) -> AppResult<()> {
-    // let cross_signing_key = e2e_cross_signing_keys::table
-    //     .filter(e2e_cross_signing_keys::user_id.eq(target_id))
-    //     .filter(e2e_cross_signing_keys::key_type.eq("master"))
-    //     .order_by(e2e_cross_signing_keys::id.desc())
-    //     .first::<DbCrossSigningKey>(&mut connect()?)?;
-    // let mut cross_signing_key: CrossSigningKey =
-    // serde_json::from_value(cross_signing_key.key_data.clone())?;
-    let origin_key_id = DeviceKeyId::parse(&signature.0)?.to_owned();
-
-    // cross_signing_key
-    //     .signatures
-    //     .entry(sender_id.to_owned())
-    //     .or_defaut()
-    //     .insert(key_id.clone(), signature.1);
-
-    diesel::insert_into(e2e_cross_signing_sigs::table)
-        .values(NewDbCrossSignature {
-            origin_user_id: sender_id.to_owned(),
-            origin_key_id,
-            target_user_id: target_user_id.to_owned(),
-            target_device_id: OwnedDeviceId::from(target_device_id),
-            signature: signature.1,
-        })
+    let Some((id, mut key_data)) = e2e_cross_signing_keys::table
+        .filter(e2e_cross_signing_keys::user_id.eq(user_id))
+        .filter(e2e_cross_signing_keys::key_type.eq("master"))
+        .order_by(e2e_cross_signing_keys::id.desc())
+        .select((e2e_cross_signing_keys::id, e2e_cross_signing_keys::key_data))
+        .first::<(i64, JsonValue)>(&mut connect()?)
+        .optional()?
+    else {
+        return Ok(());
+    };
+    // Parsing checks the signatures are objects, which makes indexing them
+    // safe. The key is edited as JSON so fields unknown to `CrossSigningKey`
+    // are kept.
+    serde_json::from_value::<CrossSigningKey>(key_data.clone())?;
+    key_data["signatures"][sender_id.as_str()][signing_key_id.as_str()] =
+        JsonValue::String(signature);
+    diesel::update(e2e_cross_signing_keys::table.find(id))
+        .set(e2e_cross_signing_keys::key_data.eq(key_data))
         .execute(&mut connect()?)?;
-    mark_signing_key_update(target_user_id)
+    Ok(())
 }
 
 pub fn mark_signing_key_update(user_id: &UserId) -> AppResult<()> {
@@ -534,6 +927,9 @@ pub fn mark_signing_key_update(user_id: &UserId) -> AppResult<()> {
     diesel::insert_into(e2e_key_changes::table)
         .values(&change)
         .execute(&mut connect()?)?;
+    if let Err(e) = crate::appservice::send_device_list_update(user_id, &joined_rooms) {
+        warn!("failed to send device list update of {user_id} to appservices: {e}");
+    }
 
     if user_id.is_local() {
         let remote_servers = room_joined_servers::table
@@ -542,7 +938,10 @@ pub fn mark_signing_key_update(user_id: &UserId) -> AppResult<()> {
             .distinct()
             .load::<OwnedServerName>(&mut connect()?)?;
 
-        let content = SigningKeyUpdateContent::new(user_id.to_owned());
+        let mut content = SigningKeyUpdateContent::new(user_id.to_owned());
+        // Signatures other users made of the master key are only for them to see.
+        content.master_key = get_allowed_master_key(None, user_id, &|_| false)?;
+        content.self_signing_key = get_self_signing_key(user_id)?;
         let edu = Edu::SigningKeyUpdate(content);
 
         let _ = sending::send_edu_servers(remote_servers.into_iter(), &edu);
@@ -596,6 +995,9 @@ pub fn mark_device_key_update(user_id: &UserId, _device_id: &DeviceId) -> AppRes
     diesel::insert_into(e2e_key_changes::table)
         .values(&change)
         .execute(&mut connect()?)?;
+    if let Err(e) = crate::appservice::send_device_list_update(user_id, &joined_rooms) {
+        warn!("failed to send device list update of {user_id} to appservices: {e}");
+    }
 
     Ok(())
 }
@@ -625,6 +1027,9 @@ pub fn mark_device_key_update_with_joined_rooms(
             .values(&change)
             .execute(&mut connect()?)?;
     }
+    if let Err(e) = crate::appservice::send_device_list_update(user_id, joined_rooms) {
+        warn!("failed to send device list update of {user_id} to appservices: {e}");
+    }
     Ok(())
 }
 
@@ -656,3 +1061,71 @@ fn send_device_key_update_with_joined_rooms(
 
     sending::send_edu_servers(remote_servers.into_iter(), &edu)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_key(user_id: &UserId) -> (CanonicalJsonObject, OwnedDeviceKeyId, String) {
+        let keypair = crate::utils::generate_keypair();
+        let mut object = CanonicalJsonObject::new();
+        object.insert("user_id".to_owned(), user_id.to_string().into());
+        signatures::sign_json(user_id.as_str(), &keypair, &mut object).unwrap();
+        let key_id = DeviceKeyId::parse(format!("ed25519:{}", keypair.version())).unwrap();
+        let public_key =
+            Base64::<crate::core::serde::base64::Standard>::new(keypair.public_key().to_vec());
+        (object, key_id, public_key.encode())
+    }
+
+    #[test]
+    fn accepts_key_signed_by_user() {
+        let user_id = UserId::parse("@alice:example.org").unwrap();
+        let (mut object, key_id, public_key) = signed_key(&user_id);
+        // Signatures of other users are not checked.
+        let other_keypair = crate::utils::generate_keypair();
+        signatures::sign_json("@bob:example.org", &other_keypair, &mut object).unwrap();
+        assert!(verify_key_signature(&object, &user_id, &key_id, &public_key).is_ok());
+    }
+
+    #[test]
+    fn accepts_device_keys_with_unknown_signed_fields() {
+        let user_id = UserId::parse("@alice:example.org").unwrap();
+        let keypair = crate::utils::generate_keypair();
+        let device_id = OwnedDeviceId::from(keypair.version());
+        let public_key =
+            Base64::<crate::core::serde::base64::Standard>::new(keypair.public_key().to_vec());
+        let mut object: CanonicalJsonObject = serde_json::from_value(serde_json::json!({
+            "user_id": user_id,
+            "device_id": device_id,
+            "algorithms": ["m.olm.v1.curve25519-aes-sha2"],
+            "keys": {
+                format!("curve25519:{device_id}"): "curve25519+key",
+                format!("ed25519:{device_id}"): public_key.encode(),
+            },
+            "dehydrated": true,
+        }))
+        .unwrap();
+        signatures::sign_json(user_id.as_str(), &keypair, &mut object).unwrap();
+        let device_keys = RawJson::new(&object).unwrap().cast::<DeviceKeys>();
+        assert!(verify_device_keys(&user_id, &device_id, &device_keys).is_ok());
+
+        // Keys served from the typed form keep the field, and so their signature.
+        let typed_keys = RawJson::new(&device_keys.deserialize().unwrap()).unwrap();
+        assert!(verify_device_keys(&user_id, &device_id, &typed_keys).is_ok());
+
+        object.insert("dehydrated".to_owned(), false.into());
+        let tampered_keys = RawJson::new(&object).unwrap().cast::<DeviceKeys>();
+        assert!(verify_device_keys(&user_id, &device_id, &tampered_keys).is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_key() {
+        let user_id = UserId::parse("@alice:example.org").unwrap();
+        let (mut object, key_id, public_key) = signed_key(&user_id);
+        object.insert(
+            "user_id".to_owned(),
+            "@mallory:example.org".to_owned().into(),
+        );
+        assert!(verify_key_signature(&object, &user_id, &key_id, &public_key).is_err());
+    }
+}
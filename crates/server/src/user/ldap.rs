@@ -1,9 +1,25 @@
 use std::collections::HashMap;
+use std::time::Duration;
 
-use ldap3::{LdapConnAsync, Scope, SearchEntry};
+use diesel::prelude::*;
+use ldap3::{Ldap, LdapConnAsync, Scope, SearchEntry};
+use tokio::task::JoinHandle;
 
-use crate::core::UserId;
-use crate::{AppError, AppResult, MatrixError, config};
+use crate::config::LdapConfig;
+use crate::core::UnixMillis;
+use crate::core::identifiers::*;
+use crate::data::connect;
+use crate::data::schema::*;
+use crate::data::user::{DbUser, NewDbUser};
+use crate::{AppError, AppResult, IsRemoteOrLocal, MatrixError, TOKEN_LENGTH, config, data, utils};
+
+/// User type of the accounts created on their first LDAP login.
+const LDAP_USER_TYPE: &str = "ldap";
+
+/// Returns whether the account was created by an LDAP login.
+pub fn is_ldap_user(user: &DbUser) -> bool {
+    user.ty.as_deref() == Some(LDAP_USER_TYPE)
+}
 
 /// Escape special characters in LDAP filter values according to RFC 4515.
 ///
@@ -28,14 +44,51 @@ fn escape_ldap_filter_value(value: &str) -> String {
     escaped
 }
 
+/// Escape special characters in a DN attribute value according to RFC 4514.
+fn escape_ldap_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() * 2);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' | ' ' if i == 0 => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\0' => escaped.push_str("\\00"),
+            _ => escaped.push(c),
+        }
+    }
+    if value.len() > 1 && value.ends_with(' ') {
+        escaped.pop();
+        escaped.push_str("\\ ");
+    }
+    escaped
+}
+
+/// Attributes of group entries that list their members, by DN or by uid.
+const MEMBER_ATTRIBUTES: [&str; 3] = ["member", "uniqueMember", "memberUid"];
+
+/// Looks up the DNs of the user, along with whether each is an admin.
 pub async fn search_ldap(user_id: &UserId) -> AppResult<Vec<(String, bool)>> {
-    let localpart = user_id.localpart().to_owned();
-    let lowercased_localpart = localpart.to_lowercase();
-    // Escape special LDAP filter characters to prevent LDAP injection attacks
-    let escaped_localpart = escape_ldap_filter_value(&lowercased_localpart);
+    let config = config::get();
+    let conf = config
+        .enabled_ldap()
+        .ok_or_else(|| AppError::public("LDAP is not enabled in the configuration"))?;
+    let (mut ldap, driver) = connect_ldap(conf).await?;
+    let dns = search_user(&mut ldap, conf, user_id).await;
+
+    ldap.unbind()
+        .await
+        .map_err(|e| AppError::public(format!("LDAP unbind error: {e}")))?;
+    driver.await.ok();
+    dns
+}
 
-    let conf =
-        config::enabled_ldap().ok_or_else(|| AppError::public("LDAP is not enabled in the configuration"))?;
+/// Opens a connection and binds with the configured search credentials.
+async fn connect_ldap(conf: &LdapConfig) -> AppResult<(Ldap, JoinHandle<()>)> {
     let uri = conf
         .uri
         .as_ref()
@@ -44,7 +97,7 @@ pub async fn search_ldap(user_id: &UserId) -> AppResult<Vec<(String, bool)>> {
     debug!(?uri, "LDAP creating connection...");
     let (conn, mut ldap) = LdapConnAsync::new(uri.as_str())
         .await
-        .map_err(|e| AppError::public("LDAP connection setup error: {e}"))?;
+        .map_err(|e| AppError::public(format!("LDAP connection setup error: {e}")))?;
 
     let driver = tokio::spawn(async move {
         match conn.drive().await {
@@ -53,16 +106,33 @@ pub async fn search_ldap(user_id: &UserId) -> AppResult<Vec<(String, bool)>> {
         }
     });
 
-    match (&conf.bind_dn, &conf.bind_password_file) {
-        (Some(bind_dn), Some(bind_password_file)) => {
-            let bind_pw = String::from_utf8(std::fs::read(bind_password_file)?)?;
-            ldap.simple_bind(bind_dn, bind_pw.trim())
-                .await
-                .and_then(ldap3::LdapResult::success)
-                .map_err(|e| AppError::public(format!("LDAP bind error: {e}")))?;
-        }
-        (..) => {}
+    if let (Some(bind_dn), Some(bind_password_file)) = (&conf.bind_dn, &conf.bind_password_file) {
+        let bind_pw = String::from_utf8(std::fs::read(bind_password_file)?)?;
+        ldap.simple_bind(bind_dn, bind_pw.trim())
+            .await
+            .and_then(ldap3::LdapResult::success)
+            .map_err(|e| AppError::public(format!("LDAP bind error: {e}")))?;
     }
+    Ok((ldap, driver))
+}
+
+async fn search_user(
+    ldap: &mut Ldap,
+    conf: &LdapConfig,
+    user_id: &UserId,
+) -> AppResult<Vec<(String, bool)>> {
+    let localpart = user_id.localpart().to_owned();
+    let lowercased_localpart = localpart.to_lowercase();
+    // Escape special LDAP filter characters to prevent LDAP injection attacks
+    let escaped_localpart = escape_ldap_filter_value(&lowercased_localpart);
+    let names_user = |entry: &SearchEntry| {
+        entry
+            .attrs
+            .get(&conf.uid_attribute)
+            .into_iter()
+            .chain(entry.attrs.get(&conf.name_attribute))
+            .any(|ids| ids.contains(&localpart) || ids.contains(&lowercased_localpart))
+    };
 
     let attr = [&conf.uid_attribute, &conf.name_attribute];
 
@@ -80,13 +150,7 @@ pub async fn search_ldap(user_id: &UserId) -> AppResult<Vec<(String, bool)>> {
         .filter_map(|entry| {
             let search_entry = SearchEntry::construct(entry);
             debug!(?search_entry, "LDAP search entry");
-            search_entry
-                .attrs
-                .get(&conf.uid_attribute)
-                .into_iter()
-                .chain(search_entry.attrs.get(&conf.name_attribute))
-                .any(|ids| ids.contains(&localpart) || ids.contains(&lowercased_localpart))
-                .then_some((search_entry.dn, false))
+            names_user(&search_entry).then_some((search_entry.dn, false))
         })
         .collect();
 
@@ -98,43 +162,56 @@ pub async fn search_ldap(user_id: &UserId) -> AppResult<Vec<(String, bool)>> {
         };
 
         let admin_filter = &conf.admin_filter.replace("{username}", &escaped_localpart);
+        let admin_attr = [conf.uid_attribute.as_str(), conf.name_attribute.as_str()]
+            .into_iter()
+            .chain(MEMBER_ATTRIBUTES)
+            .collect::<Vec<_>>();
 
         let (admin_entries, _result) = ldap
-            .search(admin_base_dn, Scope::Subtree, admin_filter, &attr)
+            .search(admin_base_dn, Scope::Subtree, admin_filter, admin_attr)
             .await
             .and_then(ldap3::SearchResult::success)
             .inspect(|(entries, result)| trace!(?entries, ?result, "LDAP Admin Search"))
             .map_err(|e| AppError::public(format!("Ldap admin search error: {e}")))?;
 
-        dns.extend(admin_entries.into_iter().filter_map(|entry| {
+        for entry in admin_entries {
             let search_entry = SearchEntry::construct(entry);
-            debug!(?search_entry, "LDAP search entry");
-            search_entry
-                .attrs
-                .get(&conf.uid_attribute)
-                .into_iter()
-                .chain(search_entry.attrs.get(&conf.name_attribute))
-                .any(|ids| ids.contains(&localpart) || ids.contains(&lowercased_localpart))
-                .then_some((search_entry.dn, true))
-        }));
+            debug!(?search_entry, "LDAP admin search entry");
+            if names_user(&search_entry) {
+                // The admin filter matched the user entry itself.
+                dns.insert(search_entry.dn, true);
+                continue;
+            }
+            // Otherwise it matched a group, which only makes its members admins.
+            let members = MEMBER_ATTRIBUTES
+                .iter()
+                .filter_map(|attr| search_entry.attrs.get(*attr))
+                .flatten()
+                .collect::<Vec<_>>();
+            for (dn, is_admin) in dns.iter_mut() {
+                if members.iter().any(|member| {
+                    member.eq_ignore_ascii_case(dn)
+                        || **member == localpart
+                        || **member == lowercased_localpart
+                }) {
+                    *is_admin = true;
+                }
+            }
+        }
     }
 
-    ldap.unbind()
-        .await
-        .map_err(|e| AppError::public(format!("LDAP unbind error: {e}")))?;
-
-    driver.await.ok();
-
     Ok(dns.drain().collect())
 }
 
 pub async fn auth_ldap(user_dn: &str, password: &str) -> AppResult<()> {
-    let conf =
-        config::enabled_ldap().ok_or_else(|| AppError::public("LDAP is not enabled in the configuration"))?;
+    let config = config::get();
+    let conf = config
+        .enabled_ldap()
+        .ok_or_else(|| AppError::public("LDAP is not enabled in the configuration"))?;
     let uri = conf
         .uri
         .as_ref()
-        .ok_or_else(|| AppError::public(format!("LDAP URI is not configured")))?;
+        .ok_or_else(|| AppError::public("LDAP URI is not configured."))?;
 
     debug!(?uri, "LDAP creating connection...");
     let (conn, mut ldap) = LdapConnAsync::new(uri.as_str())
@@ -161,3 +238,193 @@ pub async fn auth_ldap(user_dn: &str, password: &str) -> AppResult<()> {
 
     Ok(())
 }
+
+/// Authenticates a password login against LDAP, creating the account on the
+/// first successful login and syncing its admin flag when `admin_filter` is set.
+///
+/// Returns `false` if the user is not known to LDAP, in which case the login
+/// falls back to the local password.
+pub async fn login_ldap(user_id: &UserId, password: &str) -> AppResult<bool> {
+    let config = config::get();
+    let conf = config
+        .enabled_ldap()
+        .ok_or_else(|| AppError::public("LDAP is not enabled in the configuration"))?;
+    // An empty password would make an unauthenticated bind, which always succeeds.
+    if password.is_empty() {
+        return Err(MatrixError::forbidden("Wrong username or password.", None).into());
+    }
+
+    let (user_dn, is_ldap_admin, direct_bind) = match conf.bind_dn.as_ref() {
+        Some(bind_dn) if bind_dn.contains("{username}") => (
+            bind_dn.replace("{username}", &escape_ldap_dn_value(user_id.localpart())),
+            None,
+            true,
+        ),
+        _ => {
+            let mut dns = search_ldap(user_id).await?;
+            if dns.len() >= 2 {
+                return Err(MatrixError::forbidden(
+                    "LDAP search returned two or more results.",
+                    None,
+                )
+                .into());
+            }
+            let Some((user_dn, is_admin)) = dns.pop() else {
+                return Ok(false);
+            };
+            (
+                user_dn,
+                (!conf.admin_filter.is_empty()).then_some(is_admin),
+                false,
+            )
+        }
+    };
+
+    let user = data::user::get_user(user_id).ok();
+    if user
+        .as_ref()
+        .is_some_and(|user| user.deactivated_at.is_some())
+    {
+        return Err(MatrixError::user_deactivated("the user has been deactivated").into());
+    }
+    if let Err(e) = auth_ldap(&user_dn, password).await {
+        // A failed direct bind can't tell a wrong password from a user missing in LDAP.
+        if direct_bind {
+            debug!("LDAP bind failed for {user_id}: {e}");
+            return Ok(false);
+        }
+        return Err(e);
+    }
+
+    // LDAP users are automatically created on first login attempt. This is a very
+    // common feature that can be seen on many services using a LDAP provider for
+    // their users (synapse, Nextcloud, Jellyfin, ...).
+    let created_by_ldap = match &user {
+        Some(user) => is_ldap_user(user),
+        None => {
+            create_ldap_user(user_id)?;
+            info!("Created user {user_id} on first LDAP login.");
+            true
+        }
+    };
+
+    // Local accounts that merely share a localpart with an LDAP entry keep the
+    // admin flag they were given on this server.
+    if let Some(is_ldap_admin) = is_ldap_admin
+        && created_by_ldap
+        && data::user::is_admin(user_id)? != is_ldap_admin
+    {
+        data::user::set_admin(user_id, is_ldap_admin)?;
+        info!("Set admin of {user_id} to {is_ldap_admin} from LDAP.");
+    }
+    Ok(true)
+}
+
+fn create_ldap_user(user_id: &UserId) -> AppResult<DbUser> {
+    let new_user = NewDbUser {
+        id: user_id.to_owned(),
+        ty: Some(LDAP_USER_TYPE.to_owned()),
+        is_admin: false,
+        is_guest: false,
+        is_local: user_id.server_name().is_local(),
+        localpart: user_id.localpart().to_owned(),
+        server_name: user_id.server_name().to_owned(),
+        appservice_id: None,
+        created_at: UnixMillis::now(),
+    };
+    let user = diesel::insert_into(users::table)
+        .values(&new_user)
+        .on_conflict(users::id)
+        .do_update()
+        .set(&new_user)
+        .get_result::<DbUser>(&mut connect()?)?;
+
+    // LDAP users get a random password because an empty one is reserved for
+    // deactivated accounts. It is never used to log them in.
+    crate::user::set_password(&user.id, &utils::random_string(TOKEN_LENGTH))?;
+    if let Err(e) = data::user::set_display_name(&user.id, user.id.localpart()) {
+        tracing::warn!("failed to set profile for new user (non-fatal): {}", e);
+    }
+    Ok(user)
+}
+
+/// Periodically deactivates the users created through LDAP that were removed
+/// from the directory.
+pub fn start_ldap_sync() {
+    let Some(conf) = config::get().enabled_ldap().cloned() else {
+        return;
+    };
+    if conf.deactivation_interval == 0 {
+        return;
+    }
+    if conf
+        .bind_dn
+        .as_ref()
+        .is_some_and(|bind_dn| bind_dn.contains("{username}"))
+    {
+        warn!(
+            "LDAP users can't be searched with a direct bind, removed users won't be deactivated"
+        );
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(conf.deactivation_interval));
+        loop {
+            interval.tick().await;
+            if let Err(e) = deactivate_removed_users().await {
+                error!("failed to sync users with LDAP: {e}");
+            }
+        }
+    });
+}
+
+async fn deactivate_removed_users() -> AppResult<()> {
+    let config = config::get();
+    let conf = config
+        .enabled_ldap()
+        .ok_or_else(|| AppError::public("LDAP is not enabled in the configuration"))?;
+    let user_ids = data::user::active_users_of_type(LDAP_USER_TYPE)?;
+    if user_ids.is_empty() {
+        return Ok(());
+    }
+
+    // One connection serves the lookups of the whole round.
+    let (mut ldap, driver) = connect_ldap(conf).await?;
+    let mut removed = Vec::new();
+    for user_id in user_ids {
+        // Errors abort the whole round, so an unreachable LDAP server never
+        // deactivates anybody.
+        if search_user(&mut ldap, conf, &user_id).await?.is_empty() {
+            removed.push(user_id);
+        }
+    }
+    ldap.unbind()
+        .await
+        .map_err(|e| AppError::public(format!("LDAP unbind error: {e}")))?;
+    driver.await.ok();
+
+    for user_id in removed {
+        info!("Deactivating {user_id}, it was removed from LDAP.");
+        let joined_rooms = data::user::joined_rooms(&user_id)?;
+        crate::user::full_user_deactivate(&user_id, &joined_rooms).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_filter_values() {
+        assert_eq!(escape_ldap_filter_value("a*(b)\\c"), "a\\2a\\28b\\29\\5cc");
+    }
+
+    #[test]
+    fn escapes_dn_values() {
+        assert_eq!(escape_ldap_dn_value("a,b=c+d"), "a\\,b\\=c\\+d");
+        assert_eq!(escape_ldap_dn_value("#admin "), "\\#admin\\ ");
+        assert_eq!(escape_ldap_dn_value("alice"), "alice");
+    }
+}
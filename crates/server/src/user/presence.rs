@@ -102,6 +102,10 @@ pub fn set_presence(
             .load::<OwnedServerName>(&mut connect()?)?;
 
         sending::send_edu_servers(remote_servers.into_iter(), &edu)?;
+        // The presence is already stored, appservices only get a copy.
+        if let Err(e) = crate::appservice::send_presence(&last_presence(sender_id)?) {
+            warn!("failed to send presence to appservices: {e}");
+        }
     }
 
     Ok(state_changed)
@@ -14,7 +14,9 @@ use crate::data::connect;
 use crate::data::schema::*;
 use crate::data::user::pusher::NewDbPusher;
 use crate::event::PduEvent;
-use crate::{AppError, AppResult, AuthedInfo, data, room};
+use crate::{AppError, AppResult, AuthedInfo, MatrixError, data, room};
+
+pub mod mailer;
 
 pub fn set_pusher(authed: &AuthedInfo, pusher: PusherAction) -> AppResult<()> {
     match pusher {
@@ -32,6 +34,19 @@ pub fn set_pusher(authed: &AuthedInfo, pusher: PusherAction) -> AppResult<()> {
                     },
                 append,
             } = data;
+            if matches!(kind, PusherKind::Email(_))
+                && data::user::get_user_by_threepid(
+                    "email",
+                    &super::threepid::normalize_email(&pushkey),
+                )?
+                .as_deref()
+                    != Some(authed.user_id())
+            {
+                return Err(MatrixError::invalid_param(
+                    "Email pushers require an email address bound to the account.",
+                )
+                .into());
+            }
             if !append {
                 diesel::delete(
                     user_pushers::table
@@ -187,7 +202,6 @@ async fn send_notice(
     tweaks: Vec<Tweak>,
     event: &PduEvent,
 ) -> AppResult<()> {
-    // TODO: email
     match &pusher.kind {
         PusherKind::Http(http) => {
             // Two problems with this
@@ -246,7 +260,7 @@ async fn send_notice(
 
             Ok(())
         }
-        // TODO: Handle email
+        // Email pushers are batched by the mailer rather than notified per event.
         PusherKind::Email(_) => Ok(()),
         _ => Ok(()),
     }
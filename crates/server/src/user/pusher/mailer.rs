@@ -0,0 +1,419 @@
+//! Batched email notifications for users with an email pusher.
+//!
+//! Email pushers are not notified per event. Instead, the mailer periodically
+//! looks at what each of them has left unread and sends a single summary,
+//! once the oldest notification is `notif_delay` old and no more often than
+//! every `notif_throttle`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use subtle::ConstantTimeEq;
+use url::Url;
+
+use crate::config::{self, EmailConfig};
+use crate::core::UnixMillis;
+use crate::core::events::TimelineEventType;
+use crate::core::identifiers::*;
+use crate::core::signatures::KeyPair;
+use crate::data::user::pusher::{DbPusher, set_pusher_failing, set_pusher_success};
+use crate::event::SnPduEvent;
+use crate::utils::HtmlEscape;
+use crate::{AppResult, data, room};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// Messages listed per room, the rest are only counted.
+const MAX_MESSAGES_PER_ROOM: usize = 10;
+/// A failing pusher waits `POLL_INTERVAL` doubled for each consecutive
+/// failure before it is tried again, but never longer than this.
+const MAX_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Consecutive failures of each failing pusher and when it may be tried again,
+/// in milliseconds.
+static BACKOFF: LazyLock<Mutex<HashMap<i64, (u32, u64)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Templates loaded at startup and again on config reload.
+static TEMPLATES: ArcSwapOption<Templates> = ArcSwapOption::const_empty();
+
+pub fn start() {
+    let Some(conf) = config::get().enabled_email().cloned() else {
+        return;
+    };
+    if !conf.enable_notifs {
+        return;
+    }
+    reload_templates();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = notify_all(&conf).await {
+                error!("failed to send email notifications: {e}");
+            }
+        }
+    });
+}
+
+/// Reads the notification templates again, so changes to the files of
+/// `template_dir` apply without a restart.
+pub fn reload_templates() {
+    if let Some(conf) = config::get().enabled_email() {
+        TEMPLATES.store(Some(Arc::new(Templates::load(conf))));
+    }
+}
+
+async fn notify_all(conf: &EmailConfig) -> AppResult<()> {
+    let templates = TEMPLATES
+        .load_full()
+        .unwrap_or_else(|| Arc::new(Templates::load(conf)));
+    let pushers = data::user::pusher::get_email_pushers()?;
+    let mut seen = HashSet::new();
+    for pusher in &pushers {
+        // The same address may be registered by several apps, only email it once.
+        if !seen.insert((pusher.user_id.clone(), pusher.pushkey.clone())) {
+            continue;
+        }
+        let now = UnixMillis::now().get();
+        if let Some((_, retry_at)) = BACKOFF
+            .lock()
+            .expect("locking should not fail")
+            .get(&pusher.id)
+            && *retry_at > now
+        {
+            continue;
+        }
+        match notify_pusher(conf, &templates, pusher).await {
+            Ok(()) => {
+                BACKOFF
+                    .lock()
+                    .expect("locking should not fail")
+                    .remove(&pusher.id);
+            }
+            Err(e) => {
+                let retry_at = {
+                    let mut backoff = BACKOFF.lock().expect("locking should not fail");
+                    let (failures, retry_at) = backoff.entry(pusher.id).or_insert((0, 0));
+                    *failures += 1;
+                    let delay = POLL_INTERVAL
+                        .saturating_mul(2u32.saturating_pow(*failures))
+                        .min(MAX_BACKOFF);
+                    *retry_at = now + delay.as_millis() as u64;
+                    *retry_at
+                };
+                warn!(
+                    "failed to email notifications to {} for {}, retrying at {retry_at}: {e}",
+                    pusher.pushkey, pusher.user_id
+                );
+                if let Err(e) = set_pusher_failing(
+                    &pusher.user_id,
+                    &pusher.app_id,
+                    &pusher.pushkey,
+                    UnixMillis::now(),
+                ) {
+                    warn!(
+                        "failed to mark email pusher {} of {} as failing: {e}",
+                        pusher.pushkey, pusher.user_id
+                    );
+                }
+            }
+        }
+    }
+    // Forget the failures of pushers that were removed since.
+    BACKOFF
+        .lock()
+        .expect("locking should not fail")
+        .retain(|id, _| pushers.iter().any(|pusher| pusher.id == *id));
+    Ok(())
+}
+
+async fn notify_pusher(
+    conf: &EmailConfig,
+    templates: &Templates,
+    pusher: &DbPusher,
+) -> AppResult<()> {
+    let now = UnixMillis::now().get();
+    if let Some(last_success) = pusher.last_success
+        && last_success as u64 + conf.notif_throttle * 1000 > now
+    {
+        return Ok(());
+    }
+
+    let pdus = crate::room::push_action::get_unread_notifications(
+        &pusher.user_id,
+        pusher.last_stream_ordering.unwrap_or_default(),
+        conf.notif_highlights_only,
+    )?
+    .into_iter()
+    // Without a previous email, only notify about what happened since the pusher was added.
+    .filter(|pdu| {
+        pusher.last_stream_ordering.is_some() || pdu.origin_server_ts >= pusher.created_at
+    })
+    .collect::<Vec<_>>();
+    let (Some(first), Some(last)) = (pdus.first(), pdus.last()) else {
+        return Ok(());
+    };
+    if first.origin_server_ts.get() + conf.notif_delay * 1000 > now {
+        return Ok(());
+    }
+    let last_sn = last.event_sn;
+
+    let mut rooms = BTreeMap::<OwnedRoomId, Vec<&SnPduEvent>>::new();
+    for pdu in &pdus {
+        rooms.entry(pdu.room_id.clone()).or_default().push(pdu);
+    }
+    let room_names = rooms
+        .keys()
+        .map(|room_id| {
+            let name = room::get_name(room_id).unwrap_or_else(|_| room_id.to_string());
+            (room_id.clone(), name)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    let subject = if rooms.len() == 1 {
+        format!(
+            "[{}] {} unread message{} in {}",
+            conf.app_name,
+            pdus.len(),
+            if pdus.len() == 1 { "" } else { "s" },
+            room_names
+                .values()
+                .next()
+                .map(String::as_str)
+                .unwrap_or_default()
+        )
+    } else {
+        format!(
+            "[{}] {} unread messages in {} rooms",
+            conf.app_name,
+            pdus.len(),
+            rooms.len()
+        )
+    };
+    let unsubscribe_url = unsubscribe_url(&pusher.user_id, &pusher.app_id, &pusher.pushkey)?;
+
+    let mut rooms_text = String::new();
+    let mut rooms_html = String::new();
+    for (room_id, pdus) in &rooms {
+        let room_name = &room_names[room_id];
+        let link = room_link(conf, room_id);
+        let mut messages_text = String::new();
+        let mut messages_html = String::new();
+        for pdu in pdus.iter().take(MAX_MESSAGES_PER_ROOM) {
+            let sender = data::user::display_name(&pdu.sender)
+                .ok()
+                .flatten()
+                .unwrap_or_else(|| pdu.sender.to_string());
+            let body = message_body(pdu);
+            messages_text.push_str(&fill(
+                &templates.message.text,
+                &[("sender", &sender), ("body", &body)],
+            ));
+            messages_html.push_str(&fill(
+                &templates.message.html,
+                &[("sender", &escape(&sender)), ("body", &escape(&body))],
+            ));
+        }
+        if pdus.len() > MAX_MESSAGES_PER_ROOM {
+            let more = (pdus.len() - MAX_MESSAGES_PER_ROOM).to_string();
+            messages_text.push_str(&fill(&templates.more.text, &[("count", &more)]));
+            messages_html.push_str(&fill(&templates.more.html, &[("count", &more)]));
+        }
+        rooms_text.push_str(&fill(
+            &templates.room.text,
+            &[
+                ("room_name", room_name),
+                ("room_link", &link),
+                ("messages", &messages_text),
+            ],
+        ));
+        rooms_html.push_str(&fill(
+            &templates.room.html,
+            &[
+                ("room_name", &escape(room_name)),
+                ("room_link", &escape(&link)),
+                ("messages", &messages_html),
+            ],
+        ));
+    }
+    let count = pdus.len().to_string();
+    let noun = if pdus.len() == 1 {
+        "message"
+    } else {
+        "messages"
+    };
+    let text = fill(
+        &templates.notif.text,
+        &[
+            ("count", &count),
+            ("noun", noun),
+            ("app_name", &conf.app_name),
+            ("rooms", &rooms_text),
+            ("unsubscribe_url", unsubscribe_url.as_str()),
+        ],
+    );
+    let html = fill(
+        &templates.notif.html,
+        &[
+            ("count", &count),
+            ("noun", noun),
+            ("app_name", &escape(&conf.app_name)),
+            ("rooms", &rooms_html),
+            ("unsubscribe_url", &escape(unsubscribe_url.as_str())),
+        ],
+    );
+
+    crate::email::send_notification(
+        &pusher.pushkey,
+        &subject,
+        text,
+        html,
+        unsubscribe_url.as_str(),
+    )
+    .await?;
+    set_pusher_success(
+        &pusher.user_id,
+        &pusher.app_id,
+        &pusher.pushkey,
+        last_sn,
+        UnixMillis::now(),
+    )?;
+    Ok(())
+}
+
+/// The html and plain text variant of an email template.
+struct Template {
+    html: String,
+    text: String,
+}
+
+impl Template {
+    /// Loads the template from `template_dir` when it has a file of that name,
+    /// and falls back to the built in one.
+    fn load(conf: &EmailConfig, name: &str, html: &str, text: &str) -> Self {
+        let read = |ext: &str, builtin: &str| {
+            conf.template_dir
+                .as_ref()
+                .and_then(|dir| {
+                    std::fs::read_to_string(Path::new(dir).join(format!("{name}.{ext}"))).ok()
+                })
+                .unwrap_or_else(|| builtin.to_owned())
+        };
+        Self {
+            html: read("html", html),
+            text: read("txt", text),
+        }
+    }
+}
+
+/// Templates the notification emails are built from.
+struct Templates {
+    notif: Template,
+    room: Template,
+    message: Template,
+    more: Template,
+}
+
+impl Templates {
+    fn load(conf: &EmailConfig) -> Self {
+        macro_rules! template {
+            ($name:literal) => {
+                Template::load(
+                    conf,
+                    $name,
+                    include_str!(concat!("../../../templates/email/", $name, ".html")),
+                    include_str!(concat!("../../../templates/email/", $name, ".txt")),
+                )
+            };
+        }
+        Self {
+            notif: template!("notif"),
+            room: template!("notif_room"),
+            message: template!("notif_message"),
+            more: template!("notif_more"),
+        }
+    }
+}
+
+/// Replaces the `{{name}}` placeholders of a template with their values.
+/// Unknown placeholders are kept as they are.
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        filled.push_str(&rest[..start]);
+        let Some(len) = rest[start..].find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+        let placeholder = &rest[start..start + len + 2];
+        let name = placeholder[2..placeholder.len() - 2].trim();
+        match values.iter().find(|(key, _)| *key == name) {
+            Some((_, value)) => filled.push_str(value),
+            None => filled.push_str(placeholder),
+        }
+        rest = &rest[start + len + 2..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+fn escape(value: &str) -> String {
+    HtmlEscape(value).to_string()
+}
+
+fn message_body(pdu: &SnPduEvent) -> String {
+    match &pdu.event_ty {
+        TimelineEventType::RoomEncrypted => "[encrypted message]".to_owned(),
+        TimelineEventType::RoomMessage => pdu
+            .get_content::<serde_json::Value>()
+            .ok()
+            .and_then(|content| content.get("body")?.as_str().map(ToOwned::to_owned))
+            .unwrap_or_default(),
+        event_ty => format!("sent a {event_ty} event"),
+    }
+}
+
+fn room_link(conf: &EmailConfig, room_id: &RoomId) -> String {
+    match &conf.client_base_url {
+        Some(base) => format!("{}/#/room/{room_id}", base.trim_end_matches('/')),
+        None => room_id.matrix_to_uri().to_string(),
+    }
+}
+
+fn unsubscribe_url(user_id: &UserId, app_id: &str, pushkey: &str) -> AppResult<Url> {
+    let mut url = Url::parse(&format!(
+        "{}/_matrix/client/unstable/pushers/remove",
+        config::get().well_known_client().trim_end_matches('/')
+    ))?;
+    url.query_pairs_mut()
+        .append_pair("user_id", user_id.as_str())
+        .append_pair("app_id", app_id)
+        .append_pair("pushkey", pushkey)
+        .append_pair("token", &unsubscribe_token(user_id, app_id, pushkey));
+    Ok(url)
+}
+
+/// Token proving an unsubscribe link was issued by this server, so the link
+/// works without logging in.
+pub fn unsubscribe_token(user_id: &UserId, app_id: &str, pushkey: &str) -> String {
+    let signature = config::keypair().sign(format!("{user_id}\n{app_id}\n{pushkey}").as_bytes());
+    URL_SAFE_NO_PAD.encode(signature.as_bytes())
+}
+
+pub fn verify_unsubscribe_token(
+    user_id: &UserId,
+    app_id: &str,
+    pushkey: &str,
+    token: &str,
+) -> bool {
+    unsubscribe_token(user_id, app_id, pushkey)
+        .as_bytes()
+        .ct_eq(token.as_bytes())
+        .into()
+}
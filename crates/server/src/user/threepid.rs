@@ -0,0 +1,631 @@
+use std::collections::BTreeMap;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::core::UnixMillis;
+use crate::core::events::room::third_party_invite::{PublicKey, RoomThirdPartyInviteEventContent};
+use crate::core::identifiers::*;
+use crate::core::serde::{Base64, Base64DecodeError};
+use crate::core::third_party::Medium;
+use crate::core::third_party_invite::IdentityServerBase64PublicKey;
+use crate::data::user::threepid::{
+    DbThreepidSession, NewDbThreepidSession, NewDbThreepidToken, add_validation_session,
+    add_validation_token, delete_validation_session, find_validation_session, get_valid_token,
+    get_validation_session, prune_validation_sessions, set_session_send_attempt,
+    set_session_validated,
+};
+use crate::{AppError, AppResult, MatrixError, SESSION_ID_LENGTH, TOKEN_LENGTH, config, utils};
+
+/// Sessions can be used for a day after they were started, in milliseconds.
+const SESSION_LIFETIME: u64 = 24 * 60 * 60 * 1000;
+
+/// What a validation email is sent for, which decides its wording.
+#[derive(Clone, Copy, Debug)]
+pub enum ValidationPurpose {
+    Registration,
+    AddThreepid,
+    PasswordReset,
+}
+
+impl ValidationPurpose {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Registration => "registration",
+            Self::AddThreepid => "add_threepid",
+            Self::PasswordReset => "password_reset",
+        }
+    }
+
+    fn subject(&self, app_name: &str) -> String {
+        match self {
+            Self::Registration | Self::AddThreepid => format!("[{app_name}] Validate your email"),
+            Self::PasswordReset => format!("[{app_name}] Password reset"),
+        }
+    }
+
+    fn intro(&self, app_name: &str) -> String {
+        match self {
+            Self::Registration => {
+                format!("You have asked us to register this email with a new {app_name} account.")
+            }
+            Self::AddThreepid => {
+                format!("You have asked us to add this email to your {app_name} account.")
+            }
+            Self::PasswordReset => {
+                format!("A password reset request has been received for your {app_name} account.")
+            }
+        }
+    }
+}
+
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Starts or continues a validation session for an email address and sends
+/// the validation link when `send_attempt` is new. Returns the session id.
+pub async fn request_email_token(
+    client_secret: &ClientSecret,
+    email: &str,
+    send_attempt: u64,
+    next_link: Option<&str>,
+    purpose: ValidationPurpose,
+) -> AppResult<OwnedSessionId> {
+    let conf = crate::email::config()?;
+    if let Some(next_link) = next_link {
+        let url = Url::parse(next_link)
+            .map_err(|_| MatrixError::invalid_param("next_link is not a valid url"))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(MatrixError::invalid_param("next_link must be an http(s) url").into());
+        }
+    }
+
+    let send_attempt = send_attempt as i64;
+    let session_id =
+        match find_validation_session("email", email, client_secret.as_str(), purpose.as_str())? {
+            Some(session) if session.last_send_attempt >= send_attempt => {
+                return Ok(OwnedSessionId::try_from(session.session_id)?);
+            }
+            Some(session) => {
+                set_session_send_attempt(&session.session_id, send_attempt)?;
+                session.session_id
+            }
+            None => {
+                prune_validation_sessions(session_expiry())?;
+                let session_id = utils::random_string(SESSION_ID_LENGTH);
+                add_validation_session(&NewDbThreepidSession {
+                    session_id: session_id.clone(),
+                    medium: "email".to_owned(),
+                    address: email.to_owned(),
+                    client_secret: client_secret.to_string(),
+                    last_send_attempt: send_attempt,
+                    validated_at: None,
+                    created_at: UnixMillis::now(),
+                    purpose: purpose.as_str().to_owned(),
+                })?;
+                session_id
+            }
+        };
+
+    let token = utils::random_string(TOKEN_LENGTH);
+    add_validation_token(&NewDbThreepidToken {
+        token: token.clone(),
+        session_id: session_id.clone(),
+        next_link: next_link.map(ToOwned::to_owned),
+        expires_at: UnixMillis(
+            UnixMillis::now().get() + conf.validation_token_lifetime.saturating_mul(1000),
+        ),
+        created_at: UnixMillis::now(),
+    })?;
+
+    let mut link = Url::parse(&format!(
+        "{}/_matrix/client/v3/account/3pid/email/submitToken",
+        config::get().well_known_client().trim_end_matches('/')
+    ))?;
+    link.query_pairs_mut()
+        .append_pair("sid", &session_id)
+        .append_pair("client_secret", client_secret.as_str())
+        .append_pair("token", &token);
+
+    let intro = purpose.intro(&conf.app_name);
+    let text = format!(
+        "{intro}\n\nFollow this link to confirm it:\n\n{link}\n\n\
+         If this was not you, you can safely ignore this email.\n"
+    );
+    let html = format!(
+        "<p>{intro}</p><p><a href=\"{link}\">Confirm your email address</a></p>\
+         <p>If this was not you, you can safely ignore this email.</p>"
+    );
+    crate::email::send(email, &purpose.subject(&conf.app_name), text, html).await?;
+
+    Ok(OwnedSessionId::try_from(session_id)?)
+}
+
+/// Marks a session as validated if the token matches. Returns the `next_link`
+/// supplied when the token was requested.
+pub fn submit_token(
+    session_id: &SessionId,
+    client_secret: &ClientSecret,
+    token: &str,
+) -> AppResult<Option<String>> {
+    let session = get_validation_session(session_id.as_str(), client_secret.as_str())?
+        .ok_or_else(|| MatrixError::threepid_auth_failed("Unknown validation session."))?;
+    let token = get_valid_token(&session.session_id, token)?
+        .ok_or_else(|| MatrixError::threepid_auth_failed("Invalid or expired token."))?;
+    if session.validated_at.is_none() {
+        set_session_validated(&session.session_id, UnixMillis::now())?;
+    }
+    Ok(token.next_link)
+}
+
+/// Sessions created before this are expired.
+fn session_expiry() -> UnixMillis {
+    UnixMillis(UnixMillis::now().get().saturating_sub(SESSION_LIFETIME))
+}
+
+/// Returns the session if the client has proven ownership of its address for
+/// the given purpose.
+pub fn get_validated_session(
+    session_id: &SessionId,
+    client_secret: &ClientSecret,
+    purpose: ValidationPurpose,
+) -> AppResult<DbThreepidSession> {
+    let session = get_validation_session(session_id.as_str(), client_secret.as_str())?
+        .filter(|session| session.purpose == purpose.as_str())
+        .filter(|session| session.created_at >= session_expiry())
+        .ok_or_else(|| MatrixError::threepid_auth_failed("Unknown validation session."))?;
+    if session.validated_at.is_none() {
+        return Err(MatrixError::threepid_auth_failed("Email has not been validated yet.").into());
+    }
+    Ok(session)
+}
+
+/// Like `get_validated_session`, but removes the session so it can not be
+/// used again.
+pub fn consume_validated_session(
+    session_id: &SessionId,
+    client_secret: &ClientSecret,
+    purpose: ValidationPurpose,
+) -> AppResult<DbThreepidSession> {
+    let session = get_validated_session(session_id, client_secret, purpose)?;
+    if !delete_validation_session(&session.session_id)? {
+        return Err(
+            MatrixError::threepid_auth_failed("Validation session was already used.").into(),
+        );
+    }
+    Ok(session)
+}
+
+/// Asks an identity server to remove the association of a threepid with the
+/// user. Returns whether the identity server accepted the request.
+pub async fn unbind_from_id_server(
+    user_id: &UserId,
+    medium: &Medium,
+    address: &str,
+    id_server: &str,
+) -> AppResult<bool> {
+    let result: AppResult<()> = async {
+        ensure_trusted_identity_server(id_server)?;
+        let access_token = register_on_id_server(user_id, id_server).await?;
+        crate::sending::default_client()
+            .post(format!(
+                "{}/_matrix/identity/v2/3pid/unbind",
+                identity_server_url(id_server)
+            ))
+            .bearer_auth(access_token)
+            .json(&serde_json::json!({
+                "mxid": user_id,
+                "threepid": {
+                    "medium": medium.as_str(),
+                    "address": address,
+                },
+            }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+    .await;
+    match result {
+        Ok(()) => {
+            crate::data::user::threepid::remove_threepid_id_server(
+                user_id,
+                medium.as_str(),
+                address,
+                id_server,
+            )?;
+            Ok(true)
+        }
+        Err(e) => {
+            warn!("failed to unbind {address} from identity server {id_server}: {e}");
+            Ok(false)
+        }
+    }
+}
+
+/// Gets an access token of the identity server for the user, by proving who
+/// the user is with an OpenID token.
+async fn register_on_id_server(user_id: &UserId, id_server: &str) -> AppResult<String> {
+    #[derive(Deserialize)]
+    struct Registered {
+        token: String,
+    }
+    let (openid_token, expires_in) = crate::user::create_openid_token(user_id)?;
+    let registered = crate::sending::default_client()
+        .post(format!(
+            "{}/_matrix/identity/v2/account/register",
+            identity_server_url(id_server)
+        ))
+        .json(&serde_json::json!({
+            "access_token": openid_token,
+            "token_type": "Bearer",
+            "matrix_server_name": config::server_name(),
+            "expires_in": expires_in,
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Registered>()
+        .await?;
+    Ok(registered.token)
+}
+
+/// Fails unless the identity server is listed in `trusted_identity_servers`
+/// or `insecure_identity_servers`, so clients can not make the server send
+/// requests to arbitrary hosts.
+pub fn ensure_trusted_identity_server(id_server: &str) -> AppResult<()> {
+    let conf = config::get();
+    if conf
+        .trusted_identity_servers
+        .iter()
+        .chain(&conf.insecure_identity_servers)
+        .any(|server| server == id_server)
+    {
+        Ok(())
+    } else {
+        Err(
+            MatrixError::forbidden(format!("Identity server {id_server} is not trusted."), None)
+                .into(),
+        )
+    }
+}
+
+/// Base URL of the identity server, reached over HTTPS unless it is listed in
+/// `insecure_identity_servers`.
+pub fn identity_server_url(id_server: &str) -> String {
+    if config::get()
+        .insecure_identity_servers
+        .iter()
+        .any(|server| server == id_server)
+    {
+        format!("http://{id_server}")
+    } else {
+        format!("https://{id_server}")
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct HashDetails {
+    lookup_pepper: String,
+    algorithms: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LookupResult {
+    #[serde(default)]
+    mappings: BTreeMap<String, OwnedUserId>,
+}
+
+/// Looks up the user the threepid is bound to on the identity server.
+pub async fn lookup_on_id_server(
+    id_server: &str,
+    id_access_token: &str,
+    medium: &Medium,
+    address: &str,
+) -> AppResult<Option<OwnedUserId>> {
+    ensure_trusted_identity_server(id_server)?;
+    lookup_address(
+        &crate::sending::default_client(),
+        &identity_server_url(id_server),
+        id_access_token,
+        medium,
+        address,
+    )
+    .await
+}
+
+async fn lookup_address(
+    client: &reqwest::Client,
+    base_url: &str,
+    id_access_token: &str,
+    medium: &Medium,
+    address: &str,
+) -> AppResult<Option<OwnedUserId>> {
+    let details = client
+        .get(format!("{base_url}/_matrix/identity/v2/hash_details"))
+        .bearer_auth(id_access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<HashDetails>()
+        .await?;
+
+    let lookup = format!("{address} {}", medium.as_str());
+    let (algorithm, hashed_address) = if details.algorithms.iter().any(|a| a == "sha256") {
+        let hash = Sha256::digest(format!("{lookup} {}", details.lookup_pepper));
+        ("sha256", URL_SAFE_NO_PAD.encode(hash))
+    } else if details.algorithms.iter().any(|a| a == "none") {
+        ("none", lookup)
+    } else {
+        return Err(AppError::public(format!(
+            "identity server {base_url} supports no known lookup algorithm"
+        )));
+    };
+
+    let result = client
+        .post(format!("{base_url}/_matrix/identity/v2/lookup"))
+        .bearer_auth(id_access_token)
+        .json(&serde_json::json!({
+            "algorithm": algorithm,
+            "pepper": details.lookup_pepper,
+            "addresses": [hashed_address],
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<LookupResult>()
+        .await?;
+    Ok(result.mappings.get(&hashed_address).cloned())
+}
+
+#[derive(Deserialize, Debug)]
+struct StoredInvite {
+    token: String,
+    #[serde(default)]
+    public_keys: Vec<StoredInviteKey>,
+    #[serde(default)]
+    display_name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct StoredInviteKey {
+    public_key: String,
+    key_validity_url: Option<String>,
+}
+
+/// Stores an invite of the threepid on the identity server, which hands it to
+/// the user the threepid gets bound to. Returns the token of the invite with
+/// the content of its `m.room.third_party_invite` event.
+pub async fn store_invite_on_id_server(
+    id_server: &str,
+    id_access_token: &str,
+    medium: &Medium,
+    address: &str,
+    room_id: &RoomId,
+    sender_id: &UserId,
+) -> AppResult<(String, RoomThirdPartyInviteEventContent)> {
+    ensure_trusted_identity_server(id_server)?;
+    let invite = serde_json::json!({
+        "medium": medium.as_str(),
+        "address": address,
+        "room_id": room_id,
+        "sender": sender_id,
+        "room_name": crate::room::get_name(room_id).ok(),
+        "sender_display_name": crate::data::user::display_name(sender_id).ok().flatten(),
+    });
+    store_invite(
+        &crate::sending::default_client(),
+        &identity_server_url(id_server),
+        id_access_token,
+        &invite,
+    )
+    .await
+}
+
+async fn store_invite(
+    client: &reqwest::Client,
+    base_url: &str,
+    id_access_token: &str,
+    invite: &serde_json::Value,
+) -> AppResult<(String, RoomThirdPartyInviteEventContent)> {
+    let response = client
+        .post(format!("{base_url}/_matrix/identity/v2/store-invite"))
+        .bearer_auth(id_access_token)
+        .json(invite)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        warn!(
+            "identity server {base_url} refused to store invite of {}: {}",
+            invite["address"],
+            response.status()
+        );
+        return Err(MatrixError::unknown("Identity server refused to store the invite.").into());
+    }
+    let stored = response.json::<StoredInvite>().await?;
+
+    let Some(first_key) = stored.public_keys.first() else {
+        return Err(AppError::public(format!(
+            "identity server {base_url} stored an invite without public key"
+        )));
+    };
+    let mut content = RoomThirdPartyInviteEventContent::new(
+        stored.display_name,
+        first_key.key_validity_url.clone().unwrap_or_default(),
+        IdentityServerBase64PublicKey(first_key.public_key.clone()),
+    );
+    let public_keys = stored
+        .public_keys
+        .into_iter()
+        .map(|key| {
+            Ok(PublicKey {
+                key_validity_url: key.key_validity_url,
+                public_key: Base64::parse(&key.public_key)?,
+            })
+        })
+        .collect::<Result<Vec<_>, Base64DecodeError>>()
+        .map_err(|e| {
+            AppError::public(format!(
+                "identity server {base_url} sent an invalid key: {e}"
+            ))
+        })?;
+    content.public_keys = Some(public_keys);
+    Ok((stored.token, content))
+}
+
+/// Checks with the identity server that the public key was not revoked.
+///
+/// The url comes from room state, which anyone in the room can set, so only
+/// its path is used and it must point to a trusted identity server.
+pub async fn is_key_valid(key_validity_url: &str, public_key: &str) -> AppResult<bool> {
+    #[derive(Deserialize)]
+    struct KeyValidity {
+        valid: bool,
+    }
+    let url = Url::parse(key_validity_url)?;
+    let Some(host) = url.host_str() else {
+        return Err(MatrixError::invalid_param("key validity url has no host").into());
+    };
+    let id_server = match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_owned(),
+    };
+    ensure_trusted_identity_server(&id_server)?;
+    let mut url = Url::parse(&format!(
+        "{}{}",
+        identity_server_url(&id_server),
+        url.path()
+    ))?;
+    url.query_pairs_mut().append_pair("public_key", public_key);
+    let validity = crate::sending::default_client()
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<KeyValidity>()
+        .await?;
+    Ok(validity.valid)
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::conn::{Acceptor, TcpListener};
+    use salvo::prelude::*;
+
+    use super::*;
+
+    #[handler]
+    async fn hash_details(req: &mut Request, res: &mut Response) {
+        assert_eq!(
+            req.header::<String>("authorization").unwrap(),
+            "Bearer secret"
+        );
+        res.render(Json(serde_json::json!({
+            "lookup_pepper": "pepper",
+            "algorithms": ["none", "sha256"],
+        })));
+    }
+
+    #[handler]
+    async fn lookup(req: &mut Request, res: &mut Response) {
+        let body: serde_json::Value = req.parse_json().await.unwrap();
+        assert_eq!(body["algorithm"], "sha256");
+        assert_eq!(body["pepper"], "pepper");
+        let hash = Sha256::digest("bob@example.org email pepper");
+        let known = URL_SAFE_NO_PAD.encode(hash);
+        let mappings = body["addresses"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|address| **address == *known)
+            .map(|address| (address.as_str().unwrap().to_owned(), "@bob:example.org"))
+            .collect::<BTreeMap<_, _>>();
+        res.render(Json(serde_json::json!({ "mappings": mappings })));
+    }
+
+    #[handler]
+    async fn store_invite_handler(req: &mut Request, res: &mut Response) {
+        let body: serde_json::Value = req.parse_json().await.unwrap();
+        assert_eq!(body["address"], "carol@example.org");
+        res.render(Json(serde_json::json!({
+            "token": "invite-token",
+            "display_name": "c...@e...",
+            "public_keys": [{
+                "public_key": "c2VjcmV0IGtleQ",
+                "key_validity_url": "https://id.example.org/_matrix/identity/v2/pubkey/isvalid",
+            }],
+        })));
+    }
+
+    /// Serves a fake identity server, returning its base url.
+    async fn identity_server() -> String {
+        let router = Router::with_path("_matrix/identity/v2")
+            .push(Router::with_path("hash_details").get(hash_details))
+            .push(Router::with_path("lookup").post(lookup))
+            .push(Router::with_path("store-invite").post(store_invite_handler));
+        let acceptor = TcpListener::new("127.0.0.1:0").bind().await;
+        let addr = acceptor.holdings()[0]
+            .local_addr
+            .clone()
+            .into_std()
+            .unwrap();
+        tokio::spawn(Server::new(acceptor).serve(router));
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn looks_up_bound_threepids() {
+        let base_url = identity_server().await;
+        let client = reqwest::Client::new();
+        let bound = lookup_address(
+            &client,
+            &base_url,
+            "secret",
+            &Medium::Email,
+            "bob@example.org",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            bound.as_deref().map(UserId::as_str),
+            Some("@bob:example.org")
+        );
+
+        let unbound = lookup_address(
+            &client,
+            &base_url,
+            "secret",
+            &Medium::Email,
+            "carol@example.org",
+        )
+        .await
+        .unwrap();
+        assert_eq!(unbound, None);
+    }
+
+    #[tokio::test]
+    async fn stores_invites_of_unbound_threepids() {
+        let base_url = identity_server().await;
+        let invite = serde_json::json!({
+            "medium": "email",
+            "address": "carol@example.org",
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+        });
+        let (token, content) = store_invite(&reqwest::Client::new(), &base_url, "secret", &invite)
+            .await
+            .unwrap();
+        assert_eq!(token, "invite-token");
+        assert_eq!(content.display_name, "c...@e...");
+        assert_eq!(
+            content.key_validity_url,
+            "https://id.example.org/_matrix/identity/v2/pubkey/isvalid"
+        );
+        assert_eq!(content.public_key.0, "c2VjcmV0IGtleQ");
+        assert_eq!(content.public_keys.unwrap().len(), 1);
+    }
+}
@@ -47,6 +47,38 @@ macro_rules! extract_variant {
     };
 }
 
+/// Runs `f` on the blocking thread pool.
+///
+/// Meant for the synchronous database sections of request paths, such as sync
+/// or sending events, so they don't stall the async worker threads. Async work
+/// around them stays on the runtime.
+pub async fn spawn_blocking<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let span = tracing::Span::current();
+    match tokio::task::spawn_blocking(move || span.in_scope(f)).await {
+        Ok(output) => output,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Runs `future` in place on the current worker thread, after the runtime
+/// moved the other tasks of this thread to another one.
+///
+/// For the database sections of request paths which borrow request state, like
+/// a room state lock, so can't go through [`spawn_blocking`]. They must not
+/// wait on the network.
+pub async fn run_in_place<F: Future>(future: F) -> F::Output {
+    let handle = tokio::runtime::Handle::current();
+    if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread {
+        tokio::task::block_in_place(|| handle.block_on(future))
+    } else {
+        future.await
+    }
+}
+
 pub fn select_config_path() -> &'static str {
     if cfg!(windows) {
         "palpo.toml"
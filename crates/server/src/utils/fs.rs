@@ -6,7 +6,7 @@ use std::path::{Path, PathBuf};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 
-use crate::AppResult;
+use crate::{AppError, AppResult};
 
 /// Validates that a directory path is safe and does not contain path traversal sequences.
 ///
@@ -131,6 +131,34 @@ pub fn write_text<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> AppRe
     Ok(())
 }
 
+/// Prepares `path` for binding a UNIX socket: creates its parent directory and
+/// removes a socket left behind by a previous run that did not shut down
+/// cleanly. Fails if the path is not a socket or is still being listened on.
+#[cfg(unix)]
+pub fn prepare_unix_socket(path: &Path) -> AppResult<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixStream;
+
+    std::fs::create_dir_all(get_parent_dir(path))?;
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(AppError::internal(format!(
+            "{} already exists and is not a UNIX socket",
+            path.display()
+        )));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(AppError::internal(format!(
+            "UNIX socket {} is in use by another process",
+            path.display()
+        )));
+    }
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
 pub fn get_parent_dir<T>(path: T) -> PathBuf
 where
     T: AsRef<Path>,
@@ -53,3 +53,29 @@ pub fn current_exe_deleted() -> bool {
     std::env::current_exe()
         .is_ok_and(|exe| exe.to_str().is_some_and(|exe| exe.ends_with(" (deleted)")))
 }
+
+/// Replaces the current process with a new instance of the server, started
+/// with the same arguments. Only returns if that failed.
+#[cfg(unix)]
+pub fn restart() -> AppResult<()> {
+    use std::os::unix::process::CommandExt;
+
+    // SAFETY: the server is started again from the path it was started from.
+    let exe = unsafe { current_exe()? };
+    let err = std::process::Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .exec();
+    Err(err.into())
+}
+
+/// Starts a new instance of the server with the same arguments, the current
+/// one exits once this returns.
+#[cfg(not(unix))]
+pub fn restart() -> AppResult<()> {
+    // SAFETY: the server is started again from the path it was started from.
+    let exe = unsafe { current_exe()? };
+    std::process::Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .spawn()?;
+    Ok(())
+}
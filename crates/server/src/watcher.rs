@@ -1,5 +1,6 @@
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::time::Duration;
 
 use diesel::prelude::*;
@@ -10,111 +11,107 @@ use crate::AppResult;
 use crate::core::Seqnum;
 use crate::core::identifiers::*;
 use crate::data::schema::*;
-use crate::data::{self, connect};
+use crate::data::{self, DataResult};
 
 pub async fn watch(user_id: &UserId, device_id: &DeviceId) -> AppResult<()> {
+    // `joined_rooms` takes its own connection, so it is loaded before the one
+    // used for the positions.
+    let room_ids = {
+        let user_id = user_id.to_owned();
+        Arc::new(crate::utils::spawn_blocking(move || data::user::joined_rooms(&user_id)).await?)
+    };
+    let positions = {
+        let (user_id, device_id, room_ids) =
+            (user_id.to_owned(), device_id.to_owned(), room_ids.clone());
+        data::connect_async(move |conn| latest_positions(conn, &user_id, &device_id, &room_ids))
+            .await?
+    };
+
+    let mut futures: FuturesUnordered<Pin<Box<dyn Future<Output = AppResult<()>> + Send>>> =
+        FuturesUnordered::new();
+
+    for room_id in room_ids.iter() {
+        let room_id = room_id.clone();
+        futures.push(Box::into_pin(Box::new(async move {
+            crate::room::typing::wait_for_update(&room_id).await
+        })));
+    }
+    let user_id = user_id.to_owned();
+    let device_id = device_id.to_owned();
+    futures.push(Box::into_pin(Box::new(async move {
+        loop {
+            let (user_id, device_id, room_ids) =
+                (user_id.clone(), device_id.clone(), room_ids.clone());
+            let latest = data::connect_async(move |conn| {
+                latest_positions(conn, &user_id, &device_id, &room_ids)
+            })
+            .await?;
+            if latest
+                .iter()
+                .zip(positions)
+                .any(|(latest, pos)| *latest > pos)
+            {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    })));
+    // Return early on shutdown, so waiting syncs don't hold it up.
+    let rotate = crate::ROTATE.watch();
+    futures.push(Box::pin(async move {
+        rotate.await;
+        Ok(())
+    }));
+    // Wait until one of them finds something
+    futures.next().await;
+    Ok(())
+}
+
+/// Latest ids of the device inbox, key changes, memberships, room events and
+/// account data seen by the user, in this order.
+fn latest_positions(
+    conn: &mut PgConnection,
+    user_id: &UserId,
+    device_id: &DeviceId,
+    room_ids: &[OwnedRoomId],
+) -> DataResult<[i64; 5]> {
     let inbox_id = device_inboxes::table
         .filter(device_inboxes::user_id.eq(user_id))
         .filter(device_inboxes::device_id.eq(device_id))
         .order_by(device_inboxes::id.desc())
         .select(device_inboxes::id)
-        .first::<i64>(&mut connect()?)
+        .first::<i64>(conn)
         .unwrap_or_default();
     let key_change_id = e2e_key_changes::table
         .filter(e2e_key_changes::user_id.eq(user_id))
         .order_by(e2e_key_changes::id.desc())
         .select(e2e_key_changes::id)
-        .first::<i64>(&mut connect()?)
+        .first::<i64>(conn)
         .unwrap_or_default();
     let room_user_id = room_users::table
         .filter(room_users::user_id.eq(user_id))
         .order_by(room_users::id.desc())
         .select(room_users::id)
-        .first::<i64>(&mut connect()?)
+        .first::<i64>(conn)
         .unwrap_or_default();
-
-    let room_ids = data::user::joined_rooms(user_id)?;
     let last_event_sn = event_points::table
-        .filter(event_points::room_id.eq_any(&room_ids))
+        .filter(event_points::room_id.eq_any(room_ids))
         .filter(event_points::frame_id.is_not_null())
         .order_by(event_points::event_sn.desc())
         .select(event_points::event_sn)
-        .first::<Seqnum>(&mut connect()?)
+        .first::<Seqnum>(conn)
         .unwrap_or_default();
-
     let push_rule_sn = user_datas::table
         .filter(user_datas::user_id.eq(user_id))
         .order_by(user_datas::occur_sn.desc())
         .select(user_datas::occur_sn)
-        .first::<i64>(&mut connect()?)
+        .first::<i64>(conn)
         .unwrap_or_default();
-
-    let mut futures: FuturesUnordered<Pin<Box<dyn Future<Output = AppResult<()>> + Send>>> =
-        FuturesUnordered::new();
-
-    for room_id in room_ids.clone() {
-        futures.push(Box::into_pin(Box::new(async move {
-            crate::room::typing::wait_for_update(&room_id).await
-        })));
-    }
-    futures.push(Box::into_pin(Box::new(async move {
-        loop {
-            if inbox_id
-                < device_inboxes::table
-                    .filter(device_inboxes::user_id.eq(user_id))
-                    .filter(device_inboxes::device_id.eq(device_id))
-                    .order_by(device_inboxes::id.desc())
-                    .select(device_inboxes::id)
-                    .first::<i64>(&mut connect()?)
-                    .unwrap_or_default()
-            {
-                return Ok(());
-            }
-            if key_change_id
-                < e2e_key_changes::table
-                    .filter(e2e_key_changes::user_id.eq(user_id))
-                    .order_by(e2e_key_changes::id.desc())
-                    .select(e2e_key_changes::id)
-                    .first::<i64>(&mut connect()?)
-                    .unwrap_or_default()
-            {
-                return Ok(());
-            }
-            if room_user_id
-                < room_users::table
-                    .filter(room_users::user_id.eq(user_id))
-                    .order_by(room_users::id.desc())
-                    .select(room_users::id)
-                    .first::<i64>(&mut connect()?)
-                    .unwrap_or_default()
-            {
-                return Ok(());
-            }
-            if last_event_sn
-                < event_points::table
-                    .filter(event_points::room_id.eq_any(&room_ids))
-                    .filter(event_points::frame_id.is_not_null())
-                    .order_by(event_points::event_sn.desc())
-                    .select(event_points::event_sn)
-                    .first::<Seqnum>(&mut connect()?)
-                    .unwrap_or_default()
-            {
-                return Ok(());
-            }
-            if push_rule_sn
-                < user_datas::table
-                    .filter(user_datas::user_id.eq(user_id))
-                    .order_by(user_datas::occur_sn.desc())
-                    .select(user_datas::occur_sn)
-                    .first::<i64>(&mut connect()?)
-                    .unwrap_or_default()
-            {
-                return Ok(());
-            }
-            tokio::time::sleep(Duration::from_secs(1)).await;
-        }
-    })));
-    // Wait until one of them finds something
-    futures.next().await;
-    Ok(())
+    Ok([
+        inbox_id,
+        key_change_id,
+        room_user_id,
+        last_event_sn,
+        push_rule_sn,
+    ])
 }
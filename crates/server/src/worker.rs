@@ -0,0 +1,37 @@
+//! Roles of this process, when the subsystems are split across several
+//! processes sharing the database.
+
+use std::sync::OnceLock;
+
+use crate::config::{self, WorkerRole};
+use crate::{AppError, AppResult};
+
+static ROLES: OnceLock<Vec<WorkerRole>> = OnceLock::new();
+
+/// Sets the roles of this process, those given on the command line take
+/// precedence over the configured ones.
+///
+/// Split roles need a shared coordination backend, otherwise the other
+/// processes are not notified of the work queued by this one.
+pub fn init(roles: Vec<WorkerRole>) -> AppResult<()> {
+    let roles = if roles.is_empty() {
+        config::get().worker.roles.clone()
+    } else {
+        roles
+    };
+    if !roles.contains(&WorkerRole::All) && !config::get().coordination.is_shared() {
+        return Err(AppError::public(format!(
+            "running with roles {roles:?} requires the \"postgres\" coordination backend"
+        )));
+    }
+    info!("running with roles {roles:?}");
+    let _ = ROLES.set(roles);
+    Ok(())
+}
+
+/// Whether this process runs the subsystem, everything does before `init`.
+pub fn has_role(role: WorkerRole) -> bool {
+    ROLES
+        .get()
+        .is_none_or(|roles| roles.contains(&WorkerRole::All) || roles.contains(&role))
+}